@@ -20,6 +20,27 @@ fn main() {
     println!("cargo:rerun-if-changed=cuttlefish/CMakeLists.txt");
     println!("cargo:rerun-if-changed=piscem-cpp/CMakeLists.txt");
 
+    // record the commits of the bundled C++ sources, which `piscem build` writes
+    // into the provenance of every index; they are read from the superproject, so
+    // nothing is recorded when building outside of a git checkout.
+    for (var, submodule) in [
+        ("PISCEM_CUTTLEFISH_COMMIT", "cuttlefish"),
+        ("PISCEM_CPP_COMMIT", "piscem-cpp"),
+    ] {
+        if let Ok(out) = std::process::Command::new("git")
+            .args(["rev-parse", &format!("HEAD:{}", submodule)])
+            .output()
+        {
+            if out.status.success() {
+                println!(
+                    "cargo:rustc-env={}={}",
+                    var,
+                    String::from_utf8_lossy(&out.stdout).trim()
+                );
+            }
+        }
+    }
+
     let mut cfg_piscem_cpp = Box::new(Config::new("piscem-cpp"));
     let mut cfg_cf = Box::new(Config::new("cuttlefish"));
 
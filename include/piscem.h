@@ -0,0 +1,59 @@
+/*
+ * The C interface of the piscem shared library (libpiscem), available when
+ * built with the `capi` feature:
+ *
+ *     cargo rustc --release --lib --features capi --crate-type cdylib
+ *
+ * Commands take the same options as the piscem executable, as an argument
+ * vector without the program and command names. They return 0 on success and
+ * otherwise the exit code the executable would report (see the README), with
+ * the error message available from piscem_last_error().
+ */
+#ifndef PISCEM_H
+#define PISCEM_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* flags: only log warnings and errors, and pass --quiet to the mapper and indexer */
+#define PISCEM_QUIET 1u
+/* flags: validate the command and print what it would run, without running it */
+#define PISCEM_DRY_RUN 2u
+
+/*
+ * Build an index; argv holds the argc options of `piscem build`, e.g.
+ * {"--ref-seqs", "tx.fa", "--output", "idx", "--threads", "8"}.
+ */
+int piscem_build(int argc, const char *const *argv, unsigned int flags);
+
+/*
+ * Map reads with the command `command` ("map-sc", "map-sc-atac" or
+ * "map-bulk"), whose argc options are given in argv.
+ */
+int piscem_map(const char *command, int argc, const char *const *argv, unsigned int flags);
+
+/*
+ * The statistics of the index with the prefix `index`, as reported by
+ * `piscem inspect --json`, or NULL on failure. The string must be released
+ * with piscem_free_string().
+ */
+char *piscem_index_info(const char *index);
+
+/* Release a string returned by piscem_index_info(). */
+void piscem_free_string(char *s);
+
+/*
+ * The message of the last error reported by a call on this thread, or NULL if
+ * it succeeded. The string is valid until the next call on this thread.
+ */
+const char *piscem_last_error(void);
+
+/* The version of piscem. */
+const char *piscem_version(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PISCEM_H */
@@ -0,0 +1,214 @@
+use anyhow::{bail, Context, Result};
+use flate2::read::MultiGzDecoder;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::fragments;
+use crate::permit_list::decode_barcode;
+use crate::refinfo::RefInfo;
+
+/// The name of the file, written into the output directory by `map-sc-atac` when
+/// `--barcode-stats` is given, that lists the QC metrics of every barcode.
+pub(crate) const BARCODE_STATS_FILE: &str = "barcode_stats.tsv";
+
+/// The sorted, non-overlapping regions of each reference listed in a blacklist.
+struct Blacklist {
+    regions: Vec<Vec<(u32, u32)>>,
+}
+
+impl Blacklist {
+    /// Read the regions of the references `refs` from the BED file (possibly
+    /// gzipped) at `path`. Header lines are skipped, and regions on references
+    /// that are not in `refs` are ignored.
+    fn from_bed(path: &Path, refs: &RefInfo) -> Result<Self> {
+        let f = File::open(path)
+            .with_context(|| format!("could not open blacklist {}", path.display()))?;
+        let reader: Box<dyn Read> = if path.extension().is_some_and(|e| e == "gz") {
+            Box::new(MultiGzDecoder::new(f))
+        } else {
+            Box::new(f)
+        };
+        let ref_ids = refs
+            .names
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (n.as_str(), i))
+            .collect::<HashMap<&str, usize>>();
+        let mut regions = vec![Vec::new(); refs.names.len()];
+        let mut num_unknown = 0_u64;
+        for (line_num, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser")
+            {
+                continue;
+            }
+            let fields = line.split('\t').collect::<Vec<&str>>();
+            let (start, end) = match (
+                fields.get(1).and_then(|s| s.trim().parse::<u32>().ok()),
+                fields.get(2).and_then(|s| s.trim().parse::<u32>().ok()),
+            ) {
+                (Some(s), Some(e)) => (s, e),
+                _ => bail!(
+                    "line {} of blacklist {} is not a valid BED record.",
+                    line_num + 1,
+                    path.display()
+                ),
+            };
+            match ref_ids.get(fields[0]) {
+                Some(&r) => regions[r].push((start, end)),
+                None => num_unknown += 1,
+            }
+        }
+        if num_unknown > 0 {
+            warn!(
+                "{} regions of blacklist {} are on references that are not in the index; they were ignored.",
+                num_unknown,
+                path.display()
+            );
+        }
+        for r in regions.iter_mut() {
+            r.sort_unstable();
+            let mut merged: Vec<(u32, u32)> = Vec::with_capacity(r.len());
+            for &(s, e) in r.iter() {
+                match merged.last_mut() {
+                    Some(last) if s <= last.1 => last.1 = last.1.max(e),
+                    _ => merged.push((s, e)),
+                }
+            }
+            *r = merged;
+        }
+        Ok(Self { regions })
+    }
+
+    /// Whether the interval [`start`, `end`) of reference `r` overlaps a region.
+    fn overlaps(&self, r: usize, start: u32, end: u32) -> bool {
+        let regions = &self.regions[r];
+        // the first region ending after `start` is the only one that can overlap
+        let i = regions.partition_point(|&(_, e)| e <= start);
+        regions.get(i).is_some_and(|&(s, _)| s < end)
+    }
+}
+
+/// The fragment counts of a barcode.
+#[derive(Default)]
+struct BarcodeCounts {
+    total: u64,
+    mito: u64,
+    blacklist: u64,
+}
+
+/// Compute the QC metrics of every barcode from the distinct fragments of the
+/// uniquely mapped read pairs in the single-cell ATAC-seq RAD file at `rad_path`
+/// (see `fragments::collect_fragments`): the number of fragments, along with the
+/// number and fraction of those on a mitochondrial reference (any of the references
+/// of `refs` named in `mito_refs`) and overlapping a region of the BED file
+/// `blacklist`, if given. The metrics are written to `barcode_stats.tsv` in
+/// `out_dir`, from the barcode with the most fragments to that with the fewest.
+/// Returns a summary of the metrics, including the number of fragments on each
+/// reference.
+pub(crate) fn write_barcode_stats(
+    rad_path: &Path,
+    refs: &RefInfo,
+    mito_refs: &[String],
+    blacklist: Option<&Path>,
+    out_dir: &Path,
+) -> Result<Value> {
+    let is_mito = refs
+        .names
+        .iter()
+        .map(|n| mito_refs.contains(n))
+        .collect::<Vec<bool>>();
+    if !is_mito.contains(&true) {
+        warn!(
+            "none of the mitochondrial references ({}) is in the index; no fragment will be counted as mitochondrial.",
+            mito_refs.join(", ")
+        );
+    }
+    let blacklist = blacklist
+        .map(|p| Blacklist::from_bed(p, refs))
+        .transpose()?;
+
+    let (frags, bc_len) = fragments::collect_fragments(rad_path, refs)?;
+    let mut counts = HashMap::<u64, BarcodeCounts>::new();
+    let mut ref_counts = vec![0_u64; refs.names.len()];
+    // fragments are sorted, so duplicates are adjacent and counted once
+    for group in frags.frags.chunk_by(|a, b| a == b) {
+        let frag = group[0];
+        let r = frag.ref_id as usize;
+        let c = counts.entry(frag.barcode).or_default();
+        c.total += 1;
+        if is_mito[r] {
+            c.mito += 1;
+        }
+        if blacklist
+            .as_ref()
+            .is_some_and(|b| b.overlaps(r, frag.start, frag.end))
+        {
+            c.blacklist += 1;
+        }
+        ref_counts[r] += 1;
+    }
+    let mut sorted = counts.into_iter().collect::<Vec<(u64, BarcodeCounts)>>();
+    sorted.sort_unstable_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(&b.0)));
+
+    let path = out_dir.join(BARCODE_STATS_FILE);
+    let f = File::create(&path).with_context(|| format!("could not create {}", path.display()))?;
+    let mut out = BufWriter::new(f);
+    writeln!(
+        out,
+        "barcode\tnum_fragments\tnum_mito_fragments\tmito_fraction\tnum_blacklist_fragments\tblacklist_fraction"
+    )?;
+    for (bc, c) in &sorted {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{:.6}\t{}\t{:.6}",
+            decode_barcode(*bc, bc_len),
+            c.total,
+            c.mito,
+            c.mito as f64 / c.total as f64,
+            c.blacklist,
+            c.blacklist as f64 / c.total as f64
+        )?;
+    }
+    out.flush()?;
+
+    let num_frags = ref_counts.iter().sum::<u64>();
+    let num_mito = sorted.iter().map(|(_, c)| c.mito).sum::<u64>();
+    let num_blacklist = sorted.iter().map(|(_, c)| c.blacklist).sum::<u64>();
+    info!(
+        "wrote the QC metrics of {} barcodes ({} fragments, {} mitochondrial) to {}.",
+        sorted.len(),
+        num_frags,
+        num_mito,
+        path.display()
+    );
+    let per_ref = refs
+        .names
+        .iter()
+        .zip(ref_counts.iter())
+        .filter(|(_, c)| **c > 0)
+        .map(|(n, c)| (n.clone(), json!(c)))
+        .collect::<Map<String, Value>>();
+    Ok(json!({
+        "file": BARCODE_STATS_FILE,
+        "num_barcodes": sorted.len(),
+        "num_fragments": num_frags,
+        "num_mito_fragments": num_mito,
+        "num_blacklist_fragments": blacklist.is_some().then_some(num_blacklist),
+        "mito_refs": refs
+            .names
+            .iter()
+            .zip(is_mito.iter())
+            .filter(|(_, m)| **m)
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<String>>(),
+        "fragments_per_ref": per_ref,
+    }))
+}
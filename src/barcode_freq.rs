@@ -0,0 +1,114 @@
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tracing::info;
+
+use crate::permit_list::decode_barcode;
+use crate::rad::RadReader;
+
+/// The name of the file, written into the output directory by `map-sc` when
+/// `--barcode-freqs` is given, that lists the number of reads of every barcode.
+pub(crate) const BARCODE_FREQ_FILE: &str = "barcode_frequencies.tsv";
+
+/// Estimate the number of cells from the barcode counts `sorted`, in decreasing
+/// order, as the knee of the barcode rank plot: the rank, on log-log scale, that
+/// is farthest above the line joining the first and the last barcode. Returns
+/// `None` if there are too few distinct counts for a knee to be found.
+fn knee_point(sorted: &[u64]) -> Option<usize> {
+    let (first, last) = (*sorted.first()?, *sorted.last()?);
+    if sorted.len() < 3 || first == last {
+        return None;
+    }
+    let point = |i: usize| (((i + 1) as f64).log10(), (sorted[i] as f64).log10());
+    let (x0, y0) = point(0);
+    let (x1, y1) = point(sorted.len() - 1);
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    let mut best = None;
+    let mut best_dist = 0.0;
+    for i in 1..sorted.len() - 1 {
+        // only the last of a run of barcodes with the same count can be the knee
+        if sorted[i + 1] == sorted[i] {
+            continue;
+        }
+        let (x, y) = point(i);
+        let dist = (dx * (y - y0) - dy * (x - x0)) / dx.hypot(dy);
+        if dist > best_dist {
+            best_dist = dist;
+            best = Some(i + 1);
+        }
+    }
+    best
+}
+
+/// Count the reads of every barcode in the RAD file at `rad_path` and write the
+/// counts, from the most to the least frequent barcode, to `barcode_frequencies.tsv`
+/// in `out_dir`. Returns a summary of the counts, along with the number of cells
+/// estimated from the knee of the barcode rank plot.
+pub(crate) fn write_barcode_freqs(rad_path: &Path, out_dir: &Path) -> Result<Value> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    let bc_tag = match rad.header.read_tags.iter().position(|t| t.name == "b") {
+        Some(i) => i,
+        None => bail!(
+            "RAD file {} does not record cell barcodes.",
+            rad_path.display()
+        ),
+    };
+    let bc_len = match rad
+        .header
+        .file_tags
+        .iter()
+        .position(|t| t.name == "cblen")
+        .and_then(|i| rad.header.file_tag_values.get(i)?.as_u64())
+    {
+        Some(l) => l as usize,
+        None => bail!(
+            "RAD file {} does not record the length of its barcodes.",
+            rad_path.display()
+        ),
+    };
+
+    let mut counts = HashMap::<u64, u64>::new();
+    let mut num_reads = 0_u64;
+    while let Some(rec) = rad.next_record()? {
+        let bc = match rec.read_tags.get(bc_tag).and_then(|v| v.as_u64()) {
+            Some(bc) => bc,
+            None => bail!("RAD record is missing its barcode"),
+        };
+        *counts.entry(bc).or_insert(0) += 1;
+        num_reads += 1;
+    }
+    let mut sorted = counts.into_iter().collect::<Vec<(u64, u64)>>();
+    sorted.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let freq_path = out_dir.join(BARCODE_FREQ_FILE);
+    let mut out = BufWriter::new(File::create(&freq_path)?);
+    for (bc, count) in &sorted {
+        writeln!(out, "{}\t{}", decode_barcode(*bc, bc_len), count)?;
+    }
+    out.flush()?;
+
+    let knee = knee_point(&sorted.iter().map(|(_, c)| *c).collect::<Vec<u64>>());
+    match knee {
+        Some(k) => info!(
+            "wrote the read counts of {} barcodes to {}; the knee of the barcode rank plot suggests about {} cells.",
+            sorted.len(),
+            freq_path.display(),
+            k
+        ),
+        None => info!(
+            "wrote the read counts of {} barcodes to {}; too few barcodes to estimate the number of cells.",
+            sorted.len(),
+            freq_path.display()
+        ),
+    }
+    Ok(json!({
+        "file": BARCODE_FREQ_FILE,
+        "num_barcodes": sorted.len(),
+        "num_reads": num_reads,
+        "knee_estimated_cells": knee,
+        "knee_min_reads": knee.map(|k| sorted[k - 1].1),
+    }))
+}
@@ -0,0 +1,197 @@
+use anyhow::{Context, Result};
+use needletail::parse_fastx_file;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+use tempfile::TempDir;
+use tracing::{info, warn};
+
+use crate::exit_code::{bail_with, ExitKind};
+use crate::piscem_commands::{parse_opts, BenchOpts};
+use crate::{map_info, LogLevel, MapBulkConfig, RunOptions};
+
+/// The CPU time (user and system) used so far by this process, including all of
+/// its threads, in seconds.
+fn cpu_time_secs() -> Option<f64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage only writes to the provided struct
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let secs = |t: libc::timeval| t.tv_sec as f64 + t.tv_usec as f64 / 1e6;
+    Some(secs(usage.ru_utime) + secs(usage.ru_stime))
+}
+
+/// Copy the first `num_reads` reads of the read files `files`, taken in order, to
+/// the file `out`. Returns the number of reads copied.
+fn sample_reads(files: &[String], num_reads: u64, out: &Path) -> Result<u64> {
+    let f = File::create(out).with_context(|| format!("could not create {}", out.display()))?;
+    let mut out = BufWriter::new(f);
+    let mut n = 0_u64;
+    'files: for path in files {
+        let mut reader =
+            parse_fastx_file(path).with_context(|| format!("could not open read file {}", path))?;
+        while let Some(rec) = reader.next() {
+            if n == num_reads {
+                break 'files;
+            }
+            rec.with_context(|| format!("could not parse read file {}", path))?
+                .write(&mut out, None)?;
+            n += 1;
+        }
+    }
+    out.flush()?;
+    Ok(n)
+}
+
+/// Map the first `--num-reads` reads (or read pairs) of the bulk reads given in
+/// `opts` against the index given there, once for every thread count of
+/// `--threads`, and measure the throughput of each run: its wall time, the number
+/// of reads mapped per second, the CPU utilization (the CPU time used per second
+/// of wall time, relative to the number of threads) and the peak memory use of
+/// the process so far. Returns the measurements.
+pub(crate) fn bench(opts: &BenchOpts) -> Result<Value> {
+    let ncpus = num_cpus::get();
+    let mut thread_counts = Vec::with_capacity(opts.threads.len());
+    for &t in &opts.threads {
+        let t = if t == 0 { ncpus } else { t };
+        if t > ncpus {
+            warn!(
+                "skipping {} threads, which is more than the number of logical CPUs ({}).",
+                t, ncpus
+            );
+        } else if !thread_counts.contains(&t) {
+            thread_counts.push(t);
+        }
+    }
+    if thread_counts.is_empty() {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "none of the thread counts can be used with {} logical CPUs.",
+            ncpus
+        );
+    }
+
+    let tmp_dir = TempDir::new()?;
+    let mut read_args = Vec::new();
+    let mut num_reads = None;
+    for (flag, files, name) in [
+        ("--read1", &opts.read1, "reads_1.fq"),
+        ("--read2", &opts.read2, "reads_2.fq"),
+        ("--reads", &opts.reads, "reads.fq"),
+    ] {
+        let Some(files) = files else {
+            continue;
+        };
+        let path = tmp_dir.path().join(name);
+        let n = sample_reads(files, opts.num_reads, &path)?;
+        if num_reads.is_some_and(|m| m != n) {
+            bail_with!(
+                ExitKind::InvalidArguments,
+                "the read 1 and read 2 files hold different numbers of reads."
+            );
+        }
+        num_reads = Some(n);
+        read_args.extend([flag.to_string(), path.display().to_string()]);
+    }
+    let num_reads = num_reads.unwrap_or(0);
+    if num_reads == 0 {
+        bail_with!(ExitKind::InvalidArguments, "the read files hold no reads.");
+    }
+    info!(
+        "sampled {} reads; mapping them with {} thread counts.",
+        num_reads,
+        thread_counts.len()
+    );
+
+    let mut runs = Vec::with_capacity(thread_counts.len());
+    for &threads in &thread_counts {
+        let run_dir = tmp_dir.path().join(format!("threads_{}", threads));
+        std::fs::create_dir_all(&run_dir)?;
+        let output = run_dir.join("map");
+        let mut args = vec![
+            "--index".to_string(),
+            opts.index.clone(),
+            "--threads".to_string(),
+            threads.to_string(),
+            "--output".to_string(),
+            output.display().to_string(),
+        ];
+        args.extend(read_args.iter().cloned());
+        let config = parse_opts::<MapBulkConfig>("map-bulk", args)?;
+        let run = RunOptions {
+            log_level: LogLevel::Warn,
+            dry_run: false,
+        };
+        let cpu_start = cpu_time_secs();
+        let start = Instant::now();
+        let summary = crate::map_bulk(config, &run)?;
+        let wall = start.elapsed().as_secs_f64();
+        let cpu = cpu_start.zip(cpu_time_secs()).map(|(s, e)| e - s);
+        let reads_per_sec = num_reads as f64 / wall.max(1e-9);
+        let utilization = cpu.map(|c| c / wall.max(1e-9) / threads as f64 * 100.0);
+        info!(
+            "{} threads: {:.0} reads/s ({:.2}s).",
+            threads, reads_per_sec, wall
+        );
+        runs.push(json!({
+            "threads": threads,
+            "wall_time_secs": wall,
+            "reads_per_sec": reads_per_sec,
+            "cpu_time_secs": cpu,
+            "cpu_utilization_percent": utilization,
+            "peak_rss_bytes": map_info::peak_rss_bytes(),
+            "percent_mapped": summary.percent_mapped,
+        }));
+        std::fs::remove_dir_all(&run_dir)?;
+    }
+    tmp_dir.close()?;
+    Ok(json!({
+        "index": opts.index,
+        "num_reads": num_reads,
+        "paired": opts.read1.is_some(),
+        "runs": runs,
+    }))
+}
+
+/// Render the measurements `summary`, as returned by `bench`, as a table.
+pub(crate) fn format_summary(summary: &Value) -> String {
+    let mut out = format!(
+        "{} {} of index {}\n",
+        summary["num_reads"],
+        if summary["paired"] == Value::Bool(true) {
+            "read pairs"
+        } else {
+            "reads"
+        },
+        summary["index"].as_str().unwrap_or_default()
+    );
+    out += &format!(
+        "{:>8}  {:>10}  {:>12}  {:>8}  {:>12}\n",
+        "threads", "wall (s)", "reads/s", "CPU (%)", "peak RSS (MiB)"
+    );
+    for run in summary["runs"].as_array().into_iter().flatten() {
+        let f = |k: &str, prec: usize| {
+            run[k]
+                .as_f64()
+                .map_or(String::from("unknown"), |v| format!("{:.*}", prec, v))
+        };
+        let rss = run["peak_rss_bytes"]
+            .as_u64()
+            .map_or(String::from("unknown"), |b| {
+                format!("{:.1}", b as f64 / (1 << 20) as f64)
+            });
+        out += &format!(
+            "{:>8}  {:>10}  {:>12}  {:>8}  {:>12}\n",
+            run["threads"].as_u64().unwrap_or_default(),
+            f("wall_time_secs", 2),
+            f("reads_per_sec", 0),
+            f("cpu_utilization_percent", 1),
+            rss
+        );
+    }
+    out
+}
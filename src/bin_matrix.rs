@@ -0,0 +1,116 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tracing::info;
+
+use crate::fragments;
+use crate::permit_list::decode_barcode;
+use crate::refinfo::RefInfo;
+
+/// The name of the directory, written into the output directory by `map-sc-atac`
+/// when `--bin-matrix` is given, that holds the barcode × bin count matrix.
+pub(crate) const BIN_MATRIX_DIR: &str = "bin_matrix";
+
+/// Create `path` for writing, wrapped in a `BufWriter`.
+fn create(path: &Path) -> Result<BufWriter<File>> {
+    let f = File::create(path).with_context(|| format!("could not create {}", path.display()))?;
+    Ok(BufWriter::new(f))
+}
+
+/// Count the Tn5 insertions (i.e. the two ends of each distinct fragment of the
+/// uniquely mapped read pairs, see `fragments::collect_fragments`) of every barcode
+/// in every bin of `bin_size` bases of the references `refs`, from the single-cell
+/// ATAC-seq RAD file at `rad_path`. The counts are written to the `bin_matrix`
+/// directory of `out_dir`, as a sparse barcode × bin matrix in Matrix Market
+/// format (`matrix.mtx`), along with the barcodes of its rows (`barcodes.txt`)
+/// and the bins of its columns (`bins.bed`); only bins with at least one insertion
+/// are listed. Returns a summary of the matrix.
+pub(crate) fn write_bin_matrix(
+    rad_path: &Path,
+    refs: &RefInfo,
+    bin_size: u32,
+    out_dir: &Path,
+) -> Result<Value> {
+    if bin_size == 0 {
+        bail!("the bin size must be greater than 0.");
+    }
+    let (frags, bc_len) = fragments::collect_fragments(rad_path, refs)?;
+    let mut counts = HashMap::<(u64, u32, u32), u32>::new();
+    // fragments are sorted, so duplicates are adjacent and counted once
+    for group in frags.frags.chunk_by(|a, b| a == b) {
+        let frag = group[0];
+        for pos in [frag.start, frag.end - 1] {
+            *counts
+                .entry((frag.barcode, frag.ref_id, pos / bin_size))
+                .or_insert(0) += 1;
+        }
+    }
+
+    let mut barcodes = counts.keys().map(|k| k.0).collect::<Vec<u64>>();
+    barcodes.sort_unstable();
+    barcodes.dedup();
+    let mut bins = counts
+        .keys()
+        .map(|k| (k.1, k.2))
+        .collect::<Vec<(u32, u32)>>();
+    bins.sort_unstable();
+    bins.dedup();
+    let rows = barcodes
+        .iter()
+        .enumerate()
+        .map(|(i, bc)| (*bc, i))
+        .collect::<HashMap<u64, usize>>();
+    let cols = bins
+        .iter()
+        .enumerate()
+        .map(|(i, b)| (*b, i))
+        .collect::<HashMap<(u32, u32), usize>>();
+    let mut entries = counts
+        .into_iter()
+        .map(|((bc, r, b), c)| (rows[&bc], cols[&(r, b)], c))
+        .collect::<Vec<(usize, usize, u32)>>();
+    entries.sort_unstable();
+
+    let dir = out_dir.join(BIN_MATRIX_DIR);
+    std::fs::create_dir_all(&dir).with_context(|| format!("could not create {}", dir.display()))?;
+    let mut out = create(&dir.join("matrix.mtx"))?;
+    writeln!(out, "%%MatrixMarket matrix coordinate integer general")?;
+    writeln!(out, "{} {} {}", barcodes.len(), bins.len(), entries.len())?;
+    for (row, col, count) in &entries {
+        writeln!(out, "{} {} {}", row + 1, col + 1, count)?;
+    }
+    out.flush()?;
+
+    let mut out = create(&dir.join("barcodes.txt"))?;
+    for bc in &barcodes {
+        writeln!(out, "{}", decode_barcode(*bc, bc_len))?;
+    }
+    out.flush()?;
+
+    let mut out = create(&dir.join("bins.bed"))?;
+    for (r, b) in &bins {
+        let start = b * bin_size;
+        let end = (start as u64 + bin_size as u64).min(refs.lengths[*r as usize]);
+        writeln!(out, "{}\t{}\t{}", refs.names[*r as usize], start, end)?;
+    }
+    out.flush()?;
+
+    info!(
+        "wrote the insertion counts of {} barcodes in {} bins of {} bases to {}.",
+        barcodes.len(),
+        bins.len(),
+        bin_size,
+        dir.display()
+    );
+    Ok(json!({
+        "dir": BIN_MATRIX_DIR,
+        "bin_size": bin_size,
+        "num_barcodes": barcodes.len(),
+        "num_bins": bins.len(),
+        "num_nonzero": entries.len(),
+        "num_fragments": frags.frags.chunk_by(|a, b| a == b).count(),
+    }))
+}
@@ -0,0 +1,106 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The phases of `piscem build` whose completion is recorded, so that a failed
+/// build can be resumed with `--resume`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum BuildPhase {
+    /// the construction of the compacted de Bruijn graph by cuttlefish
+    Cdbg,
+    /// the construction of the SSHash dictionary, contig and equivalence class tables
+    Index,
+    /// the construction of the poison k-mer table from the decoys
+    PoisonTable,
+}
+
+impl BuildPhase {
+    const ALL: [BuildPhase; 3] = [Self::Cdbg, Self::Index, Self::PoisonTable];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Cdbg => "cdbg",
+            Self::Index => "index",
+            Self::PoisonTable => "poison_table",
+        }
+    }
+}
+
+/// The completion markers of the phases of the build of the index with the
+/// output stem `output`. The marker of a phase is a file in the work directory
+/// that holds the parameters the phase was run with; a phase is only considered
+/// complete if they match those of the current build and its outputs exist.
+pub(crate) struct BuildCheckpoints {
+    work_dir: PathBuf,
+    stem: String,
+}
+
+impl BuildCheckpoints {
+    pub fn new(work_dir: &Path, output: &Path) -> Self {
+        Self {
+            work_dir: work_dir.to_path_buf(),
+            stem: output
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "index".to_string()),
+        }
+    }
+
+    fn marker_path(&self, phase: BuildPhase) -> PathBuf {
+        self.work_dir
+            .join(format!("{}.{}.done", self.stem, phase.name()))
+    }
+
+    /// Whether `phase` was completed by an earlier build with the parameters
+    /// `params`, and all of its `outputs` are still present.
+    pub fn is_done(&self, phase: BuildPhase, params: &str, outputs: &[PathBuf]) -> bool {
+        let marker = self.marker_path(phase);
+        let recorded = match std::fs::read_to_string(&marker) {
+            Ok(r) => r,
+            Err(_) => return false,
+        };
+        if recorded != params {
+            warn!(
+                "the {} phase was completed with different parameters; it will be run again.",
+                phase.name()
+            );
+            return false;
+        }
+        if let Some(missing) = outputs.iter().find(|o| !o.exists()) {
+            warn!(
+                "the {} phase was completed, but its output {} is missing; it will be run again.",
+                phase.name(),
+                missing.display()
+            );
+            return false;
+        }
+        info!(
+            "skipping the {} phase, which was completed by an earlier build.",
+            phase.name()
+        );
+        true
+    }
+
+    /// Record that `phase` was completed with the parameters `params`.
+    pub fn mark_done(&self, phase: BuildPhase, params: &str) -> Result<()> {
+        let marker = self.marker_path(phase);
+        std::fs::write(&marker, params).with_context(|| {
+            format!(
+                "could not write the completion marker {} of the {} phase",
+                marker.display(),
+                phase.name()
+            )
+        })
+    }
+
+    /// Remove the completion markers of all phases.
+    pub fn clear(&self) -> Result<()> {
+        for phase in BuildPhase::ALL {
+            let marker = self.marker_path(phase);
+            if marker.exists() {
+                std::fs::remove_file(&marker)?;
+            }
+        }
+        Ok(())
+    }
+}
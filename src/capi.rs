@@ -0,0 +1,170 @@
+use anyhow::{bail, Context, Result};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_uint, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::exit_code::ExitKind;
+use crate::piscem_commands::parse_opts;
+use crate::{BuildConfig, LogLevel, MapBulkConfig, MapSCAtacConfig, MapSCConfig, RunOptions};
+
+/// `flags` bit: only log warnings and errors (the `warn` log level), and pass
+/// `--quiet` to the mapper and indexer.
+pub const PISCEM_QUIET: c_uint = 1;
+/// `flags` bit: validate the command and print what it would run, without running it.
+pub const PISCEM_DRY_RUN: c_uint = 2;
+
+thread_local! {
+    /// the message of the last error reported on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: String) {
+    let msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+fn run_options(flags: c_uint) -> RunOptions {
+    RunOptions {
+        log_level: if flags & PISCEM_QUIET != 0 {
+            LogLevel::Warn
+        } else {
+            LogLevel::Info
+        },
+        dry_run: flags & PISCEM_DRY_RUN != 0,
+    }
+}
+
+/// The string `s` passed from C.
+unsafe fn str_from_c<'a>(s: *const c_char, what: &str) -> Result<&'a str> {
+    if s.is_null() {
+        bail!("{} must not be NULL.", what);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .with_context(|| format!("{} is not valid UTF-8", what))
+}
+
+/// The `argc` arguments `argv` passed from C.
+unsafe fn args_from_c(argc: c_int, argv: *const *const c_char) -> Result<Vec<String>> {
+    if argc < 0 || (argc > 0 && argv.is_null()) {
+        bail!("invalid argument vector (argc = {}).", argc);
+    }
+    (0..argc as usize)
+        .map(|i| str_from_c(*argv.add(i), "every argument").map(str::to_string))
+        .collect()
+}
+
+/// Run `f`, returning 0 if it succeeds and otherwise the exit code of its error,
+/// whose message is kept for `piscem_last_error`. Panics are not unwound into C.
+fn run_c(f: impl FnOnce() -> Result<()>) -> c_int {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_last_error(format!("{:#}", e));
+            crate::exit_code(&e) as c_int
+        }
+        Err(_) => {
+            set_last_error(String::from("piscem panicked"));
+            ExitKind::Internal.code() as c_int
+        }
+    }
+}
+
+/// Build an index. `argv` holds the `argc` options of `piscem build` (e.g.
+/// `{"--ref-seqs", "tx.fa", "--output", "idx", "--threads", "8"}`), without the
+/// program and command names. Returns 0 on success, and otherwise the exit code
+/// the piscem executable would report.
+///
+/// # Safety
+///
+/// `argv` must point to `argc` valid, NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn piscem_build(
+    argc: c_int,
+    argv: *const *const c_char,
+    flags: c_uint,
+) -> c_int {
+    run_c(|| {
+        let config = parse_opts::<BuildConfig>("build", args_from_c(argc, argv)?)?;
+        crate::build_index(config, &run_options(flags))?;
+        Ok(())
+    })
+}
+
+/// Map reads with the command `command` (`"map-sc"`, `"map-sc-atac"` or
+/// `"map-bulk"`), whose `argc` options are given in `argv`. Returns 0 on success,
+/// and otherwise the exit code the piscem executable would report.
+///
+/// # Safety
+///
+/// `command` must be a valid, NUL-terminated string and `argv` must point to
+/// `argc` such strings.
+#[no_mangle]
+pub unsafe extern "C" fn piscem_map(
+    command: *const c_char,
+    argc: c_int,
+    argv: *const *const c_char,
+    flags: c_uint,
+) -> c_int {
+    run_c(|| {
+        let run = run_options(flags);
+        let args = args_from_c(argc, argv)?;
+        match str_from_c(command, "the command")? {
+            "map-sc" => crate::map_sc(parse_opts::<MapSCConfig>("map-sc", args)?, &run)?,
+            "map-sc-atac" => {
+                crate::map_sc_atac(parse_opts::<MapSCAtacConfig>("map-sc-atac", args)?, &run)?
+            }
+            "map-bulk" => crate::map_bulk(parse_opts::<MapBulkConfig>("map-bulk", args)?, &run)?,
+            other => crate::exit_code::bail_with!(
+                ExitKind::InvalidArguments,
+                "unknown mapping command {}; expected map-sc, map-sc-atac or map-bulk.",
+                other
+            ),
+        };
+        Ok(())
+    })
+}
+
+/// The statistics of the index with the prefix `index` (as reported by `piscem
+/// inspect --json`), as a JSON string to be released with `piscem_free_string`,
+/// or NULL on failure.
+///
+/// # Safety
+///
+/// `index` must be a valid, NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn piscem_index_info(index: *const c_char) -> *mut c_char {
+    let mut info = None;
+    run_c(|| {
+        let summary = crate::inspect_index(str_from_c(index, "the index prefix")?)?;
+        info = Some(CString::new(summary.to_string())?);
+        Ok(())
+    });
+    info.map_or(std::ptr::null_mut(), CString::into_raw)
+}
+
+/// Release a string returned by `piscem_index_info`.
+///
+/// # Safety
+///
+/// `s` must be NULL or a string returned by this library that wasn't released yet.
+#[no_mangle]
+pub unsafe extern "C" fn piscem_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// The message of the last error reported by a call on this thread, or NULL if
+/// it succeeded. The string is valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn piscem_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(std::ptr::null(), |m| m.as_ptr()))
+}
+
+/// The version of piscem.
+#[no_mangle]
+pub extern "C" fn piscem_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
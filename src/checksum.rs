@@ -0,0 +1,142 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::exit_code::{bail_with, ExitKind};
+use crate::inspect;
+
+/// The SHA-256 digest of the file `path`, as a lowercase hexadecimal string.
+pub(crate) fn sha256_file(path: &Path) -> Result<String> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("could not open {}", path.display()))?,
+    );
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// The suffix of the manifest, written alongside an index, that records the
+/// SHA-256 digest of each of its components.
+pub(crate) const CHECKSUMS_SUFFIX: &str = ".sha256.json";
+
+/// Compute the SHA-256 digests of the files `paths`, one thread per file.
+pub(crate) fn sha256_files(paths: &[PathBuf]) -> Vec<Result<String>> {
+    std::thread::scope(|s| {
+        let handles = paths
+            .iter()
+            .map(|p| s.spawn(move || sha256_file(p)))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| bail!("a thread computing a digest panicked"))
+            })
+            .collect()
+    })
+}
+
+/// Write the manifest (`<index>.sha256.json`) of the digests of all the present
+/// components of the index with the prefix `index`. Components are recorded by
+/// their suffix, so that the index can be moved or renamed.
+pub(crate) fn write_index_checksums(index: &str) -> Result<PathBuf> {
+    let suffixes = inspect::INDEX_COMPONENTS
+        .iter()
+        .map(|c| c.suffix)
+        .filter(|s| *s != CHECKSUMS_SUFFIX && inspect::component_path(index, s).exists())
+        .collect::<Vec<&str>>();
+    let paths = suffixes
+        .iter()
+        .map(|s| inspect::component_path(index, s))
+        .collect::<Vec<PathBuf>>();
+    let mut components = serde_json::Map::new();
+    for (suffix, digest) in suffixes.iter().zip(sha256_files(&paths)) {
+        components.insert(suffix.to_string(), json!(digest?));
+    }
+    let manifest = inspect::component_path(index, CHECKSUMS_SUFFIX);
+    std::fs::write(
+        &manifest,
+        serde_json::to_string_pretty(&json!({
+            "algorithm": "sha256",
+            "components": components,
+        }))?,
+    )?;
+    Ok(manifest)
+}
+
+/// Check the components of the index with the prefix `index` against its
+/// manifest of digests. Returns `None` if the index has no manifest (e.g. it
+/// was built by an older version of piscem), and otherwise the description of
+/// every component that is missing or whose digest doesn't match.
+pub(crate) fn check_index_checksums(index: &str) -> Result<Option<Vec<String>>> {
+    let manifest_path = inspect::component_path(index, CHECKSUMS_SUFFIX);
+    if !manifest_path.exists() {
+        return Ok(None);
+    }
+    let manifest: Value = serde_json::from_str(&std::fs::read_to_string(&manifest_path)?)
+        .with_context(|| format!("could not parse {}", manifest_path.display()))?;
+    let expected = match manifest.get("components").and_then(|c| c.as_object()) {
+        Some(c) => c
+            .iter()
+            .map(|(suffix, d)| (suffix.clone(), d.as_str().unwrap_or_default().to_string()))
+            .collect::<Vec<(String, String)>>(),
+        None => bail!(
+            "the checksum manifest {} does not list any components.",
+            manifest_path.display()
+        ),
+    };
+    let paths = expected
+        .iter()
+        .map(|(suffix, _)| inspect::component_path(index, suffix))
+        .collect::<Vec<PathBuf>>();
+    let problems = expected
+        .iter()
+        .zip(paths.iter())
+        .zip(sha256_files(&paths))
+        .filter_map(|(((_, want), path), got)| match got {
+            Ok(got) if got == *want => None,
+            Ok(got) => Some(format!(
+                "{} has SHA-256 digest {}, but {} was recorded",
+                path.display(),
+                got,
+                want
+            )),
+            Err(e) => Some(format!("{:#}", e)),
+        })
+        .collect();
+    Ok(Some(problems))
+}
+
+/// Verify the components of the index with the prefix `index` against its
+/// manifest of digests before it is loaded, failing if any is missing or
+/// corrupt. Indexes without a manifest are not verified.
+pub(crate) fn verify_index(index: &str) -> Result<()> {
+    match check_index_checksums(index)? {
+        None => info!(
+            "index {} has no checksum manifest ({}); it was not verified.",
+            index, CHECKSUMS_SUFFIX
+        ),
+        Some(problems) if problems.is_empty() => {
+            info!(
+                "verified the checksums of the components of index {}.",
+                index
+            )
+        }
+        Some(problems) => bail_with!(
+            ExitKind::CorruptIndexComponent,
+            "index {} failed verification (use --no-verify to skip it):\n  {}",
+            index,
+            problems.join("\n  ")
+        ),
+    }
+    Ok(())
+}
@@ -0,0 +1,348 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::map_info;
+use crate::piscem_commands::ClassifyOpts;
+use crate::rad::{self, decode_ref_id, RadReader};
+
+/// The taxid of the root of the taxonomy.
+const ROOT_TAXID: u32 = 1;
+
+/// The path of the file with the suffix `suffix` for the output prefix `output`.
+fn prefixed_path(output: &Path, suffix: &str) -> PathBuf {
+    PathBuf::from(output.to_string_lossy().into_owned() + suffix)
+}
+
+/// Open `path` for reading, naming it as `what` if it can't be opened.
+fn open(path: &Path, what: &str) -> Result<BufReader<File>> {
+    let f =
+        File::open(path).with_context(|| format!("could not open {} {}", what, path.display()))?;
+    Ok(BufReader::new(f))
+}
+
+/// The fields of a line of an NCBI taxonomy dump (e.g. `nodes.dmp`), which are
+/// separated by `\t|\t` and end with `\t|`.
+fn dmp_fields(line: &str) -> Vec<&str> {
+    line.trim_end_matches(['\t', '|', '\n', '\r'])
+        .split("\t|\t")
+        .map(|f| f.trim())
+        .collect()
+}
+
+/// A taxonomy: the parent and rank of every taxon, along with its name if known.
+/// Without a tree, every taxon is a direct child of the root.
+#[derive(Default)]
+struct Taxonomy {
+    parents: HashMap<u32, u32>,
+    ranks: HashMap<u32, String>,
+    names: HashMap<u32, String>,
+}
+
+impl Taxonomy {
+    /// Read the tree of the taxonomy from the NCBI `nodes.dmp` file at `nodes`,
+    /// and the scientific names of the taxa from the `names.dmp` file at `names`.
+    fn read(nodes: Option<&Path>, names: Option<&Path>) -> Result<Self> {
+        let mut tax = Self::default();
+        if let Some(path) = nodes {
+            for (line_num, line) in open(path, "taxonomy nodes")?.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let fields = dmp_fields(&line);
+                match (
+                    fields.first().and_then(|f| f.parse::<u32>().ok()),
+                    fields.get(1).and_then(|f| f.parse::<u32>().ok()),
+                ) {
+                    (Some(taxid), Some(parent)) => {
+                        tax.parents.insert(taxid, parent);
+                        if let Some(rank) = fields.get(2) {
+                            tax.ranks.insert(taxid, rank.to_string());
+                        }
+                    }
+                    _ => bail!(
+                        "line {} of taxonomy nodes {} is not a valid nodes.dmp record.",
+                        line_num + 1,
+                        path.display()
+                    ),
+                }
+            }
+        }
+        if let Some(path) = names {
+            for line in open(path, "taxonomy names")?.lines() {
+                let line = line?;
+                let fields = dmp_fields(&line);
+                if fields.get(3) != Some(&"scientific name") {
+                    continue;
+                }
+                if let Some(taxid) = fields.first().and_then(|f| f.parse::<u32>().ok()) {
+                    tax.names.insert(taxid, fields[1].to_string());
+                }
+            }
+        }
+        Ok(tax)
+    }
+
+    /// The parent of `taxid`; taxa that are not in the tree are children of the root.
+    fn parent(&self, taxid: u32) -> u32 {
+        self.parents.get(&taxid).copied().unwrap_or(ROOT_TAXID)
+    }
+
+    /// The path from `taxid` up to the root (both included).
+    fn lineage(&self, mut taxid: u32) -> Vec<u32> {
+        let mut path = vec![taxid];
+        // the length limit guards against cycles in a malformed tree
+        while taxid != ROOT_TAXID && path.len() <= self.parents.len() + 1 {
+            let p = self.parent(taxid);
+            if p == taxid {
+                break;
+            }
+            path.push(p);
+            taxid = p;
+        }
+        path
+    }
+
+    /// The lowest common ancestor of the taxa `taxids`, which must not be empty.
+    fn lca(&self, taxids: &[u32]) -> u32 {
+        let mut lineage = self.lineage(taxids[0]);
+        for &t in &taxids[1..] {
+            let other = self.lineage(t);
+            lineage.retain(|a| other.contains(a));
+        }
+        lineage.first().copied().unwrap_or(ROOT_TAXID)
+    }
+
+    /// The rank code of `taxid` in a Kraken report.
+    fn rank_code(&self, taxid: u32) -> &'static str {
+        if taxid == ROOT_TAXID {
+            return "R";
+        }
+        match self.ranks.get(&taxid).map(|r| r.as_str()) {
+            Some("superkingdom") | Some("domain") => "D",
+            Some("kingdom") => "K",
+            Some("phylum") => "P",
+            Some("class") => "C",
+            Some("order") => "O",
+            Some("family") => "F",
+            Some("genus") => "G",
+            Some("species") => "S",
+            _ => "-",
+        }
+    }
+
+    fn name(&self, taxid: u32) -> String {
+        match self.names.get(&taxid) {
+            Some(n) => n.clone(),
+            None if taxid == ROOT_TAXID => "root".to_string(),
+            None => format!("taxid {}", taxid),
+        }
+    }
+}
+
+/// Read the taxid of every reference in `ref_names` from the two-column file at
+/// `path` (a reference name and its taxid per line, separated by whitespace).
+/// References that are not listed get no taxid.
+fn read_seq2taxid(path: &Path, ref_names: &[String]) -> Result<Vec<Option<u32>>> {
+    let mut taxids = HashMap::<String, u32>::new();
+    for (line_num, line) in open(path, "sequence to taxid map")?.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (
+            fields.next(),
+            fields.next().and_then(|f| f.parse::<u32>().ok()),
+        ) {
+            (Some(name), Some(taxid)) => {
+                taxids.insert(name.to_string(), taxid);
+            }
+            _ => bail!(
+                "line {} of sequence to taxid map {} must hold a reference name and a taxid.",
+                line_num + 1,
+                path.display()
+            ),
+        }
+    }
+    let ref_taxids = ref_names
+        .iter()
+        .map(|n| taxids.get(n).copied())
+        .collect::<Vec<Option<u32>>>();
+    let num_missing = ref_taxids.iter().filter(|t| t.is_none()).count();
+    if num_missing == ref_names.len() {
+        bail!(
+            "none of the references of the index is listed in the sequence to taxid map {}.",
+            path.display()
+        );
+    } else if num_missing > 0 {
+        warn!(
+            "{} references are not listed in the sequence to taxid map; reads mapping only to them are left unclassified.",
+            num_missing
+        );
+    }
+    Ok(ref_taxids)
+}
+
+/// Write the Kraken-style report of the reads assigned to each taxon, `direct`,
+/// along with the `num_unclassified` reads, to `path`: one line per taxon with at
+/// least one read in its clade, in depth-first order (the children of a taxon
+/// from the one with the most reads to that with the fewest), with the percentage
+/// of reads in its clade, the number of reads in its clade and assigned to it, its
+/// rank code, taxid and (indented) name.
+fn write_report(
+    path: &Path,
+    tax: &Taxonomy,
+    direct: &HashMap<u32, u64>,
+    num_unclassified: u64,
+) -> Result<()> {
+    let mut clade = HashMap::<u32, u64>::new();
+    let mut children = HashMap::<u32, Vec<u32>>::new();
+    for (&taxid, &count) in direct {
+        let lineage = tax.lineage(taxid);
+        for (i, &t) in lineage.iter().enumerate() {
+            *clade.entry(t).or_insert(0) += count;
+            if let Some(&p) = lineage.get(i + 1) {
+                let c = children.entry(p).or_default();
+                if !c.contains(&t) {
+                    c.push(t);
+                }
+            }
+        }
+    }
+    let total = (clade.get(&ROOT_TAXID).copied().unwrap_or(0) + num_unclassified).max(1) as f64;
+
+    let f = File::create(path).with_context(|| format!("could not create {}", path.display()))?;
+    let mut out = BufWriter::new(f);
+    if num_unclassified > 0 {
+        writeln!(
+            out,
+            "{:.2}\t{}\t{}\tU\t0\tunclassified",
+            num_unclassified as f64 / total * 100.0,
+            num_unclassified,
+            num_unclassified
+        )?;
+    }
+    let mut stack = vec![(ROOT_TAXID, 0_usize)];
+    while let Some((taxid, depth)) = stack.pop() {
+        let Some(&count) = clade.get(&taxid) else {
+            continue;
+        };
+        writeln!(
+            out,
+            "{:.2}\t{}\t{}\t{}\t{}\t{}{}",
+            count as f64 / total * 100.0,
+            count,
+            direct.get(&taxid).copied().unwrap_or(0),
+            tax.rank_code(taxid),
+            taxid,
+            "  ".repeat(depth),
+            tax.name(taxid)
+        )?;
+        if let Some(c) = children.get_mut(&taxid) {
+            // pushed from the fewest to the most reads, so the most are popped first
+            c.sort_unstable_by(|a, b| clade[a].cmp(&clade[b]).then(b.cmp(a)));
+            stack.extend(c.iter().map(|t| (*t, depth + 1)));
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Classify the reads mapped by `map-bulk` as described by `opts`: every read is
+/// assigned to the lowest common ancestor of the taxa of the references it maps
+/// to. The classification of every read is written, in the format of Kraken's
+/// output, to `<output>.classifications.tsv`, and the number of reads assigned to
+/// every taxon, in the format of Kraken's report, to `<output>.report.txt`.
+/// Returns a summary of the classification.
+pub(crate) fn classify(opts: &ClassifyOpts) -> Result<Value> {
+    let stem_rad = rad::bulk_rad_path(&opts.input);
+    let (rad_path, map_info_path) = if !opts.input.exists() && stem_rad.exists() {
+        (stem_rad, Some(map_info::bulk_map_info_path(&opts.input)))
+    } else {
+        (rad::resolve_rad_path(&opts.input)?, None)
+    };
+    let tax = Taxonomy::read(opts.nodes.as_deref(), opts.names.as_deref())?;
+    let mut rad = RadReader::from_path(&rad_path)?;
+    let ref_taxids = read_seq2taxid(&opts.seq2taxid, &rad.header.ref_names)?;
+
+    let out_path = prefixed_path(&opts.output, ".classifications.tsv");
+    let f = File::create(&out_path)
+        .with_context(|| format!("could not create {}", out_path.display()))?;
+    let mut out = BufWriter::new(f);
+    let mut direct = HashMap::<u32, u64>::new();
+    let (mut num_classified, mut num_unclassified) = (0_u64, 0_u64);
+    let mut frag_num = 0_u64;
+    while let Some(rec) = rad.next_record()? {
+        frag_num += 1;
+        // the number of alignments to each taxon, in taxid order
+        let mut hits = BTreeMap::<u32, u64>::new();
+        for aln in &rec.alns {
+            let ref_id = match aln.first().and_then(|v| v.as_u64()) {
+                Some(v) => decode_ref_id(v).0 as usize,
+                None => bail!("RAD alignment record is missing its reference id"),
+            };
+            if let Some(taxid) = ref_taxids.get(ref_id).copied().flatten() {
+                *hits.entry(taxid).or_insert(0) += 1;
+            }
+        }
+        let hit_list = hits
+            .iter()
+            .map(|(t, c)| format!("{}:{}", t, c))
+            .collect::<Vec<String>>()
+            .join(" ");
+        if hits.is_empty() {
+            num_unclassified += 1;
+            writeln!(out, "U\tfrag{}\t0\t{}\t", frag_num, rec.alns.len())?;
+        } else {
+            let taxid = tax.lca(&hits.keys().copied().collect::<Vec<u32>>());
+            *direct.entry(taxid).or_insert(0) += 1;
+            num_classified += 1;
+            writeln!(
+                out,
+                "C\tfrag{}\t{}\t{}\t{}",
+                frag_num,
+                taxid,
+                rec.alns.len(),
+                hit_list
+            )?;
+        }
+    }
+    out.flush()?;
+
+    // reads the mapper left unmapped are not in the RAD file, but are counted in
+    // its mapping summary
+    let num_unmapped = map_info_path
+        .filter(|p| p.exists())
+        .map(|p| map_info::read_map_info(&p))
+        .transpose()?
+        .and_then(|m| {
+            let num = |k: &str| m.get(k).and_then(|v| v.as_u64());
+            Some(num("num_reads")?.saturating_sub(num("num_mapped")?))
+        })
+        .unwrap_or(0);
+
+    let report_path = prefixed_path(&opts.output, ".report.txt");
+    write_report(&report_path, &tax, &direct, num_unclassified + num_unmapped)?;
+    info!(
+        "classified {} of {} mapped reads into {} taxa; wrote the report to {}.",
+        num_classified,
+        frag_num,
+        direct.len(),
+        report_path.display()
+    );
+    Ok(json!({
+        "classifications": out_path.display().to_string(),
+        "report": report_path.display().to_string(),
+        "num_mapped_reads": frag_num,
+        "num_classified": num_classified,
+        "num_unclassified": num_unclassified,
+        "num_unmapped": num_unmapped,
+        "num_taxa": direct.len(),
+    }))
+}
@@ -0,0 +1,174 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::build_checkpoint::BuildCheckpoints;
+use crate::dump_unitigs::{SEGMENTS_SUFFIX, TILINGS_SUFFIX};
+use crate::piscem_commands::CleanOpts;
+use crate::splici::AugmentedRefType;
+use crate::{decoys, dry_run, inspect, probe_set, ref_prep, splici, RunOptions};
+
+/// The paths of all the files `build` may write for the index with the prefix
+/// `prefix`: the components of the index, the intermediate compacted de Bruijn
+/// graph, and the references, annotations and reports generated along the way.
+fn build_files(prefix: &str) -> Vec<PathBuf> {
+    let stem = Path::new(prefix);
+    let mut files = inspect::INDEX_COMPONENTS
+        .iter()
+        .map(|c| c.suffix)
+        .chain([SEGMENTS_SUFFIX, TILINGS_SUFFIX])
+        .map(|suffix| inspect::component_path(prefix, suffix))
+        .collect::<Vec<PathBuf>>();
+    for ref_type in [AugmentedRefType::Splici, AugmentedRefType::Spliceu] {
+        files.push(splici::augmented_ref_fasta_path(stem, ref_type));
+        files.push(inspect::component_path(
+            prefix,
+            &format!(".{}_t2g.tsv", ref_type),
+        ));
+        files.push(inspect::component_path(
+            prefix,
+            &format!(".{}_t2g_3col.tsv", ref_type),
+        ));
+    }
+    files.extend([
+        inspect::component_path(prefix, ".t2g.tsv"),
+        inspect::component_path(prefix, ".t2g_3col.tsv"),
+        probe_set::probe_set_fasta_path(stem),
+        inspect::component_path(prefix, ".probes_t2g.tsv"),
+        ref_prep::prepared_refs_path(stem),
+        ref_prep::ambig_report_path(stem),
+        decoys::genome_decoys_path(stem),
+    ]);
+    files
+}
+
+/// The files written by `build` for the index with the prefix `prefix` that
+/// exist, other than those in `keep` (e.g. the inputs of a build).
+fn existing_build_files(prefix: &str, keep: &[PathBuf]) -> Vec<PathBuf> {
+    let keep = keep
+        .iter()
+        .map(|p| p.canonicalize().unwrap_or_else(|_| p.clone()))
+        .collect::<Vec<PathBuf>>();
+    build_files(prefix)
+        .into_iter()
+        .filter(|p| p.is_file())
+        .filter(|p| !keep.contains(&p.canonicalize().unwrap_or_else(|_| p.clone())))
+        .collect()
+}
+
+/// Whether an index with the prefix `prefix` exists, i.e. any of its required
+/// components does.
+pub(crate) fn index_exists(prefix: &str) -> bool {
+    inspect::INDEX_COMPONENTS
+        .iter()
+        .filter(|c| c.required)
+        .any(|c| inspect::component_path(prefix, c.suffix).exists())
+}
+
+/// Remove the files written by `build` for the index with the prefix `prefix`,
+/// other than those in `keep`. Either all of them are removed or none is: they
+/// are first moved into a staging directory next to the index, and moved back
+/// if any of them cannot be, before the directory is removed. Returns the paths
+/// of the files removed.
+pub(crate) fn remove_index(prefix: &str, keep: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let files = existing_build_files(prefix, keep);
+    if files.is_empty() {
+        return Ok(files);
+    }
+    let stem = Path::new(prefix);
+    let staging = PathBuf::from(format!(
+        "{}.removing.{}",
+        stem.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::create_dir(&staging)
+        .with_context(|| format!("could not create {}", staging.display()))?;
+
+    let mut moved = Vec::<(PathBuf, PathBuf)>::with_capacity(files.len());
+    for (i, f) in files.iter().enumerate() {
+        let dest = staging.join(i.to_string());
+        if let Err(e) = std::fs::rename(f, &dest) {
+            for (src, dest) in moved.iter().rev() {
+                if let Err(e) = std::fs::rename(dest, src) {
+                    warn!(
+                        "could not restore {} from {}: {}",
+                        src.display(),
+                        staging.display(),
+                        e
+                    );
+                }
+            }
+            let _ = std::fs::remove_dir(&staging);
+            return Err(e).with_context(|| {
+                format!(
+                    "could not remove {}; the index with prefix {} was left unchanged",
+                    f.display(),
+                    prefix
+                )
+            });
+        }
+        moved.push((f.clone(), dest));
+    }
+    if let Err(e) = std::fs::remove_dir_all(&staging) {
+        warn!(
+            "the files of index {} were moved to {}, but it could not be removed: {}",
+            prefix,
+            staging.display(),
+            e
+        );
+    }
+    Ok(files)
+}
+
+/// Remove the index given in `opts`, the files written alongside it by `build`,
+/// and the markers of an interrupted build of it in the work directory, if one is
+/// given (which is removed as well if it is left empty). For a dry run, the files are only
+/// listed. Returns a summary of the files removed.
+pub(crate) fn clean(opts: &CleanOpts, run: &RunOptions) -> Result<Value> {
+    let files = existing_build_files(&opts.prefix, &[]);
+    let num_bytes = files
+        .iter()
+        .filter_map(|f| std::fs::metadata(f).ok())
+        .map(|m| m.len())
+        .sum::<u64>();
+    if run.dry_run {
+        for f in &files {
+            dry_run::print_step(&format!("remove {}", f.display()));
+        }
+        if let Some(ref work_dir) = opts.work_dir {
+            dry_run::print_step(&format!(
+                "remove the build markers of {} in {}",
+                opts.prefix,
+                work_dir.display()
+            ));
+        }
+    } else {
+        remove_index(&opts.prefix, &[])?;
+        if let Some(ref work_dir) = opts.work_dir {
+            BuildCheckpoints::new(work_dir, Path::new(&opts.prefix)).clear()?;
+            let is_empty = std::fs::read_dir(work_dir)
+                .map(|mut d| d.next().is_none())
+                .unwrap_or(false);
+            if is_empty {
+                std::fs::remove_dir(work_dir)
+                    .with_context(|| format!("could not remove {}", work_dir.display()))?;
+            }
+        }
+    }
+
+    if files.is_empty() {
+        warn!("no files of index {} were found.", opts.prefix);
+    } else if !run.dry_run {
+        info!(
+            "removed {} files of index {} ({} bytes).",
+            files.len(),
+            opts.prefix,
+            num_bytes
+        );
+    }
+    Ok(json!({
+        "files": files.iter().map(|f| f.display().to_string()).collect::<Vec<String>>(),
+        "num_bytes": num_bytes,
+    }))
+}
@@ -0,0 +1,172 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::rad::{decode_ref_id, RadReader};
+use crate::refinfo::RefInfo;
+
+/// The length assumed for fragments whose length is not recorded in the RAD
+/// file (e.g. single-end reads), if no fragment length is recorded at all.
+const DEFAULT_FRAG_LEN: u64 = 250;
+
+/// The path of the file with the suffix `suffix` for the coverage prefix `prefix`.
+fn prefixed_path(prefix: &Path, suffix: &str) -> PathBuf {
+    PathBuf::from(prefix.to_string_lossy().into_owned() + suffix)
+}
+
+/// The coverage of the references by the mapped fragments, as the changes in
+/// coverage at the positions where any fragment starts or ends.
+struct Coverage {
+    deltas: Vec<HashMap<u32, f64>>,
+}
+
+impl Coverage {
+    fn new(num_refs: usize) -> Self {
+        Self {
+            deltas: vec![HashMap::new(); num_refs],
+        }
+    }
+
+    /// Add `weight` to the coverage of the bases `start..end` of reference `r`,
+    /// of length `ref_len`, clamped to the bounds of the reference.
+    fn add(&mut self, r: usize, start: i64, end: i64, ref_len: u64, weight: f64) {
+        let start = start.clamp(0, ref_len as i64) as u32;
+        let end = end.clamp(0, ref_len as i64) as u32;
+        if start >= end {
+            return;
+        }
+        *self.deltas[r].entry(start).or_insert(0.0) += weight;
+        *self.deltas[r].entry(end).or_insert(0.0) -= weight;
+    }
+
+    /// Write the coverage in bedGraph format to `out`, merging adjacent bases
+    /// with the same coverage and leaving out those that aren't covered.
+    /// Returns the number of intervals written.
+    fn write_bedgraph<W: Write>(&self, refs: &RefInfo, out: &mut W) -> Result<u64> {
+        let mut num_intervals = 0_u64;
+        for (r, deltas) in self.deltas.iter().enumerate() {
+            let mut positions = deltas.iter().collect::<Vec<_>>();
+            positions.sort_unstable_by_key(|(p, _)| **p);
+            let mut depth = 0.0;
+            let mut start = 0_u32;
+            for (&pos, &delta) in positions {
+                // fragments ending where others start leave the coverage unchanged,
+                // and differences below this are only left by rounding the weights
+                if delta.abs() < 1e-9 {
+                    continue;
+                }
+                if depth > 1e-9 && pos > start {
+                    writeln!(out, "{}\t{}\t{}\t{}", refs.names[r], start, pos, depth)?;
+                    num_intervals += 1;
+                }
+                depth += delta;
+                start = pos;
+            }
+        }
+        Ok(num_intervals)
+    }
+}
+
+/// Accumulate the coverage of the references `refs` by the fragments mapped in
+/// the bulk RAD file at `rad_path` and write it, in bedGraph format, to
+/// `<prefix>.bedGraph`, along with the reference lengths in `<prefix>.chrom.sizes`
+/// (as needed to convert it to bigWig with `bedGraphToBigWig`). Each fragment
+/// covers the bases from its mapping position for its length (or the mean
+/// fragment length, if its length isn't recorded), and contributes equally to
+/// each of its alignments. Returns a summary of the coverage.
+pub(crate) fn write_coverage(rad_path: &Path, refs: &RefInfo, prefix: &Path) -> Result<Value> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    if rad.header.ref_names != refs.names {
+        bail!(
+            "the references of RAD file {} do not match those of the index.",
+            rad_path.display()
+        );
+    }
+    let tag_pos = |name: &str| rad.header.aln_tags.iter().position(|t| t.name == name);
+    let (pos_tag, frag_len_tag) = match (tag_pos("pos"), tag_pos("frag_len")) {
+        (Some(p), f) => (p, f),
+        (None, _) => bail!(
+            "RAD file {} does not record mapping positions.",
+            rad_path.display()
+        ),
+    };
+
+    // fragments without a recorded length are placed once their mean length is known
+    let mut coverage = Coverage::new(refs.names.len());
+    let mut unsized_alns = Vec::<(usize, i64, f64)>::new();
+    let (mut frag_len_sum, mut num_frag_lens) = (0_u64, 0_u64);
+    let mut num_frags = 0_u64;
+    while let Some(rec) = rad.next_record()? {
+        if rec.alns.is_empty() {
+            continue;
+        }
+        num_frags += 1;
+        let weight = 1.0 / rec.alns.len() as f64;
+        for aln in &rec.alns {
+            let r = match aln.first().and_then(|v| v.as_u64()) {
+                Some(v) => decode_ref_id(v).0 as usize,
+                None => bail!("RAD alignment record is missing its reference id"),
+            };
+            if r >= refs.names.len() {
+                bail!("RAD alignment refers to unknown reference id {}", r);
+            }
+            // positions are stored as (possibly negative) 0-based offsets
+            let pos = aln
+                .get(pos_tag)
+                .and_then(|p| p.as_u64())
+                .map_or(0, |p| p as u32 as i32 as i64);
+            let frag_len = frag_len_tag
+                .and_then(|f| aln.get(f)?.as_u64())
+                .map(|l| l as u32 as i32)
+                .filter(|l| *l > 0);
+            match frag_len {
+                Some(l) => {
+                    frag_len_sum += l as u64;
+                    num_frag_lens += 1;
+                    coverage.add(r, pos, pos + l as i64, refs.lengths[r], weight);
+                }
+                None => unsized_alns.push((r, pos, weight)),
+            }
+        }
+    }
+    let mean_frag_len = frag_len_sum
+        .checked_div(num_frag_lens)
+        .unwrap_or(DEFAULT_FRAG_LEN);
+    for (r, pos, weight) in unsized_alns {
+        coverage.add(r, pos, pos + mean_frag_len as i64, refs.lengths[r], weight);
+    }
+
+    let bedgraph_path = prefixed_path(prefix, ".bedGraph");
+    let f = File::create(&bedgraph_path)
+        .with_context(|| format!("could not create {}", bedgraph_path.display()))?;
+    let mut out = BufWriter::new(f);
+    let num_intervals = coverage.write_bedgraph(refs, &mut out)?;
+    out.flush()?;
+
+    let sizes_path = prefixed_path(prefix, ".chrom.sizes");
+    let f = File::create(&sizes_path)
+        .with_context(|| format!("could not create {}", sizes_path.display()))?;
+    let mut out = BufWriter::new(f);
+    for (name, len) in refs.names.iter().zip(refs.lengths.iter()) {
+        writeln!(out, "{}\t{}", name, len)?;
+    }
+    out.flush()?;
+
+    info!(
+        "wrote the coverage of {} fragments ({} intervals) to {}.",
+        num_frags,
+        num_intervals,
+        bedgraph_path.display()
+    );
+    Ok(json!({
+        "bedgraph": bedgraph_path.display().to_string(),
+        "chrom_sizes": sizes_path.display().to_string(),
+        "num_fragments": num_frags,
+        "num_intervals": num_intervals,
+        "mean_frag_len": mean_frag_len,
+    }))
+}
@@ -0,0 +1,201 @@
+use anyhow::{bail, Context, Result};
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::num::NonZeroUsize;
+use std::thread;
+use tempfile::TempDir;
+use tracing::{info, warn};
+
+use crate::fifo_reads;
+use crate::stream_input;
+
+/// How a read file is compressed, as far as decompression is concerned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Compression {
+    None,
+    /// a gzip stream, which can only be decompressed sequentially
+    Gzip,
+    /// a BGZF file (e.g. as written by `bgzip`), made of independent gzip
+    /// blocks that can be decompressed in parallel
+    Bgzf,
+}
+
+/// Identify the compression of the file `f` from its gzip header, if any: a
+/// BGZF block is a gzip member with an extra field holding a `BC` subfield.
+fn detect_compression(f: &str) -> Result<Compression> {
+    let mut head = Vec::with_capacity(14);
+    File::open(f)
+        .with_context(|| format!("could not open read file {}", f))?
+        .take(14)
+        .read_to_end(&mut head)?;
+    Ok(match head.as_slice() {
+        [0x1f, 0x8b, _, flags, .., b'B', b'C'] if flags & 0x04 != 0 && head.len() == 14 => {
+            Compression::Bgzf
+        }
+        [0x1f, 0x8b, ..] => Compression::Gzip,
+        _ => Compression::None,
+    })
+}
+
+/// Read files that are being decompressed into fifos by separate threads, from
+/// which the mapper reads them as though they were uncompressed files.
+pub(crate) struct DecompressedReads {
+    /// the directory holding the fifos, which is removed once they are written
+    tmp_dir: TempDir,
+    /// the handles of the threads decompressing the reads
+    join_handles: Vec<thread::JoinHandle<Result<()>>>,
+}
+
+impl DecompressedReads {
+    /// Wait for all the threads decompressing the reads to finish.
+    pub fn join(self) -> Result<()> {
+        for h in self.join_handles {
+            match h.join() {
+                Ok(r) => r?,
+                Err(_) => bail!("a thread decompressing reads panicked"),
+            }
+        }
+        self.tmp_dir.close()?;
+        Ok(())
+    }
+}
+
+/// Decompress the read files `files` one after the other into the fifo `fifo`,
+/// using `threads` threads for BGZF files and a single one for other gzipped files.
+fn decompress_into(
+    files: Vec<String>,
+    fifo: std::path::PathBuf,
+    threads: NonZeroUsize,
+) -> Result<()> {
+    // opening the fifo blocks until the mapper opens it for reading
+    let mut out = BufWriter::new(File::create(&fifo)?);
+    for f in &files {
+        let file = File::open(f).with_context(|| format!("could not open read file {}", f))?;
+        let copied = match detect_compression(f)? {
+            Compression::Bgzf => std::io::copy(
+                &mut noodles_bgzf::io::MultithreadedReader::with_worker_count(threads, file),
+                &mut out,
+            ),
+            Compression::Gzip => {
+                std::io::copy(&mut MultiGzDecoder::new(BufReader::new(file)), &mut out)
+            }
+            Compression::None => std::io::copy(&mut BufReader::new(file), &mut out),
+        };
+        copied.with_context(|| format!("failed to decompress read file {}", f))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Decompress the (gzipped) read files in `lists` with `threads` threads each,
+/// rather than leaving the decompression to the mapper's reader. The lists hold
+/// the files of the different mates (or barcodes) of the same reads, so they are
+/// handled together: if any of them contains a gzipped file, every list is
+/// replaced with a single fifo, into which its files are decompressed (or, for
+/// plain files, copied), one after the other, by a separate thread. This keeps
+/// the lists the same length, so that their files stay paired. BGZF files are
+/// decompressed in parallel by `threads` threads; other gzip files can only be
+/// decompressed sequentially, but this still moves their decompression off the
+/// mapper's reader thread. Since streams can only be read once, nothing is
+/// decompressed if any file is a stream. Returns `None` if no list was replaced.
+pub(crate) fn decompress_to_fifos<'a>(
+    lists: impl IntoIterator<Item = &'a mut Vec<String>>,
+    threads: NonZeroUsize,
+) -> Result<Option<DecompressedReads>> {
+    let mut lists = lists.into_iter().collect::<Vec<&mut Vec<String>>>();
+    if let Some(f) = lists
+        .iter()
+        .flat_map(|l| l.iter())
+        .find(|f| stream_input::is_stream(f))
+    {
+        warn!(
+            "--decompression-threads is ignored, since the read file {} is a stream; the mapper decompresses the reads itself.",
+            f
+        );
+        return Ok(None);
+    }
+    let mut num_bgzf = 0_usize;
+    let mut num_gzip = 0_usize;
+    for f in lists.iter().flat_map(|l| l.iter()) {
+        match detect_compression(f)? {
+            Compression::Bgzf => num_bgzf += 1,
+            Compression::Gzip => num_gzip += 1,
+            Compression::None => {}
+        }
+    }
+    if num_bgzf + num_gzip == 0 {
+        return Ok(None);
+    }
+    info!(
+        "decompressing {} BGZF and {} gzip read file(s) with {} thread(s) per list of files.",
+        num_bgzf, num_gzip, threads
+    );
+
+    let tmp_dir = TempDir::new()?;
+    let mut join_handles = Vec::new();
+    for (i, list) in lists.iter_mut().enumerate() {
+        let fifo = tmp_dir.path().join(format!("reads_{}.pipe", i));
+        fifo_reads::make_fifo(&fifo)?;
+        let files = std::mem::replace(*list, vec![fifo.to_string_lossy().into_owned()]);
+        join_handles.push(thread::spawn(move || decompress_into(files, fifo, threads)));
+    }
+    Ok(Some(DecompressedReads {
+        tmp_dir,
+        join_handles,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression as GzLevel;
+
+    fn write_gz(path: &std::path::Path, content: &str) {
+        let mut gz = GzEncoder::new(File::create(path).unwrap(), GzLevel::default());
+        gz.write_all(content.as_bytes()).unwrap();
+        gz.finish().unwrap();
+    }
+
+    #[test]
+    fn collapses_every_list_when_one_is_gzipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut read1 = Vec::new();
+        let mut read2 = Vec::new();
+        for i in 0..2 {
+            let r1 = dir.path().join(format!("s{}_R1.fq.gz", i));
+            write_gz(&r1, &format!("@r{}/1\nACGT\n+\nIIII\n", i));
+            read1.push(r1.to_string_lossy().into_owned());
+            let r2 = dir.path().join(format!("s{}_R2.fq", i));
+            std::fs::write(&r2, format!("@r{}/2\nTTTT\n+\nIIII\n", i)).unwrap();
+            read2.push(r2.to_string_lossy().into_owned());
+        }
+
+        let threads = NonZeroUsize::new(2).unwrap();
+        let decompressed = decompress_to_fifos([&mut read1, &mut read2], threads)
+            .unwrap()
+            .unwrap();
+        assert_eq!((read1.len(), read2.len()), (1, 1));
+        let readers = [read1[0].clone(), read2[0].clone()]
+            .map(|f| thread::spawn(move || std::fs::read_to_string(f).unwrap()));
+        let [r1, r2] = readers.map(|h| h.join().unwrap());
+        decompressed.join().unwrap();
+        assert_eq!(r1, "@r0/1\nACGT\n+\nIIII\n@r1/1\nACGT\n+\nIIII\n");
+        assert_eq!(r2, "@r0/2\nTTTT\n+\nIIII\n@r1/2\nTTTT\n+\nIIII\n");
+    }
+
+    #[test]
+    fn leaves_plain_lists_as_they_are() {
+        let dir = tempfile::tempdir().unwrap();
+        let r1 = dir.path().join("R1.fq");
+        std::fs::write(&r1, "@r/1\nACGT\n+\nIIII\n").unwrap();
+        let mut read1 = vec![r1.to_string_lossy().into_owned()];
+        let before = read1.clone();
+        let threads = NonZeroUsize::new(2).unwrap();
+        assert!(decompress_to_fifos([&mut read1], threads)
+            .unwrap()
+            .is_none());
+        assert_eq!(read1, before);
+    }
+}
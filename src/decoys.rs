@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use needletail::parse_fastx_file;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::splici;
+
+/// The path of the decoys written by `write_genome_decoys` for the stem `out_stem`.
+pub(crate) fn genome_decoys_path(out_stem: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.genome_decoys.fa", out_stem.to_string_lossy()))
+}
+
+/// Derive the decoy sequences of an index over a transcriptome from the genome
+/// FASTA file `genome`, and write them to `<out_stem>.genome_decoys.fa`. The
+/// poison k-mers are the k-mers of the decoys that are not in the index, so the
+/// genome can serve as the decoys as is; but if the GTF file `gtf` annotating the
+/// transcriptome is given, the genomic sequence covered by its exons is masked
+/// (with Ns), so that the poison table builder does not have to look up the
+/// k-mers of the transcriptome itself. Only the k-mers lying entirely within an
+/// exon are masked (the first and last `k - 1` bases of each exon are kept), so
+/// that the k-mers spanning exon-intron boundaries, which are not in the
+/// transcriptome, still become poison. Returns the path of the decoys.
+pub(crate) fn write_genome_decoys(
+    genome: &Path,
+    gtf: Option<&Path>,
+    k: usize,
+    out_stem: &Path,
+) -> Result<PathBuf> {
+    let exons = match gtf {
+        Some(gtf) => splici::read_gtf_exons(gtf)?,
+        None => HashMap::new(),
+    };
+    let decoys = genome_decoys_path(out_stem);
+    if let Some(parent_path) = decoys.parent() {
+        if !parent_path.as_os_str().is_empty() && !parent_path.exists() {
+            std::fs::create_dir_all(parent_path)?;
+        }
+    }
+    let f =
+        File::create(&decoys).with_context(|| format!("could not create {}", decoys.display()))?;
+    let mut out = BufWriter::new(f);
+
+    let (mut num_seqs, mut num_bases, mut num_masked) = (0_u64, 0_u64, 0_u64);
+    let mut reader = parse_fastx_file(genome)
+        .with_context(|| format!("could not open decoy genome {}", genome.display()))?;
+    while let Some(rec) = reader.next() {
+        let rec = rec.with_context(|| format!("could not parse {}", genome.display()))?;
+        let name = String::from_utf8_lossy(rec.id());
+        let name = name.split_whitespace().next().unwrap_or_default();
+        let mut seq = rec.seq().into_owned();
+        for &(s, e) in exons.get(name).into_iter().flatten() {
+            // the 0-based, half-open interval of the bases only covered by
+            // k-mers within the exon
+            let start = (s as usize - 1 + (k - 1)).min(seq.len());
+            let end = (e as usize).saturating_sub(k - 1).min(seq.len());
+            if start < end {
+                seq[start..end].fill(b'N');
+                num_masked += (end - start) as u64;
+            }
+        }
+        writeln!(out, ">{}", name)?;
+        out.write_all(&seq)?;
+        writeln!(out)?;
+        num_seqs += 1;
+        num_bases += seq.len() as u64;
+    }
+    out.flush()?;
+
+    info!(
+        "wrote {} decoy sequences ({} bases, of which {} exonic bases were masked) derived from the genome {} to {}.",
+        num_seqs,
+        num_bases,
+        num_masked,
+        genome.display(),
+        decoys.display()
+    );
+    Ok(decoys)
+}
@@ -0,0 +1,238 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::map_info;
+use crate::permit_list::{barcode_tag, decode_barcode};
+use crate::rad::{self, RadReader, RadWriter, TagValue};
+
+/// The samples of a demultiplexing map, along with the barcode prefixes (or
+/// suffixes) that identify the reads of each.
+pub(crate) struct DemuxMap {
+    /// the barcode prefixes, sorted from the longest to the shortest, with the
+    /// index of their sample in `samples`.
+    prefixes: Vec<(String, usize)>,
+    samples: Vec<String>,
+    /// whether the prefixes are matched against the end of the barcodes (i.e.
+    /// are suffixes), e.g. for the probe barcode of 10x Flex reads
+    match_end: bool,
+}
+
+impl DemuxMap {
+    /// Read the demultiplexing map at `path`: a tab-separated file with a barcode
+    /// prefix (or suffix, if `match_end`) and a sample name on each line. Several
+    /// prefixes may belong to the same sample, but a prefix may only be listed once.
+    pub(crate) fn from_tsv(path: &Path, match_end: bool) -> Result<Self> {
+        let kind = if match_end { "suffix" } else { "prefix" };
+        let f = File::open(path)
+            .with_context(|| format!("could not open demultiplexing map {}", path.display()))?;
+        let mut prefixes: Vec<(String, usize)> = Vec::new();
+        let mut samples: Vec<String> = Vec::new();
+        for (line_num, line) in BufReader::new(f).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split('\t').map(str::trim).collect::<Vec<&str>>();
+            let (prefix, sample) = match fields[..] {
+                [p, s] if !p.is_empty() && !s.is_empty() => (p.to_ascii_uppercase(), s),
+                _ => bail!(
+                    "line {} of demultiplexing map {} is not a barcode {} and a sample name separated by a tab.",
+                    line_num + 1,
+                    path.display(),
+                    kind
+                ),
+            };
+            if !prefix.bytes().all(|c| b"ACGT".contains(&c)) {
+                bail!(
+                    "barcode {} {} of demultiplexing map {} contains characters other than A, C, G and T.",
+                    kind,
+                    prefix,
+                    path.display()
+                );
+            }
+            if sample == "." || sample == ".." || sample.contains(['/', '\\']) {
+                bail!(
+                    "sample name {} of demultiplexing map {} can't be used as a directory name.",
+                    sample,
+                    path.display()
+                );
+            }
+            if prefixes.iter().any(|(p, _)| *p == prefix) {
+                bail!(
+                    "barcode {} {} is listed more than once in demultiplexing map {}.",
+                    kind,
+                    prefix,
+                    path.display()
+                );
+            }
+            let s = match samples.iter().position(|n| n == sample) {
+                Some(s) => s,
+                None => {
+                    samples.push(sample.to_string());
+                    samples.len() - 1
+                }
+            };
+            prefixes.push((prefix, s));
+        }
+        if prefixes.is_empty() {
+            bail!(
+                "demultiplexing map {} does not list any sample.",
+                path.display()
+            );
+        }
+        prefixes.sort_by_key(|(p, _)| std::cmp::Reverse(p.len()));
+        Ok(Self {
+            prefixes,
+            samples,
+            match_end,
+        })
+    }
+
+    /// "suffix" if the map holds barcode suffixes, and "prefix" otherwise.
+    fn kind(&self) -> &'static str {
+        if self.match_end {
+            "suffix"
+        } else {
+            "prefix"
+        }
+    }
+
+    /// The index of the sample of barcode `bc`, i.e. that of its longest prefix
+    /// (or suffix) in the map, if any.
+    fn sample_of(&self, bc: &str) -> Option<usize> {
+        self.prefixes
+            .iter()
+            .find(|(p, _)| {
+                if self.match_end {
+                    bc.ends_with(p.as_str())
+                } else {
+                    bc.starts_with(p.as_str())
+                }
+            })
+            .map(|(_, s)| *s)
+    }
+}
+
+/// Split the records of the single-cell RAD file at `rad_path` among the samples
+/// of `demux_map`, by the prefix (or suffix) of their barcode. The records of each sample are
+/// written to `map.rad` in a directory named after the sample in `out_dir`, along
+/// with a copy of the mapping summary at `map_info_path` recording the number of
+/// records of the sample under `demux`. Records whose barcode matches no prefix
+/// are not written anywhere. Returns a summary of the demultiplexing.
+pub(crate) fn demux_rad(
+    rad_path: &Path,
+    demux_map: &DemuxMap,
+    map_info_path: &Path,
+    out_dir: &Path,
+) -> Result<Value> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    let (bc_tag, bc_len) = barcode_tag(&rad, rad_path)?;
+    let Some(bc_len) = bc_len else {
+        bail!(
+            "RAD file {} does not record the length of its barcodes.",
+            rad_path.display()
+        );
+    };
+    if let Some((p, _)) = demux_map.prefixes.iter().find(|(p, _)| p.len() > bc_len) {
+        bail!(
+            "barcode {} {} of the demultiplexing map is longer than the barcodes ({} bases).",
+            demux_map.kind(),
+            p,
+            bc_len
+        );
+    }
+    let mut header = rad.header.clone();
+    header.num_chunks = 0;
+    let mut writers = Vec::with_capacity(demux_map.samples.len());
+    for sample in &demux_map.samples {
+        let dir = out_dir.join(sample);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("could not create {}", dir.display()))?;
+        writers.push(RadWriter::create(&dir.join(rad::SC_RAD_FILE), &header)?);
+    }
+
+    let mut num_records = vec![0_u64; demux_map.samples.len()];
+    let mut num_unassigned = 0_u64;
+    while let Some(rec) = rad.next_record()? {
+        let bc = match rec.read_tags.get(bc_tag).and_then(TagValue::as_u64) {
+            Some(bc) => decode_barcode(bc, bc_len),
+            None => bail!(
+                "a record of RAD file {} has no barcode.",
+                rad_path.display()
+            ),
+        };
+        match demux_map.sample_of(&bc) {
+            Some(s) => {
+                writers[s].write_record(&rec)?;
+                num_records[s] += 1;
+            }
+            None => num_unassigned += 1,
+        }
+    }
+    for w in writers {
+        w.finish()?;
+    }
+
+    let info = map_info::read_map_info(map_info_path)?;
+    let mut per_sample = Map::new();
+    for (sample, n) in demux_map.samples.iter().zip(num_records.iter()) {
+        let mut sample_info = info.clone();
+        sample_info.insert(
+            "demux".to_string(),
+            json!({ "sample": sample, "num_records": n }),
+        );
+        std::fs::write(
+            out_dir.join(sample).join(map_info::SC_MAP_INFO_FILE),
+            serde_json::to_string_pretty(&sample_info)?,
+        )?;
+        if *n == 0 {
+            warn!("no record was assigned to sample {}.", sample);
+        }
+        per_sample.insert(sample.clone(), json!(n));
+    }
+    info!(
+        "demultiplexed {} records into {} samples ({} records matched no barcode {}).",
+        num_records.iter().sum::<u64>(),
+        demux_map.samples.len(),
+        num_unassigned,
+        demux_map.kind()
+    );
+    Ok(json!({
+        "matched_by": demux_map.kind(),
+        "num_records": per_sample,
+        "num_unassigned": num_unassigned,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn demux_map(tsv: &str, match_end: bool) -> DemuxMap {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("demux.tsv");
+        std::fs::write(&path, tsv).unwrap();
+        DemuxMap::from_tsv(&path, match_end).unwrap()
+    }
+
+    #[test]
+    fn matches_the_longest_prefix() {
+        let map = demux_map("AC\ts1\nACGT\ts2\nTT\ts1\n", false);
+        assert_eq!(map.sample_of("ACGTAAAA"), Some(1));
+        assert_eq!(map.sample_of("ACTTAAAA"), Some(0));
+        assert_eq!(map.sample_of("TTAAAAAC"), Some(0));
+        assert_eq!(map.sample_of("GGGGACGT"), None);
+    }
+
+    #[test]
+    fn matches_the_longest_suffix() {
+        // e.g. the probe barcodes appended to the cell barcodes of 10x Flex reads
+        let map = demux_map("ACGTACGT\tsample1\nTTTTCCCC\tsample2\n", true);
+        assert_eq!(map.sample_of("AAAAAAAATTTTCCCC"), Some(1));
+        assert_eq!(map.sample_of("ACGTACGTGGGGGGGG"), None);
+    }
+}
@@ -0,0 +1,386 @@
+use anyhow::{bail, Context, Result};
+use needletail::parse_fastx_file;
+use seq_geom_parser::{FragmentGeomDesc, GeomLen, GeomPiece};
+use seq_geom_xform::{FragmentGeomDescExt, SeqPair};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use tracing::{info, warn};
+
+use crate::geometry;
+use crate::stream_input;
+
+/// The number of read pairs sampled from the start of the input
+/// to detect the chemistry.
+const NUM_SAMPLED_PAIRS: usize = 100_000;
+
+/// The minimum length of an unbounded biological read sequence for
+/// a read to be considered usable under a candidate geometry.
+const MIN_READ_SEQ_LEN: usize = 31;
+
+/// The fraction of sampled reads that must match an anchored (i.e. complex)
+/// candidate geometry for it to be preferred over the unanchored ones.
+const MIN_ANCHORED_MATCH_RATE: f64 = 0.5;
+
+/// The minimum score the selected geometry must reach.
+const MIN_SCORE: f64 = 0.05;
+
+/// The chemistries considered by `--geometry auto`. The 10x GEM-X, Visium and
+/// Flex and the Parse v1 chemistries can't be distinguished from Chromium v3 and
+/// Parse v2 (respectively) by the read layout alone, and Visium HD reads are not
+/// distinguished reliably from their barcode repeat rate, so they are not candidates.
+const CANDIDATES: &[&str] = &[
+    "chromium_v2",
+    "chromium_v3",
+    "bd_rhapsody",
+    "bd_rhapsody_enhanced",
+    "parse_evercode_v2",
+    "10x_crispr_direct_capture",
+];
+
+/// The evidence collected for one candidate geometry.
+#[derive(Debug)]
+pub(crate) struct CandidateEvidence {
+    /// the name of the chemistry
+    pub name: &'static str,
+    /// the fraction of sampled read pairs that parse under the geometry
+    pub match_rate: f64,
+    /// the fraction of parsed read pairs whose barcode was already seen in
+    /// an earlier read pair; this is high for real cell barcodes and low for
+    /// sequence drawn from the cDNA
+    pub barcode_repeat_rate: f64,
+    /// the fraction of the bases of the parsed read pairs that are not
+    /// discarded by the geometry
+    pub utilization: f64,
+    /// the least entropy of the bases at any position of the UMI of the parsed
+    /// read pairs, relative to that of uniformly random bases (1 if the geometry
+    /// has no UMI); real UMIs are close to random at every position, while a UMI
+    /// taken too long runs into the poly(dT) that follows it (as for Chromium v2
+    /// reads under the Chromium v3 geometry, whose UMI is 2 bases longer)
+    pub umi_entropy: f64,
+    /// whether the geometry uses fixed anchor sequences or variable-length pieces
+    pub anchored: bool,
+}
+
+impl CandidateEvidence {
+    fn score(&self) -> f64 {
+        self.match_rate * self.barcode_repeat_rate * self.umi_entropy
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "match_rate": self.match_rate,
+            "barcode_repeat_rate": self.barcode_repeat_rate,
+            "utilization": self.utilization,
+            "umi_entropy": self.umi_entropy,
+            "score": self.score(),
+        })
+    }
+}
+
+/// The outcome of the chemistry detection.
+#[derive(Debug)]
+pub(crate) struct GeometryDetection {
+    /// the name of the selected chemistry
+    pub selected: &'static str,
+    /// the number of read pairs sampled
+    pub num_sampled: usize,
+    /// the evidence collected for every candidate
+    pub candidates: Vec<CandidateEvidence>,
+}
+
+impl GeometryDetection {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "selected": self.selected,
+            "num_sampled_pairs": self.num_sampled,
+            "candidates": self.candidates.iter().map(|c| c.to_json()).collect::<Vec<Value>>(),
+        })
+    }
+}
+
+/// Read (up to) the first `NUM_SAMPLED_PAIRS` read pairs from the files `r1` and `r2`.
+fn sample_read_pairs(r1: &str, r2: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut reader1 =
+        parse_fastx_file(r1).with_context(|| format!("could not open read file {}", r1))?;
+    let mut reader2 =
+        parse_fastx_file(r2).with_context(|| format!("could not open read file {}", r2))?;
+    let mut pairs = Vec::with_capacity(NUM_SAMPLED_PAIRS);
+    while pairs.len() < NUM_SAMPLED_PAIRS {
+        match (reader1.next(), reader2.next()) {
+            (Some(rec1), Some(rec2)) => {
+                let rec1 = rec1.with_context(|| format!("invalid record in {}", r1))?;
+                let rec2 = rec2.with_context(|| format!("invalid record in {}", r2))?;
+                pairs.push((
+                    rec1.seq().to_ascii_uppercase(),
+                    rec2.seq().to_ascii_uppercase(),
+                ));
+            }
+            _ => break,
+        }
+    }
+    Ok(pairs)
+}
+
+/// Append the barcode and UMI pieces of the parsed read `s`, laid out according
+/// to the simplified geometry `pieces`, to `bc` and `umi` respectively. Returns
+/// the length of any unbounded read sequence piece, or `None` if there is none.
+fn extract_pieces(
+    pieces: &[GeomPiece],
+    s: &str,
+    bc: &mut String,
+    umi: &mut String,
+) -> Option<usize> {
+    let mut offset = 0;
+    let mut read_seq_len = None;
+    for p in pieces {
+        let len = match p {
+            GeomPiece::Barcode(GeomLen::FixedLen(l))
+            | GeomPiece::Umi(GeomLen::FixedLen(l))
+            | GeomPiece::ReadSeq(GeomLen::FixedLen(l)) => *l as usize,
+            _ => s.len().saturating_sub(offset),
+        };
+        let end = (offset + len).min(s.len());
+        match p {
+            GeomPiece::Barcode(_) => bc.push_str(&s[offset..end]),
+            GeomPiece::Umi(_) => umi.push_str(&s[offset..end]),
+            GeomPiece::ReadSeq(GeomLen::Unbounded) => read_seq_len = Some(end - offset),
+            _ => {}
+        }
+        offset = end;
+    }
+    read_seq_len
+}
+
+/// The least entropy of the bases at any position, given the number of times
+/// every base was seen at every position in `base_counts`, relative to the
+/// entropy of uniformly random bases (2 bits). Returns 1 if there are no positions.
+fn min_relative_entropy(base_counts: &[[usize; 4]]) -> f64 {
+    base_counts
+        .iter()
+        .map(|counts| {
+            let total = counts.iter().sum::<usize>() as f64;
+            counts
+                .iter()
+                .filter(|c| **c > 0)
+                .map(|c| {
+                    let p = *c as f64 / total;
+                    -p * p.log2()
+                })
+                .sum::<f64>()
+                / 2.0
+        })
+        .fold(1.0, f64::min)
+}
+
+/// Evaluate the candidate geometry `spec`, named `name`, on the sampled read `pairs`.
+fn evaluate_candidate(
+    name: &'static str,
+    spec: &str,
+    pairs: &[(Vec<u8>, Vec<u8>)],
+) -> Result<CandidateEvidence> {
+    let geo_desc = match FragmentGeomDesc::try_from(spec) {
+        Ok(g) => g,
+        Err(e) => bail!("Could not parse geometry description {}: {}", spec, e),
+    };
+    let mut geo_re = geo_desc.as_regex()?;
+    let simple_desc = geo_re.get_simplified_geo_desc();
+
+    let mut parsed = SeqPair::new();
+    let mut barcodes = HashSet::new();
+    let mut bc = String::new();
+    let mut umi = String::new();
+    let mut umi_base_counts = Vec::<[usize; 4]>::new();
+    let (mut num_matched, mut num_repeated) = (0_usize, 0_usize);
+    let (mut total_bases, mut kept_bases) = (0_usize, 0_usize);
+    for (r1, r2) in pairs {
+        if !geo_re.parse_into(r1, r2, &mut parsed) {
+            continue;
+        }
+        bc.clear();
+        umi.clear();
+        let rs1 = extract_pieces(&simple_desc.read1_desc, &parsed.s1, &mut bc, &mut umi);
+        let rs2 = extract_pieces(&simple_desc.read2_desc, &parsed.s2, &mut bc, &mut umi);
+        if rs1.or(rs2).is_some_and(|l| l < MIN_READ_SEQ_LEN) {
+            continue;
+        }
+        num_matched += 1;
+        if !barcodes.insert(bc.clone()) {
+            num_repeated += 1;
+        }
+        if umi_base_counts.len() < umi.len() {
+            umi_base_counts.resize(umi.len(), [0; 4]);
+        }
+        for (counts, b) in umi_base_counts.iter_mut().zip(umi.bytes()) {
+            match b {
+                b'A' => counts[0] += 1,
+                b'C' => counts[1] += 1,
+                b'G' => counts[2] += 1,
+                b'T' => counts[3] += 1,
+                _ => {}
+            }
+        }
+        total_bases += r1.len() + r2.len();
+        kept_bases += parsed.s1.len() + parsed.s2.len();
+    }
+
+    let ratio = |a: usize, b: usize| if b > 0 { a as f64 / b as f64 } else { 0.0 };
+    Ok(CandidateEvidence {
+        name,
+        match_rate: ratio(num_matched, pairs.len()),
+        barcode_repeat_rate: ratio(num_repeated, num_matched),
+        utilization: ratio(kept_bases, total_bases),
+        umi_entropy: min_relative_entropy(&umi_base_counts),
+        anchored: geo_desc.is_complex_geometry(),
+    })
+}
+
+/// Detect the chemistry of the reads in `read1` and `read2` by sampling read
+/// pairs from the first pair of files and evaluating every candidate geometry
+/// on them. If any anchored geometry (one with fixed linker or scaffold
+/// sequences) matches most reads, the best scoring anchored geometry is
+/// selected, since the anchors are strong evidence. Otherwise, the geometry
+/// maximizing the product of the match rate, the barcode repeat rate and the
+/// UMI entropy is selected, ties being broken by the fraction of bases used
+/// (so that, e.g., Chromium v3 reads, whose UMI is as random under the Chromium
+/// v2 geometry, are detected as such).
+pub(crate) fn detect_geometry(read1: &[String], read2: &[String]) -> Result<GeometryDetection> {
+    let (r1, r2) = match (read1.first(), read2.first()) {
+        (Some(r1), Some(r2)) => (r1, r2),
+        _ => bail!("read files are required to detect the geometry."),
+    };
+    if stream_input::is_stream(r1) || stream_input::is_stream(r2) {
+        bail!("the geometry cannot be detected from reads streamed through standard input, a named pipe or from a URL; please provide the geometry explicitly.");
+    }
+    let pairs = sample_read_pairs(r1, r2)?;
+    if pairs.is_empty() {
+        bail!(
+            "no read pairs could be sampled from {} and {} to detect the geometry.",
+            r1,
+            r2
+        );
+    }
+    if pairs.len() < NUM_SAMPLED_PAIRS {
+        warn!(
+            "only {} read pairs were available to detect the geometry; the detection may be unreliable.",
+            pairs.len()
+        );
+    }
+
+    let mut candidates = Vec::with_capacity(CANDIDATES.len());
+    for name in CANDIDATES {
+        let spec = match geometry::lookup_preset(name) {
+            Some(preset) => preset.spec,
+            None => geometry::MAPPER_GEOMETRIES
+                .iter()
+                .find(|g| g.name == *name)
+                .map(|g| g.spec)
+                .with_context(|| format!("no geometry description for candidate {}", name))?,
+        };
+        candidates.push(evaluate_candidate(name, spec, &pairs)?);
+    }
+
+    let anchored_match = candidates
+        .iter()
+        .any(|c| c.anchored && c.match_rate >= MIN_ANCHORED_MATCH_RATE);
+    let best = candidates
+        .iter()
+        .filter(|c| !anchored_match || c.anchored)
+        .max_by(|a, b| {
+            a.score()
+                .total_cmp(&b.score())
+                .then(a.utilization.total_cmp(&b.utilization))
+        });
+
+    let selected = match best {
+        Some(c) if c.score() >= MIN_SCORE => c.name,
+        _ => bail!(
+            "could not detect the geometry of the reads in {} and {}; please provide it with --geometry.",
+            r1,
+            r2
+        ),
+    };
+    info!(
+        "detected geometry {} from {} sampled read pairs.",
+        selected,
+        pairs.len()
+    );
+    Ok(GeometryDetection {
+        selected,
+        num_sampled: pairs.len(),
+        candidates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// A minimal linear congruential generator, so that the reads are the same
+    /// on every run.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn seq(&mut self, len: usize) -> String {
+            (0..len)
+                .map(|_| {
+                    self.0 = self
+                        .0
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    b"ACGT"[(self.0 >> 33) as usize % 4] as char
+                })
+                .collect()
+        }
+    }
+
+    /// Write read pairs of the 10x Chromium 3' layout, with a 16-base barcode
+    /// (drawn from 50 cells), a UMI of `umi_len` bases and the poly(dT) that
+    /// follows it in a 30-base read 1, and a 90-base read 2, to `r1.fq` and
+    /// `r2.fq` in `dir`.
+    fn write_chromium_reads(dir: &std::path::Path, umi_len: usize) -> (String, String) {
+        let mut rng = Lcg(umi_len as u64);
+        let cells = (0..50).map(|_| rng.seq(16)).collect::<Vec<String>>();
+        let r1 = dir.join("r1.fq");
+        let r2 = dir.join("r2.fq");
+        let mut f1 = std::fs::File::create(&r1).unwrap();
+        let mut f2 = std::fs::File::create(&r2).unwrap();
+        for i in 0..2000 {
+            let s1 = format!(
+                "{}{}{}",
+                cells[i % cells.len()],
+                rng.seq(umi_len),
+                "T".repeat(30 - 16 - umi_len)
+            );
+            let s2 = rng.seq(90);
+            writeln!(f1, "@r{}\n{}\n+\n{}", i, s1, "I".repeat(s1.len())).unwrap();
+            writeln!(f2, "@r{}\n{}\n+\n{}", i, s2, "I".repeat(s2.len())).unwrap();
+        }
+        (r1.display().to_string(), r2.display().to_string())
+    }
+
+    #[test]
+    fn tells_chromium_v2_from_v3() {
+        let dir = tempfile::tempdir().unwrap();
+        let (r1, r2) = write_chromium_reads(dir.path(), 10);
+        let detection = detect_geometry(&[r1], &[r2]).unwrap();
+        assert_eq!(detection.selected, "chromium_v2");
+
+        let dir = tempfile::tempdir().unwrap();
+        let (r1, r2) = write_chromium_reads(dir.path(), 12);
+        let detection = detect_geometry(&[r1], &[r2]).unwrap();
+        assert_eq!(detection.selected, "chromium_v3");
+    }
+
+    #[test]
+    fn measures_the_entropy_of_umi_positions() {
+        assert_eq!(min_relative_entropy(&[]), 1.0);
+        assert_eq!(min_relative_entropy(&[[25, 25, 25, 25]]), 1.0);
+        assert_eq!(
+            min_relative_entropy(&[[25, 25, 25, 25], [0, 0, 0, 100]]),
+            0.0
+        );
+        assert_eq!(min_relative_entropy(&[[50, 50, 0, 0]]), 0.5);
+    }
+}
@@ -0,0 +1,71 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::raw::c_int;
+
+fn check(ret: c_int, what: &str) -> Result<c_int> {
+    if ret < 0 {
+        Err(std::io::Error::last_os_error()).context(what.to_string())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Flush the buffered output of both Rust and C (and so C++) streams, so that
+/// none of it ends up on the wrong side of a redirection.
+fn flush_all() {
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+    unsafe { libc::fflush(std::ptr::null_mut()) };
+}
+
+/// Discards what is written to the standard output and, optionally, the
+/// standard error of the process (by piscem itself and by the C++ components it
+/// calls) while it is alive, e.g. to silence a C++ stage that has no option to
+/// be quiet. The original file descriptors are restored when it is dropped.
+pub(crate) struct DiscardOutput {
+    /// the redirected file descriptors, with duplicates of the originals
+    saved: Vec<(RawFd, RawFd)>,
+}
+
+impl DiscardOutput {
+    pub fn new(stderr: bool) -> Result<Self> {
+        let null = File::options()
+            .write(true)
+            .open("/dev/null")
+            .context("could not open /dev/null")?;
+        flush_all();
+        let mut guard = Self { saved: Vec::new() };
+        let fds = if stderr {
+            vec![libc::STDOUT_FILENO, libc::STDERR_FILENO]
+        } else {
+            vec![libc::STDOUT_FILENO]
+        };
+        for fd in fds {
+            let saved = check(
+                unsafe { libc::dup(fd) },
+                "could not duplicate a file descriptor",
+            )?;
+            // recorded first, so that it is restored even if the redirection fails
+            guard.saved.push((fd, saved));
+            check(
+                unsafe { libc::dup2(null.as_raw_fd(), fd) },
+                "could not redirect output to /dev/null",
+            )?;
+        }
+        Ok(guard)
+    }
+}
+
+impl Drop for DiscardOutput {
+    fn drop(&mut self) {
+        flush_all();
+        for &(fd, saved) in self.saved.iter().rev() {
+            unsafe {
+                libc::dup2(saved, fd);
+                libc::close(saved);
+            }
+        }
+    }
+}
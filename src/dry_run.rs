@@ -0,0 +1,28 @@
+use std::ffi::CString;
+use std::fmt::Debug;
+
+/// Print the validated configuration `config` of the command `command`, at the
+/// start of a dry run.
+pub(crate) fn print_config(command: &str, config: &impl Debug) {
+    println!("dry run of `piscem {}`; nothing will be executed.", command);
+    println!("configuration: {:#?}", config);
+}
+
+/// Print the argument vector `args` that would be passed to the C++ entry point
+/// `entry_point`, as a JSON array of strings.
+pub(crate) fn print_argv(entry_point: &str, args: &[CString]) {
+    let argv = args
+        .iter()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect::<Vec<String>>();
+    println!(
+        "{}: {}",
+        entry_point,
+        serde_json::to_string(&argv).unwrap_or_default()
+    );
+}
+
+/// Print that the step `step` would be performed by a real run.
+pub(crate) fn print_step(step: &str) {
+    println!("would {}", step);
+}
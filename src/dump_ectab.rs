@@ -0,0 +1,132 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use tracing::info;
+
+use crate::dump_unitigs::{self, TILINGS_SUFFIX};
+use crate::exit_code::{bail_with, ExitKind};
+use crate::inspect;
+use crate::piscem_commands::DumpEctabOpts;
+
+/// Write the equivalence classes of the unitigs of the compacted de Bruijn graph
+/// underlying the index given in `opts` as TSV: one row per class, with its id,
+/// the number of unitigs in it, and the (reference, orientation) pairs it
+/// consists of. These are the classes of the `.ectab` table of the index, but
+/// they are computed from the tilings of the references by the unitigs kept by
+/// `build --keep-intermediate-dbg`, since the table itself is stored in the
+/// binary format of the mapper. Classes are numbered in the order of the id of
+/// their first unitig. With `--unitigs`, the class of every unitig is written as
+/// well. Returns a summary of the classes.
+pub(crate) fn dump_ectab(opts: &DumpEctabOpts) -> Result<Value> {
+    let seq_path = inspect::component_path(&opts.index, TILINGS_SUFFIX);
+    if !seq_path.exists() {
+        bail_with!(
+            ExitKind::MissingIndexComponent,
+            "the compacted de Bruijn graph of index {} was not found ({} does not exist); it is only kept if the index is built with --keep-intermediate-dbg.",
+            opts.index,
+            seq_path.display()
+        );
+    }
+
+    // the (reference, orientation) pairs of every unitig, by unitig id
+    let f =
+        File::open(&seq_path).with_context(|| format!("could not open {}", seq_path.display()))?;
+    let mut unitig_refs = BTreeMap::<u64, Vec<(u32, bool)>>::new();
+    let mut names = Vec::<String>::new();
+    for (line_num, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let Some((name, tiling)) = line.split_once('\t') else {
+            bail!(
+                "line {} of {} is not a reference name and a tiling.",
+                line_num + 1,
+                seq_path.display()
+            );
+        };
+        let name = dump_unitigs::ref_name(name);
+        // a reference split at runs of Ns has one tiling per piece
+        let r = match names.iter().rposition(|n| n == name) {
+            Some(r) => r,
+            None => {
+                names.push(name.to_string());
+                names.len() - 1
+            }
+        } as u32;
+        for t in tiling.split_ascii_whitespace() {
+            let (id, fw) = match t.strip_suffix('+') {
+                Some(id) => (id, true),
+                None => (t.strip_suffix('-').unwrap_or(t), false),
+            };
+            let Ok(id) = id.parse::<u64>() else {
+                bail!(
+                    "{} is not a valid unitig in line {} of {}.",
+                    t,
+                    line_num + 1,
+                    seq_path.display()
+                );
+            };
+            unitig_refs.entry(id).or_default().push((r, fw));
+        }
+    }
+
+    // the distinct sets of pairs, numbered in the order of their first unitig
+    let mut ec_ids = HashMap::<Vec<(u32, bool)>, usize>::new();
+    let mut ecs = Vec::<(Vec<(u32, bool)>, u64)>::new();
+    let mut unitig_ecs = Vec::with_capacity(unitig_refs.len());
+    for (unitig, mut refs) in unitig_refs {
+        refs.sort_unstable();
+        refs.dedup();
+        let ec = *ec_ids.entry(refs).or_insert_with_key(|refs| {
+            ecs.push((refs.clone(), 0));
+            ecs.len() - 1
+        });
+        ecs[ec].1 += 1;
+        unitig_ecs.push((unitig, ec));
+    }
+
+    let mut out: Box<dyn Write> = match opts.output {
+        Some(ref p) => Box::new(BufWriter::new(
+            File::create(p).with_context(|| format!("could not create {}", p.display()))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    writeln!(out, "ec\tnum_unitigs\tnum_refs\trefs")?;
+    for (ec, (refs, num_unitigs)) in ecs.iter().enumerate() {
+        let refs_str = refs
+            .iter()
+            .map(|&(r, fw)| format!("{}:{}", names[r as usize], if fw { '+' } else { '-' }))
+            .collect::<Vec<String>>()
+            .join(",");
+        writeln!(out, "{}\t{}\t{}\t{}", ec, num_unitigs, refs.len(), refs_str)?;
+    }
+    out.flush()?;
+
+    if let Some(ref p) = opts.unitigs {
+        let f = File::create(p).with_context(|| format!("could not create {}", p.display()))?;
+        let mut out = BufWriter::new(f);
+        writeln!(out, "unitig\tec")?;
+        for (unitig, ec) in &unitig_ecs {
+            writeln!(out, "{}\t{}", unitig, ec)?;
+        }
+        out.flush()?;
+    }
+
+    let max_refs = ecs.iter().map(|(refs, _)| refs.len()).max().unwrap_or(0);
+    info!(
+        "wrote {} equivalence classes of {} unitigs over {} references (the largest of {} pairs).",
+        ecs.len(),
+        unitig_ecs.len(),
+        names.len(),
+        max_refs
+    );
+    Ok(json!({
+        "num_ecs": ecs.len(),
+        "num_unitigs": unitig_ecs.len(),
+        "num_refs": names.len(),
+        "max_ec_size": max_refs,
+    }))
+}
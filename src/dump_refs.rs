@@ -0,0 +1,217 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::dump_unitigs::{self, SEGMENTS_SUFFIX, TILINGS_SUFFIX};
+use crate::exit_code::{bail_with, ExitKind};
+use crate::piscem_commands::DumpRefsOpts;
+use crate::{inspect, query, refinfo, splici};
+
+/// Read the sequences of the unitigs of the compacted de Bruijn graph from `path`,
+/// by unitig id.
+fn read_unitigs(path: &Path) -> Result<HashMap<u64, Vec<u8>>> {
+    let f = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut unitigs = HashMap::new();
+    for (line_num, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let Some((id, seq)) = line
+            .split_once('\t')
+            .and_then(|(id, seq)| Some((id.parse::<u64>().ok()?, seq)))
+        else {
+            bail!(
+                "line {} of {} is not a unitig id and sequence.",
+                line_num + 1,
+                path.display()
+            );
+        };
+        unitigs.insert(id, seq.as_bytes().to_vec());
+    }
+    Ok(unitigs)
+}
+
+/// Spell out the sequence tiled by the oriented unitigs of `tiling`, each of which
+/// overlaps the previous one by `k - 1` bases.
+fn spell_tiling(tiling: &str, unitigs: &HashMap<u64, Vec<u8>>, k: usize) -> Result<Vec<u8>> {
+    let mut seq = Vec::new();
+    for t in tiling.split_ascii_whitespace() {
+        let (id, fw) = match t.strip_suffix('+') {
+            Some(id) => (id, true),
+            None => (t.strip_suffix('-').unwrap_or(t), false),
+        };
+        let Some(unitig) = id.parse::<u64>().ok().and_then(|id| unitigs.get(&id)) else {
+            bail!("the tiling refers to the unknown unitig {}.", t);
+        };
+        let mut unitig = unitig.clone();
+        if !fw {
+            splici::reverse_complement(&mut unitig);
+        }
+        if seq.is_empty() {
+            seq = unitig;
+        } else if unitig.len() < k || !seq.ends_with(&unitig[..k - 1]) {
+            bail!(
+                "the unitig {} does not overlap the previous one by {} bases.",
+                t,
+                k - 1
+            );
+        } else {
+            seq.extend_from_slice(&unitig[k - 1..]);
+        }
+    }
+    Ok(seq)
+}
+
+/// Write the reference `name` with the sequence `seq` as FASTA, wrapping it at
+/// `line_width` bases (not at all if it is 0).
+fn write_fasta<W: Write>(out: &mut W, name: &str, seq: &[u8], line_width: usize) -> Result<()> {
+    writeln!(out, ">{}", name)?;
+    if line_width == 0 {
+        out.write_all(seq)?;
+        writeln!(out)?;
+    } else {
+        for line in seq.chunks(line_width) {
+            out.write_all(line)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reconstruct the reference sequences of the index given in `opts` and write
+/// them to FASTA, in the order in which they were indexed. Each reference is
+/// spelled out from its tiling by the unitigs of the compacted de Bruijn graph
+/// kept by `build --keep-intermediate-dbg`. A reference with runs of
+/// characters other than ACGT is tiled piecewise, and the number and placement of
+/// the bases between the pieces are not recorded; these are filled with Ns, so
+/// that the reference has the length recorded in the index, but such references
+/// are not reconstructed exactly. Returns a summary of the references.
+pub(crate) fn dump_refs(opts: &DumpRefsOpts) -> Result<Value> {
+    let seg_path = inspect::component_path(&opts.index, SEGMENTS_SUFFIX);
+    let seq_path = inspect::component_path(&opts.index, TILINGS_SUFFIX);
+    for path in [&seg_path, &seq_path] {
+        if !path.exists() {
+            bail_with!(
+                ExitKind::MissingIndexComponent,
+                "the compacted de Bruijn graph of index {} was not found ({} does not exist); it is only kept if the index is built with --keep-intermediate-dbg.",
+                opts.index,
+                path.display()
+            );
+        }
+    }
+    let k = query::index_k(&opts.index)?;
+    let refs = refinfo::read_refinfo(&inspect::component_path(&opts.index, ".refinfo"))?;
+    let lengths = refs
+        .names
+        .iter()
+        .zip(refs.lengths.iter())
+        .map(|(n, &l)| (n.as_str(), l as usize))
+        .collect::<HashMap<&str, usize>>();
+    let unitigs = read_unitigs(&seg_path)?;
+
+    let mut out: Box<dyn Write> = match opts.output {
+        Some(ref p) => Box::new(BufWriter::new(
+            File::create(p).with_context(|| format!("could not create {}", p.display()))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let (mut num_refs, mut total_len) = (0_u64, 0_u64);
+    let mut inexact = Vec::new();
+    // the name and the pieces of the reference being reconstructed
+    let mut current: Option<(String, Vec<Vec<u8>>)> = None;
+    let mut emit = |name: String, pieces: Vec<Vec<u8>>| -> Result<()> {
+        let spelled = pieces.iter().map(|p| p.len()).sum::<usize>();
+        let len = lengths.get(name.as_str()).copied().unwrap_or(spelled);
+        if len < spelled {
+            bail!(
+                "reference {} spells out {} bases, but the index records a length of {}.",
+                name,
+                spelled,
+                len
+            );
+        }
+        let mut missing = len - spelled;
+        let seq = if pieces.len() == 1 && missing == 0 {
+            pieces.into_iter().next().unwrap_or_default()
+        } else {
+            // spread the bases that are not tiled evenly over the gaps between
+            // the pieces, or put them at the end if there is only one piece
+            let num_gaps = pieces.len().saturating_sub(1).max(1);
+            let mut seq = Vec::with_capacity(len);
+            for (i, piece) in pieces.iter().enumerate() {
+                seq.extend_from_slice(piece);
+                if i + 1 < pieces.len() || pieces.len() == 1 {
+                    let gap = missing / (num_gaps - i.min(num_gaps - 1));
+                    seq.resize(seq.len() + gap, b'N');
+                    missing -= gap;
+                }
+            }
+            inexact.push(name.clone());
+            seq
+        };
+        write_fasta(&mut out, &name, &seq, opts.line_width)?;
+        num_refs += 1;
+        total_len += seq.len() as u64;
+        Ok(())
+    };
+
+    let f =
+        File::open(&seq_path).with_context(|| format!("could not open {}", seq_path.display()))?;
+    for (line_num, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let Some((name, tiling)) = line.split_once('\t') else {
+            bail!(
+                "line {} of {} is not a reference name and a tiling.",
+                line_num + 1,
+                seq_path.display()
+            );
+        };
+        let name = dump_unitigs::ref_name(name);
+        let piece = spell_tiling(tiling, &unitigs, k).with_context(|| {
+            format!(
+                "could not reconstruct line {} of {}",
+                line_num + 1,
+                seq_path.display()
+            )
+        })?;
+        // a reference split at runs of Ns has one tiling per piece
+        match current {
+            Some((ref n, ref mut pieces)) if n == name => pieces.push(piece),
+            _ => {
+                if let Some((n, pieces)) = current.take() {
+                    emit(n, pieces)?;
+                }
+                current = Some((name.to_string(), vec![piece]));
+            }
+        }
+    }
+    if let Some((n, pieces)) = current.take() {
+        emit(n, pieces)?;
+    }
+    out.flush()?;
+
+    if !inexact.is_empty() {
+        warn!(
+            "{} references (e.g. {}) held characters other than ACGT, which were replaced by Ns at approximate positions.",
+            inexact.len(),
+            inexact[0]
+        );
+    }
+    info!(
+        "wrote {} references ({} bases in total).",
+        num_refs, total_len
+    );
+    Ok(json!({
+        "num_refs": num_refs,
+        "total_length": total_len,
+        "num_inexact_refs": inexact.len(),
+    }))
+}
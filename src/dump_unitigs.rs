@@ -0,0 +1,167 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use tracing::info;
+
+use crate::exit_code::{bail_with, ExitKind};
+use crate::inspect;
+use crate::piscem_commands::DumpUnitigsOpts;
+
+/// The suffix of the unitig sequences of the compacted de Bruijn graph, kept
+/// alongside the index by `build --keep-intermediate-dbg`.
+pub(crate) const SEGMENTS_SUFFIX: &str = "_cfish.cf_seg";
+
+/// The suffix of the tilings of the references by the unitigs of the compacted de
+/// Bruijn graph, kept alongside the index by `build --keep-intermediate-dbg`.
+pub(crate) const TILINGS_SUFFIX: &str = "_cfish.cf_seq";
+
+/// The name of a reference as written in the tilings by cuttlefish, which prefixes
+/// it with the index of its file (e.g. `Reference:1_Sequence:tx1`).
+pub(crate) fn ref_name(name: &str) -> &str {
+    name.strip_prefix("Reference:")
+        .and_then(|n| n.split_once("_Sequence:"))
+        .map_or(name, |(_, n)| n)
+}
+
+/// The colors of unitigs, i.e. the references they occur in.
+pub(crate) struct Colors {
+    /// the indices, into `names`, of the references of each unitig
+    sets: HashMap<u64, Vec<u32>>,
+    names: Vec<String>,
+}
+
+impl Colors {
+    /// The names of the references the unitig `unitig` occurs in.
+    pub(crate) fn ref_names(&self, unitig: u64) -> Vec<&str> {
+        self.sets
+            .get(&unitig)
+            .into_iter()
+            .flatten()
+            .map(|&r| self.names[r as usize].as_str())
+            .collect()
+    }
+}
+
+/// Read the colors of the unitigs (only of those in `keep`, if given) from the
+/// reference tilings at `path`.
+pub(crate) fn read_colors(path: &Path, keep: Option<&HashSet<u64>>) -> Result<Colors> {
+    let f = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut colors = HashMap::<u64, Vec<u32>>::new();
+    let mut names = Vec::new();
+    for (line_num, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let Some((name, tiling)) = line.split_once('\t') else {
+            bail!(
+                "line {} of {} is not a reference name and a tiling.",
+                line_num + 1,
+                path.display()
+            );
+        };
+        let name = ref_name(name);
+        // a reference split at runs of Ns has one tiling per piece
+        let r = match names.iter().rposition(|n: &String| n == name) {
+            Some(r) => r,
+            None => {
+                names.push(name.to_string());
+                names.len() - 1
+            }
+        } as u32;
+        for id in tiling
+            .split_ascii_whitespace()
+            .filter_map(|t| t.strip_suffix(['+', '-'])?.parse::<u64>().ok())
+            .filter(|id| keep.is_none_or(|k| k.contains(id)))
+        {
+            let c = colors.entry(id).or_default();
+            if c.last() != Some(&r) {
+                c.push(r);
+            }
+        }
+    }
+    for c in colors.values_mut() {
+        c.sort_unstable();
+        c.dedup();
+    }
+    Ok(Colors {
+        sets: colors,
+        names,
+    })
+}
+
+/// Write the unitigs of the compacted de Bruijn graph underlying the index given
+/// in `opts` to FASTA, named after their id in the graph and, with `--colors`,
+/// annotated with the references they occur in. The graph is read from the files
+/// kept by `build --keep-intermediate-dbg`, since the index itself stores the
+/// unitigs in the packed format of its k-mer dictionary. Returns a summary of the
+/// unitigs.
+pub(crate) fn dump_unitigs(opts: &DumpUnitigsOpts) -> Result<Value> {
+    let seg_path = inspect::component_path(&opts.index, SEGMENTS_SUFFIX);
+    let seq_path = inspect::component_path(&opts.index, TILINGS_SUFFIX);
+    for path in std::iter::once(&seg_path).chain(opts.colors.then_some(&seq_path)) {
+        if !path.exists() {
+            bail_with!(
+                ExitKind::MissingIndexComponent,
+                "the compacted de Bruijn graph of index {} was not found ({} does not exist); it is only kept if the index is built with --keep-intermediate-dbg.",
+                opts.index,
+                path.display()
+            );
+        }
+    }
+    let colors = if opts.colors {
+        Some(read_colors(&seq_path, None)?)
+    } else {
+        None
+    };
+
+    let mut out: Box<dyn Write> = match opts.output {
+        Some(ref p) => Box::new(BufWriter::new(
+            File::create(p).with_context(|| format!("could not create {}", p.display()))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let f =
+        File::open(&seg_path).with_context(|| format!("could not open {}", seg_path.display()))?;
+    let (mut num_unitigs, mut total_len, mut max_len) = (0_u64, 0_u64, 0_u64);
+    for (line_num, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let Some((id, seq)) = line.split_once('\t') else {
+            bail!(
+                "line {} of {} is not a unitig id and sequence.",
+                line_num + 1,
+                seg_path.display()
+            );
+        };
+        write!(out, ">{}", id)?;
+        if let Some(ref colors) = colors {
+            let refs = id
+                .parse::<u64>()
+                .map(|i| colors.ref_names(i).join(","))
+                .unwrap_or_default();
+            write!(out, " refs={}", refs)?;
+        }
+        writeln!(out, "\n{}", seq)?;
+        num_unitigs += 1;
+        total_len += seq.len() as u64;
+        max_len = max_len.max(seq.len() as u64);
+    }
+    out.flush()?;
+
+    info!(
+        "wrote {} unitigs ({} bases in total, the longest of {} bases).",
+        num_unitigs, total_len, max_len
+    );
+    Ok(json!({
+        "num_unitigs": num_unitigs,
+        "total_length": total_len,
+        "max_length": max_len,
+        "num_refs": colors.as_ref().map(|c| c.names.len()),
+    }))
+}
@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::rad::RadReader;
+
+/// The path of the equivalence classes written by `map-bulk` for the output
+/// stem `output`.
+pub(crate) fn bulk_eq_classes_path(output: &Path) -> PathBuf {
+    PathBuf::from(output.to_string_lossy().into_owned() + ".eq_classes.txt")
+}
+
+/// The equivalence classes of the fragments of a RAD file: the distinct sets of
+/// references to which fragments map, along with the number of fragments mapping
+/// to each.
+pub(crate) struct EqClasses {
+    pub ref_names: Vec<String>,
+    /// the (sorted) reference ids of each class and its number of fragments, in
+    /// the order in which the classes were first seen
+    pub classes: Vec<(Vec<u32>, u64)>,
+    /// the mean length of the fragments whose length is recorded, if any
+    pub mean_frag_len: Option<f64>,
+}
+
+impl EqClasses {
+    /// The number of fragments in all classes.
+    pub fn num_fragments(&self) -> u64 {
+        self.classes.iter().map(|(_, c)| c).sum()
+    }
+
+    /// Write the classes to `path` in the format of salmon's `eq_classes.txt`:
+    /// the number of references and of classes, the names of the references (one
+    /// per line), then one line per class with its number of references, their
+    /// ids and its number of fragments.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let f =
+            File::create(path).with_context(|| format!("could not create {}", path.display()))?;
+        let mut out = BufWriter::new(f);
+        writeln!(out, "{}", self.ref_names.len())?;
+        writeln!(out, "{}", self.classes.len())?;
+        for n in &self.ref_names {
+            writeln!(out, "{}", n)?;
+        }
+        for (refs, count) in &self.classes {
+            write!(out, "{}", refs.len())?;
+            for r in refs {
+                write!(out, "\t{}", r)?;
+            }
+            writeln!(out, "\t{}", count)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Collect the equivalence classes of the fragments in the RAD file at
+/// `rad_path`, along with their mean length. The orientation of the alignments
+/// is ignored, and fragments without any alignment are not counted.
+pub(crate) fn collect_eq_classes(rad_path: &Path) -> Result<EqClasses> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    let frag_len_tag = rad
+        .header
+        .aln_tags
+        .iter()
+        .position(|t| t.name == "frag_len");
+    let mut index = HashMap::<Vec<u32>, usize>::new();
+    let mut classes = Vec::<(Vec<u32>, u64)>::new();
+    let (mut frag_len_sum, mut num_frag_lens) = (0_u64, 0_u64);
+    while let Some(rec) = rad.next_record()? {
+        // the fragment length of the first (primary) alignment, if known
+        if let Some(l) = frag_len_tag
+            .and_then(|f| rec.alns.first()?.get(f)?.as_u64())
+            .map(|l| l as u32 as i32)
+            .filter(|l| *l > 0)
+        {
+            frag_len_sum += l as u64;
+            num_frag_lens += 1;
+        }
+        let mut refs = rec.ref_ids().collect::<Vec<u32>>();
+        if refs.is_empty() {
+            continue;
+        }
+        refs.sort_unstable();
+        refs.dedup();
+        match index.get(&refs) {
+            Some(&i) => classes[i].1 += 1,
+            None => {
+                index.insert(refs.clone(), classes.len());
+                classes.push((refs, 1));
+            }
+        }
+    }
+    Ok(EqClasses {
+        ref_names: rad.header.ref_names.clone(),
+        classes,
+        mean_frag_len: (num_frag_lens > 0).then(|| frag_len_sum as f64 / num_frag_lens as f64),
+    })
+}
+
+/// Write the equivalence classes of the fragments in the bulk RAD file at
+/// `rad_path` to `<output>.eq_classes.txt` (see `EqClasses::write`), returning
+/// a summary of them.
+pub(crate) fn write_bulk_eq_classes(rad_path: &Path, output: &Path) -> Result<Value> {
+    let ecs = collect_eq_classes(rad_path)?;
+    let path = bulk_eq_classes_path(output);
+    ecs.write(&path)?;
+    info!(
+        "wrote {} equivalence classes of {} fragments to {}.",
+        ecs.classes.len(),
+        ecs.num_fragments(),
+        path.display()
+    );
+    Ok(json!({
+        "file": path.display().to_string(),
+        "num_eq_classes": ecs.classes.len(),
+        "num_fragments": ecs.num_fragments(),
+    }))
+}
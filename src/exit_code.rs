@@ -0,0 +1,111 @@
+use std::fmt;
+
+/// The kinds of failure that `piscem` reports with a distinct exit code, so that
+/// wrappers (e.g. simpleaf) can tell what went wrong without parsing the log.
+/// Any other failure exits with code 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExitKind {
+    /// the command line arguments (or the files they name) are invalid; this is
+    /// also the code used by the argument parser itself
+    InvalidArguments,
+    /// a file of the index, required by the command, is missing
+    MissingIndexComponent,
+    /// reading or writing a file failed
+    Io,
+    /// memory could not be allocated
+    OutOfMemory,
+    /// the mapper, cDBG builder or indexer reported a failure
+    Internal,
+    /// a file of the index doesn't match the digest recorded when it was built
+    CorruptIndexComponent,
+    /// the command was interrupted by SIGINT, SIGTERM or SIGHUP
+    Interrupted,
+}
+
+impl ExitKind {
+    pub fn code(self) -> u8 {
+        match self {
+            Self::InvalidArguments => 2,
+            Self::MissingIndexComponent => 3,
+            Self::Io => 4,
+            Self::OutOfMemory => 5,
+            Self::Internal => 6,
+            Self::CorruptIndexComponent => 7,
+            Self::Interrupted => 8,
+        }
+    }
+}
+
+/// The exit code of a failure that isn't classified.
+const GENERIC_FAILURE: u8 = 1;
+
+/// An error tagged with the kind of failure it represents. It is displayed
+/// exactly as the error it wraps.
+pub(crate) struct Failure {
+    pub kind: ExitKind,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl fmt::Debug for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.error)
+    }
+}
+
+impl std::error::Error for Failure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.chain().nth(1)
+    }
+}
+
+/// Tag the error of a `Result` with the kind of failure it represents.
+pub(crate) trait WithExitKind<T> {
+    fn exit_kind(self, kind: ExitKind) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> WithExitKind<T> for Result<T, E> {
+    fn exit_kind(self, kind: ExitKind) -> anyhow::Result<T> {
+        self.map_err(|e| {
+            anyhow::Error::new(Failure {
+                kind,
+                error: e.into(),
+            })
+        })
+    }
+}
+
+/// Like `anyhow::bail!`, but tagging the error with the `ExitKind` `$kind`.
+macro_rules! bail_with {
+    ($kind:expr, $($arg:tt)*) => {
+        return Err(anyhow::Error::new($crate::exit_code::Failure {
+            kind: $kind,
+            error: anyhow::anyhow!($($arg)*),
+        }))
+    };
+}
+pub(crate) use bail_with;
+
+/// The exit code for the error `err`: that of the outermost `ExitKind` it was
+/// tagged with or, failing that, of any I/O error that caused it.
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    for cause in err.chain() {
+        if let Some(f) = cause.downcast_ref::<Failure>() {
+            return f.kind.code();
+        }
+    }
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return match e.kind() {
+                std::io::ErrorKind::OutOfMemory => ExitKind::OutOfMemory.code(),
+                _ => ExitKind::Io.code(),
+            };
+        }
+    }
+    GENERIC_FAILURE
+}
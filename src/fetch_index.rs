@@ -0,0 +1,182 @@
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use tracing::info;
+
+use crate::checksum;
+use crate::inspect;
+use crate::remote_reads::{self, RetryPolicy};
+
+/// The file, in the directory of a fetched index, recording the digest of the
+/// archive it was unpacked from; it is written once the index is complete.
+const COMPLETE_MARKER: &str = ".piscem_fetch_complete";
+
+/// A prebuilt index listed in a registry.
+pub(crate) struct RegistryEntry {
+    /// the name by which the index is fetched
+    pub name: String,
+    /// a short description of the index (e.g. the reference it was built from)
+    pub description: String,
+    /// the URL of the archive (e.g. a `.tar.gz` file) holding the index
+    pub url: String,
+    /// the SHA-256 digest of the archive
+    pub sha256: String,
+    /// the prefix of the index, relative to the root of the archive
+    pub prefix: String,
+}
+
+impl RegistryEntry {
+    fn from_json(v: &Value) -> Result<Self> {
+        let field = |key: &str| -> Result<String> {
+            v.get(key)
+                .and_then(|f| f.as_str())
+                .map(str::to_string)
+                .with_context(|| format!("registry entry {} has no {} field", v, key))
+        };
+        let entry = Self {
+            name: field("name")?,
+            description: field("description").unwrap_or_default(),
+            url: field("url")?,
+            sha256: field("sha256")?.to_lowercase(),
+            prefix: field("prefix")?,
+        };
+        // the name and prefix become paths in the cache directory
+        if entry.name.is_empty() || entry.name.contains('/') || entry.name.starts_with('.') {
+            bail!("registry entry has an invalid name {:?}.", entry.name);
+        }
+        if !Path::new(&entry.prefix)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            bail!(
+                "the prefix {} of registry entry {} must be a relative path within the archive.",
+                entry.prefix,
+                entry.name
+            );
+        }
+        Ok(entry)
+    }
+}
+
+/// Read the registry `registry`, which is either a URL or the path of a local
+/// file. A registry is a JSON object whose `indexes` array lists the available
+/// indexes, each with a `name`, `description`, `url`, `sha256` and `prefix`.
+pub(crate) fn read_registry(registry: &str, retry: RetryPolicy) -> Result<Vec<RegistryEntry>> {
+    let contents = if remote_reads::is_url(registry) {
+        let tmp = tempfile::NamedTempFile::new()?;
+        remote_reads::download_to_file(registry, tmp.path(), retry)?;
+        std::fs::read_to_string(tmp.path())?
+    } else {
+        std::fs::read_to_string(registry)
+            .with_context(|| format!("could not read the index registry {}", registry))?
+    };
+    let json: Value = serde_json::from_str(&contents)
+        .with_context(|| format!("the index registry {} is not valid JSON", registry))?;
+    match json.get("indexes").and_then(|i| i.as_array()) {
+        Some(entries) => entries.iter().map(RegistryEntry::from_json).collect(),
+        None => bail!(
+            "the index registry {} does not have an `indexes` array.",
+            registry
+        ),
+    }
+}
+
+/// Format the indexes of a registry as a table, one per line.
+pub(crate) fn format_registry(entries: &[RegistryEntry]) -> String {
+    let width = entries.iter().map(|e| e.name.len()).max().unwrap_or(0);
+    entries
+        .iter()
+        .map(|e| format!("{:<width$}  {}\n", e.name, e.description, width = width))
+        .collect()
+}
+
+/// The directory in which fetched indexes are cached by default:
+/// `$XDG_CACHE_HOME/piscem/indexes`, or `~/.cache/piscem/indexes`.
+pub(crate) fn default_cache_dir() -> Result<PathBuf> {
+    let cache = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(d) if !d.is_empty() => PathBuf::from(d),
+        _ => match std::env::var_os("HOME") {
+            Some(h) => PathBuf::from(h).join(".cache"),
+            None => bail!("could not determine the cache directory; please provide --cache-dir."),
+        },
+    };
+    Ok(cache.join("piscem").join("indexes"))
+}
+
+/// Fetch the index `entry` into `cache_dir`, unless it was already fetched (from
+/// an archive with the same digest) and `force` is not set: the archive is
+/// downloaded, its SHA-256 digest is checked, and it is unpacked into
+/// `<cache_dir>/<name>`. Returns the prefix of the index.
+pub(crate) fn fetch_index(
+    entry: &RegistryEntry,
+    cache_dir: &Path,
+    force: bool,
+    retry: RetryPolicy,
+) -> Result<PathBuf> {
+    let index_dir = cache_dir.join(&entry.name);
+    let prefix = index_dir.join(&entry.prefix);
+    let marker = index_dir.join(COMPLETE_MARKER);
+    if !force && std::fs::read_to_string(&marker).is_ok_and(|d| d.trim() == entry.sha256) {
+        info!(
+            "index {} was already fetched into {}.",
+            entry.name,
+            index_dir.display()
+        );
+        return Ok(prefix);
+    }
+
+    std::fs::create_dir_all(cache_dir).with_context(|| {
+        format!(
+            "could not create the cache directory {}",
+            cache_dir.display()
+        )
+    })?;
+    // the archive is downloaded and unpacked within the cache directory, so that
+    // the unpacked index can be moved into place at once
+    let staging = tempfile::Builder::new()
+        .prefix(&format!(".{}.", entry.name))
+        .tempdir_in(cache_dir)?;
+    let archive = staging.path().join("archive");
+    info!("downloading index {} from {}.", entry.name, entry.url);
+    remote_reads::download_to_file(&entry.url, &archive, retry)?;
+
+    let digest = checksum::sha256_file(&archive)?;
+    if digest != entry.sha256 {
+        bail!(
+            "the SHA-256 digest of the archive of index {} is {}, but the registry lists {}; the download may be corrupt.",
+            entry.name,
+            digest,
+            entry.sha256
+        );
+    }
+
+    let unpacked = staging.path().join("index");
+    std::fs::create_dir(&unpacked)?;
+    let status = Command::new("tar")
+        .arg("-xf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(&unpacked)
+        .status()
+        .context("could not run tar to unpack the index")?;
+    if !status.success() {
+        bail!("failed to unpack the archive of index {}.", entry.name);
+    }
+    let unpacked_prefix = unpacked.join(&entry.prefix);
+    if !inspect::component_path(&unpacked_prefix.to_string_lossy(), ".sshash").exists() {
+        bail!(
+            "the archive of index {} does not contain an index with the prefix {}.",
+            entry.name,
+            entry.prefix
+        );
+    }
+    std::fs::write(unpacked.join(COMPLETE_MARKER), &entry.sha256)?;
+
+    if index_dir.exists() {
+        std::fs::remove_dir_all(&index_dir)?;
+    }
+    std::fs::rename(&unpacked, &index_dir)?;
+    info!("fetched index {} into {}.", entry.name, index_dir.display());
+    Ok(prefix)
+}
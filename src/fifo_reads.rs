@@ -0,0 +1,120 @@
+use anyhow::{bail, Context, Result};
+use needletail::parse_fastx_file;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use tempfile::TempDir;
+use tracing::info;
+
+use crate::stream_input;
+
+/// Create a fifo (named pipe) at `path`, readable and writable by the owner.
+pub(crate) fn make_fifo(path: &Path) -> Result<()> {
+    let c_path = CString::new(path.to_string_lossy().into_owned())?;
+    // SAFETY: c_path is a valid, NUL-terminated path
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o700) } != 0 {
+        bail!(
+            "could not create fifo {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Read pairs that are being written to a pair of fifos by a separate thread,
+/// from which the mapper reads them as though they were regular files.
+pub(crate) struct FifoReads {
+    pub r1_fifo: PathBuf,
+    pub r2_fifo: PathBuf,
+    /// the handle of the thread writing the reads; it returns the number
+    /// of read pairs written
+    pub join_handle: thread::JoinHandle<Result<u64>>,
+}
+
+impl FifoReads {
+    /// Wait for the thread writing the reads to finish, returning the number of
+    /// read pairs written.
+    pub fn join(self) -> Result<u64> {
+        match self.join_handle.join() {
+            Ok(r) => r,
+            Err(_) => bail!("the thread writing reads to the mapper panicked"),
+        }
+    }
+}
+
+/// The name of a read with any mate suffix (`/1` or `/2`) and comment removed.
+pub(crate) fn mate_name(id: &[u8]) -> &[u8] {
+    let name = id.split(|c| c.is_ascii_whitespace()).next().unwrap_or(id);
+    match name {
+        [n @ .., b'/', b'1' | b'2'] => n,
+        _ => name,
+    }
+}
+
+/// Split the interleaved read files `files`, in which every read 1 record is
+/// immediately followed by its mate, into a pair of fifos from which the mapper
+/// can read them as paired-end reads. The mates are written by a separate thread,
+/// which fails if a file holds an odd number of records or if the names of two
+/// consecutive records don't match.
+pub(crate) fn deinterleave_to_fifos(files: Vec<String>) -> Result<FifoReads> {
+    // check the inputs up front, since once the mapper is waiting on
+    // the fifos, errors can only be reported after it finishes.
+    // streams are not opened here, since they can only be read once.
+    for f in files.iter().filter(|f| !stream_input::is_stream(f)) {
+        File::open(f).with_context(|| format!("could not open interleaved read file {}", f))?;
+    }
+
+    let tmp_dir = TempDir::new()?;
+    let r1_fifo = tmp_dir.path().join("r1.pipe");
+    let r2_fifo = tmp_dir.path().join("r2.pipe");
+    make_fifo(&r1_fifo)?;
+    make_fifo(&r2_fifo)?;
+
+    let (r1_path, r2_path) = (r1_fifo.clone(), r2_fifo.clone());
+    let join_handle = thread::spawn(move || -> Result<u64> {
+        // opening the fifos blocks until the mapper opens them for reading
+        let mut out1 = BufWriter::new(File::create(&r1_path)?);
+        let mut out2 = BufWriter::new(File::create(&r2_path)?);
+        let mut num_pairs = 0_u64;
+        for f in &files {
+            let mut reader = parse_fastx_file(f)?;
+            while let Some(rec1) = reader.next() {
+                let rec1 = rec1?;
+                let id1 = rec1.id().to_vec();
+                rec1.write(&mut out1, None)?;
+                let rec2 = match reader.next() {
+                    Some(r) => r?,
+                    None => bail!(
+                        "interleaved read file {} contains an odd number of records.",
+                        f
+                    ),
+                };
+                if mate_name(&id1) != mate_name(rec2.id()) {
+                    bail!(
+                        "consecutive records {} and {} in interleaved read file {} are not mates.",
+                        String::from_utf8_lossy(&id1),
+                        String::from_utf8_lossy(rec2.id()),
+                        f
+                    );
+                }
+                rec2.write(&mut out2, None)?;
+                num_pairs += 1;
+            }
+        }
+        out1.flush()?;
+        out2.flush()?;
+        drop((out1, out2));
+        tmp_dir.close()?;
+        info!("read {} read pairs from the interleaved input.", num_pairs);
+        Ok(num_pairs)
+    });
+
+    Ok(FifoReads {
+        r1_fifo,
+        r2_fifo,
+        join_handle,
+    })
+}
@@ -0,0 +1,350 @@
+use anyhow::{bail, Context, Result};
+use noodles_bgzf as bgzf;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::permit_list::decode_barcode;
+use crate::rad::{decode_ref_id, RadReader};
+use crate::refinfo::RefInfo;
+use crate::sam::reg2bin;
+
+/// The name of the fragment file written into the output directory by
+/// `map-sc-atac` when `--write-fragments` is given.
+pub(crate) const FRAGMENTS_FILE: &str = "fragments.tsv.gz";
+
+/// The name of the file, written into the output directory by `map-sc-atac` when
+/// `--dedup-fragments` is given, that lists the duplication of every barcode.
+pub(crate) const FRAGMENT_DUPLICATION_FILE: &str = "fragment_duplication.tsv";
+
+/// The size (as a power of 2) of the windows of the linear index of a tabix index.
+const TBI_WINDOW_SHIFT: u32 = 14;
+
+/// A fragment, delimited by the ends of a uniquely mapped read pair.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Fragment {
+    pub ref_id: u32,
+    /// the 0-based start and (exclusive) end of the fragment on the reference
+    pub start: u32,
+    pub end: u32,
+    /// the 2-bit encoded cell barcode of the read pair
+    pub barcode: u64,
+}
+
+/// The fragments of a RAD file, along with the number of records that could not
+/// be turned into a fragment.
+#[derive(Debug, Default)]
+pub(crate) struct Fragments {
+    pub frags: Vec<Fragment>,
+    pub num_multimapping: u64,
+    pub num_without_len: u64,
+}
+
+/// Collect the fragments of the single-cell ATAC-seq RAD file at `rad_path`,
+/// whose references must be those of `refs`. As in 10x's fragment files, only
+/// the read pairs that map to a single location are reported; unpaired reads,
+/// whose fragment length isn't known, are left out. Returns the fragments
+/// (sorted by position, then barcode) along with the length of the barcodes.
+pub(crate) fn collect_fragments(rad_path: &Path, refs: &RefInfo) -> Result<(Fragments, usize)> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    if rad.header.ref_names != refs.names {
+        bail!(
+            "the references of RAD file {} do not match those of the index.",
+            rad_path.display()
+        );
+    }
+    let bc_tag = match rad.header.read_tags.iter().position(|t| t.name == "b") {
+        Some(i) => i,
+        None => bail!(
+            "RAD file {} does not record cell barcodes.",
+            rad_path.display()
+        ),
+    };
+    let bc_len = match rad
+        .header
+        .file_tags
+        .iter()
+        .position(|t| t.name == "cblen")
+        .and_then(|i| rad.header.file_tag_values.get(i)?.as_u64())
+    {
+        Some(l) => l as usize,
+        None => bail!(
+            "RAD file {} does not record the length of its barcodes.",
+            rad_path.display()
+        ),
+    };
+    let tag_pos = |name: &str| rad.header.aln_tags.iter().position(|t| t.name == name);
+    let (pos_tag, frag_len_tag) = match (tag_pos("pos"), tag_pos("frag_len")) {
+        (Some(p), f) => (p, f),
+        (None, _) => bail!(
+            "RAD file {} does not record mapping positions.",
+            rad_path.display()
+        ),
+    };
+
+    let mut frags = Fragments::default();
+    while let Some(rec) = rad.next_record()? {
+        let aln = match rec.alns.as_slice() {
+            [] => continue,
+            [aln] => aln,
+            _ => {
+                frags.num_multimapping += 1;
+                continue;
+            }
+        };
+        let barcode = match rec.read_tags.get(bc_tag).and_then(|v| v.as_u64()) {
+            Some(bc) => bc,
+            None => bail!("RAD record is missing its barcode"),
+        };
+        let ref_id = match aln.first().and_then(|v| v.as_u64()) {
+            Some(v) => decode_ref_id(v).0,
+            None => bail!("RAD alignment record is missing its reference id"),
+        };
+        if ref_id as usize >= refs.names.len() {
+            bail!("RAD alignment refers to unknown reference id {}", ref_id);
+        }
+        let frag_len = match frag_len_tag
+            .and_then(|f| aln.get(f)?.as_u64())
+            .map(|l| l as u32 as i32)
+            .filter(|l| *l > 0)
+        {
+            Some(l) => l as i64,
+            None => {
+                frags.num_without_len += 1;
+                continue;
+            }
+        };
+        // positions are stored as (possibly negative) 0-based offsets
+        let pos = aln
+            .get(pos_tag)
+            .and_then(|p| p.as_u64())
+            .map_or(0, |p| p as u32 as i32 as i64);
+        let ref_len = refs.lengths[ref_id as usize] as i64;
+        let (start, end) = (pos.clamp(0, ref_len), (pos + frag_len).clamp(0, ref_len));
+        if start >= end {
+            frags.num_without_len += 1;
+            continue;
+        }
+        frags.frags.push(Fragment {
+            ref_id,
+            start: start as u32,
+            end: end as u32,
+            barcode,
+        });
+    }
+    frags.frags.sort_unstable();
+    Ok((frags, bc_len))
+}
+
+/// The tabix index of a position-sorted file with one line per reference
+/// interval: for each reference, the chunks of the file (as pairs of virtual
+/// offsets) holding the intervals of each bin, and the offset of the first
+/// interval overlapping each window of the linear index.
+struct TabixIndex {
+    bins: Vec<BTreeMap<u32, Vec<(u64, u64)>>>,
+    linear: Vec<Vec<u64>>,
+}
+
+impl TabixIndex {
+    fn new(num_refs: usize) -> Self {
+        Self {
+            bins: vec![BTreeMap::new(); num_refs],
+            linear: vec![Vec::new(); num_refs],
+        }
+    }
+
+    /// Record that the line for the interval [`start`, `end`) of reference `r`
+    /// spans the virtual offsets [`vbeg`, `vend`) of the file.
+    fn add(&mut self, r: usize, start: u32, end: u32, vbeg: u64, vend: u64) {
+        let bin = reg2bin(start as i32, end as i32) as u32;
+        let chunks = self.bins[r].entry(bin).or_default();
+        match chunks.last_mut() {
+            Some(c) if c.1 == vbeg => c.1 = vend,
+            _ => chunks.push((vbeg, vend)),
+        }
+        let linear = &mut self.linear[r];
+        let last_window = ((end - 1) >> TBI_WINDOW_SHIFT) as usize;
+        if linear.len() <= last_window {
+            linear.resize(last_window + 1, u64::MAX);
+        }
+        let first_window = (start >> TBI_WINDOW_SHIFT) as usize;
+        for off in &mut linear[first_window..=last_window] {
+            *off = (*off).min(vbeg);
+        }
+    }
+
+    /// Write the index, for a BED-like file with the reference names `names`, in
+    /// the (BGZF compressed) tabix format to `path`.
+    fn write(mut self, names: &[String], path: &Path) -> Result<()> {
+        let f =
+            File::create(path).with_context(|| format!("could not create {}", path.display()))?;
+        let mut out = bgzf::io::Writer::new(f);
+        out.write_all(b"TBI\x01")?;
+        let mut header = Vec::new();
+        // a generic format with 0-based, half-open intervals, whose reference name,
+        // start and end are in the first 3 columns, with '#' starting comment lines
+        for v in [names.len() as i32, 0x10000, 1, 2, 3, b'#' as i32, 0] {
+            header.extend_from_slice(&v.to_le_bytes());
+        }
+        let name_block = names
+            .iter()
+            .flat_map(|n| n.bytes().chain(std::iter::once(0)))
+            .collect::<Vec<u8>>();
+        header.extend_from_slice(&(name_block.len() as i32).to_le_bytes());
+        header.extend_from_slice(&name_block);
+        out.write_all(&header)?;
+        for (bins, linear) in self.bins.iter().zip(self.linear.iter_mut()) {
+            out.write_all(&(bins.len() as i32).to_le_bytes())?;
+            for (bin, chunks) in bins {
+                out.write_all(&bin.to_le_bytes())?;
+                out.write_all(&(chunks.len() as i32).to_le_bytes())?;
+                for (beg, end) in chunks {
+                    out.write_all(&beg.to_le_bytes())?;
+                    out.write_all(&end.to_le_bytes())?;
+                }
+            }
+            // windows without any interval start where the previous window starts
+            let mut prev = 0;
+            for off in linear.iter_mut() {
+                if *off == u64::MAX {
+                    *off = prev;
+                }
+                prev = *off;
+            }
+            out.write_all(&(linear.len() as i32).to_le_bytes())?;
+            for off in linear.iter() {
+                out.write_all(&off.to_le_bytes())?;
+            }
+        }
+        out.finish()?;
+        Ok(())
+    }
+}
+
+/// Write the fragments `frags`, with barcodes of length `bc_len`, to `path` as a
+/// BGZF compressed 10x-style fragment file (reference name, start, end, barcode
+/// and number of read pairs, tab-separated), along with its tabix index in
+/// `<path>.tbi`. If `dedup`, identical fragments (with the same barcode, start
+/// and end), which are adjacent in `frags`, are collapsed into a single line
+/// counting them; otherwise, each is reported with a count of 1. Returns the
+/// path of the index and the number of lines written.
+fn write_fragment_file(
+    path: &Path,
+    refs: &RefInfo,
+    frags: &[Fragment],
+    bc_len: usize,
+    dedup: bool,
+) -> Result<(PathBuf, u64)> {
+    let f = File::create(path).with_context(|| format!("could not create {}", path.display()))?;
+    let mut out = bgzf::io::Writer::new(f);
+    let mut index = TabixIndex::new(refs.names.len());
+    let mut num_lines = 0_u64;
+    let groups: Box<dyn Iterator<Item = &[Fragment]>> = if dedup {
+        Box::new(frags.chunk_by(|a, b| a == b))
+    } else {
+        Box::new(frags.chunks(1))
+    };
+    for group in groups {
+        let frag = group[0];
+        let vbeg = u64::from(out.virtual_position());
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}",
+            refs.names[frag.ref_id as usize],
+            frag.start,
+            frag.end,
+            decode_barcode(frag.barcode, bc_len),
+            group.len()
+        )?;
+        let vend = u64::from(out.virtual_position());
+        index.add(frag.ref_id as usize, frag.start, frag.end, vbeg, vend);
+        num_lines += 1;
+    }
+    out.finish()?;
+
+    let index_path = PathBuf::from(path.to_string_lossy().into_owned() + ".tbi");
+    index.write(&refs.names, &index_path)?;
+    Ok((index_path, num_lines))
+}
+
+/// Write the duplication statistics of every barcode of the fragments `frags`
+/// (sorted as by `collect_fragments`), with barcodes of length `bc_len`, to
+/// `fragment_duplication.tsv` in `out_dir`: the number of read pairs, the number
+/// of distinct fragments and the fraction of read pairs that are duplicates of
+/// another, from the barcode with the most read pairs to that with the fewest.
+fn write_duplication_stats(frags: &[Fragment], bc_len: usize, out_dir: &Path) -> Result<()> {
+    let mut counts = HashMap::<u64, (u64, u64)>::new();
+    for group in frags.chunk_by(|a, b| a == b) {
+        let c = counts.entry(group[0].barcode).or_insert((0, 0));
+        c.0 += group.len() as u64;
+        c.1 += 1;
+    }
+    let mut sorted = counts.into_iter().collect::<Vec<(u64, (u64, u64))>>();
+    sorted.sort_unstable_by(|a, b| b.1 .0.cmp(&a.1 .0).then(a.0.cmp(&b.0)));
+
+    let path = out_dir.join(FRAGMENT_DUPLICATION_FILE);
+    let f = File::create(&path).with_context(|| format!("could not create {}", path.display()))?;
+    let mut out = BufWriter::new(f);
+    writeln!(
+        out,
+        "barcode\tnum_read_pairs\tnum_unique_fragments\tduplication_rate"
+    )?;
+    for (bc, (num_pairs, num_unique)) in sorted {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{:.6}",
+            decode_barcode(bc, bc_len),
+            num_pairs,
+            num_unique,
+            1.0 - num_unique as f64 / num_pairs as f64
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Write the fragments of the uniquely mapped read pairs in the single-cell ATAC-seq
+/// RAD file at `rad_path` to `fragments.tsv.gz` in `out_dir`, sorted by position
+/// and compressed and indexed so that they can be queried with `tabix`. If `dedup`,
+/// duplicate fragments are collapsed (see `write_fragment_file`), and the duplication
+/// statistics of every barcode are written to `fragment_duplication.tsv`. Returns a
+/// summary of the fragments.
+pub(crate) fn write_sc_atac_fragments(
+    rad_path: &Path,
+    refs: &RefInfo,
+    out_dir: &Path,
+    dedup: bool,
+) -> Result<Value> {
+    let (frags, bc_len) = collect_fragments(rad_path, refs)?;
+    let path = out_dir.join(FRAGMENTS_FILE);
+    let (index_path, num_lines) = write_fragment_file(&path, refs, &frags.frags, bc_len, dedup)?;
+    if dedup {
+        write_duplication_stats(&frags.frags, bc_len, out_dir)?;
+    }
+    info!(
+        "wrote {} fragments ({} read pairs) to {} (skipped {} multi-mapping and {} unpaired reads).",
+        num_lines,
+        frags.frags.len(),
+        path.display(),
+        frags.num_multimapping,
+        frags.num_without_len
+    );
+    Ok(json!({
+        "file": FRAGMENTS_FILE,
+        "index": index_path.file_name().map(|n| n.to_string_lossy().into_owned()),
+        "deduplicated": dedup,
+        "duplication_stats": dedup.then_some(FRAGMENT_DUPLICATION_FILE),
+        "num_read_pairs": frags.frags.len(),
+        "num_fragments": num_lines,
+        "duplication_rate": if frags.frags.is_empty() {
+            0.0
+        } else {
+            1.0 - num_lines as f64 / frags.frags.len() as f64
+        },
+        "num_multimapping_skipped": frags.num_multimapping,
+        "num_unpaired_skipped": frags.num_without_len,
+    }))
+}
@@ -0,0 +1,499 @@
+use anyhow::{bail, Result};
+use seq_geom_parser::{FragmentGeomDesc, GeomLen, GeomPiece};
+use seq_geom_xform::{FifoXFormData, FragmentGeomDescExt};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use tracing::info;
+
+use crate::linker_xform;
+
+/// A named single-cell chemistry for which we know the
+/// fragment geometry description. These are resolved in the
+/// Rust layer (rather than by the `pesc-sc` mapper itself),
+/// so they may make use of complex geometry features like
+/// fixed anchor sequences or variable-length pieces.
+pub(crate) struct GeometryPreset {
+    /// the name by which this chemistry is selected with `--geometry`
+    pub name: &'static str,
+    /// a short, human-readable description of the chemistry
+    pub description: &'static str,
+    /// the fragment geometry description corresponding to this chemistry
+    pub spec: &'static str,
+}
+
+pub(crate) const GEOMETRY_PRESETS: &[GeometryPreset] = &[
+    // three 9nt cell label sections separated by fixed linkers, followed by an 8nt UMI
+    GeometryPreset {
+        name: "bd_rhapsody",
+        description: "BD Rhapsody WTA (original beads)",
+        spec: "1{b[9]f[ACTGGCCTGCGA]b[9]f[GGTAGCGGTGACA]b[9]u[8]x:}2{r:}",
+    },
+    // GEM-X keeps the Chromium v3 read layout, but uses a new barcode whitelist
+    GeometryPreset {
+        name: "chromium_v4",
+        description: "10x Genomics Chromium GEM-X (v4)",
+        spec: "1{b[16]u[12]x:}2{r:}",
+    },
+    // the 16nt spatial barcode identifies the capture spot, using the
+    // Chromium v3 read layout
+    GeometryPreset {
+        name: "visium_v1",
+        description: "10x Genomics Visium spatial gene expression",
+        spec: "1{b[16]u[12]x:}2{r:}",
+    },
+    // read 1 starts with the UMI, followed by the two parts of the spatial
+    // barcode, which identify the row and column of the 2um bin on the slide.
+    GeometryPreset {
+        name: "visium_hd_v1",
+        description: "10x Genomics Visium HD spatial gene expression",
+        spec: "1{u[9]b[16]x[4]b[14]x:}2{r:}",
+    },
+    // the enhanced beads prepend a 0-3nt diversity insert to the first cell label
+    // section and use shorter linkers; the insert is kept as part of the barcode.
+    GeometryPreset {
+        name: "bd_rhapsody_enhanced",
+        description: "BD Rhapsody WTA (enhanced beads)",
+        spec: "1{b[9-12]f[GTGA]b[9]f[GACA]b[9]u[8]x:}2{r:}",
+    },
+    // read 2 holds the UMI followed by the round 3, round 2 and round 1 barcodes,
+    // separated by the two 30nt linkers ligated in rounds 2 and 3. Matching the
+    // linkers (best with --linker-mismatches) rejects reads that are not properly
+    // ligated.
+    GeometryPreset {
+        name: "splitseq_v1",
+        description: "SPLiT-seq (Rosenberg et al. 2018)",
+        spec: "1{r:}2{u[10]b[8]f[GTGGCCGATGTTTCGCATCGGCGTACGACT]b[8]f[ATCCACGTGCTTGAGAGGCCAGAGCATTCG]b[8]x:}",
+    },
+    // the cDNA is in read 1, while read 2 holds the UMI followed by the round 3,
+    // round 2 and round 1 barcodes at fixed offsets. The round 1 barcode
+    // identifies the sample well.
+    GeometryPreset {
+        name: "parse_evercode_v1",
+        description: "Parse Biosciences Evercode WT v1",
+        spec: "1{r:}2{u[10]b[8]x[30]b[8]x[30]b[8]x:}",
+    },
+    GeometryPreset {
+        name: "parse_evercode_v2",
+        description: "Parse Biosciences Evercode WT v2",
+        spec: "1{r:}2{u[10]b[8]x[30]b[8]x[22]b[8]x:}",
+    },
+    // read 2 starts with the 50nt probe sequence, and the 8nt probe barcode
+    // (identifying the sample) follows an 18nt constant region. The probe barcode
+    // is appended to the cell barcode.
+    GeometryPreset {
+        name: "10x_flex_v1",
+        description: "10x Genomics Flex (fixed RNA profiling) v1",
+        spec: "1{b[16]u[12]x:}2{r[50]x[18]b[8]x:}",
+    },
+    // the 20nt protospacer sits at a variable offset in read 2, immediately
+    // upstream of the constant sgRNA scaffold sequence, which anchors it. The
+    // offset is left unbounded, since bounded ranges can vary by at most 4 bases.
+    GeometryPreset {
+        name: "10x_crispr_direct_capture",
+        description: "10x Genomics CRISPR guide direct capture (Chromium v3)",
+        spec: "1{b[16]u[12]x:}2{x:r[20]f[GTTTAAGAGCTAAGCTGGAA]x:}",
+    },
+];
+
+/// The chemistries built into the `pesc-sc` mapper, which are passed to it by
+/// name, along with their equivalent geometry descriptions.
+pub(crate) const MAPPER_GEOMETRIES: &[GeometryPreset] = &[
+    GeometryPreset {
+        name: "chromium_v2",
+        description: "10x Genomics Chromium v2",
+        spec: "1{b[16]u[10]x:}2{r:}",
+    },
+    GeometryPreset {
+        name: "chromium_v3",
+        description: "10x Genomics Chromium v3",
+        spec: "1{b[16]u[12]x:}2{r:}",
+    },
+];
+
+/// Returns the preset with the given name, if one exists.
+pub(crate) fn lookup_preset(name: &str) -> Option<&'static GeometryPreset> {
+    GEOMETRY_PRESETS.iter().find(|p| p.name == name)
+}
+
+/// Resolve the `--geometry` argument provided by the user. If it names one of
+/// our presets, the preset's geometry description is returned, otherwise the
+/// argument is returned unchanged (it may be a chemistry built into the mapper,
+/// or a custom geometry description).
+pub(crate) fn resolve_geometry(geometry: &str) -> &str {
+    match lookup_preset(geometry) {
+        Some(preset) => {
+            info!(
+                "using geometry {} for {} ({})",
+                preset.spec, preset.name, preset.description
+            );
+            preset.spec
+        }
+        None => geometry,
+    }
+}
+
+/// The largest total barcode or UMI length that the mapper can encode.
+const MAX_BC_UMI_LEN: u32 = 32;
+
+/// The upper bound on the length of a geometry piece, or `None` if it is unbounded.
+fn max_piece_len(len: &GeomLen) -> Option<u32> {
+    match *len {
+        GeomLen::FixedLen(l) => Some(l),
+        GeomLen::LenRange(_, h) => Some(h),
+        GeomLen::Unbounded => None,
+    }
+}
+
+/// Parse and validate the custom geometry description `geometry` (e.g.
+/// `1{b[16]u[12]x:}2{r:}`), so that mistakes are reported before any reads
+/// are processed. Besides being syntactically valid, the description must
+/// contain at least one barcode, UMI and biological read piece, and barcodes
+/// and UMIs must have bounded lengths, with a total of at most 32 bases each.
+/// Named geometries are left to the mapper (or resolved to presets) and are
+/// not checked here.
+pub(crate) fn validate_geometry(geometry: &str) -> Result<()> {
+    if !geometry.contains('{') {
+        return Ok(());
+    }
+    let geo_desc = match FragmentGeomDesc::try_from(geometry) {
+        Ok(g) => g,
+        Err(_) => {
+            bail!(
+                "could not parse geometry description {}; a description has the form 1{{..}}2{{..}}, \
+                where each read is described by a sequence of pieces such as b[16] (barcode), u[12] (UMI), \
+                r: (biological read), x[3] (discarded) or f[ACGT] (fixed sequence), e.g. 1{{b[16]u[12]x:}}2{{r:}}. \
+                A piece may be unbounded (e.g. x:) if it is the last piece of its read, or if a fixed \
+                sequence later in the read anchors the pieces that follow it (e.g. 2{{x:r[20]f[GTTTAAGAGC]x:}}).",
+                geometry
+            );
+        }
+    };
+
+    let (mut bc_len, mut umi_len, mut num_read_pieces) = (0_u32, 0_u32, 0_usize);
+    let (mut num_bc_pieces, mut num_umi_pieces) = (0_usize, 0_usize);
+    for piece in geo_desc.read1_desc.iter().chain(geo_desc.read2_desc.iter()) {
+        match piece {
+            GeomPiece::Barcode(len) | GeomPiece::Umi(len) => {
+                let is_bc = matches!(piece, GeomPiece::Barcode(_));
+                let l = match max_piece_len(len) {
+                    Some(l) => l,
+                    None => bail!(
+                        "in geometry description {}, the length of the {} piece {} must be bounded.",
+                        geometry,
+                        if is_bc { "barcode" } else { "UMI" },
+                        piece
+                    ),
+                };
+                if is_bc {
+                    bc_len += l;
+                    num_bc_pieces += 1;
+                } else {
+                    umi_len += l;
+                    num_umi_pieces += 1;
+                }
+            }
+            GeomPiece::ReadSeq(_) => num_read_pieces += 1,
+            GeomPiece::Discard(_) | GeomPiece::Fixed(_) => {}
+        }
+    }
+
+    if num_bc_pieces == 0 || num_umi_pieces == 0 || num_read_pieces == 0 {
+        bail!(
+            "geometry description {} must contain at least one barcode (b), UMI (u) and read (r) piece.",
+            geometry
+        );
+    }
+    if bc_len > MAX_BC_UMI_LEN || umi_len > MAX_BC_UMI_LEN {
+        bail!(
+            "in geometry description {}, the barcode ({} bases) and UMI ({} bases) can each be at most {} bases long.",
+            geometry,
+            bc_len,
+            umi_len,
+            MAX_BC_UMI_LEN
+        );
+    }
+    info!(
+        "validated geometry description {} ({}-base barcode, {}-base UMI).",
+        geo_desc, bc_len, umi_len
+    );
+    Ok(())
+}
+
+/// The minimum and maximum total length of the barcode pieces (or the UMI
+/// pieces, if `barcode` is false) of `geo_desc`. The maximum is `None` if any
+/// of the pieces is unbounded.
+fn total_len_bounds(geo_desc: &FragmentGeomDesc, barcode: bool) -> (u32, Option<u32>) {
+    let mut bounds = (0, Some(0));
+    for piece in geo_desc.read1_desc.iter().chain(geo_desc.read2_desc.iter()) {
+        let len = match (piece, barcode) {
+            (GeomPiece::Barcode(len), true) | (GeomPiece::Umi(len), false) => len,
+            _ => continue,
+        };
+        let min = match *len {
+            GeomLen::FixedLen(l) | GeomLen::LenRange(l, _) => l,
+            GeomLen::Unbounded => 0,
+        };
+        bounds.0 += min;
+        bounds.1 = bounds.1.zip(max_piece_len(len)).map(|(a, b)| a + b);
+    }
+    bounds
+}
+
+/// Describe the length bounds returned by `total_len_bounds`, e.g. `16` or `27-30`.
+pub(crate) fn format_len_bounds(bounds: (u32, Option<u32>)) -> String {
+    match bounds {
+        (min, Some(max)) if min == max => min.to_string(),
+        (min, Some(max)) => format!("{}-{}", min, max),
+        (min, None) => format!("{}+", min),
+    }
+}
+
+/// The geometry description of the geometry `geometry`, which may be a named
+/// geometry (a preset or a chemistry built into the mapper) or a description.
+fn spec_of(geometry: &str) -> &str {
+    match lookup_preset(geometry) {
+        Some(preset) => preset.spec,
+        None => MAPPER_GEOMETRIES
+            .iter()
+            .find(|g| g.name == geometry)
+            .map_or(geometry, |g| g.spec),
+    }
+}
+
+/// The minimum and maximum barcode length (as in `total_len_bounds`) of the
+/// geometry `geometry`, which may be a named geometry or a geometry description.
+/// Returns `None` if the geometry is not known.
+pub(crate) fn barcode_len_bounds(geometry: &str) -> Option<(u32, Option<u32>)> {
+    let geo_desc = FragmentGeomDesc::try_from(spec_of(geometry)).ok()?;
+    Some(total_len_bounds(&geo_desc, true))
+}
+
+/// The minimum and maximum length (as in `total_len_bounds`) of each barcode
+/// piece of the geometry `geometry` (a named geometry or a geometry description),
+/// in the order in which they are concatenated to form the barcode. Returns
+/// `None` if the geometry is not known.
+pub(crate) fn barcode_segment_bounds(geometry: &str) -> Option<Vec<(u32, Option<u32>)>> {
+    let geo_desc = FragmentGeomDesc::try_from(spec_of(geometry)).ok()?;
+    let segments = geo_desc
+        .read1_desc
+        .iter()
+        .chain(geo_desc.read2_desc.iter())
+        .filter_map(|p| match p {
+            GeomPiece::Barcode(len) => {
+                let min = match *len {
+                    GeomLen::FixedLen(l) | GeomLen::LenRange(l, _) => l,
+                    GeomLen::Unbounded => 0,
+                };
+                Some((min, max_piece_len(len)))
+            }
+            _ => None,
+        })
+        .collect();
+    Some(segments)
+}
+
+/// The lengths of the barcode pieces of the geometry `geometry` (a named
+/// geometry or a geometry description), in the order in which they are
+/// concatenated to form the barcode, e.g. `["16"]` or `["9-12", "9", "9"]`.
+/// This lets downstream tools split barcodes that encode several parts (such
+/// as the row and column of a spatial bin) back into them. Returns `None` if
+/// the geometry is not known.
+pub(crate) fn barcode_segments(geometry: &str) -> Option<Vec<String>> {
+    let bounds = barcode_segment_bounds(geometry)?;
+    Some(bounds.into_iter().map(format_len_bounds).collect())
+}
+
+/// A barcode or UMI piece of a geometry, at a fixed position in its read.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TagPiece {
+    /// 0 for read 1, 1 for read 2
+    pub mate: usize,
+    pub start: usize,
+    pub len: usize,
+    pub is_umi: bool,
+}
+
+/// The barcode and UMI pieces of the geometry `geometry` (a named geometry or a
+/// geometry description), along with their positions in the reads. Returns `None`
+/// if the geometry is not known, or if the position of any of its barcode or UMI
+/// pieces is not fixed (i.e. the geometry is complex).
+pub(crate) fn fixed_tag_pieces(geometry: &str) -> Option<Vec<TagPiece>> {
+    let geo_desc = FragmentGeomDesc::try_from(spec_of(geometry)).ok()?;
+    let mut pieces = Vec::new();
+    for (mate, desc) in [&geo_desc.read1_desc, &geo_desc.read2_desc]
+        .into_iter()
+        .enumerate()
+    {
+        let mut start = 0_usize;
+        for piece in desc {
+            let len = match piece {
+                GeomPiece::Barcode(GeomLen::FixedLen(l))
+                | GeomPiece::Umi(GeomLen::FixedLen(l))
+                | GeomPiece::ReadSeq(GeomLen::FixedLen(l))
+                | GeomPiece::Discard(GeomLen::FixedLen(l)) => *l as usize,
+                // nothing follows an unbounded piece
+                GeomPiece::ReadSeq(GeomLen::Unbounded) | GeomPiece::Discard(GeomLen::Unbounded) => {
+                    break
+                }
+                _ => return None,
+            };
+            if let GeomPiece::Barcode(_) | GeomPiece::Umi(_) = piece {
+                pieces.push(TagPiece {
+                    mate,
+                    start,
+                    len,
+                    is_umi: matches!(piece, GeomPiece::Umi(_)),
+                });
+            }
+            start += len;
+        }
+    }
+    Some(pieces)
+}
+
+/// List the named geometries that can be given to `--geometry`, i.e. the
+/// chemistries built into the mapper followed by our presets, along with their
+/// barcode and UMI lengths and geometry descriptions. The list is rendered as
+/// a JSON array if `as_json` is true, and as a table otherwise.
+pub(crate) fn list_geometries(as_json: bool) -> Result<String> {
+    let mut rows = Vec::new();
+    for (provider, geometries) in [("mapper", MAPPER_GEOMETRIES), ("piscem", GEOMETRY_PRESETS)] {
+        for g in geometries {
+            let geo_desc = FragmentGeomDesc::try_from(g.spec)?;
+            rows.push(json!({
+                "name": g.name,
+                "description": g.description,
+                "provided_by": provider,
+                "barcode_length": format_len_bounds(total_len_bounds(&geo_desc, true)),
+                "umi_length": format_len_bounds(total_len_bounds(&geo_desc, false)),
+                "geometry": g.spec,
+            }));
+        }
+    }
+    if as_json {
+        return Ok(serde_json::to_string_pretty(&rows)? + "\n");
+    }
+
+    let cols = [
+        ("name", "name"),
+        ("provided_by", "provided by"),
+        ("barcode_length", "barcode"),
+        ("umi_length", "UMI"),
+        ("geometry", "geometry"),
+        ("description", "description"),
+    ];
+    let field = |r: &Value, k: &str| {
+        r.get(k)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let widths = cols
+        .iter()
+        .map(|(k, h)| {
+            rows.iter()
+                .map(|r| field(r, k).len())
+                .chain([h.len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<usize>>();
+    let mut out = String::new();
+    let header = cols
+        .iter()
+        .map(|(_, h)| h.to_string())
+        .collect::<Vec<String>>();
+    for line in std::iter::once(header).chain(
+        rows.iter()
+            .map(|r| cols.iter().map(|(k, _)| field(r, k)).collect()),
+    ) {
+        let cells = line
+            .iter()
+            .zip(widths.iter())
+            .map(|(c, w)| format!("{:<w$}", c, w = w))
+            .collect::<Vec<String>>();
+        out += cells.join("  ").trim_end();
+        out += "\n";
+    }
+    Ok(out)
+}
+
+/// Holds the information about reads that are being transformed from a
+/// complex geometry into a simple one on-the-fly.
+pub(crate) struct XformedReads {
+    /// the simple geometry description of the transformed reads
+    pub geometry: String,
+    /// the fifos to which the transformed reads are written, and the
+    /// handle of the thread doing the writing
+    pub fifo_data: FifoXFormData,
+}
+
+/// If `geometry` is a complex geometry (i.e. it contains fixed anchor sequences
+/// or variable-length pieces), the `pesc-sc` mapper can't consume it directly.
+/// In that case, start a thread that transforms the reads in `read1` and `read2`
+/// into the equivalent simple geometry, writing them to a pair of fifos, and
+/// return the information about those fifos. Fixed sequences must match exactly
+/// unless `linker_mismatches` is non-zero, in which case each may match with up
+/// to that many mismatches. If the geometry is not complex (or is not a geometry
+/// description at all), `None` is returned.
+pub(crate) fn xform_if_complex(
+    geometry: &str,
+    read1: &[String],
+    read2: &[String],
+    linker_mismatches: u32,
+) -> Result<Option<XformedReads>> {
+    // named geometries are handled directly by the mapper
+    if !geometry.contains('{') {
+        return Ok(None);
+    }
+
+    let geo_desc = match FragmentGeomDesc::try_from(geometry) {
+        Ok(g) => g,
+        Err(e) => {
+            bail!("Could not parse geometry description {}: {}", geometry, e);
+        }
+    };
+
+    if !geo_desc.is_complex_geometry() {
+        return Ok(None);
+    }
+
+    info!(
+        "geometry {} is complex; reads will be transformed before mapping.",
+        geometry
+    );
+    let geo_re = geo_desc.as_regex()?;
+    let simple_geometry = geo_re.get_simplified_description_string();
+    let r1 = read1.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+    let r2 = read2.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+    let fifo_data = if linker_mismatches > 0 {
+        info!(
+            "fixed sequences may match with up to {} mismatches.",
+            linker_mismatches
+        );
+        linker_xform::xform_read_pairs_to_fifo(geo_desc, linker_mismatches, r1, r2)?
+    } else {
+        seq_geom_xform::xform_read_pairs_to_fifo(geo_re, r1, r2)?
+    };
+
+    Ok(Some(XformedReads {
+        geometry: simple_geometry,
+        fifo_data,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_geometry_can_be_parsed() {
+        for g in GEOMETRY_PRESETS.iter().chain(MAPPER_GEOMETRIES) {
+            let geo_desc = FragmentGeomDesc::try_from(g.spec)
+                .unwrap_or_else(|e| panic!("could not parse {}: {}", g.name, e));
+            if let Err(e) = geo_desc.as_regex() {
+                panic!("could not compile {}: {}", g.name, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::checksum;
+use crate::inspect;
+use crate::refinfo;
+
+/// The suffix of the file, written alongside an index, that records how the
+/// index was built.
+pub(crate) const INDEX_INFO_SUFFIX: &str = ".index_info.json";
+
+/// The commits of the bundled cuttlefish and piscem-cpp (which includes SSHash)
+/// sources, as recorded by the build script; `None` if they could not be
+/// determined (e.g. when building from a source archive rather than a clone).
+const CUTTLEFISH_COMMIT: Option<&str> = option_env!("PISCEM_CUTTLEFISH_COMMIT");
+const PISCEM_CPP_COMMIT: Option<&str> = option_env!("PISCEM_CPP_COMMIT");
+
+/// The reference files named by the `--ref-seqs`, `--ref-lists` and `--ref-dirs`
+/// inputs of a build: the files listed (one per line) in each list file, and the
+/// files in each directory, are included in place of the list or directory.
+pub(crate) fn expand_ref_inputs(
+    seqs: &[String],
+    lists: &[String],
+    dirs: &[String],
+) -> Result<Vec<PathBuf>> {
+    let mut files = seqs.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+    for l in lists {
+        let contents = std::fs::read_to_string(l)
+            .with_context(|| format!("could not read the reference list {}", l))?;
+        files.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|f| !f.is_empty())
+                .map(PathBuf::from),
+        );
+    }
+    for d in dirs {
+        let mut entries = std::fs::read_dir(d)
+            .with_context(|| format!("could not read the reference directory {}", d))?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<PathBuf>>>()?;
+        entries.retain(|p| p.is_file());
+        entries.sort();
+        files.extend(entries);
+    }
+    Ok(files)
+}
+
+/// The path, size and SHA-256 digest of each of the files `paths`.
+fn describe_files(paths: &[PathBuf]) -> Result<Vec<Value>> {
+    paths
+        .iter()
+        .zip(checksum::sha256_files(paths))
+        .map(|(p, digest)| {
+            Ok(json!({
+                "path": p.display().to_string(),
+                "size_bytes": std::fs::metadata(p)?.len(),
+                "sha256": digest?,
+            }))
+        })
+        .collect()
+}
+
+/// Records the provenance of an index as it is built: when the build started
+/// and how long each of its phases took.
+pub(crate) struct BuildProvenance {
+    start: Instant,
+    phases: Vec<Value>,
+}
+
+impl BuildProvenance {
+    /// Start recording a build.
+    pub fn start() -> Self {
+        Self {
+            start: Instant::now(),
+            phases: Vec::new(),
+        }
+    }
+
+    /// Record that the phase `name` was run, taking `elapsed`.
+    pub fn record_phase(&mut self, name: &str, elapsed: Duration) {
+        self.phases.push(json!({
+            "phase": name,
+            "wall_time_secs": elapsed.as_secs_f64(),
+            "resumed": false,
+        }));
+    }
+
+    /// Record that the phase `name` was skipped, because it was completed by an
+    /// earlier build that this one resumes.
+    pub fn record_resumed_phase(&mut self, name: &str) {
+        self.phases.push(json!({
+            "phase": name,
+            "wall_time_secs": null,
+            "resumed": true,
+        }));
+    }
+
+    /// Write the provenance of the index with the prefix `index` to
+    /// `<index>.index_info.json`: the `ref_files` and `decoys` it was built
+    /// from (each with its size and digest), the build `parameters`, the versions
+    /// of piscem and of the bundled cuttlefish and piscem-cpp, the wall time of
+    /// each phase and the number of indexed references.
+    pub fn write(
+        &self,
+        index: &str,
+        ref_files: &[PathBuf],
+        decoys: &[PathBuf],
+        parameters: Value,
+    ) -> Result<PathBuf> {
+        let num_refs = refinfo::read_refinfo(&inspect::component_path(index, ".refinfo"))
+            .map(|r| r.names.len())
+            .ok();
+        let info = json!({
+            "versions": {
+                "piscem": clap::crate_version!(),
+                "cuttlefish_commit": CUTTLEFISH_COMMIT,
+                "piscem_cpp_commit": PISCEM_CPP_COMMIT,
+            },
+            "parameters": parameters,
+            "ref_files": describe_files(ref_files)?,
+            "decoys": describe_files(decoys)?,
+            "num_refs": num_refs,
+            "phases": self.phases,
+            "wall_time_secs": self.start.elapsed().as_secs_f64(),
+        });
+        let path = inspect::component_path(index, INDEX_INFO_SUFFIX);
+        std::fs::write(&path, serde_json::to_string_pretty(&info)?)
+            .with_context(|| format!("could not write {}", path.display()))?;
+        Ok(path)
+    }
+}
@@ -0,0 +1,756 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+use std::time::Instant;
+use tracing::{debug, error, info, info_span, warn};
+
+use crate::exit_code::{bail_with, ExitKind};
+use crate::{
+    build_checkpoint, call_entry_point, cf_build, checksum, clean, decoys, discard_output, dry_run,
+    index_info, inspect, map_info, probe_set, ref_prep, resolve_threads, run_build,
+    run_build_poison_table, signals, splici, BuildConfig, BuildOpts, IndexInfo, LogLevel,
+    RunOptions,
+};
+
+// from: https://stackoverflow.com/questions/74322541/how-to-append-to-pathbuf
+fn append_to_path(p: impl Into<OsString>, s: impl AsRef<OsStr>) -> PathBuf {
+    let mut p = p.into();
+    p.push(s);
+    p.into()
+}
+
+/// Upon a signal during the build phase `phase`, started at `start`, remove its
+/// incomplete `outputs`, so that they are not mistaken for complete ones, and
+/// report how far the build got.
+fn on_phase_interrupt(
+    phase: build_checkpoint::BuildPhase,
+    start: Instant,
+    outputs: &[PathBuf],
+) -> Result<signals::OnSignal> {
+    let outputs = outputs.to_vec();
+    signals::OnSignal::run(move || {
+        for f in outputs.iter().filter(|f| f.exists()) {
+            match std::fs::remove_file(f) {
+                Ok(()) => info!("removed the incomplete {}.", f.display()),
+                Err(e) => warn!("could not remove {}: {}", f.display(), e),
+            }
+        }
+        warn!(
+            "the build was interrupted in the {} phase, after {:.1}s; the phases it completed can be skipped by rerunning it with the same --work-dir and --resume.",
+            phase.name(),
+            start.elapsed().as_secs_f64()
+        );
+    })
+}
+
+/// Build the index described by `build_opts`, returning its prefix and provenance.
+pub fn build_index(build_opts: BuildConfig, run: &RunOptions) -> Result<IndexInfo> {
+    let RunOptions { log_level, dry_run } = *run;
+    let quiet = log_level.quiet();
+    let ncpus = num_cpus::get();
+    if dry_run {
+        dry_run::print_config("build", &build_opts);
+    }
+    let BuildOpts {
+        mut ref_seqs,
+        mut ref_lists,
+        mut ref_dirs,
+        probe_set,
+        genome,
+        gtf,
+        ref_type,
+        read_length,
+        flank_trim_length,
+        dedup_policy,
+        ambig_policy,
+        min_ref_length,
+        max_n_run,
+        klen,
+        mlen,
+        threads,
+        output,
+        keep_intermediate_dbg,
+        work_dir,
+        max_memory,
+        resume,
+        overwrite,
+        no_ec_table,
+        mut decoy_paths,
+        decoy_genome,
+        seed,
+        skew_threshold,
+        bucket_load_factor,
+        check_dict,
+    } = build_opts;
+    info!("starting piscem build");
+    let threads = resolve_threads(threads, ncpus)?;
+    if mlen >= klen {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "minimizer length ({}) must be < k-mer length ({})",
+            mlen,
+            klen
+        );
+    }
+
+    // if the decoy sequences are provided, ensure they are valid paths
+    if let Some(ref decoys) = decoy_paths {
+        for d in decoys {
+            match d.try_exists() {
+                Ok(true) => {}
+                Ok(false) => {
+                    bail_with!(
+                        ExitKind::InvalidArguments,
+                        "Path for decoy file {} seems not to point to a valid file",
+                        d.display()
+                    );
+                }
+                Err(e) => {
+                    bail!(
+                        "Error {} when checking the existence of decoy file {}",
+                        e,
+                        d.display()
+                    );
+                }
+            }
+        }
+    }
+
+    // the provenance of the index is recorded once it is built; the inputs and
+    // parameters are captured here, before the inputs are replaced by any
+    // generated reference.
+    let mut provenance = index_info::BuildProvenance::start();
+    let mut input_files = index_info::expand_ref_inputs(
+        ref_seqs.as_deref().unwrap_or_default(),
+        ref_lists.as_deref().unwrap_or_default(),
+        ref_dirs.as_deref().unwrap_or_default(),
+    )?;
+    let ref_files = input_files.clone();
+    input_files.extend([&probe_set, &genome, &gtf].into_iter().flatten().cloned());
+    let mut decoy_files = decoy_paths.clone().unwrap_or_default();
+    decoy_files.extend(decoy_genome.iter().cloned());
+    let parameters = serde_json::json!({
+        "k": klen,
+        "m": mlen,
+        "seed": seed,
+        "skew_threshold": skew_threshold,
+        "bucket_load_factor": bucket_load_factor,
+        "threads": threads,
+        "max_memory_gb": max_memory,
+        "ec_table": !no_ec_table,
+        "ref_type": genome.as_ref().map(|_| &ref_type),
+        "read_length": read_length,
+        "flank_trim_length": genome.as_ref().map(|_| flank_trim_length),
+        "dedup_policy": dedup_policy,
+        "ambig_policy": ambig_policy,
+        "min_ref_length": min_ref_length,
+        "max_n_run": max_n_run,
+    });
+
+    let mut args: Vec<CString> = vec![];
+
+    let cf_out = PathBuf::from(output.as_path().to_string_lossy().into_owned() + "_cfish");
+    let cf_base_path = cf_out.as_path();
+    let seg_file = append_to_path(cf_base_path, ".cf_seg");
+    let seq_file = append_to_path(cf_base_path, ".cf_seq");
+    let struct_file = append_to_path(cf_base_path, ".json");
+    let mut build_ret;
+
+    // an existing index is removed as a whole, so that none of its files can be
+    // mixed with those of the new one; the inputs of the build are kept even if
+    // they are named like one of them.
+    if overwrite {
+        if dry_run {
+            dry_run::print_step("remove the existing index with the same prefix");
+        } else {
+            let inputs = input_files
+                .iter()
+                .chain(&decoy_files)
+                .cloned()
+                .collect::<Vec<PathBuf>>();
+            let removed = clean::remove_index(&output.to_string_lossy(), &inputs)?;
+            if !removed.is_empty() {
+                info!(
+                    "removed {} files of the existing index {}.",
+                    removed.len(),
+                    output.display()
+                );
+            }
+        }
+    } else if !resume && clean::index_exists(&output.to_string_lossy()) {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "an index with the prefix {} already exists; use --overwrite to replace it, or `piscem clean {}` to remove it.",
+            output.display(),
+            output.display()
+        );
+    }
+
+    if !resume && struct_file.exists() && (!seq_file.exists() || !seg_file.exists()) {
+        warn!("The prefix you have chosen for output already corresponds to an existing cDBG structure file {:?}.", struct_file.display());
+        warn!("However, the corresponding seq and seg files do not exist. Please either delete this structure file, choose another output prefix, or use the --overwrite flag.");
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "Cannot write over existing index without the --overwrite flag."
+        );
+    }
+
+    args.push(CString::new("cdbg_builder").unwrap());
+
+    // We can treat the different input options independently
+    // here because the argument parser should have enforced
+    // their exclusivity.
+    let mut has_input = false;
+
+    let phase_start = Instant::now();
+    let references_phase = info_span!("build", phase = "references").entered();
+    // a probe set is indexed by way of the probe sequences it contains
+    if let Some(ref probe_set_path) = probe_set {
+        let probe_fasta = if dry_run {
+            dry_run::print_step("extract the probe sequences of the probe set");
+            probe_set::probe_set_fasta_path(&output)
+        } else {
+            probe_set::write_probe_set_refs(probe_set_path, &output)?
+        };
+        ref_seqs = Some(vec![probe_fasta.to_string_lossy().into_owned()]);
+    }
+
+    // the transcripts of an annotated transcriptome are mapped to their genes
+    if let (None, Some(ref gtf)) = (&genome, &gtf) {
+        if dry_run {
+            dry_run::print_step("write the t2g files of the references from the annotation");
+        } else {
+            splici::write_transcriptome_t2g(&ref_files, gtf, &output)?;
+        }
+    }
+
+    // an augmented transcriptome is built from the genome and annotation,
+    // and then indexed like any other reference
+    if let (Some(genome), Some(gtf)) = (&genome, &gtf) {
+        let ref_type = ref_type.parse::<splici::AugmentedRefType>()?;
+        let flank_len = match (ref_type, read_length) {
+            (splici::AugmentedRefType::Splici, Some(l)) => {
+                if l <= flank_trim_length {
+                    bail_with!(
+                        ExitKind::InvalidArguments,
+                        "the read length ({}) must be greater than the flank trim length ({}).",
+                        l,
+                        flank_trim_length
+                    );
+                }
+                l - flank_trim_length
+            }
+            (splici::AugmentedRefType::Splici, None) => {
+                bail_with!(
+                    ExitKind::InvalidArguments,
+                    "--read-length is required to build a splici reference."
+                )
+            }
+            (splici::AugmentedRefType::Spliceu, _) => 0,
+        };
+        let ref_fasta = if dry_run {
+            dry_run::print_step(&format!(
+                "build the {} reference from the genome and annotation",
+                ref_type
+            ));
+            splici::augmented_ref_fasta_path(&output, ref_type)
+        } else {
+            splici::write_augmented_ref(genome, gtf, ref_type, flank_len, &output)?
+        };
+        ref_seqs = Some(vec![ref_fasta.to_string_lossy().into_owned()]);
+    }
+
+    // duplicate references, ambiguous bases, runs of Ns and short references are
+    // handled before they reach the cDBG builder, which would otherwise fail on
+    // (or silently index) them
+    let prep_config = ref_prep::RefPrepConfig {
+        dedup: dedup_policy.parse::<ref_prep::DedupPolicy>()?,
+        ambig: ambig_policy.parse::<ref_prep::AmbigPolicy>()?,
+        seed,
+        min_length: min_ref_length,
+        max_n_run,
+    };
+    let refs = index_info::expand_ref_inputs(
+        ref_seqs.as_deref().unwrap_or_default(),
+        ref_lists.as_deref().unwrap_or_default(),
+        ref_dirs.as_deref().unwrap_or_default(),
+    )?;
+    if dry_run {
+        dry_run::print_step(&format!(
+            "prepare the references (duplicate policy {}, ambiguity policy {}, minimum length {}, maximum run of Ns {})",
+            prep_config.dedup,
+            prep_config.ambig,
+            prep_config.min_length,
+            prep_config
+                .max_n_run
+                .map_or(String::from("unlimited"), |n| n.to_string())
+        ));
+    } else if let Some(prepared) = ref_prep::prepare_refs(&refs, &prep_config, &output)? {
+        ref_seqs = Some(vec![prepared.to_string_lossy().into_owned()]);
+        ref_lists = None;
+        ref_dirs = None;
+    }
+
+    // the decoys derived from a genome are added to those given explicitly
+    if let Some(ref decoy_genome) = decoy_genome {
+        let decoys = if dry_run {
+            dry_run::print_step("derive the decoy sequences from the genome");
+            decoys::genome_decoys_path(&output)
+        } else {
+            decoys::write_genome_decoys(decoy_genome, gtf.as_deref(), klen, &output)?
+        };
+        decoy_paths.get_or_insert_with(Vec::new).push(decoys);
+    }
+
+    if let Some(seqs) = ref_seqs {
+        if !seqs.is_empty() {
+            let out_stem = PathBuf::from(output.as_path().to_string_lossy().into_owned() + ".sigs");
+            let configs = prepare_fasta::RecordParseConfig {
+                input: seqs.clone(),
+                output_stem: out_stem,
+                polya_clip_length: None,
+            };
+            if dry_run {
+                dry_run::print_step("compute and record the reference signatures");
+            } else {
+                info!("Computing and recording reference signatures...");
+                // as around cf_build, only stdout is discarded for a quiet build,
+                // so that warnings and errors logged meanwhile are kept; stderr
+                // (where prepare_fasta reports what it trimmed) only when logging
+                // is turned off
+                let discard = if quiet {
+                    Some(discard_output::DiscardOutput::new(
+                        log_level == LogLevel::Off,
+                    )?)
+                } else {
+                    None
+                };
+                let parsed = prepare_fasta::parse_records(configs);
+                drop(discard);
+                parsed?;
+                info!("done.");
+            }
+            args.push(CString::new("--seq").unwrap());
+            let reflist = seqs.join(",");
+            args.push(CString::new(reflist.as_str()).unwrap());
+            has_input = true;
+        }
+    }
+    provenance.record_phase("references", phase_start.elapsed());
+    drop(references_phase);
+
+    if let Some(lists) = ref_lists {
+        if !lists.is_empty() {
+            args.push(CString::new("--list").unwrap());
+            let reflist = lists.join(",");
+            args.push(CString::new(reflist.as_str()).unwrap());
+            has_input = true;
+        }
+    }
+
+    if let Some(dirs) = ref_dirs {
+        if !dirs.is_empty() {
+            args.push(CString::new("--dir").unwrap());
+            let reflist = dirs.join(",");
+            args.push(CString::new(reflist.as_str()).unwrap());
+            has_input = true;
+        }
+    }
+
+    assert!(
+        has_input,
+        "Input (via --ref-seqs, --ref-lists, or --ref-dirs) must be provided."
+    );
+
+    args.push(CString::new("-k").unwrap());
+    args.push(CString::new(klen.to_string()).unwrap());
+    args.push(CString::new("--track-short-seqs").unwrap());
+    args.push(CString::new("--poly-N-stretch").unwrap());
+
+    // without a work directory, a unique one is created in the temporary
+    // directory of the system; it is removed when the build returns, whether it
+    // succeeded or not, and by the signal handler if the build is interrupted.
+    let tmp_work_dir;
+    let _remove_on_signal;
+    let work_dir = match work_dir {
+        Some(d) => d,
+        None if dry_run => {
+            let d = std::env::temp_dir().join("piscem-build-XXXXXX");
+            dry_run::print_step(&format!(
+                "create the temporary work directory {}",
+                d.display()
+            ));
+            d
+        }
+        None => {
+            tmp_work_dir = tempfile::Builder::new()
+                .prefix("piscem-build-")
+                .tempdir()
+                .context("could not create a temporary work directory")?;
+            _remove_on_signal = signals::OnSignal::remove(&[tmp_work_dir.path().to_path_buf()])?;
+            tmp_work_dir.path().to_path_buf()
+        }
+    };
+
+    // check if the provided work directory exists.
+    // If not, then try and create it.
+    match work_dir.try_exists() {
+        Ok(true) => {
+            info!(
+                "will use {} as the work directory for temporary files.",
+                work_dir.display()
+            );
+        }
+        Ok(false) if dry_run => {
+            dry_run::print_step(&format!("create the work directory {}", work_dir.display()));
+        }
+        Ok(false) => {
+            // try to create it
+            match std::fs::create_dir_all(&work_dir) {
+                Ok(_) => {}
+                Err(e) => {
+                    error!(
+                        "when attempting to create working directory {}, encountered error {:#?}",
+                        &work_dir.display(),
+                        e
+                    );
+                    bail!(
+                        "Failed to create working directory {} for index construction : {:#?}",
+                        &work_dir.display(),
+                        e
+                    );
+                }
+            }
+        }
+        Err(e) => {
+            error!("when checking existence of working directory {:#?}", e);
+            bail!(
+                "Failed to create working directory for index construction : {:#?}",
+                e
+            );
+        }
+    }
+
+    // check if the provided output path is more than just a prefix
+    // if so, check if the specified directory exists and create it
+    // if it doesn't.
+    if let Some(parent_path) = cf_out.parent() {
+        if !parent_path.exists() && !dry_run {
+            std::fs::create_dir_all(parent_path)?;
+            info!(
+                "directory {} did not already exist; creating it.",
+                parent_path.display()
+            );
+        }
+    }
+
+    args.push(CString::new("-o").unwrap());
+    args.push(CString::new(cf_out.as_path().to_string_lossy().into_owned()).unwrap());
+
+    // the completion of each phase is recorded in the work directory; unless
+    // resuming, the markers left by an earlier build are discarded. Once a
+    // phase is run again, all later phases are run again as well.
+    let checkpoints = build_checkpoint::BuildCheckpoints::new(&work_dir, &output);
+    if !resume && !dry_run {
+        checkpoints.clear()?;
+    }
+    let mut resuming = resume;
+    let cdbg_params = format!("{:?}", args);
+
+    args.push(CString::new("-t").unwrap());
+    args.push(CString::new(threads.to_string()).unwrap());
+    // output format
+    args.push(CString::new("-f").unwrap());
+    args.push(CString::new("3").unwrap());
+    // work directory
+    args.push(CString::new("-w").unwrap());
+    args.push(CString::new(work_dir.as_path().to_string_lossy().into_owned()).unwrap());
+    // memory budget
+    if let Some(max_memory) = max_memory {
+        args.push(CString::new("-m").unwrap());
+        args.push(CString::new(max_memory.to_string()).unwrap());
+    }
+
+    let cdbg_outputs = [seg_file.clone(), seq_file.clone(), struct_file.clone()];
+    resuming = resuming
+        && checkpoints.is_done(
+            build_checkpoint::BuildPhase::Cdbg,
+            &cdbg_params,
+            &cdbg_outputs,
+        );
+    if !resuming {
+        let phase_start = Instant::now();
+        let _phase =
+            info_span!("build", phase = build_checkpoint::BuildPhase::Cdbg.name()).entered();
+        // remove what an interrupted earlier build may have left behind
+        if resume && !dry_run {
+            for f in &cdbg_outputs {
+                if f.exists() {
+                    std::fs::remove_file(f)?;
+                }
+            }
+        }
+        debug!("args = {:?}", args);
+        let on_interrupt = on_phase_interrupt(
+            build_checkpoint::BuildPhase::Cdbg,
+            phase_start,
+            &cdbg_outputs,
+        )?;
+        // cuttlefish has no option to be quiet, so its progress (written to
+        // stdout) is discarded for a quiet build, and its errors as well when
+        // logging is turned off
+        let discard = if quiet && !dry_run {
+            Some(discard_output::DiscardOutput::new(
+                log_level == LogLevel::Off,
+            )?)
+        } else {
+            None
+        };
+        build_ret = call_entry_point("cf_build", cf_build, &args, dry_run);
+        drop(discard);
+        drop(on_interrupt);
+
+        if build_ret != 0 {
+            bail_with!(
+                ExitKind::Internal,
+                "cDBG constructor returned exit code {}; failure.",
+                build_ret
+            );
+        }
+        if !dry_run {
+            checkpoints.mark_done(build_checkpoint::BuildPhase::Cdbg, &cdbg_params)?;
+        }
+        provenance.record_phase(
+            build_checkpoint::BuildPhase::Cdbg.name(),
+            phase_start.elapsed(),
+        );
+    } else {
+        provenance.record_resumed_phase(build_checkpoint::BuildPhase::Cdbg.name());
+    }
+
+    args.clear();
+    args.push(CString::new("ref_index_builder").unwrap());
+
+    args.push(CString::new("-i").unwrap());
+    args.push(CString::new(cf_out.as_path().to_string_lossy().into_owned()).unwrap());
+    args.push(CString::new("-k").unwrap());
+    args.push(CString::new(klen.to_string()).unwrap());
+    args.push(CString::new("-m").unwrap());
+    args.push(CString::new(mlen.to_string()).unwrap()); // minimizer length
+
+    args.push(CString::new("--canonical-parsing").unwrap());
+    if !no_ec_table {
+        args.push(CString::new("--build-ec-table").unwrap());
+    }
+    args.push(CString::new("--seed").unwrap());
+    args.push(CString::new(seed.to_string()).unwrap());
+    args.push(CString::new("-l").unwrap());
+    args.push(CString::new(skew_threshold.to_string()).unwrap());
+    args.push(CString::new("-c").unwrap());
+    args.push(CString::new(bucket_load_factor.to_string()).unwrap());
+    args.push(CString::new("-o").unwrap());
+    args.push(CString::new(output.as_path().to_string_lossy().into_owned()).unwrap());
+    let index_params = format!("{:?}", args);
+
+    args.push(CString::new("-d").unwrap());
+    args.push(CString::new(work_dir.as_path().to_string_lossy().into_owned()).unwrap());
+
+    args.push(CString::new("-t").unwrap());
+    args.push(CString::new(threads.to_string()).unwrap());
+
+    if check_dict {
+        args.push(CString::new("--check").unwrap());
+    }
+
+    if let Some(max_memory) = max_memory {
+        args.push(CString::new("-g").unwrap());
+        args.push(CString::new(max_memory.to_string()).unwrap());
+    }
+
+    if quiet {
+        args.push(CString::new("--quiet").unwrap());
+    }
+
+    let mut index_outputs = [".sshash", ".ctab", ".refinfo"]
+        .iter()
+        .map(|suffix| inspect::component_path(&output.to_string_lossy(), suffix))
+        .collect::<Vec<PathBuf>>();
+    if !no_ec_table {
+        index_outputs.push(inspect::component_path(&output.to_string_lossy(), ".ectab"));
+    }
+    resuming = resuming
+        && checkpoints.is_done(
+            build_checkpoint::BuildPhase::Index,
+            &index_params,
+            &index_outputs,
+        );
+    if !resuming {
+        let phase_start = Instant::now();
+        let _phase =
+            info_span!("build", phase = build_checkpoint::BuildPhase::Index.name()).entered();
+        debug!("args = {:?}", args);
+        let on_interrupt = on_phase_interrupt(
+            build_checkpoint::BuildPhase::Index,
+            phase_start,
+            &index_outputs,
+        )?;
+        build_ret = call_entry_point("run_build", run_build, &args, dry_run);
+        drop(on_interrupt);
+
+        if build_ret != 0 {
+            bail_with!(
+                ExitKind::Internal,
+                "indexer returned exit code {}; failure.",
+                build_ret
+            );
+        }
+        if !dry_run {
+            checkpoints.mark_done(build_checkpoint::BuildPhase::Index, &index_params)?;
+        }
+        provenance.record_phase(
+            build_checkpoint::BuildPhase::Index.name(),
+            phase_start.elapsed(),
+        );
+    } else {
+        provenance.record_resumed_phase(build_checkpoint::BuildPhase::Index.name());
+    }
+
+    // now, build the poison table if there are decoys
+    if let Some(decoy_pathbufs) = decoy_paths {
+        args.clear();
+        args.push(CString::new("poison_table_builder").unwrap());
+
+        // index is the one we just built
+        args.push(CString::new("-i").unwrap());
+        args.push(CString::new(output.as_path().to_string_lossy().into_owned()).unwrap());
+
+        args.push(CString::new("-t").unwrap());
+        args.push(CString::new(threads.to_string()).unwrap());
+
+        // a poison table left by an interrupted build is replaced when resuming
+        if overwrite || resume {
+            args.push(CString::new("--overwrite").unwrap());
+        }
+
+        let path_args = decoy_pathbufs
+            .into_iter()
+            .map(|x| x.to_string_lossy().into_owned())
+            .collect::<Vec<String>>()
+            .join(",");
+        args.push(CString::new("-d").unwrap());
+        let poison_params = format!("{:?}", path_args);
+        args.push(CString::new(path_args).unwrap());
+
+        if quiet {
+            args.push(CString::new("--quiet").unwrap());
+        }
+
+        let poison_outputs = [inspect::component_path(
+            &output.to_string_lossy(),
+            ".poison",
+        )];
+        resuming = resuming
+            && checkpoints.is_done(
+                build_checkpoint::BuildPhase::PoisonTable,
+                &poison_params,
+                &poison_outputs,
+            );
+        if !resuming {
+            let phase_start = Instant::now();
+            let _phase = info_span!(
+                "build",
+                phase = build_checkpoint::BuildPhase::PoisonTable.name()
+            )
+            .entered();
+            debug!("args = {:?}", args);
+            let on_interrupt = on_phase_interrupt(
+                build_checkpoint::BuildPhase::PoisonTable,
+                phase_start,
+                &poison_outputs,
+            )?;
+            build_ret = call_entry_point(
+                "run_build_poison_table",
+                run_build_poison_table,
+                &args,
+                dry_run,
+            );
+            drop(on_interrupt);
+            if build_ret != 0 {
+                bail_with!(
+                    ExitKind::Internal,
+                    "building poison table returned exit code {}; failure.",
+                    build_ret
+                );
+            }
+            if !dry_run {
+                checkpoints.mark_done(build_checkpoint::BuildPhase::PoisonTable, &poison_params)?;
+            }
+            provenance.record_phase(
+                build_checkpoint::BuildPhase::PoisonTable.name(),
+                phase_start.elapsed(),
+            );
+        } else {
+            provenance.record_resumed_phase(build_checkpoint::BuildPhase::PoisonTable.name());
+        }
+    }
+
+    if dry_run {
+        return Ok(IndexInfo::new(output, klen, mlen, serde_json::Value::Null));
+    }
+
+    let info_path = provenance.write(
+        &output.to_string_lossy(),
+        &input_files,
+        &decoy_files,
+        parameters,
+    )?;
+    info!("wrote the index provenance to {}", info_path.display());
+
+    let manifest = checksum::write_index_checksums(&output.to_string_lossy())?;
+    info!("wrote index checksums to {}", manifest.display());
+
+    // the build is complete, so there is nothing left to resume
+    checkpoints.clear()?;
+
+    if !keep_intermediate_dbg {
+        info!("removing intermediate cdBG files produced by cuttlefish.");
+
+        match std::fs::remove_file(seg_file.clone()) {
+            Ok(_) => {
+                info!("removed segment file {}", seg_file.display());
+            }
+            Err(e) => {
+                warn!(
+                    "cannot remove {}, encountered error {:?}!",
+                    seg_file.display(),
+                    e
+                );
+            }
+        };
+
+        match std::fs::remove_file(seq_file.clone()) {
+            Ok(_) => {
+                info!("removed tiling file {}", seq_file.display());
+            }
+            Err(e) => {
+                warn!(
+                    "cannot remove {}, encountered error {:?}!",
+                    seq_file.display(),
+                    e
+                );
+            }
+        };
+        // for now, let the json file stick around. It's
+        // generally very small and may contain useful information
+        // about the references being indexed.
+    }
+
+    info!("piscem build finished.");
+    Ok(IndexInfo::new(
+        output,
+        klen,
+        mlen,
+        map_info::read_map_info(&info_path)?.into(),
+    ))
+}
@@ -0,0 +1,331 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::checksum;
+use crate::exit_code::{bail_with, ExitKind};
+use crate::index_info;
+use crate::map_info;
+use crate::refinfo;
+
+/// A file making up (part of) a piscem index, identified by the
+/// suffix appended to the index prefix.
+pub(crate) struct IndexComponent {
+    /// the suffix appended to the index prefix
+    pub suffix: &'static str,
+    /// a short description of the contents of the file
+    pub description: &'static str,
+    /// whether the mapper requires this file to load the index
+    pub required: bool,
+}
+
+pub(crate) const INDEX_COMPONENTS: &[IndexComponent] = &[
+    IndexComponent {
+        suffix: ".sshash",
+        description: "k-mer dictionary",
+        required: true,
+    },
+    IndexComponent {
+        suffix: ".ctab",
+        description: "contig table",
+        required: true,
+    },
+    IndexComponent {
+        suffix: ".refinfo",
+        description: "reference information",
+        required: true,
+    },
+    IndexComponent {
+        suffix: ".ectab",
+        description: "equivalence class table",
+        required: false,
+    },
+    IndexComponent {
+        suffix: ".poison",
+        description: "poison k-mer table",
+        required: false,
+    },
+    IndexComponent {
+        suffix: ".json",
+        description: "reference signatures",
+        required: false,
+    },
+    IndexComponent {
+        suffix: "_cfish.json",
+        description: "compacted de Bruijn graph summary",
+        required: false,
+    },
+    IndexComponent {
+        suffix: index_info::INDEX_INFO_SUFFIX,
+        description: "build provenance",
+        required: false,
+    },
+    IndexComponent {
+        suffix: checksum::CHECKSUMS_SUFFIX,
+        description: "component checksums",
+        required: false,
+    },
+];
+
+/// The path of the index component with the suffix `suffix` for the
+/// index prefix `index`.
+pub(crate) fn component_path(index: &str, suffix: &str) -> PathBuf {
+    PathBuf::from(format!("{}{}", index, suffix))
+}
+
+/// The parameters recorded at the start of the k-mer dictionary.
+#[derive(Debug)]
+pub(crate) struct DictParams {
+    /// the number of distinct k-mers in the dictionary
+    pub num_kmers: u64,
+    pub k: u64,
+    /// the minimizer length
+    pub m: u64,
+}
+
+/// Read the k-mer dictionary parameters from the start of the `.sshash` file
+/// `path`, where the number of k-mers, the hash seed, k and m are stored as
+/// little-endian 64-bit integers. Returns `None` if the values read are not
+/// plausible, e.g. if the file was written by an incompatible version.
+pub(crate) fn read_dict_params(path: &Path) -> Result<Option<DictParams>> {
+    let mut buf = [0_u8; 32];
+    let mut f = File::open(path)?;
+    if f.read_exact(&mut buf).is_err() {
+        return Ok(None);
+    }
+    let field = |i: usize| u64::from_le_bytes(buf[8 * i..8 * (i + 1)].try_into().unwrap());
+    let (num_kmers, k, m) = (field(0), field(2), field(3));
+    if k == 0 || k > 63 || m == 0 || m >= k {
+        return Ok(None);
+    }
+    Ok(Some(DictParams { num_kmers, k, m }))
+}
+
+/// Collect the statistics of the index with the prefix `index` as a JSON object.
+pub(crate) fn summarize_index(index: &str) -> Result<Value> {
+    if !component_path(index, ".sshash").exists() {
+        bail_with!(
+            ExitKind::MissingIndexComponent,
+            "no index with the prefix {} was found; the file {} does not exist.",
+            index,
+            component_path(index, ".sshash").display()
+        );
+    }
+
+    let mut components = serde_json::Map::new();
+    for c in INDEX_COMPONENTS {
+        let p = component_path(index, c.suffix);
+        let size = std::fs::metadata(&p).ok().map(|m| m.len());
+        components.insert(
+            c.suffix.to_string(),
+            json!({
+                "path": p.display().to_string(),
+                "description": c.description,
+                "required": c.required,
+                "present": size.is_some(),
+                "size_bytes": size,
+            }),
+        );
+    }
+
+    let dict = read_dict_params(&component_path(index, ".sshash"))?;
+    // these summaries are informative only, so don't fail if they can't be read
+    let sigs = map_info::read_map_info(&component_path(index, ".json")).unwrap_or_default();
+    let cdbg = map_info::read_map_info(&component_path(index, "_cfish.json")).unwrap_or_default();
+    let num_unitigs = cdbg
+        .get("basic info")
+        .and_then(|b| b.get("unitig count"))
+        .cloned();
+
+    Ok(json!({
+        "index": index,
+        "k": dict.as_ref().map(|d| d.k),
+        "m": dict.as_ref().map(|d| d.m),
+        "num_kmers": dict.as_ref().map(|d| d.num_kmers),
+        "num_unitigs": num_unitigs,
+        "num_refs": sigs.get("num_records"),
+        "has_ec_table": component_path(index, ".ectab").exists(),
+        "has_poison_table": component_path(index, ".poison").exists(),
+        "components": components,
+    }))
+}
+
+/// Render the index summary `summary`, produced by `summarize_index`, as text.
+pub(crate) fn format_summary(summary: &Value) -> String {
+    let val = |k: &str| match summary.get(k) {
+        Some(Value::Null) | None => String::from("unknown"),
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+    };
+    let mut out = String::new();
+    for (k, label) in [
+        ("index", "index prefix"),
+        ("k", "k-mer length"),
+        ("m", "minimizer length"),
+        ("num_kmers", "number of k-mers"),
+        ("num_unitigs", "number of unitigs"),
+        ("num_refs", "number of references"),
+        ("has_ec_table", "equivalence class table"),
+        ("has_poison_table", "poison table"),
+    ] {
+        out += &format!("{:<26}{}\n", label, val(k));
+    }
+    out += "components:\n";
+    if let Some(Value::Object(components)) = summary.get("components") {
+        for c in components.values() {
+            let size = match c.get("size_bytes").and_then(|s| s.as_u64()) {
+                Some(s) => format!("{} bytes", s),
+                None if c.get("required") == Some(&Value::Bool(true)) => {
+                    String::from("MISSING (required)")
+                }
+                None => String::from("absent"),
+            };
+            out += &format!(
+                "  {:<50}{:<36}{}\n",
+                c.get("path").and_then(|p| p.as_str()).unwrap_or_default(),
+                c.get("description")
+                    .and_then(|d| d.as_str())
+                    .unwrap_or_default(),
+                size
+            );
+        }
+    }
+    out
+}
+
+/// The outcome of one of the checks performed by `validate_index`.
+#[derive(Debug)]
+pub struct IndexCheck {
+    pub description: String,
+    pub passed: bool,
+    /// details about the outcome (e.g. why the check failed)
+    pub details: String,
+}
+
+impl IndexCheck {
+    fn new(description: impl Into<String>, passed: bool, details: impl Into<String>) -> Self {
+        Self {
+            description: description.into(),
+            passed,
+            details: details.into(),
+        }
+    }
+}
+
+/// Check the integrity of the index with the prefix `index` on disk: every
+/// required file must be present and non-empty, the parameters of the k-mer
+/// dictionary must be plausible and the dictionary large enough to hold its
+/// k-mers (which catches truncated copies), the reference information must be
+/// readable, and the summaries written during indexing (if present) must agree
+/// with the index itself.
+pub(crate) fn validate_index(index: &str) -> Vec<IndexCheck> {
+    let mut checks = Vec::new();
+    for c in INDEX_COMPONENTS {
+        let p = component_path(index, c.suffix);
+        let size = std::fs::metadata(&p).ok().map(|m| m.len());
+        match size {
+            Some(0) => checks.push(IndexCheck::new(
+                format!("{} ({})", p.display(), c.description),
+                false,
+                "file is empty",
+            )),
+            Some(s) => checks.push(IndexCheck::new(
+                format!("{} ({})", p.display(), c.description),
+                true,
+                format!("{} bytes", s),
+            )),
+            None if c.required => checks.push(IndexCheck::new(
+                format!("{} ({})", p.display(), c.description),
+                false,
+                "required file is missing",
+            )),
+            None => {}
+        }
+    }
+
+    let sshash = component_path(index, ".sshash");
+    let dict = read_dict_params(&sshash).ok().flatten();
+    match dict {
+        Some(ref d) => {
+            checks.push(IndexCheck::new(
+                "k-mer dictionary parameters",
+                d.num_kmers > 0,
+                format!("k = {}, m = {}, {} k-mers", d.k, d.m, d.num_kmers),
+            ));
+            // every k-mer takes at least 2 bits in the dictionary
+            let size = std::fs::metadata(&sshash).map_or(0, |m| m.len());
+            checks.push(IndexCheck::new(
+                "k-mer dictionary size",
+                size >= d.num_kmers / 4,
+                format!(
+                    "{} bytes for {} k-mers (at least {} expected)",
+                    size,
+                    d.num_kmers,
+                    d.num_kmers / 4
+                ),
+            ));
+        }
+        None => checks.push(IndexCheck::new(
+            "k-mer dictionary parameters",
+            false,
+            "could not read plausible parameters from the k-mer dictionary",
+        )),
+    }
+
+    let refs = refinfo::read_refinfo(&component_path(index, ".refinfo"));
+    match refs {
+        Ok(ref r) => checks.push(IndexCheck::new(
+            "reference information",
+            !r.names.is_empty(),
+            format!("{} references", r.names.len()),
+        )),
+        Err(ref e) => checks.push(IndexCheck::new(
+            "reference information",
+            false,
+            format!("{:#}", e),
+        )),
+    }
+
+    let sigs = map_info::read_map_info(&component_path(index, ".json")).unwrap_or_default();
+    if let (Some(n), Ok(r)) = (sigs.get("num_records").and_then(|n| n.as_u64()), &refs) {
+        checks.push(IndexCheck::new(
+            "number of references matches the reference signatures",
+            n == r.names.len() as u64,
+            format!("{} in signatures, {} in index", n, r.names.len()),
+        ));
+    }
+
+    match checksum::check_index_checksums(index) {
+        Ok(Some(problems)) => checks.push(IndexCheck::new(
+            "components match their recorded checksums",
+            problems.is_empty(),
+            if problems.is_empty() {
+                String::from("all digests match")
+            } else {
+                problems.join("; ")
+            },
+        )),
+        Ok(None) => {}
+        Err(e) => checks.push(IndexCheck::new(
+            "components match their recorded checksums",
+            false,
+            format!("{:#}", e),
+        )),
+    }
+
+    let cdbg = map_info::read_map_info(&component_path(index, "_cfish.json")).unwrap_or_default();
+    let cdbg_k = cdbg
+        .get("parameters info")
+        .and_then(|p| p.get("k"))
+        .and_then(|k| k.as_u64());
+    if let (Some(ck), Some(d)) = (cdbg_k, &dict) {
+        checks.push(IndexCheck::new(
+            "k matches the compacted de Bruijn graph",
+            ck == d.k,
+            format!("k = {} in graph, k = {} in dictionary", ck, d.k),
+        ));
+    }
+    checks
+}
@@ -0,0 +1,496 @@
+//! Indexing and mapping to compacted colored de Bruijn graphs.
+//!
+//! The functions of this crate run the commands of the `piscem` executable in
+//! process: [`build_index`] builds an index, and [`map_sc`], [`map_sc_atac`] and
+//! [`map_bulk`] map reads against it. They are configured with the same options
+//! as the corresponding commands, and fail with an error whose exit code (as
+//! reported by the executable) is given by [`exit_code`].
+//!
+//! With the `pyo3` feature, the crate also builds the `piscem` Python module
+//! (e.g. with `maturin build --features pyo3`), and with the `capi` feature, its
+//! shared library exports the C interface declared in `include/piscem.h`.
+
+use anyhow::Result;
+use serde_json::Value;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+mod atac_stats;
+mod barcode_freq;
+mod bench;
+mod bin_matrix;
+mod build_checkpoint;
+#[cfg(feature = "capi")]
+mod capi;
+mod checksum;
+mod classify;
+mod clean;
+mod coverage;
+mod decompress;
+mod decoys;
+mod demux;
+mod detect_geometry;
+mod discard_output;
+mod dry_run;
+mod dump_ectab;
+mod dump_refs;
+mod dump_unitigs;
+mod eq_classes;
+mod exit_code;
+mod fetch_index;
+mod fifo_reads;
+mod fragments;
+mod geometry;
+mod index_info;
+mod indexing;
+mod inspect;
+mod linker_xform;
+mod map_info;
+mod mapping;
+mod orientation;
+mod permit_list;
+mod piscem_commands;
+mod plate_metadata;
+mod prefetch;
+mod probe_set;
+#[cfg(feature = "pyo3")]
+mod python;
+mod qc_report;
+mod quant;
+mod query;
+mod rad;
+mod rad_inspect;
+mod rad_merge;
+mod read_groups;
+mod read_preprocess;
+mod read_validation;
+mod ref_filter;
+mod ref_prep;
+mod refinfo;
+mod remote_reads;
+mod sam;
+mod selftest;
+mod signals;
+mod splice_status;
+mod splici;
+mod stream_input;
+
+use exit_code::{bail_with, ExitKind};
+
+pub use exit_code::exit_code;
+pub use indexing::build_index;
+pub use inspect::IndexCheck;
+pub use mapping::{map_bulk, map_sc, map_sc_atac};
+pub use piscem_commands::{
+    BenchOpts, BuildOpts, ClassifyOpts, CleanOpts, DumpEctabOpts, DumpRefsOpts, DumpUnitigsOpts,
+    FetchIndexOpts, InspectOpts, MapBulkOpts, MapSCAtacOpts, MapSCOpts, PrefetchIndexOpts,
+    QuantBulkOpts, QueryOpts, RadInspectOpts, RadMergeOpts, RadToSamOpts, SelftestOpts,
+    ValidateIndexOpts,
+};
+
+/// The configuration of an index build.
+pub type BuildConfig = BuildOpts;
+/// The configuration of the mapping of single-cell reads.
+pub type MapSCConfig = MapSCOpts;
+/// The configuration of the mapping of single-cell ATAC-seq reads.
+pub type MapSCAtacConfig = MapSCAtacOpts;
+/// The configuration of the mapping of bulk reads.
+pub type MapBulkConfig = MapBulkOpts;
+
+#[link(name = "pesc_static", kind = "static")]
+extern "C" {
+    pub(crate) fn run_pesc_sc(args: c_int, argsv: *const *const c_char) -> c_int;
+    pub(crate) fn run_pesc_bulk(args: c_int, argsv: *const *const c_char) -> c_int;
+    pub(crate) fn run_pesc_sc_atac(args: c_int, argsv: *const *const c_char) -> c_int;
+}
+
+#[link(name = "build_static", kind = "static")]
+extern "C" {
+    pub(crate) fn run_build(args: c_int, argsv: *const *const c_char) -> c_int;
+    pub(crate) fn run_build_poison_table(args: c_int, argsv: *const *const c_char) -> c_int;
+}
+
+#[link(name = "cfcore_static", kind = "static", modifiers = "+whole-archive")]
+extern "C" {
+    pub(crate) fn cf_build(args: c_int, argsv: *const *const c_char) -> c_int;
+}
+
+/// The names of the log levels, from the least to the most verbose.
+pub const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// The level of the messages that are logged, by piscem and its C++ components.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// log nothing; the output of the cDBG builder is discarded as well
+    Off,
+    /// only log errors
+    Error,
+    /// only log warnings and errors; the C++ components are run with `--quiet`,
+    /// and the progress output of the cDBG builder is discarded
+    Warn,
+    /// log progress as well
+    #[default]
+    Info,
+    /// log debugging messages as well
+    Debug,
+    /// log everything
+    Trace,
+}
+
+impl LogLevel {
+    /// The most verbose level of the `tracing` events that are logged.
+    pub fn level_filter(self) -> tracing::level_filters::LevelFilter {
+        use tracing::level_filters::LevelFilter;
+        match self {
+            Self::Off => LevelFilter::OFF,
+            Self::Error => LevelFilter::ERROR,
+            Self::Warn => LevelFilter::WARN,
+            Self::Info => LevelFilter::INFO,
+            Self::Debug => LevelFilter::DEBUG,
+            Self::Trace => LevelFilter::TRACE,
+        }
+    }
+
+    /// Whether the C++ components, whose own log only distinguishes being quiet
+    /// (warnings and errors) or not, are run with `--quiet`.
+    pub(crate) fn quiet(self) -> bool {
+        self <= Self::Warn
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            "trace" => Ok(Self::Trace),
+            _ => anyhow::bail!(
+                "unknown log level {}; must be one of {}",
+                s,
+                LOG_LEVELS.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", LOG_LEVELS[*self as usize])
+    }
+}
+
+/// The options that apply to every command.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunOptions {
+    /// the level of the messages logged by piscem and the mapper, indexer and
+    /// cDBG builder
+    pub log_level: LogLevel,
+    /// validate the command and print what it would run, without running it
+    pub dry_run: bool,
+}
+
+/// The outcome of an index build.
+#[derive(Clone, Debug)]
+pub struct IndexInfo {
+    /// the prefix of the index
+    pub prefix: PathBuf,
+    /// the k-mer length of the index
+    pub k: usize,
+    /// the minimizer length of the index
+    pub m: usize,
+    /// the number of references indexed (`None` for a dry run)
+    pub num_refs: Option<u64>,
+    /// the wall time of the build, in seconds (`None` for a dry run)
+    pub wall_time_secs: Option<f64>,
+    /// the provenance of the index, as recorded in `<prefix>.index_info.json`
+    /// (`null` for a dry run)
+    pub provenance: Value,
+}
+
+impl IndexInfo {
+    /// The outcome of the build of the index with the prefix `prefix` and the
+    /// k-mer and minimizer lengths `k` and `m`, whose recorded provenance is
+    /// `provenance`.
+    pub(crate) fn new(prefix: PathBuf, k: usize, m: usize, provenance: Value) -> Self {
+        Self {
+            prefix,
+            k,
+            m,
+            num_refs: provenance.get("num_refs").and_then(Value::as_u64),
+            wall_time_secs: provenance.get("wall_time_secs").and_then(Value::as_f64),
+            provenance,
+        }
+    }
+}
+
+/// The outcome of a mapping run.
+#[derive(Clone, Debug)]
+pub struct MapSummary {
+    /// the output directory (or, for bulk reads, the output stem)
+    pub output: PathBuf,
+    /// the number of reads (or read pairs) processed (`None` for a dry run)
+    pub num_reads: Option<u64>,
+    /// the number of reads (or read pairs) mapped (`None` for a dry run)
+    pub num_mapped: Option<u64>,
+    /// the percentage of the reads that were mapped (`None` for a dry run)
+    pub percent_mapped: Option<f64>,
+    /// the wall time of the run, in seconds (`None` for a dry run)
+    pub wall_time_secs: Option<f64>,
+    /// the mapping summary written by the mapper and completed by piscem (the
+    /// contents of `map_info.json`; `null` for a dry run)
+    pub map_info: Value,
+}
+
+impl MapSummary {
+    /// The outcome of a mapping run into `output`, whose summary is `map_info`.
+    pub(crate) fn new(output: PathBuf, map_info: Value) -> Self {
+        let num_reads = map_info.get("num_reads").and_then(Value::as_u64);
+        let num_mapped = map_info.get("num_mapped").and_then(Value::as_u64);
+        let percent_mapped = map_info
+            .get("percent_mapped")
+            .and_then(Value::as_f64)
+            .or_else(|| match (num_reads, num_mapped) {
+                (Some(n), Some(m)) if n > 0 => Some(100.0 * m as f64 / n as f64),
+                _ => None,
+            });
+        Self {
+            output,
+            num_reads,
+            num_mapped,
+            percent_mapped,
+            wall_time_secs: map_info
+                .pointer("/run_stats/wall_time_secs")
+                .and_then(Value::as_f64),
+            map_info,
+        }
+    }
+}
+
+/// Set the function called right before `piscem` exits when a build or mapping
+/// run is interrupted by SIGINT, SIGTERM or SIGHUP, once the incomplete outputs
+/// of the run have been removed (e.g. to finish writing a log). It can only be
+/// set once.
+pub fn on_interrupted_exit(hook: fn()) {
+    signals::set_exit_hook(hook);
+}
+
+/// Collect the statistics of the index with the prefix `index` as a JSON object.
+pub fn inspect_index(index: &str) -> Result<Value> {
+    inspect::summarize_index(index)
+}
+
+/// Render the index statistics `summary`, as returned by `inspect_index`, as text.
+pub fn format_index_summary(summary: &Value) -> String {
+    inspect::format_summary(summary)
+}
+
+/// Summarize the RAD file at `path` (or the `map.rad` file of the `map-sc` or
+/// `map-sc-atac` output directory `path`): its header, tag schema, number of
+/// chunks and records, and the number of alignments to each reference.
+pub fn inspect_rad(path: &Path) -> Result<Value> {
+    rad_inspect::summarize_rad(path)
+}
+
+/// Render the RAD file summary `summary`, as returned by `inspect_rad`, as text,
+/// listing the `top` references with the most alignments (all if `top` is 0).
+pub fn format_rad_summary(summary: &Value, top: usize) -> String {
+    rad_inspect::format_summary(summary, top)
+}
+
+/// Convert the RAD file given in `opts` into SAM or BAM records, using the
+/// reference names and lengths of the index the reads were mapped against.
+/// Returns the number of records written.
+pub fn rad_to_sam(opts: &RadToSamOpts) -> Result<u64> {
+    sam::convert_rad(opts)
+}
+
+/// Merge the RAD files (and mapping summaries) given in `opts`, which must have
+/// been mapped against the same index, into one. Returns a summary of the merge.
+pub fn rad_merge(opts: &RadMergeOpts) -> Result<Value> {
+    rad_merge::rad_merge(opts)
+}
+
+/// Quantify the references of the index given in `opts` from the bulk RAD file
+/// given there, by an EM over the equivalence classes of the mapped fragments,
+/// and write their abundances in the format of salmon's `quant.sf`. Returns a
+/// summary of the quantification.
+pub fn quant_bulk(opts: &QuantBulkOpts) -> Result<Value> {
+    quant::quant_bulk(opts)
+}
+
+/// Classify the reads mapped by `map-bulk`, given in `opts`, through the taxonomy
+/// given there, writing Kraken-style per-read classifications and report.
+/// Returns a summary of the classification.
+pub fn classify(opts: &ClassifyOpts) -> Result<Value> {
+    classify::classify(opts)
+}
+
+/// Map a sample of the reads given in `opts` with each of the thread counts given
+/// there, and measure the throughput, CPU utilization and peak memory use of
+/// each run.
+pub fn bench(opts: &BenchOpts) -> Result<Value> {
+    bench::bench(opts)
+}
+
+/// Render the measurements `summary`, as returned by `bench`, as a table.
+pub fn format_bench_summary(summary: &Value) -> String {
+    bench::format_summary(summary)
+}
+
+/// Build an index over a bundled miniature reference, map bundled reads against
+/// it, and check the results, to verify that piscem works end-to-end. Returns the
+/// outcome of every check.
+pub fn selftest(opts: &SelftestOpts, log_level: LogLevel) -> Result<Value> {
+    selftest::selftest(opts, log_level)
+}
+
+/// Remove the index given in `opts`, the files written alongside it by `build`
+/// and the markers of an interrupted build of it. Returns a summary of the files
+/// removed.
+pub fn clean(opts: &CleanOpts, run: &RunOptions) -> Result<Value> {
+    clean::clean(opts, run)
+}
+
+/// Read the files of the index given in `opts` into the page cache, so that a
+/// subsequent mapping run does not stall on page faults. Returns a summary of the
+/// prefetched files.
+pub fn prefetch_index(opts: &PrefetchIndexOpts) -> Result<Value> {
+    prefetch::prefetch_index(opts)
+}
+
+/// Write the unitigs of the compacted de Bruijn graph underlying the index given
+/// in `opts` to FASTA, optionally annotated with the references they occur in.
+/// Returns a summary of the unitigs.
+pub fn dump_unitigs(opts: &DumpUnitigsOpts) -> Result<Value> {
+    dump_unitigs::dump_unitigs(opts)
+}
+
+/// Write the equivalence classes of the unitigs of the compacted de Bruijn graph
+/// underlying the index given in `opts`, i.e. the distinct sets of (reference,
+/// orientation) pairs the unitigs occur with, as TSV. Returns a summary of the
+/// classes.
+pub fn dump_ectab(opts: &DumpEctabOpts) -> Result<Value> {
+    dump_ectab::dump_ectab(opts)
+}
+
+/// Reconstruct the reference sequences of the index given in `opts` from the
+/// tilings of its compacted de Bruijn graph and write them to FASTA. Returns a
+/// summary of the references.
+pub fn dump_refs(opts: &DumpRefsOpts) -> Result<Value> {
+    dump_refs::dump_refs(opts)
+}
+
+/// Look up the k-mers of the sequences given in `opts` in the index given there,
+/// writing where each occurs in the compacted de Bruijn graph of the index.
+/// Returns a summary of the lookups.
+pub fn query(opts: &QueryOpts) -> Result<Value> {
+    query::query(opts)
+}
+
+/// Check the integrity of the index with the prefix `index` on disk.
+pub fn validate_index(index: &str) -> Vec<IndexCheck> {
+    inspect::validate_index(index)
+}
+
+/// The named geometries known to `map_sc`, as a table or as JSON.
+pub fn list_geometries(json: bool) -> Result<String> {
+    geometry::list_geometries(json)
+}
+
+/// Fetch the index named in `fetch_opts` from its registry into the cache and
+/// return its prefix. If the registry is only listed (`--list`), its table is
+/// returned instead, and for a dry run, nothing is returned.
+pub fn fetch_index(fetch_opts: &FetchIndexOpts, run: &RunOptions) -> Result<FetchOutcome> {
+    let retry = remote_reads::RetryPolicy {
+        retries: fetch_opts.url_retries,
+        delay: fetch_opts.url_retry_delay,
+    };
+    let entries = fetch_index::read_registry(&fetch_opts.registry, retry)?;
+    if fetch_opts.list {
+        return Ok(FetchOutcome::Listed(fetch_index::format_registry(&entries)));
+    }
+    let name = fetch_opts.name.clone().unwrap_or_default();
+    let entry = match entries.iter().find(|e| e.name == name) {
+        Some(e) => e,
+        None => bail_with!(
+            ExitKind::InvalidArguments,
+            "the registry {} does not list an index named {}; use --list to see the available indexes.",
+            fetch_opts.registry,
+            name
+        ),
+    };
+    let cache_dir = match fetch_opts.cache_dir.clone() {
+        Some(d) => d,
+        None => fetch_index::default_cache_dir()?,
+    };
+    if run.dry_run {
+        dry_run::print_config("fetch-index", fetch_opts);
+        dry_run::print_step(&format!(
+            "fetch index {} from {} into {}",
+            entry.name,
+            entry.url,
+            cache_dir.join(&entry.name).display()
+        ));
+        return Ok(FetchOutcome::DryRun);
+    }
+    let prefix = fetch_index::fetch_index(entry, &cache_dir, fetch_opts.force, retry)?;
+    Ok(FetchOutcome::Fetched(prefix))
+}
+
+/// The outcome of `fetch_index`.
+#[derive(Clone, Debug)]
+pub enum FetchOutcome {
+    /// the prefix of the fetched index
+    Fetched(PathBuf),
+    /// the table of the indexes in the registry
+    Listed(String),
+    /// nothing was fetched, since this was a dry run
+    DryRun,
+}
+
+/// Validate the number of threads `threads` given that there are `ncpus` logical CPUs
+/// (as reported by `num_cpus::get`, which respects cgroup CPU quotas), returning the
+/// number of threads to use: `threads` itself, or all `ncpus` CPUs if it is 0.
+pub(crate) fn resolve_threads(threads: usize, ncpus: usize) -> Result<usize> {
+    if threads == 0 {
+        info!("using all {} available logical CPUs.", ncpus);
+        return Ok(ncpus);
+    }
+    if threads > ncpus {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "the number of provided threads ({}) should be <= the number of logical CPUs ({}).",
+            threads,
+            ncpus
+        );
+    }
+    Ok(threads)
+}
+
+/// The signature of the C++ entry points of the mapper, indexer and cDBG builder.
+pub(crate) type EntryPoint = unsafe extern "C" fn(c_int, *const *const c_char) -> c_int;
+
+/// Call the C++ entry point `f`, named `name`, with the arguments `args`. In a
+/// dry run, the arguments are printed instead, and the call reports success.
+pub(crate) fn call_entry_point(
+    name: &str,
+    f: EntryPoint,
+    args: &[CString],
+    dry_run: bool,
+) -> c_int {
+    if dry_run {
+        dry_run::print_argv(name, args);
+        return 0;
+    }
+    let arg_ptrs: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
+    let args_len: c_int = args.len() as c_int;
+    let ret = unsafe { f(args_len, arg_ptrs.as_ptr()) };
+    signals::wait_if_interrupted();
+    ret
+}
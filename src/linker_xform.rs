@@ -0,0 +1,195 @@
+use anyhow::{bail, Result};
+use needletail::parse_fastx_file;
+use seq_geom_parser::{FragmentGeomDesc, GeomLen, GeomPiece, NucStr};
+use seq_geom_xform::{FifoXFormData, XformStats};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::thread;
+use tempfile::TempDir;
+
+use crate::fifo_reads;
+
+/// The padding appended to a variable-length piece, indexed by the number of
+/// bases it falls short of one more than its maximum length. This is the scheme
+/// used by `seq_geom_xform`, so that the transformed reads match the simplified
+/// geometry it reports.
+pub(crate) const VAR_LEN_PADDING: &[&[u8]] = &[b"A", b"AC", b"AAG", b"AAAT"];
+
+/// The number of mismatches between `a` and `b`, which must be of the same length.
+fn hamming(a: &[u8], b: &[u8]) -> u32 {
+    a.iter()
+        .zip(b.iter())
+        .filter(|(x, y)| !x.eq_ignore_ascii_case(y))
+        .count() as u32
+}
+
+/// The best way to extract the pieces `pieces` from `seq`, starting at `pos`:
+/// returns the total number of mismatches in the fixed sequences and the
+/// extracted (and padded) barcode, UMI and read sequence, or `None` if `seq`
+/// doesn't match. Every fixed sequence may match with up to `max_mismatches`
+/// mismatches, and every length of a variable-length piece is tried, keeping
+/// the one that leads to the fewest mismatches overall.
+fn extract(
+    pieces: &[GeomPiece],
+    seq: &[u8],
+    pos: usize,
+    max_mismatches: u32,
+) -> Option<(u32, Vec<u8>)> {
+    let (piece, rest) = match pieces.split_first() {
+        Some(p) => p,
+        // any bases after the last piece are ignored
+        None => return Some((0, Vec::new())),
+    };
+    let captured = !matches!(piece, GeomPiece::Discard(_) | GeomPiece::Fixed(_));
+    let len = match piece {
+        GeomPiece::Barcode(l)
+        | GeomPiece::Umi(l)
+        | GeomPiece::ReadSeq(l)
+        | GeomPiece::Discard(l) => l,
+        GeomPiece::Fixed(NucStr::Seq(s)) => {
+            let end = pos + s.len();
+            let mm = hamming(seq.get(pos..end)?, s.as_bytes());
+            if mm > max_mismatches {
+                return None;
+            }
+            let (rest_mm, out) = extract(rest, seq, end, max_mismatches)?;
+            return Some((mm + rest_mm, out));
+        }
+    };
+
+    let (min, max, padded) = match *len {
+        GeomLen::FixedLen(l) => (l as usize, l as usize, false),
+        GeomLen::LenRange(l, h) => (l as usize, h as usize, h != l),
+        GeomLen::Unbounded if rest.is_empty() => {
+            let tail = seq.get(pos..)?;
+            return Some((0, if captured { tail.to_vec() } else { Vec::new() }));
+        }
+        // an unbounded piece followed by others (e.g. the variable offset of
+        // an anchored sequence) may take up any number of the remaining bases
+        GeomLen::Unbounded => (0, seq.len().checked_sub(pos)?, false),
+    };
+    let mut best: Option<(u32, Vec<u8>)> = None;
+    for l in min..=max {
+        let end = pos + l;
+        let piece_seq = match seq.get(pos..end) {
+            Some(s) => s,
+            None => break,
+        };
+        if let Some((mm, rest_out)) = extract(rest, seq, end, max_mismatches) {
+            if best.as_ref().is_none_or(|b| mm < b.0) {
+                let mut out = Vec::new();
+                if captured {
+                    out.extend_from_slice(piece_seq);
+                    if padded {
+                        out.extend_from_slice(VAR_LEN_PADDING[max - l]);
+                    }
+                }
+                out.extend_from_slice(&rest_out);
+                best = Some((mm, out));
+            }
+        }
+    }
+    best
+}
+
+/// Transform the reads in `read1` and `read2` from the complex geometry
+/// `geo_desc` into its simplified geometry, like
+/// `seq_geom_xform::xform_read_pairs_to_fifo`, but allowing up to
+/// `max_mismatches` mismatches in each fixed (linker or anchor) sequence.
+/// The transformed reads are written to a pair of fifos by a separate thread;
+/// read pairs that don't match the geometry are skipped.
+pub(crate) fn xform_read_pairs_to_fifo(
+    geo_desc: FragmentGeomDesc,
+    max_mismatches: u32,
+    read1: Vec<PathBuf>,
+    read2: Vec<PathBuf>,
+) -> Result<FifoXFormData> {
+    if read1.len() != read2.len() {
+        bail!(
+            "the number of read 1 files ({}) must match the number of read 2 files ({}).",
+            read1.len(),
+            read2.len()
+        );
+    }
+
+    let tmp_dir = TempDir::new()?;
+    let r1_fifo = tmp_dir.path().join("r1.pipe");
+    let r2_fifo = tmp_dir.path().join("r2.pipe");
+    fifo_reads::make_fifo(&r1_fifo)?;
+    fifo_reads::make_fifo(&r2_fifo)?;
+
+    let (r1_path, r2_path) = (r1_fifo.clone(), r2_fifo.clone());
+    let join_handle = thread::spawn(move || -> Result<XformStats> {
+        // opening the fifos blocks until the mapper opens them for reading
+        let mut out1 = BufWriter::new(File::create(&r1_path)?);
+        let mut out2 = BufWriter::new(File::create(&r2_path)?);
+        let mut stats = XformStats::new();
+        for (f1, f2) in read1.iter().zip(read2.iter()) {
+            let mut reader1 = parse_fastx_file(f1)?;
+            let mut reader2 = parse_fastx_file(f2)?;
+            while let (Some(rec1), Some(rec2)) = (reader1.next(), reader2.next()) {
+                let (rec1, rec2) = (rec1?, rec2?);
+                stats.total_fragments += 1;
+                let x1 = extract(&geo_desc.read1_desc, &rec1.seq(), 0, max_mismatches);
+                let x2 = extract(&geo_desc.read2_desc, &rec2.seq(), 0, max_mismatches);
+                match (x1, x2) {
+                    (Some((_, s1)), Some((_, s2))) => {
+                        out1.write_all(b">")?;
+                        out1.write_all(rec1.id())?;
+                        out1.write_all(b"\n")?;
+                        out1.write_all(&s1)?;
+                        out1.write_all(b"\n")?;
+                        out2.write_all(b">")?;
+                        out2.write_all(rec2.id())?;
+                        out2.write_all(b"\n")?;
+                        out2.write_all(&s2)?;
+                        out2.write_all(b"\n")?;
+                    }
+                    _ => stats.failed_parsing += 1,
+                }
+            }
+        }
+        out1.flush()?;
+        out2.flush()?;
+        drop((out1, out2));
+        tmp_dir.close()?;
+        Ok(stats)
+    });
+
+    Ok(FifoXFormData {
+        r1_fifo,
+        r2_fifo,
+        join_handle,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_a_piece_at_a_variable_offset_before_an_anchor() {
+        let geo_desc = FragmentGeomDesc::try_from("1{r:}2{x:r[20]f[GTTTAAGAGC]x:}").unwrap();
+        let guide = b"ACGTACGTACGTACGTACGT";
+        for offset in [0, 7, 30] {
+            let mut read = b"C".repeat(offset);
+            read.extend_from_slice(guide);
+            // one mismatch in the anchor
+            read.extend_from_slice(b"GTTTAAGTGCAAAA");
+            let (mm, out) = extract(&geo_desc.read2_desc, &read, 0, 1).unwrap();
+            assert_eq!(mm, 1);
+            assert_eq!(out, guide);
+            assert!(extract(&geo_desc.read2_desc, &read, 0, 0).is_none());
+        }
+    }
+
+    #[test]
+    fn pads_variable_length_pieces() {
+        let geo_desc = FragmentGeomDesc::try_from("1{b[2-4]f[GG]u[2]x:}2{r:}").unwrap();
+        let (_, out) = extract(&geo_desc.read1_desc, b"ACGGTTAAA", 0, 0).unwrap();
+        assert_eq!(out, b"ACAAGTT");
+        let (_, out) = extract(&geo_desc.read1_desc, b"ACTAGGTTAAA", 0, 0).unwrap();
+        assert_eq!(out, b"ACTAATT");
+    }
+}
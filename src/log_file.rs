@@ -0,0 +1,216 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The name of the log written into the output directory of `build` and the
+/// `map-*` commands.
+pub(crate) const LOG_FILE: &str = "piscem.log";
+
+/// The log currently being written, if any; it is kept here so that `main` can
+/// still report a failure into it once the command has returned.
+static ACTIVE_LOG: Mutex<Option<LogTee>> = Mutex::new(None);
+
+/// A file descriptor whose output is diverted through a pipe, from which a
+/// separate thread copies it both to its original destination and to the log.
+struct Redirect {
+    fd: RawFd,
+    /// a duplicate of the original file descriptor, restored when the log ends
+    saved: RawFd,
+    handle: thread::JoinHandle<()>,
+}
+
+struct LogTee {
+    redirects: Vec<Redirect>,
+}
+
+fn check(ret: c_int, what: &str) -> Result<c_int> {
+    if ret < 0 {
+        Err(std::io::Error::last_os_error()).context(what.to_string())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// The current time (UTC) in ISO 8601 format, to millisecond precision.
+pub(crate) fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // the civil date of a number of days since the epoch, after
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        now.subsec_millis()
+    )
+}
+
+/// Remove the ANSI escape sequences (e.g. the colors of the log messages) from `line`.
+fn strip_ansi(line: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len());
+    let mut bytes = line.iter().copied().peekable();
+    while let Some(b) = bytes.next() {
+        if b == 0x1b && bytes.peek() == Some(&b'[') {
+            bytes.next();
+            // skip the parameters up to (and including) the final byte
+            for c in bytes.by_ref() {
+                if (0x40..=0x7e).contains(&c) {
+                    break;
+                }
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn write_log_line(log: &Mutex<File>, line: &[u8]) {
+    if let Ok(mut log) = log.lock() {
+        let mut entry = format!("{} ", timestamp()).into_bytes();
+        entry.extend(strip_ansi(line));
+        entry.push(b'\n');
+        // the log is a convenience, so failing to write it doesn't fail the run
+        let _ = log.write_all(&entry);
+    }
+}
+
+/// Copy everything read from `reader` to `out` as is, and to `log` line by line,
+/// with a timestamp. A carriage return discards the line so far, so that only
+/// the final state of a progress meter is logged.
+fn copy_lines(mut reader: File, mut out: File, log: Arc<Mutex<File>>) {
+    let mut buf = [0_u8; 8192];
+    let mut line = Vec::new();
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        let _ = out.write_all(&buf[..n]);
+        for &b in &buf[..n] {
+            match b {
+                b'\n' => {
+                    write_log_line(&log, &line);
+                    line.clear();
+                }
+                b'\r' => line.clear(),
+                _ => line.push(b),
+            }
+        }
+    }
+    if !line.is_empty() {
+        write_log_line(&log, &line);
+    }
+}
+
+/// Divert the output written to the file descriptor `fd` (by this process, the
+/// C++ components and any child process) through a pipe, copied into `log`.
+fn redirect(fd: RawFd, log: Arc<Mutex<File>>) -> Result<Redirect> {
+    let saved = check(
+        unsafe { libc::dup(fd) },
+        "could not duplicate a file descriptor",
+    )?;
+    let mut fds = [0 as c_int; 2];
+    check(
+        unsafe { libc::pipe(fds.as_mut_ptr()) },
+        "could not create a pipe for the log",
+    )?;
+    check(
+        unsafe { libc::dup2(fds[1], fd) },
+        "could not redirect output to the log",
+    )?;
+    unsafe { libc::close(fds[1]) };
+    // SAFETY: the read end of the pipe and the duplicate of the original file
+    // descriptor are owned by the copying thread from here on
+    let reader = unsafe { File::from_raw_fd(fds[0]) };
+    let out = unsafe {
+        File::from_raw_fd(check(
+            libc::dup(saved),
+            "could not duplicate a file descriptor",
+        )?)
+    };
+    let handle = thread::spawn(move || copy_lines(reader, out, log));
+    Ok(Redirect { fd, saved, handle })
+}
+
+/// Start copying everything written to stdout and stderr, including the
+/// messages of the C++ components, into `<dir>/piscem.log`. Each line of the log
+/// is prefixed with the time it was written; the log is appended to, so that
+/// it records every run writing into `dir`.
+pub(crate) fn start(dir: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("could not create the output directory {}", dir.display()))?;
+    let path = dir.join(LOG_FILE);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("could not open the log file {}", path.display()))?;
+    writeln!(
+        file,
+        "{} piscem {}: {}",
+        timestamp(),
+        clap::crate_version!(),
+        std::env::args().collect::<Vec<String>>().join(" ")
+    )?;
+
+    let log = Arc::new(Mutex::new(file));
+    std::io::stdout().flush()?;
+    let redirects = vec![
+        redirect(libc::STDOUT_FILENO, Arc::clone(&log))?,
+        redirect(libc::STDERR_FILENO, log)?,
+    ];
+    if let Ok(mut active) = ACTIVE_LOG.lock() {
+        *active = Some(LogTee { redirects });
+    }
+    Ok(path)
+}
+
+/// Stop copying output into the log started by `start`, if any, once all that
+/// was written so far has been logged.
+pub(crate) fn finish() -> Result<()> {
+    let tee = match ACTIVE_LOG.lock() {
+        Ok(mut active) => active.take(),
+        Err(_) => None,
+    };
+    if let Some(tee) = tee {
+        std::io::stdout().flush()?;
+        for r in tee.redirects {
+            // restoring the original file descriptor closes the write end of the
+            // pipe, so that the copying thread sees its end
+            check(
+                unsafe { libc::dup2(r.saved, r.fd) },
+                "could not restore output after logging",
+            )?;
+            unsafe { libc::close(r.saved) };
+            if r.handle.join().is_err() {
+                anyhow::bail!("the thread writing the log panicked");
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{span, Event, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::log_file;
+
+/// The formats in which the log can be written.
+pub(crate) const LOG_FORMATS: [&str; 2] = ["text", "json"];
+
+/// The phase of a command (e.g. `cdbg` for the cDBG construction of `build`),
+/// as given by the `phase` field of a span.
+struct Phase(String);
+
+/// Collects the fields of an event or span as JSON values.
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+/// Records the `phase` field of every new span, so that `JsonFormat` can report
+/// the phase an event was logged in.
+pub(crate) struct PhaseLayer;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for PhaseLayer {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        if let (Some(Value::String(phase)), Some(span)) = (fields.remove("phase"), ctx.span(id)) {
+            span.extensions_mut().insert(Phase(phase));
+        }
+    }
+}
+
+/// Formats every event as a JSON object on a line of its own, with its UTC time,
+/// level, the subcommand being run, the phase of the command (that of the
+/// innermost span with one, or `null`), its target and its fields (including
+/// the message).
+pub(crate) struct JsonFormat {
+    subcommand: String,
+}
+
+impl JsonFormat {
+    pub fn new(subcommand: &str) -> Self {
+        Self {
+            subcommand: subcommand.to_string(),
+        }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let phase = ctx.event_scope().and_then(|mut scope| {
+            scope.find_map(|span| span.extensions().get::<Phase>().map(|p| p.0.clone()))
+        });
+        let mut entry = Map::new();
+        entry.insert("timestamp".to_string(), log_file::timestamp().into());
+        entry.insert(
+            "level".to_string(),
+            event.metadata().level().to_string().into(),
+        );
+        entry.insert("subcommand".to_string(), self.subcommand.clone().into());
+        entry.insert("phase".to_string(), phase.into());
+        entry.insert("target".to_string(), event.metadata().target().into());
+        event.record(&mut JsonVisitor(&mut entry));
+        writeln!(writer, "{}", Value::Object(entry))
+    }
+}
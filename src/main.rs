@@ -1,42 +1,49 @@
-use std::ffi::CString;
-use std::ffi::{OsStr, OsString};
 use std::io;
-use std::os::raw::{c_char, c_int};
 use std::path::PathBuf;
+use std::process::ExitCode;
 
 use anyhow::{bail, Result};
-use clap::{Parser, Subcommand};
-use tracing::{error, info, warn, Level};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use tracing::info;
+use tracing_subscriber::prelude::*;
 
-mod piscem_commands;
-use piscem_commands::*;
+use piscem::{
+    BenchOpts, BuildOpts, ClassifyOpts, CleanOpts, DumpEctabOpts, DumpRefsOpts, DumpUnitigsOpts,
+    FetchIndexOpts, FetchOutcome, InspectOpts, LogLevel, MapBulkOpts, MapSCAtacOpts, MapSCOpts,
+    PrefetchIndexOpts, QuantBulkOpts, QueryOpts, RadInspectOpts, RadMergeOpts, RadToSamOpts,
+    RunOptions, SelftestOpts, ValidateIndexOpts,
+};
 
-#[link(name = "pesc_static", kind = "static")]
-extern "C" {
-    pub fn run_pesc_sc(args: c_int, argsv: *const *const c_char) -> c_int;
-    pub fn run_pesc_bulk(args: c_int, argsv: *const *const c_char) -> c_int;
-    pub fn run_pesc_sc_atac(args: c_int, argsv: *const *const c_char) -> c_int;
-}
-
-#[link(name = "build_static", kind = "static")]
-extern "C" {
-    pub fn run_build(args: c_int, argsv: *const *const c_char) -> c_int;
-    pub fn run_build_poison_table(args: c_int, argsv: *const *const c_char) -> c_int;
-}
-
-#[link(name = "cfcore_static", kind = "static", modifiers = "+whole-archive")]
-extern "C" {
-    pub fn cf_build(args: c_int, argsv: *const *const c_char) -> c_int;
-}
+mod log_file;
+mod log_format;
 
 /// Indexing and mapping to compacted colored de Bruijn graphs
 #[derive(Debug, Parser)]
 #[command(author, version, about)]
 #[command(propagate_version = true)]
 struct Cli {
-    /// be quiet (no effect yet for cDBG building phase of indexing).
-    #[arg(short, long)]
+    /// be quiet: only log warnings and errors (the same as `--log-level warn`).
+    #[arg(short, long, conflicts_with_all = ["verbose", "log_level"])]
     quiet: bool,
+    /// log more: debugging messages with `-v`, and everything with `-vv`.
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "log_level")]
+    verbose: u8,
+    /// the level of the messages to log, by piscem and its C++ components; at
+    /// `warn` and below, the progress of the cDBG builder is discarded.
+    #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(piscem::LOG_LEVELS))]
+    log_level: Option<String>,
+    /// validate the command and print its configuration and the arguments that would
+    /// be passed to the cDBG builder, indexer or mapper, without running anything.
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// don't write the log of `build` and the `map-*` commands to `piscem.log` in
+    /// their output directory.
+    #[arg(long, global = true)]
+    no_log_file: bool,
+    /// the format of the log: human-readable text, or JSON lines holding the time,
+    /// level, subcommand, phase and message of every entry.
+    #[arg(long, global = true, default_value = "text", value_parser = clap::builder::PossibleValuesParser::new(log_format::LOG_FORMATS))]
+    log_format: String,
     #[command(subcommand)]
     command: Commands,
 }
@@ -58,442 +65,280 @@ enum Commands {
     /// map reads for scAtac processing
     #[command(arg_required_else_help = true)]
     MapSCAtac(MapSCAtacOpts),
-}
 
-// from: https://stackoverflow.com/questions/74322541/how-to-append-to-pathbuf
-fn append_to_path(p: impl Into<OsString>, s: impl AsRef<OsStr>) -> PathBuf {
-    let mut p = p.into();
-    p.push(s);
-    p.into()
-}
+    /// report statistics about an index
+    #[command(arg_required_else_help = true)]
+    Inspect(InspectOpts),
 
-fn main() -> Result<(), anyhow::Error> {
-    let cli_args = Cli::parse();
-    //env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+    /// check the integrity of an index
+    #[command(arg_required_else_help = true)]
+    ValidateIndex(ValidateIndexOpts),
 
-    let quiet = cli_args.quiet;
-    if quiet {
-        tracing_subscriber::fmt()
-            .with_max_level(Level::WARN)
-            .with_writer(io::stderr)
-            .init();
-    } else {
-        tracing_subscriber::fmt()
-            .with_max_level(Level::INFO)
-            .with_writer(io::stderr)
-            .init();
-    }
+    /// remove an index and the files written alongside it
+    #[command(arg_required_else_help = true)]
+    Clean(CleanOpts),
 
-    let ncpus = num_cpus::get();
+    /// read the files of an index into the page cache ahead of mapping
+    #[command(arg_required_else_help = true)]
+    PrefetchIndex(PrefetchIndexOpts),
 
-    match cli_args.command {
-        Commands::Build(BuildOpts {
-            ref_seqs,
-            ref_lists,
-            ref_dirs,
-            klen,
-            mlen,
-            threads,
-            output,
-            keep_intermediate_dbg,
-            work_dir,
-            overwrite,
-            no_ec_table,
-            decoy_paths,
-            seed,
-        }) => {
-            info!("starting piscem build");
-            if threads == 0 {
-                bail!(
-                    "the number of provided threads ({}) must be greater than 0.",
-                    threads
-                );
-            }
-            if threads > ncpus {
-                bail!("the number of provided threads ({}) should be <= the number of logical CPUs ({}).",
-                    threads, ncpus);
-            }
-            if mlen >= klen {
-                bail!(
-                    "minimizer length ({}) must be < k-mer length ({})",
-                    mlen,
-                    klen
-                );
-            }
+    /// measure the mapping throughput of a sample of reads with several thread counts
+    #[command(arg_required_else_help = true)]
+    Bench(BenchOpts),
 
-            // if the decoy sequences are provided, ensure they are valid paths
-            if let Some(ref decoys) = decoy_paths {
-                for d in decoys {
-                    match d.try_exists() {
-                        Ok(true) => {}
-                        Ok(false) => {
-                            bail!(
-                                "Path for decoy file {} seems not to point to a valid file",
-                                d.display()
-                            );
-                        }
-                        Err(e) => {
-                            bail!(
-                                "Error {} when checking the existence of decoy file {}",
-                                e,
-                                d.display()
-                            );
-                        }
-                    }
-                }
-            }
+    /// build an index over a bundled miniature reference and map reads against it
+    Selftest(SelftestOpts),
 
-            let mut args: Vec<CString> = vec![];
-
-            let cf_out = PathBuf::from(output.as_path().to_string_lossy().into_owned() + "_cfish");
-            let cf_base_path = cf_out.as_path();
-            let seg_file = append_to_path(cf_base_path, ".cf_seg");
-            let seq_file = append_to_path(cf_base_path, ".cf_seq");
-            let struct_file = append_to_path(cf_base_path, ".json");
-            let mut build_ret;
-
-            if overwrite {
-                if struct_file.exists() {
-                    std::fs::remove_file(struct_file.clone())?;
-                }
-                if seg_file.exists() {
-                    std::fs::remove_file(seg_file.clone())?;
-                }
-                if seq_file.exists() {
-                    std::fs::remove_file(seq_file.clone())?;
-                }
-            }
+    /// write the unitigs of the compacted de Bruijn graph of an index to FASTA
+    #[command(arg_required_else_help = true)]
+    DumpUnitigs(DumpUnitigsOpts),
 
-            if struct_file.exists() && (!seq_file.exists() || !seg_file.exists()) {
-                warn!("The prefix you have chosen for output already corresponds to an existing cDBG structure file {:?}.", struct_file.display());
-                warn!("However, the corresponding seq and seg files do not exist. Please either delete this structure file, choose another output prefix, or use the --overwrite flag.");
-                bail!("Cannot write over existing index without the --overwrite flag.");
-            }
+    /// write the equivalence classes of the unitigs of an index as TSV
+    #[command(arg_required_else_help = true)]
+    DumpEctab(DumpEctabOpts),
 
-            args.push(CString::new("cdbg_builder").unwrap());
-
-            // We can treat the different input options independently
-            // here because the argument parser should have enforced
-            // their exclusivity.
-            let mut has_input = false;
-
-            if let Some(seqs) = ref_seqs {
-                if !seqs.is_empty() {
-                    let out_stem =
-                        PathBuf::from(output.as_path().to_string_lossy().into_owned() + ".sigs");
-                    let configs = prepare_fasta::RecordParseConfig {
-                        input: seqs.clone(),
-                        output_stem: out_stem,
-                        polya_clip_length: None,
-                    };
-                    info!("Computing and recording reference signatures...");
-                    prepare_fasta::parse_records(configs)?;
-                    info!("done.");
-                    args.push(CString::new("--seq").unwrap());
-                    let reflist = seqs.join(",");
-                    args.push(CString::new(reflist.as_str()).unwrap());
-                    has_input = true;
-                }
-            }
+    /// reconstruct the reference sequences of an index
+    #[command(arg_required_else_help = true)]
+    DumpRefs(DumpRefsOpts),
 
-            if let Some(lists) = ref_lists {
-                if !lists.is_empty() {
-                    args.push(CString::new("--list").unwrap());
-                    let reflist = lists.join(",");
-                    args.push(CString::new(reflist.as_str()).unwrap());
-                    has_input = true;
-                }
-            }
+    /// look up k-mers in an index
+    #[command(arg_required_else_help = true)]
+    Query(QueryOpts),
 
-            if let Some(dirs) = ref_dirs {
-                if !dirs.is_empty() {
-                    args.push(CString::new("--dir").unwrap());
-                    let reflist = dirs.join(",");
-                    args.push(CString::new(reflist.as_str()).unwrap());
-                    has_input = true;
-                }
-            }
+    /// summarize a RAD file written by a mapper
+    #[command(arg_required_else_help = true)]
+    RadInspect(RadInspectOpts),
 
-            assert!(
-                has_input,
-                "Input (via --ref-seqs, --ref-lists, or --ref-dirs) must be provided."
-            );
-
-            args.push(CString::new("-k").unwrap());
-            args.push(CString::new(klen.to_string()).unwrap());
-            args.push(CString::new("--track-short-seqs").unwrap());
-            args.push(CString::new("--poly-N-stretch").unwrap());
-
-            // check if the provided work directory exists.
-            // If not, then try and create it.
-            match work_dir.try_exists() {
-                Ok(true) => {
-                    info!(
-                        "will use {} as the work directory for temporary files.",
-                        work_dir.display()
-                    );
-                }
-                Ok(false) => {
-                    // try to create it
-                    match std::fs::create_dir_all(&work_dir) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("when attempting to create working directory {}, encountered error {:#?}", &work_dir.display(), e);
-                            bail!(
-                                "Failed to create working directory {} for index construction : {:#?}",
-                                &work_dir.display(),
-                                e
-                            );
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("when checking existence of working directory {:#?}", e);
-                    bail!(
-                        "Failed to create working directory for index construction : {:#?}",
-                        e
-                    );
-                }
-            }
+    /// convert a RAD file written by a mapper into SAM or BAM records
+    #[command(arg_required_else_help = true)]
+    RadToSam(RadToSamOpts),
 
-            // check if the provided output path is more than just a prefix
-            // if so, check if the specified directory exists and create it
-            // if it doesn't.
-            if let Some(parent_path) = cf_out.parent() {
-                if !parent_path.exists() {
-                    std::fs::create_dir_all(parent_path)?;
-                    info!(
-                        "directory {} did not already exist; creating it.",
-                        parent_path.display()
-                    );
-                }
-            }
+    /// merge RAD files mapped against the same index, e.g. of technical replicates
+    #[command(arg_required_else_help = true)]
+    RadMerge(RadMergeOpts),
 
-            args.push(CString::new("-o").unwrap());
-            args.push(CString::new(cf_out.as_path().to_string_lossy().into_owned()).unwrap());
-
-            args.push(CString::new("-t").unwrap());
-            args.push(CString::new(threads.to_string()).unwrap());
-            // output format
-            args.push(CString::new("-f").unwrap());
-            args.push(CString::new("3").unwrap());
-            // work directory
-            args.push(CString::new("-w").unwrap());
-            args.push(CString::new(work_dir.as_path().to_string_lossy().into_owned()).unwrap());
-
-            info!("args = {:?}", args);
-            {
-                let arg_ptrs: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
-                let args_len: c_int = args.len() as c_int;
-                build_ret = unsafe { cf_build(args_len, arg_ptrs.as_ptr()) };
-            }
+    /// quantify the references from the mappings of `map-bulk`
+    #[command(arg_required_else_help = true)]
+    QuantBulk(QuantBulkOpts),
 
-            if build_ret != 0 {
-                bail!(
-                    "cDBG constructor returned exit code {}; failure.",
-                    build_ret
-                );
-            }
+    /// classify the reads mapped by `map-bulk` through a taxonomy
+    #[command(arg_required_else_help = true)]
+    Classify(ClassifyOpts),
 
-            args.clear();
-            args.push(CString::new("ref_index_builder").unwrap());
+    /// download a prebuilt index from a registry and print its prefix
+    #[command(arg_required_else_help = true)]
+    FetchIndex(FetchIndexOpts),
+}
 
-            args.push(CString::new("-i").unwrap());
-            args.push(CString::new(cf_out.as_path().to_string_lossy().into_owned()).unwrap());
-            args.push(CString::new("-k").unwrap());
-            args.push(CString::new(klen.to_string()).unwrap());
-            args.push(CString::new("-m").unwrap());
-            args.push(CString::new(mlen.to_string()).unwrap()); // minimizer length
+/// The directory into which the files with the output stem `stem` are written.
+fn stem_dir(stem: &std::path::Path) -> PathBuf {
+    match stem.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
 
-            args.push(CString::new("--canonical-parsing").unwrap());
-            if !no_ec_table {
-                args.push(CString::new("--build-ec-table").unwrap());
-            }
-            args.push(CString::new("-o").unwrap());
-            args.push(CString::new(output.as_path().to_string_lossy().into_owned()).unwrap());
+fn main() -> ExitCode {
+    let code = match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // report the error as returning it from `main` would
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(piscem::exit_code(&e))
+        }
+    };
+    // the error, if any, is reported before the log ends, so that it is logged too
+    if let Err(e) = log_file::finish() {
+        eprintln!("Error: {:?}", e);
+    }
+    code
+}
 
-            args.push(CString::new("-d").unwrap());
-            args.push(CString::new(work_dir.as_path().to_string_lossy().into_owned()).unwrap());
+fn run() -> Result<(), anyhow::Error> {
+    let matches = Cli::command().get_matches();
+    let cli_args = Cli::from_arg_matches(&matches)?;
+    //env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
 
-            args.push(CString::new("-t").unwrap());
-            args.push(CString::new(threads.to_string()).unwrap());
+    let log_level = match (cli_args.quiet, cli_args.verbose, &cli_args.log_level) {
+        (_, _, Some(level)) => level.parse::<LogLevel>()?,
+        (true, _, _) => LogLevel::Warn,
+        (false, 0, None) => LogLevel::Info,
+        (false, 1, None) => LogLevel::Debug,
+        (false, _, None) => LogLevel::Trace,
+    };
+    let dry_run = cli_args.dry_run;
+    if cli_args.log_format == "json" {
+        let subcommand = matches.subcommand_name().unwrap_or_default();
+        tracing_subscriber::registry()
+            .with(log_level.level_filter())
+            .with(log_format::PhaseLayer)
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .event_format(log_format::JsonFormat::new(subcommand))
+                    .with_writer(io::stderr),
+            )
+            .init();
+    } else {
+        tracing_subscriber::fmt()
+            .with_max_level(log_level.level_filter())
+            .with_writer(io::stderr)
+            .init();
+    }
 
-            args.push(CString::new("--seed").unwrap());
-            args.push(CString::new(seed.to_string()).unwrap());
+    let log_dir = match &cli_args.command {
+        Commands::Build(opts) => Some(stem_dir(&opts.output)),
+        Commands::MapSC(opts) if opts.list_geometries.is_none() => Some(opts.output.clone()),
+        Commands::MapSCAtac(opts) => Some(opts.output.clone()),
+        Commands::MapBulk(opts) => Some(stem_dir(&opts.output)),
+        _ => None,
+    };
+    if let Some(dir) = log_dir.filter(|_| !dry_run && !cli_args.no_log_file) {
+        let path = log_file::start(&dir)?;
+        info!("writing the log to {}", path.display());
+        piscem::on_interrupted_exit(|| {
+            let _ = log_file::finish();
+        });
+    }
 
-            if quiet {
-                args.push(CString::new("--quiet").unwrap());
-            }
+    let run = RunOptions { log_level, dry_run };
 
-            {
-                println!("{:?}", args);
-                let arg_ptrs: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
-                let args_len: c_int = args.len() as c_int;
-                build_ret = unsafe { run_build(args_len, arg_ptrs.as_ptr()) };
-            }
+    match cli_args.command {
+        Commands::Build(build_opts) => {
+            piscem::build_index(build_opts, &run)?;
+        }
 
-            if build_ret != 0 {
-                bail!("indexer returned exit code {}; failure.", build_ret);
+        Commands::MapSC(sc_opts) => {
+            if let Some(ref format) = sc_opts.list_geometries {
+                print!("{}", piscem::list_geometries(format == "json")?);
+                return Ok(());
             }
+            piscem::map_sc(sc_opts, &run)?;
+        }
 
-            // now, build the poison table if there are decoys
-            if let Some(decoy_pathbufs) = decoy_paths {
-                args.clear();
-                args.push(CString::new("poison_table_builder").unwrap());
-
-                // index is the one we just built
-                args.push(CString::new("-i").unwrap());
-                args.push(CString::new(output.as_path().to_string_lossy().into_owned()).unwrap());
-
-                args.push(CString::new("-t").unwrap());
-                args.push(CString::new(threads.to_string()).unwrap());
-
-                if overwrite {
-                    args.push(CString::new("--overwrite").unwrap());
-                }
-
-                let path_args = decoy_pathbufs
-                    .into_iter()
-                    .map(|x| x.to_string_lossy().into_owned())
-                    .collect::<Vec<String>>()
-                    .join(",");
-                args.push(CString::new("-d").unwrap());
-                args.push(CString::new(path_args).unwrap());
-
-                if quiet {
-                    args.push(CString::new("--quiet").unwrap());
-                }
-
-                {
-                    println!("{:?}", args);
-                    let arg_ptrs: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
-                    let args_len: c_int = args.len() as c_int;
-                    build_ret = unsafe { run_build_poison_table(args_len, arg_ptrs.as_ptr()) };
-                }
-                if build_ret != 0 {
-                    bail!(
-                        "building poison table returned exit code {}; failure.",
-                        build_ret
-                    );
-                }
-            }
+        Commands::MapSCAtac(scatac_opts) => {
+            piscem::map_sc_atac(scatac_opts, &run)?;
+        }
 
-            if !keep_intermediate_dbg {
-                info!("removing intermediate cdBG files produced by cuttlefish.");
-
-                match std::fs::remove_file(seg_file.clone()) {
-                    Ok(_) => {
-                        info!("removed segment file {}", seg_file.display());
-                    }
-                    Err(e) => {
-                        warn!(
-                            "cannot remove {}, encountered error {:?}!",
-                            seg_file.display(),
-                            e
-                        );
-                    }
-                };
-
-                match std::fs::remove_file(seq_file.clone()) {
-                    Ok(_) => {
-                        info!("removed tiling file {}", seq_file.display());
-                    }
-                    Err(e) => {
-                        warn!(
-                            "cannot remove {}, encountered error {:?}!",
-                            seq_file.display(),
-                            e
-                        );
-                    }
-                };
-                // for now, let the json file stick around. It's
-                // generally very small and may contain useful information
-                // about the references being indexed.
-            }
+        Commands::MapBulk(bulk_opts) => {
+            piscem::map_bulk(bulk_opts, &run)?;
+        }
 
-            info!("piscem build finished.");
+        Commands::Inspect(inspect_opts) => {
+            let summary = piscem::inspect_index(&inspect_opts.index)?;
+            if inspect_opts.json {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+            } else {
+                print!("{}", piscem::format_index_summary(&summary));
+            }
         }
 
-        Commands::MapSC(sc_opts) => {
-            if sc_opts.threads == 0 {
-                bail!(
-                    "the number of provided threads ({}) must be greater than 0.",
-                    sc_opts.threads
+        Commands::ValidateIndex(validate_opts) => {
+            let checks = piscem::validate_index(&validate_opts.index);
+            for c in &checks {
+                println!(
+                    "[{}] {}: {}",
+                    if c.passed { " OK " } else { "FAIL" },
+                    c.description,
+                    c.details
                 );
             }
-            if sc_opts.threads > ncpus {
-                bail!("the number of provided threads ({}) should be <= the number of logical CPUs ({}).",
-                    sc_opts.threads, ncpus);
+            let num_failed = checks.iter().filter(|c| !c.passed).count();
+            if num_failed > 0 {
+                bail!(
+                    "{} of {} checks failed for index {}.",
+                    num_failed,
+                    checks.len(),
+                    validate_opts.index
+                );
             }
+            info!("all {} checks passed.", checks.len());
+        }
 
-            let mut args = sc_opts.as_argv()?;
-            if quiet {
-                args.push(CString::new("--quiet").unwrap());
-            }
+        Commands::DumpUnitigs(dump_opts) => {
+            piscem::dump_unitigs(&dump_opts)?;
+        }
 
-            info!("cmd: {:?}", args);
-            let arg_ptrs: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
-            let args_len: c_int = args.len() as c_int;
+        Commands::DumpEctab(dump_opts) => {
+            piscem::dump_ectab(&dump_opts)?;
+        }
 
-            let map_ret = unsafe { run_pesc_sc(args_len, arg_ptrs.as_ptr()) };
-            if map_ret != 0 {
-                bail!("mapper returned exit code {}; failure", map_ret);
-            }
+        Commands::DumpRefs(dump_opts) => {
+            piscem::dump_refs(&dump_opts)?;
         }
 
-        Commands::MapSCAtac(scatac_opts) => {
-            if scatac_opts.threads == 0 {
-                bail!(
-                    "the number of provided threads ({}) must be greater than 0.",
-                    scatac_opts.threads
-                );
-            }
-            if scatac_opts.threads > ncpus {
-                bail!("the number of provided threads ({}) should be <= the number of logical CPUs ({}).",
-                    scatac_opts.threads, ncpus);
-            }
+        Commands::Query(query_opts) => {
+            piscem::query(&query_opts)?;
+        }
 
-            let mut args = scatac_opts.as_argv()?;
-            if quiet {
-                args.push(CString::new("--quiet").unwrap());
-            }
+        Commands::Clean(clean_opts) => {
+            piscem::clean(&clean_opts, &run)?;
+        }
 
-            info!("cmd: {:?}", args);
-            let arg_ptrs: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
-            let args_len: c_int = args.len() as c_int;
+        Commands::PrefetchIndex(prefetch_opts) => {
+            piscem::prefetch_index(&prefetch_opts)?;
+        }
 
-            let map_ret = unsafe { run_pesc_sc_atac(args_len, arg_ptrs.as_ptr()) };
-            if map_ret != 0 {
-                bail!("mapper returned exit code {}; failure", map_ret);
+        Commands::Bench(bench_opts) => {
+            let summary = piscem::bench(&bench_opts)?;
+            if bench_opts.json {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+            } else {
+                print!("{}", piscem::format_bench_summary(&summary));
             }
         }
 
-        Commands::MapBulk(bulk_opts) => {
-            if bulk_opts.threads == 0 {
+        Commands::Selftest(selftest_opts) => {
+            let summary = piscem::selftest(&selftest_opts, log_level)?;
+            let checks = summary["checks"].as_array().cloned().unwrap_or_default();
+            for c in &checks {
+                println!(
+                    "[{}] {}: {}",
+                    if c["passed"] == true { " OK " } else { "FAIL" },
+                    c["description"].as_str().unwrap_or_default(),
+                    c["details"].as_str().unwrap_or_default()
+                );
+            }
+            let num_failed = checks.iter().filter(|c| c["passed"] != true).count();
+            if num_failed > 0 {
                 bail!(
-                    "the number of provided threads ({}) must be greater than 0.",
-                    bulk_opts.threads
+                    "{} of {} self-test checks failed.",
+                    num_failed,
+                    checks.len()
                 );
             }
-            if bulk_opts.threads > ncpus {
-                bail!("the number of provided threads ({}) should be <= the number of logical CPUs ({}).",
-                    bulk_opts.threads, ncpus);
+            info!("all {} self-test checks passed.", checks.len());
+        }
+
+        Commands::RadInspect(rad_opts) => {
+            let summary = piscem::inspect_rad(&rad_opts.input)?;
+            if rad_opts.json {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+            } else {
+                print!("{}", piscem::format_rad_summary(&summary, rad_opts.top));
             }
+        }
 
-            let mut args = bulk_opts.as_argv()?;
+        Commands::RadToSam(convert_opts) => {
+            piscem::rad_to_sam(&convert_opts)?;
+        }
 
-            if quiet {
-                args.push(CString::new("--quiet").unwrap());
-            }
+        Commands::RadMerge(merge_opts) => {
+            piscem::rad_merge(&merge_opts)?;
+        }
 
-            let arg_ptrs: Vec<*const c_char> = args.iter().map(|s| s.as_ptr()).collect();
-            let args_len: c_int = args.len() as c_int;
+        Commands::QuantBulk(quant_opts) => {
+            piscem::quant_bulk(&quant_opts)?;
+        }
 
-            let map_ret = unsafe { run_pesc_bulk(args_len, arg_ptrs.as_ptr()) };
-            if map_ret != 0 {
-                bail!("mapper returned exit code {}; failure", map_ret);
-            }
+        Commands::Classify(classify_opts) => {
+            piscem::classify(&classify_opts)?;
         }
+
+        Commands::FetchIndex(fetch_opts) => match piscem::fetch_index(&fetch_opts, &run)? {
+            FetchOutcome::Fetched(prefix) => println!("{}", prefix.display()),
+            FetchOutcome::Listed(table) => print!("{}", table),
+            FetchOutcome::DryRun => {}
+        },
     }
     Ok(())
 }
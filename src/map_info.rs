@@ -0,0 +1,85 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// The name of the mapping summary written into the output directory by `map-sc`
+/// and `map-sc-atac`.
+pub(crate) const SC_MAP_INFO_FILE: &str = "map_info.json";
+
+/// The path of the mapping summary written by `map-bulk` for the output stem `output`.
+pub(crate) fn bulk_map_info_path(output: &Path) -> PathBuf {
+    PathBuf::from(output.to_string_lossy().into_owned() + ".map_info.json")
+}
+
+/// Read the JSON object stored in the file at `path`; if the file does not
+/// exist, an empty object is returned.
+pub(crate) fn read_map_info(path: &Path) -> Result<Map<String, Value>> {
+    if !path.exists() {
+        return Ok(Map::new());
+    }
+    let contents = std::fs::read_to_string(path)?;
+    serde_json::from_str::<Map<String, Value>>(&contents)
+        .with_context(|| format!("could not parse {} as a JSON object", path.display()))
+}
+
+/// Add (or replace) the entry `key` with `value` in the JSON object stored
+/// in the file at `path`. If the file does not exist yet, it is created.
+pub(crate) fn add_map_info_entry(path: &Path, key: &str, value: Value) -> Result<()> {
+    let mut info = read_map_info(path)?;
+    info.insert(key.to_string(), value);
+    std::fs::write(path, serde_json::to_string_pretty(&info)?)?;
+    Ok(())
+}
+
+/// The peak resident set size of this process, in bytes, if it can be determined.
+pub(crate) fn peak_rss_bytes() -> Option<u64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage only writes to the provided struct
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let max_rss = unsafe { usage.assume_init() }.ru_maxrss as u64;
+    // the peak RSS is reported in bytes on macOS, but in kilobytes elsewhere
+    if cfg!(target_os = "macos") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+/// Tracks the resources used by a mapping run, so that they can be
+/// recorded in the mapping summary.
+#[derive(Clone)]
+pub(crate) struct RunStats {
+    start: Instant,
+}
+
+impl RunStats {
+    /// Start tracking a run.
+    pub fn start() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+
+    /// The wall time and peak memory use of the run so far, along with the size of
+    /// each of the `input_files`.
+    pub fn to_json(&self, input_files: &[String]) -> Value {
+        let files = input_files
+            .iter()
+            .map(|f| {
+                json!({
+                    "path": f,
+                    "size_bytes": std::fs::metadata(f).ok().map(|m| m.len()),
+                })
+            })
+            .collect::<Vec<Value>>();
+        json!({
+            "piscem_version": clap::crate_version!(),
+            "wall_time_secs": self.start.elapsed().as_secs_f64(),
+            "peak_rss_bytes": peak_rss_bytes(),
+            "input_files": files,
+        })
+    }
+}
@@ -0,0 +1,1024 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::io;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use tracing::{info, info_span, warn};
+
+use crate::exit_code::{bail_with, ExitKind, WithExitKind};
+use crate::piscem_commands::AsArgv;
+use crate::{
+    atac_stats, barcode_freq, bin_matrix, call_entry_point, checksum, coverage, decompress, demux,
+    detect_geometry, dry_run, eq_classes, fifo_reads, fragments, geometry, inspect, map_info,
+    orientation, permit_list, plate_metadata, qc_report, quant, rad, read_groups, read_preprocess,
+    read_validation, ref_filter, refinfo, remote_reads, resolve_threads, run_pesc_bulk,
+    run_pesc_sc, run_pesc_sc_atac, sam, signals, splice_status, stream_input, MapBulkConfig,
+    MapSCAtacConfig, MapSCConfig, MapSummary, RunOptions,
+};
+
+/// Upon a signal during the mapping run tracked by `run_stats`, remove the
+/// incomplete RAD file `rad_path`, and record the statistics of the run so far
+/// in the mapping summary `map_info_path`, marked as interrupted.
+fn on_map_interrupt(
+    run_stats: &map_info::RunStats,
+    input_files: &[String],
+    rad_path: PathBuf,
+    map_info_path: PathBuf,
+) -> Result<signals::OnSignal> {
+    let run_stats = run_stats.clone();
+    let input_files = input_files.to_vec();
+    signals::OnSignal::run(move || {
+        if rad_path.exists() {
+            match std::fs::remove_file(&rad_path) {
+                Ok(()) => info!("removed the incomplete {}.", rad_path.display()),
+                Err(e) => warn!("could not remove {}: {}", rad_path.display(), e),
+            }
+        }
+        let recorded = map_info::add_map_info_entry(&map_info_path, "interrupted", true.into())
+            .and_then(|_| {
+                map_info::add_map_info_entry(
+                    &map_info_path,
+                    "run_stats",
+                    run_stats.to_json(&input_files),
+                )
+            });
+        if let Err(e) = recorded {
+            warn!(
+                "could not record the interrupted run in {}: {:#}",
+                map_info_path.display(),
+                e
+            );
+        }
+    })
+}
+
+/// Map single-cell reads as described by `sc_opts`, writing a RAD file into the
+/// output directory, and return the mapping summary.
+pub fn map_sc(mut sc_opts: MapSCConfig, run: &RunOptions) -> Result<MapSummary> {
+    let RunOptions { log_level, dry_run } = *run;
+    let quiet = log_level.quiet();
+    let ncpus = num_cpus::get();
+    sc_opts.threads = resolve_threads(sc_opts.threads, ncpus)?;
+
+    geometry::validate_geometry(&sc_opts.geometry).exit_kind(ExitKind::InvalidArguments)?;
+    if !sc_opts.unfiltered_pl.is_empty() {
+        permit_list::check_permit_lists(&sc_opts.unfiltered_pl, &sc_opts.geometry)
+            .exit_kind(ExitKind::InvalidArguments)?;
+    }
+    let demux_map = sc_opts
+        .demux_map
+        .as_deref()
+        .map(|m| demux::DemuxMap::from_tsv(m, sc_opts.demux_suffix))
+        .transpose()
+        .exit_kind(ExitKind::InvalidArguments)?;
+    if sc_opts.keep_raw_barcodes && sc_opts.unfiltered_pl.is_empty() && !sc_opts.rescue_barcodes {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "--keep-raw-barcodes requires barcodes to be corrected with --unfiltered-pl or --rescue-barcodes."
+        );
+    }
+
+    if let Some(metadata) = sc_opts.plate_metadata.as_ref().filter(|_| !dry_run) {
+        plate_metadata::write_plate_metadata(metadata, &sc_opts.output)?;
+    }
+
+    let run_stats = map_info::RunStats::start();
+    // the reads may be replaced by fifos below, so keep the
+    // original inputs for the summary.
+    let input_files = sc_opts
+        .read1
+        .iter()
+        .chain(sc_opts.read2.iter())
+        .chain(sc_opts.interleaved.iter().flatten())
+        .cloned()
+        .collect::<Vec<String>>();
+
+    if sc_opts.interleaved.is_some() && sc_opts.geometry == "auto" {
+        bail_with!(ExitKind::InvalidArguments, "--geometry auto cannot be used with --interleaved input; please provide the geometry explicitly.");
+    }
+    stream_input::resolve_read_inputs(
+        [&mut sc_opts.read1, &mut sc_opts.read2]
+            .into_iter()
+            .chain(sc_opts.interleaved.as_mut()),
+    )
+    .exit_kind(ExitKind::InvalidArguments)?;
+    read_validation::validate_read_files(
+        sc_opts
+            .read1
+            .iter()
+            .chain(sc_opts.read2.iter())
+            .chain(sc_opts.interleaved.iter().flatten()),
+    )
+    .exit_kind(ExitKind::InvalidArguments)?;
+    read_validation::check_paired_lists(&sc_opts.read1, &sc_opts.read2)
+        .exit_kind(ExitKind::InvalidArguments)?;
+    if let Some(n) = sc_opts.check_pairing {
+        read_validation::check_pairing(&sc_opts.read1, &sc_opts.read2, n)
+            .exit_kind(ExitKind::InvalidArguments)?;
+    }
+
+    let detection = if sc_opts.geometry == "auto" {
+        let detection = detect_geometry::detect_geometry(&sc_opts.read1, &sc_opts.read2)?;
+        sc_opts.geometry = detection.selected.to_string();
+        Some(detection)
+    } else {
+        None
+    };
+
+    if !sc_opts.no_verify {
+        checksum::verify_index(&sc_opts.index)?;
+    }
+
+    // the reads are shown as given, since any fifos through which they would be
+    // passed to the mapper only exist during a run
+    if dry_run {
+        sc_opts.geometry = geometry::resolve_geometry(&sc_opts.geometry).to_string();
+        dry_run::print_config("map-sc", &sc_opts);
+        let mut args = sc_opts.as_argv()?;
+        if quiet {
+            args.push(CString::new("--quiet").unwrap());
+        }
+        dry_run::print_argv("run_pesc_sc", &args);
+        return Ok(MapSummary::new(sc_opts.output, serde_json::Value::Null));
+    }
+
+    // the reads are decompressed before any of the stages below replaces the
+    // read files with fifos, so that those stages also read plain reads
+    let decompressed = match NonZeroUsize::new(sc_opts.decompression_threads) {
+        Some(t) => decompress::decompress_to_fifos(
+            [&mut sc_opts.read1, &mut sc_opts.read2]
+                .into_iter()
+                .chain(sc_opts.interleaved.as_mut()),
+            t,
+        )?,
+        None => None,
+    };
+
+    let remote = remote_reads::stream_urls_to_fifos(
+        [&mut sc_opts.read1, &mut sc_opts.read2]
+            .into_iter()
+            .chain(sc_opts.interleaved.as_mut()),
+        remote_reads::RetryPolicy {
+            retries: sc_opts.url_retries,
+            delay: sc_opts.url_retry_delay,
+        },
+    )?;
+
+    let deinterleaved = match sc_opts.interleaved.take() {
+        Some(files) => {
+            let fr = fifo_reads::deinterleave_to_fifos(files)?;
+            sc_opts.read1 = vec![fr.r1_fifo.to_string_lossy().into_owned()];
+            sc_opts.read2 = vec![fr.r2_fifo.to_string_lossy().into_owned()];
+            Some(fr)
+        }
+        None => None,
+    };
+
+    // the reads are preprocessed as given, before any complex geometry is
+    // transformed, so that read 1 is trimmed before its barcode is extracted
+    let mut preprocess = read_preprocess::Preprocessing::from(&sc_opts);
+    preprocess.resolve_min_read_len(&sc_opts.index)?;
+    let preprocessed = read_preprocess::preprocess_to_fifos(
+        [
+            (&mut sc_opts.read1, read_preprocess::ReadKind::Mate1),
+            (&mut sc_opts.read2, read_preprocess::ReadKind::Mate2),
+        ],
+        &preprocess,
+    )?;
+
+    // if the geometry is complex, the mapper will read the
+    // transformed reads from the fifos instead.
+    // record how the barcode is assembled before complex geometries
+    // are simplified (which merges the barcode pieces).
+    let barcode_segments = geometry::barcode_segments(&sc_opts.geometry);
+    let barcode_segment_bounds = geometry::barcode_segment_bounds(&sc_opts.geometry);
+    sc_opts.geometry = geometry::resolve_geometry(&sc_opts.geometry).to_string();
+    let xformed_reads = geometry::xform_if_complex(
+        &sc_opts.geometry,
+        &sc_opts.read1,
+        &sc_opts.read2,
+        sc_opts.linker_mismatches,
+    )?;
+    if let Some(ref xf) = xformed_reads {
+        sc_opts.geometry = xf.geometry.clone();
+        sc_opts.read1 = vec![xf.fifo_data.r1_fifo.to_string_lossy().into_owned()];
+        sc_opts.read2 = vec![xf.fifo_data.r2_fifo.to_string_lossy().into_owned()];
+    }
+    // the Ns are looked for once the reads are in a simple geometry, in which
+    // the barcode and UMI are at fixed positions
+    let n_policy = match sc_opts.bc_n_policy {
+        Some(ref policy) => read_preprocess::preprocess_to_fifos(
+            [
+                (&mut sc_opts.read1, read_preprocess::ReadKind::Mate1),
+                (&mut sc_opts.read2, read_preprocess::ReadKind::Mate2),
+            ],
+            &read_preprocess::Preprocessing {
+                barcode_ns: Some(read_preprocess::BarcodeNs::new(policy, &sc_opts.geometry)?),
+                ..Default::default()
+            },
+        )?,
+        None => None,
+    };
+
+    let mut args = sc_opts.as_argv()?;
+    if quiet {
+        args.push(CString::new("--quiet").unwrap());
+    }
+
+    info!("cmd: {:?}", args);
+    let on_interrupt = on_map_interrupt(
+        &run_stats,
+        &input_files,
+        sc_opts.output.join(rad::SC_RAD_FILE),
+        sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+    )?;
+    let phase = info_span!("map", phase = "mapping").entered();
+    let map_ret = call_entry_point("run_pesc_sc", run_pesc_sc, &args, dry_run);
+    drop(phase);
+    drop(on_interrupt);
+    if map_ret != 0 {
+        bail_with!(
+            ExitKind::Internal,
+            "mapper returned exit code {}; failure",
+            map_ret
+        );
+    }
+
+    if let Some(d) = decompressed {
+        d.join()?;
+    }
+    if let Some(r) = remote {
+        r.join()?;
+    }
+
+    if let Some(xf) = xformed_reads {
+        match xf.fifo_data.join_handle.join() {
+            Ok(Ok(xform_stats)) => {
+                info!("{}", xform_stats);
+            }
+            Ok(Err(e)) => {
+                bail!("failed to transform reads into a simple geometry: {}", e);
+            }
+            Err(_) => {
+                bail!("the thread transforming reads into a simple geometry panicked");
+            }
+        }
+    }
+
+    if let Some(fr) = deinterleaved {
+        fr.join().context("failed to read the interleaved input")?;
+    }
+
+    if let Some(p) = preprocessed {
+        map_info::add_map_info_entry(
+            &sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            "read_preprocessing",
+            p.join()?,
+        )?;
+    }
+
+    if let Some(p) = n_policy {
+        map_info::add_map_info_entry(
+            &sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            "barcode_n_policy",
+            p.join()?["barcode_n_policy"].take(),
+        )?;
+    }
+
+    if let Some(segments) = barcode_segments {
+        map_info::add_map_info_entry(
+            &sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            "barcode_segments",
+            serde_json::json!(segments),
+        )?;
+    }
+
+    if let Some(detection) = detection {
+        map_info::add_map_info_entry(
+            &sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            "geometry_detection",
+            detection.to_json(),
+        )?;
+    }
+
+    if !sc_opts.unfiltered_pl.is_empty() {
+        let summary = permit_list::correct_barcodes(
+            &sc_opts.output.join(rad::SC_RAD_FILE),
+            &sc_opts.unfiltered_pl,
+            barcode_segment_bounds.as_deref(),
+            sc_opts.expected_cells,
+            sc_opts.keep_raw_barcodes,
+            &sc_opts.output,
+        )?;
+        map_info::add_map_info_entry(
+            &sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            "barcode_correction",
+            summary,
+        )?;
+    } else if sc_opts.rescue_barcodes {
+        let summary = permit_list::rescue_barcodes(
+            &sc_opts.output.join(rad::SC_RAD_FILE),
+            sc_opts.rescue_min_reads,
+            sc_opts.keep_raw_barcodes,
+        )?;
+        map_info::add_map_info_entry(
+            &sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            "barcode_rescue",
+            summary,
+        )?;
+    }
+
+    ref_filter::apply_ref_filters(
+        &sc_opts.output.join(rad::SC_RAD_FILE),
+        sc_opts.restrict_refs.as_deref(),
+        sc_opts.exclude_refs.as_deref(),
+        &sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+    )?;
+
+    orientation::apply_ori_filter(
+        &sc_opts.output.join(rad::SC_RAD_FILE),
+        &sc_opts.expected_ori,
+        sc_opts.flag_ori,
+        &sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+    )?;
+
+    if sc_opts.barcode_freqs {
+        let summary = barcode_freq::write_barcode_freqs(
+            &sc_opts.output.join(rad::SC_RAD_FILE),
+            &sc_opts.output,
+        )?;
+        map_info::add_map_info_entry(
+            &sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            "barcode_frequencies",
+            summary,
+        )?;
+    }
+
+    if let Some(ref fmt) = sc_opts.output_format {
+        let refs = refinfo::read_refinfo(&inspect::component_path(&sc_opts.index, ".refinfo"))?;
+        let rad_path = sc_opts.output.join(rad::SC_RAD_FILE);
+        let aln_path = sam::sc_aln_path(&sc_opts.output, fmt);
+        if fmt == "bam" {
+            sam::write_rad_as_bam(
+                &rad_path,
+                &refs,
+                &aln_path,
+                sam::compression_threads(sc_opts.compression_threads)?,
+            )?;
+        } else {
+            let mut sam_out = io::BufWriter::new(std::fs::File::create(&aln_path)?);
+            sam::write_rad_as_sam(&rad_path, &refs, &mut sam_out)?;
+            io::Write::flush(&mut sam_out)?;
+        }
+        info!(
+            "wrote {} output to {}.",
+            fmt.to_uppercase(),
+            aln_path.display()
+        );
+    }
+
+    if let Some(ref t2g) = sc_opts.usa_t2g {
+        let usa_counts = splice_status::count_usa_reads(
+            &sc_opts.output.join(rad::SC_RAD_FILE),
+            t2g,
+            sc_opts.usa_tag,
+        )?;
+        let map_info_path = sc_opts.output.join(map_info::SC_MAP_INFO_FILE);
+        map_info::add_map_info_entry(&map_info_path, "usa_counts", usa_counts.reads.to_json())?;
+        map_info::add_map_info_entry(&map_info_path, "usa_ec_counts", usa_counts.ecs.to_json())?;
+    }
+
+    map_info::add_map_info_entry(
+        &sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+        "run_stats",
+        run_stats.to_json(&input_files),
+    )?;
+
+    if sc_opts.qc_report {
+        qc_report::write_qc_report(
+            &sc_opts.output.join(qc_report::SC_QC_REPORT_FILE),
+            "piscem map-sc QC report",
+            &sc_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            &sc_opts.output.join(rad::SC_RAD_FILE),
+            &input_files,
+        )?;
+    }
+
+    if let Some(ref demux_map) = demux_map {
+        let map_info_path = sc_opts.output.join(map_info::SC_MAP_INFO_FILE);
+        let summary = demux::demux_rad(
+            &sc_opts.output.join(rad::SC_RAD_FILE),
+            demux_map,
+            &map_info_path,
+            &sc_opts.output,
+        )?;
+        map_info::add_map_info_entry(&map_info_path, "demux", summary)?;
+    }
+
+    let map_info = map_info::read_map_info(&sc_opts.output.join(map_info::SC_MAP_INFO_FILE))?;
+    Ok(MapSummary::new(sc_opts.output, map_info.into()))
+}
+
+/// Map single-cell ATAC-seq reads as described by `scatac_opts`, writing a RAD
+/// file into the output directory, and return the mapping summary.
+pub fn map_sc_atac(mut scatac_opts: MapSCAtacConfig, run: &RunOptions) -> Result<MapSummary> {
+    let RunOptions { log_level, dry_run } = *run;
+    let quiet = log_level.quiet();
+    let ncpus = num_cpus::get();
+    scatac_opts.threads = resolve_threads(scatac_opts.threads, ncpus)?;
+
+    let num_read_files = match (&scatac_opts.read1, &scatac_opts.read2, &scatac_opts.reads) {
+        (Some(r1), Some(r2), _) => {
+            if r1.len() != r2.len() {
+                bail_with!(
+                    ExitKind::InvalidArguments,
+                    "the number of read 1 files ({}) must match the number of read 2 files ({}).",
+                    r1.len(),
+                    r2.len()
+                );
+            }
+            r1.len()
+        }
+        (_, _, Some(r)) => r.len(),
+        _ => bail_with!(
+            ExitKind::InvalidArguments,
+            "either paired-end (-1/-2) or unpaired (-r) reads must be provided."
+        ),
+    };
+    let num_barcode_files = scatac_opts.barcode.as_ref().map_or(0, |b| b.len());
+    if num_barcode_files != num_read_files {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "the number of barcode files ({}) must match the number of read files ({}).",
+            num_barcode_files,
+            num_read_files
+        );
+    }
+
+    let run_stats = map_info::RunStats::start();
+    let input_files = [
+        &scatac_opts.read1,
+        &scatac_opts.read2,
+        &scatac_opts.reads,
+        &scatac_opts.barcode,
+    ]
+    .into_iter()
+    .flatten()
+    .flatten()
+    .cloned()
+    .collect::<Vec<String>>();
+    stream_input::resolve_read_inputs(
+        [
+            &mut scatac_opts.read1,
+            &mut scatac_opts.read2,
+            &mut scatac_opts.reads,
+            &mut scatac_opts.barcode,
+        ]
+        .into_iter()
+        .flatten(),
+    )
+    .exit_kind(ExitKind::InvalidArguments)?;
+    read_validation::validate_read_files(
+        [
+            &scatac_opts.read1,
+            &scatac_opts.read2,
+            &scatac_opts.reads,
+            &scatac_opts.barcode,
+        ]
+        .into_iter()
+        .flatten()
+        .flatten(),
+    )
+    .exit_kind(ExitKind::InvalidArguments)?;
+
+    if !scatac_opts.no_verify {
+        checksum::verify_index(&scatac_opts.index)?;
+    }
+
+    // the reads are shown as given, since any fifos through which they would be
+    // passed to the mapper only exist during a run
+    if dry_run {
+        dry_run::print_config("map-sc-atac", &scatac_opts);
+        let mut args = scatac_opts.as_argv()?;
+        if quiet {
+            args.push(CString::new("--quiet").unwrap());
+        }
+        dry_run::print_argv("run_pesc_sc_atac", &args);
+        return Ok(MapSummary::new(scatac_opts.output, serde_json::Value::Null));
+    }
+
+    // the reads are decompressed before any of the stages below replaces the
+    // read files with fifos, so that those stages also read plain reads
+    let decompressed = match NonZeroUsize::new(scatac_opts.decompression_threads) {
+        Some(t) => decompress::decompress_to_fifos(
+            [
+                &mut scatac_opts.read1,
+                &mut scatac_opts.read2,
+                &mut scatac_opts.reads,
+                &mut scatac_opts.barcode,
+            ]
+            .into_iter()
+            .flatten(),
+            t,
+        )?,
+        None => None,
+    };
+
+    let remote = remote_reads::stream_urls_to_fifos(
+        [
+            &mut scatac_opts.read1,
+            &mut scatac_opts.read2,
+            &mut scatac_opts.reads,
+            &mut scatac_opts.barcode,
+        ]
+        .into_iter()
+        .flatten(),
+        remote_reads::RetryPolicy {
+            retries: scatac_opts.url_retries,
+            delay: scatac_opts.url_retry_delay,
+        },
+    )?;
+
+    let mut preprocess = read_preprocess::Preprocessing::from(&scatac_opts);
+    preprocess.resolve_min_read_len(&scatac_opts.index)?;
+    let preprocessed = read_preprocess::preprocess_to_fifos(
+        [
+            (&mut scatac_opts.read1, read_preprocess::ReadKind::Mate1),
+            (&mut scatac_opts.read2, read_preprocess::ReadKind::Mate2),
+            (&mut scatac_opts.reads, read_preprocess::ReadKind::Mate1),
+            (&mut scatac_opts.barcode, read_preprocess::ReadKind::Barcode),
+        ]
+        .into_iter()
+        .filter_map(|(l, k)| Some((l.as_mut()?, k))),
+        &preprocess,
+    )?;
+
+    let mut args = scatac_opts.as_argv()?;
+    if quiet {
+        args.push(CString::new("--quiet").unwrap());
+    }
+
+    info!("cmd: {:?}", args);
+    let on_interrupt = on_map_interrupt(
+        &run_stats,
+        &input_files,
+        scatac_opts.output.join(rad::SC_RAD_FILE),
+        scatac_opts.output.join(map_info::SC_MAP_INFO_FILE),
+    )?;
+    let phase = info_span!("map", phase = "mapping").entered();
+    let map_ret = call_entry_point("run_pesc_sc_atac", run_pesc_sc_atac, &args, dry_run);
+    drop(phase);
+    drop(on_interrupt);
+    if map_ret != 0 {
+        bail_with!(
+            ExitKind::Internal,
+            "mapper returned exit code {}; failure",
+            map_ret
+        );
+    }
+
+    if let Some(d) = decompressed {
+        d.join()?;
+    }
+    if let Some(r) = remote {
+        r.join()?;
+    }
+
+    if let Some(p) = preprocessed {
+        map_info::add_map_info_entry(
+            &scatac_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            "read_preprocessing",
+            p.join()?,
+        )?;
+    }
+
+    if scatac_opts.output_format.as_deref() == Some("bam") {
+        let refs = refinfo::read_refinfo(&inspect::component_path(&scatac_opts.index, ".refinfo"))?;
+        let bam_path = scatac_opts.output.join(sam::SC_ATAC_BAM_FILE);
+        sam::write_rad_as_bam(
+            &scatac_opts.output.join(rad::SC_RAD_FILE),
+            &refs,
+            &bam_path,
+            sam::compression_threads(scatac_opts.compression_threads)?,
+        )?;
+        info!("wrote BAM output to {}.", bam_path.display());
+    }
+
+    if scatac_opts.write_fragments || scatac_opts.dedup_fragments {
+        let refs = refinfo::read_refinfo(&inspect::component_path(&scatac_opts.index, ".refinfo"))?;
+        let summary = fragments::write_sc_atac_fragments(
+            &scatac_opts.output.join(rad::SC_RAD_FILE),
+            &refs,
+            &scatac_opts.output,
+            scatac_opts.dedup_fragments,
+        )?;
+        map_info::add_map_info_entry(
+            &scatac_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            "fragments",
+            summary,
+        )?;
+    }
+
+    if scatac_opts.barcode_stats {
+        let refs = refinfo::read_refinfo(&inspect::component_path(&scatac_opts.index, ".refinfo"))?;
+        let summary = atac_stats::write_barcode_stats(
+            &scatac_opts.output.join(rad::SC_RAD_FILE),
+            &refs,
+            &scatac_opts.mito_refs,
+            scatac_opts.blacklist.as_deref(),
+            &scatac_opts.output,
+        )?;
+        map_info::add_map_info_entry(
+            &scatac_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            "barcode_stats",
+            summary,
+        )?;
+    }
+
+    if scatac_opts.bin_matrix {
+        let refs = refinfo::read_refinfo(&inspect::component_path(&scatac_opts.index, ".refinfo"))?;
+        let summary = bin_matrix::write_bin_matrix(
+            &scatac_opts.output.join(rad::SC_RAD_FILE),
+            &refs,
+            scatac_opts.bin_size,
+            &scatac_opts.output,
+        )?;
+        map_info::add_map_info_entry(
+            &scatac_opts.output.join(map_info::SC_MAP_INFO_FILE),
+            "bin_matrix",
+            summary,
+        )?;
+    }
+
+    map_info::add_map_info_entry(
+        &scatac_opts.output.join(map_info::SC_MAP_INFO_FILE),
+        "run_stats",
+        run_stats.to_json(&input_files),
+    )?;
+
+    let map_info = map_info::read_map_info(&scatac_opts.output.join(map_info::SC_MAP_INFO_FILE))?;
+    Ok(MapSummary::new(scatac_opts.output, map_info.into()))
+}
+
+/// Map bulk reads as described by `bulk_opts`, writing a RAD file with the output
+/// stem, and return the mapping summary.
+pub fn map_bulk(mut bulk_opts: MapBulkConfig, run: &RunOptions) -> Result<MapSummary> {
+    let RunOptions { log_level, dry_run } = *run;
+    let quiet = log_level.quiet();
+    let ncpus = num_cpus::get();
+    bulk_opts.threads = resolve_threads(bulk_opts.threads, ncpus)?;
+
+    let run_stats = map_info::RunStats::start();
+    let input_files = [
+        &bulk_opts.read1,
+        &bulk_opts.read2,
+        &bulk_opts.reads,
+        &bulk_opts.interleaved,
+    ]
+    .into_iter()
+    .flatten()
+    .flatten()
+    .cloned()
+    .collect::<Vec<String>>();
+    stream_input::resolve_read_inputs(
+        [
+            &mut bulk_opts.read1,
+            &mut bulk_opts.read2,
+            &mut bulk_opts.reads,
+            &mut bulk_opts.interleaved,
+        ]
+        .into_iter()
+        .flatten(),
+    )
+    .exit_kind(ExitKind::InvalidArguments)?;
+    read_validation::validate_read_files(
+        [
+            &bulk_opts.read1,
+            &bulk_opts.read2,
+            &bulk_opts.reads,
+            &bulk_opts.interleaved,
+        ]
+        .into_iter()
+        .flatten()
+        .flatten(),
+    )
+    .exit_kind(ExitKind::InvalidArguments)?;
+    if let Some(lib_type) = &bulk_opts.lib_type {
+        let paired = bulk_opts.read1.is_some() || bulk_opts.interleaved.is_some();
+        orientation::check_lib_type(lib_type, paired)?;
+    }
+    if let (Some(r1), Some(r2)) = (&bulk_opts.read1, &bulk_opts.read2) {
+        read_validation::check_paired_lists(r1, r2).exit_kind(ExitKind::InvalidArguments)?;
+        if let Some(n) = bulk_opts.check_pairing {
+            read_validation::check_pairing(r1, r2, n).exit_kind(ExitKind::InvalidArguments)?;
+        }
+    }
+
+    if let Some(labels) = &bulk_opts.read_groups {
+        read_groups::group_files(labels, num_bulk_read_files(&bulk_opts))?;
+    }
+
+    if !bulk_opts.no_verify {
+        checksum::verify_index(&bulk_opts.index)?;
+    }
+
+    // the reads are shown as given, since any fifos through which they would be
+    // passed to the mapper only exist during a run
+    if dry_run {
+        dry_run::print_config("map-bulk", &bulk_opts);
+        let mut args = bulk_opts.as_argv()?;
+        if quiet {
+            args.push(CString::new("--quiet").unwrap());
+        }
+        dry_run::print_argv("run_pesc_bulk", &args);
+        return Ok(MapSummary::new(bulk_opts.output, serde_json::Value::Null));
+    }
+
+    let on_interrupt = on_map_interrupt(
+        &run_stats,
+        &input_files,
+        rad::bulk_rad_path(&bulk_opts.output),
+        map_info::bulk_map_info_path(&bulk_opts.output),
+    )?;
+    let phase = info_span!("map", phase = "mapping").entered();
+    match bulk_opts.read_groups.clone() {
+        Some(labels) => map_read_groups(&bulk_opts, &labels, quiet)?,
+        None => run_bulk_mapper(&mut bulk_opts, quiet)?,
+    }
+    drop(phase);
+    drop(on_interrupt);
+
+    ref_filter::apply_ref_filters(
+        &rad::bulk_rad_path(&bulk_opts.output),
+        bulk_opts.restrict_refs.as_deref(),
+        bulk_opts.exclude_refs.as_deref(),
+        &map_info::bulk_map_info_path(&bulk_opts.output),
+    )?;
+
+    match &bulk_opts.lib_type {
+        Some(lib_type) => orientation::apply_lib_type(
+            &rad::bulk_rad_path(&bulk_opts.output),
+            lib_type,
+            bulk_opts.flag_ori,
+            &map_info::bulk_map_info_path(&bulk_opts.output),
+        )?,
+        None => orientation::apply_ori_filter(
+            &rad::bulk_rad_path(&bulk_opts.output),
+            &bulk_opts.expected_ori,
+            bulk_opts.flag_ori,
+            &map_info::bulk_map_info_path(&bulk_opts.output),
+        )?,
+    }
+
+    if bulk_opts.write_eqclasses {
+        let summary = eq_classes::write_bulk_eq_classes(
+            &rad::bulk_rad_path(&bulk_opts.output),
+            &bulk_opts.output,
+        )?;
+        map_info::add_map_info_entry(
+            &map_info::bulk_map_info_path(&bulk_opts.output),
+            "eq_classes",
+            summary,
+        )?;
+    }
+
+    if bulk_opts.quant {
+        let summary = quant::quantify(
+            &rad::bulk_rad_path(&bulk_opts.output),
+            &bulk_opts.index,
+            &quant::bulk_quant_path(&bulk_opts.output),
+            None,
+            quant::MAX_EM_ROUNDS,
+        )?;
+        map_info::add_map_info_entry(
+            &map_info::bulk_map_info_path(&bulk_opts.output),
+            "quant",
+            summary,
+        )?;
+    }
+
+    if let Some(prefix) = &bulk_opts.coverage_out {
+        let refs = refinfo::read_refinfo(&inspect::component_path(&bulk_opts.index, ".refinfo"))?;
+        let summary =
+            coverage::write_coverage(&rad::bulk_rad_path(&bulk_opts.output), &refs, prefix)?;
+        map_info::add_map_info_entry(
+            &map_info::bulk_map_info_path(&bulk_opts.output),
+            "coverage",
+            summary,
+        )?;
+    }
+
+    let output_format = if bulk_opts.sam {
+        Some("sam")
+    } else {
+        bulk_opts.output_format.as_deref()
+    };
+    if let Some(fmt) = output_format {
+        let refs = refinfo::read_refinfo(&inspect::component_path(&bulk_opts.index, ".refinfo"))?;
+        let rad_path = rad::bulk_rad_path(&bulk_opts.output);
+        let aln_path = sam::bulk_aln_path(&bulk_opts.output, fmt);
+        if fmt == "bam" {
+            sam::write_rad_as_bam(
+                &rad_path,
+                &refs,
+                &aln_path,
+                sam::compression_threads(bulk_opts.compression_threads)?,
+            )?;
+        } else {
+            let mut sam_out = io::BufWriter::new(std::fs::File::create(&aln_path)?);
+            sam::write_rad_as_sam(&rad_path, &refs, &mut sam_out)?;
+            io::Write::flush(&mut sam_out)?;
+        }
+        info!(
+            "wrote {} output to {}.",
+            fmt.to_uppercase(),
+            aln_path.display()
+        );
+    }
+
+    map_info::add_map_info_entry(
+        &map_info::bulk_map_info_path(&bulk_opts.output),
+        "run_stats",
+        run_stats.to_json(&input_files),
+    )?;
+
+    if bulk_opts.qc_report {
+        qc_report::write_qc_report(
+            &qc_report::bulk_qc_report_path(&bulk_opts.output),
+            "piscem map-bulk QC report",
+            &map_info::bulk_map_info_path(&bulk_opts.output),
+            &rad::bulk_rad_path(&bulk_opts.output),
+            &input_files,
+        )?;
+    }
+
+    let map_info = map_info::read_map_info(&map_info::bulk_map_info_path(&bulk_opts.output))?;
+    Ok(MapSummary::new(bulk_opts.output, map_info.into()))
+}
+
+/// The number of read files (or pairs of files) of `bulk_opts`.
+fn num_bulk_read_files(bulk_opts: &MapBulkConfig) -> usize {
+    [&bulk_opts.read1, &bulk_opts.reads, &bulk_opts.interleaved]
+        .into_iter()
+        .flatten()
+        .map(|l| l.len())
+        .sum()
+}
+
+/// Run the bulk mapper on the reads of `bulk_opts`, writing the RAD file and the
+/// mapping summary with its output stem. The reads are streamed to the mapper
+/// through fifos where needed (e.g. to download, deinterleave or preprocess them).
+fn run_bulk_mapper(bulk_opts: &mut MapBulkConfig, quiet: bool) -> Result<()> {
+    // the reads are decompressed before any of the stages below replaces the
+    // read files with fifos, so that those stages also read plain reads
+    let decompressed = match NonZeroUsize::new(bulk_opts.decompression_threads) {
+        Some(t) => decompress::decompress_to_fifos(
+            [
+                &mut bulk_opts.read1,
+                &mut bulk_opts.read2,
+                &mut bulk_opts.reads,
+                &mut bulk_opts.interleaved,
+            ]
+            .into_iter()
+            .flatten(),
+            t,
+        )?,
+        None => None,
+    };
+
+    let remote = remote_reads::stream_urls_to_fifos(
+        [
+            &mut bulk_opts.read1,
+            &mut bulk_opts.read2,
+            &mut bulk_opts.reads,
+            &mut bulk_opts.interleaved,
+        ]
+        .into_iter()
+        .flatten(),
+        remote_reads::RetryPolicy {
+            retries: bulk_opts.url_retries,
+            delay: bulk_opts.url_retry_delay,
+        },
+    )?;
+
+    let deinterleaved = match bulk_opts.interleaved.take() {
+        Some(files) => {
+            let fr = fifo_reads::deinterleave_to_fifos(files)?;
+            bulk_opts.read1 = Some(vec![fr.r1_fifo.to_string_lossy().into_owned()]);
+            bulk_opts.read2 = Some(vec![fr.r2_fifo.to_string_lossy().into_owned()]);
+            Some(fr)
+        }
+        None => None,
+    };
+
+    let mut preprocess = read_preprocess::Preprocessing::from(&*bulk_opts);
+    preprocess.resolve_min_read_len(&bulk_opts.index)?;
+    let preprocessed = read_preprocess::preprocess_to_fifos(
+        [
+            (&mut bulk_opts.read1, read_preprocess::ReadKind::Mate1),
+            (&mut bulk_opts.read2, read_preprocess::ReadKind::Mate2),
+            (&mut bulk_opts.reads, read_preprocess::ReadKind::Mate1),
+        ]
+        .into_iter()
+        .filter_map(|(l, k)| Some((l.as_mut()?, k))),
+        &preprocess,
+    )?;
+
+    let mut args = bulk_opts.as_argv()?;
+
+    if quiet {
+        args.push(CString::new("--quiet").unwrap());
+    }
+
+    let map_ret = call_entry_point("run_pesc_bulk", run_pesc_bulk, &args, false);
+    if map_ret != 0 {
+        bail_with!(
+            ExitKind::Internal,
+            "mapper returned exit code {}; failure",
+            map_ret
+        );
+    }
+
+    if let Some(d) = decompressed {
+        d.join()?;
+    }
+    if let Some(r) = remote {
+        r.join()?;
+    }
+
+    if let Some(fr) = deinterleaved {
+        fr.join().context("failed to read the interleaved input")?;
+    }
+
+    if let Some(p) = preprocessed {
+        map_info::add_map_info_entry(
+            &map_info::bulk_map_info_path(&bulk_opts.output),
+            "read_preprocessing",
+            p.join()?,
+        )?;
+    }
+    Ok(())
+}
+
+/// Map the reads of `bulk_opts` for each of the read groups given by `labels`
+/// (one per read file, or pair of files) in turn, and merge the mappings into the
+/// RAD file and mapping summary of its output stem, recording the read group of
+/// every record (see `read_groups::merge_rad_files`).
+fn map_read_groups(bulk_opts: &MapBulkConfig, labels: &[String], quiet: bool) -> Result<()> {
+    let groups = read_groups::group_files(labels, num_bulk_read_files(bulk_opts))?;
+    let select = |list: &Option<Vec<String>>, files: &[usize]| {
+        list.as_ref()
+            .map(|l| files.iter().map(|&i| l[i].clone()).collect::<Vec<String>>())
+    };
+    // the mappings of the groups are only kept until they are merged
+    let part_files = (0..groups.len())
+        .map(|i| read_groups::part_stem(&bulk_opts.output, i))
+        .flat_map(|p| [rad::bulk_rad_path(&p), map_info::bulk_map_info_path(&p)])
+        .collect::<Vec<_>>();
+    let _on_interrupt = signals::OnSignal::remove(&part_files)?;
+    let mut parts = Vec::with_capacity(groups.len());
+    for (i, (label, files)) in groups.iter().enumerate() {
+        info!(
+            "mapping read group {} ({} of {}).",
+            label,
+            i + 1,
+            groups.len()
+        );
+        let mut part_opts = bulk_opts.clone();
+        part_opts.read1 = select(&bulk_opts.read1, files);
+        part_opts.read2 = select(&bulk_opts.read2, files);
+        part_opts.reads = select(&bulk_opts.reads, files);
+        part_opts.interleaved = select(&bulk_opts.interleaved, files);
+        part_opts.output = read_groups::part_stem(&bulk_opts.output, i);
+        run_bulk_mapper(&mut part_opts, quiet)?;
+        parts.push(part_opts.output);
+    }
+
+    let rads = parts
+        .iter()
+        .map(|p| rad::bulk_rad_path(p))
+        .collect::<Vec<_>>();
+    let infos = parts
+        .iter()
+        .map(|p| map_info::bulk_map_info_path(p))
+        .collect::<Vec<_>>();
+    let labels = groups.into_iter().map(|(l, _)| l).collect::<Vec<String>>();
+    let num_records =
+        read_groups::merge_rad_files(&rads, Some(&labels), &rad::bulk_rad_path(&bulk_opts.output))?;
+    read_groups::merge_map_infos(
+        &infos,
+        &labels,
+        &num_records,
+        "read_groups",
+        &map_info::bulk_map_info_path(&bulk_opts.output),
+    )?;
+    info!(
+        "merged the mappings of {} read groups ({} records).",
+        labels.len(),
+        num_records.iter().sum::<u64>()
+    );
+    for path in rads.iter().chain(infos.iter()).filter(|p| p.exists()) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
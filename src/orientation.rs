@@ -0,0 +1,350 @@
+use anyhow::Result;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::exit_code::{bail_with, ExitKind};
+use crate::map_info;
+use crate::rad::{decode_ref_id, RadReader, RadRecord, RadWriter};
+
+/// Statistics about the alignments whose orientation is inconsistent
+/// with the expected orientation of the library.
+#[derive(Debug, Default)]
+pub(crate) struct OriFilterStats {
+    /// the number of alignments in the unexpected orientation
+    pub inconsistent_alns: u64,
+    /// the number of reads all of whose alignments are in the
+    /// unexpected orientation
+    pub inconsistent_reads: u64,
+}
+
+/// The fraction of reads in one orientation above which `--lib-type auto` infers
+/// a stranded library.
+const STRANDED_FRAC: f64 = 0.8;
+
+/// The read-level tag in which the bulk mapper records how a fragment mapped.
+const FRAG_MAP_TYPE_TAG: &str = "frag_map_type";
+
+/// The value of the `frag_map_type` tag of a read pair of which only read 2
+/// mapped (an orphan), whose alignments are then in the orientation of read 2.
+const MAPPED_SECOND_ORPHAN: u64 = 3;
+
+/// The position of the `frag_map_type` read-level tag in the RAD file read by
+/// `rad`, if it records one (single-cell RAD files don't).
+fn frag_map_type_tag<R: std::io::Read>(rad: &RadReader<R>) -> Option<usize> {
+    rad.header
+        .read_tags
+        .iter()
+        .position(|t| t.name == FRAG_MAP_TYPE_TAG)
+}
+
+/// The orientations of read 1 (or of the unpaired read) implied by the alignments
+/// of `rec`, where `map_type_tag` is the position of its `frag_map_type` tag.
+/// The orientation recorded for an alignment is that of the mate that mapped, so
+/// it is flipped for read pairs of which only read 2 mapped.
+fn read1_oris<'a>(
+    rec: &'a RadRecord,
+    map_type_tag: Option<usize>,
+) -> impl Iterator<Item = Option<bool>> + 'a {
+    let second_orphan = map_type_tag
+        .and_then(|i| rec.read_tags.get(i)?.as_u64())
+        .is_some_and(|t| t == MAPPED_SECOND_ORPHAN);
+    rec.alns
+        .iter()
+        .map(move |a| Some(decode_ref_id(a.first()?.as_u64()?).1 != second_orphan))
+}
+
+/// The orientation (as given to `--expected-ori`) of the reads (or of read 1 of
+/// a pair) of libraries of type `lib_type`, in salmon's notation.
+pub(crate) fn lib_type_ori(lib_type: &str) -> &'static str {
+    match lib_type {
+        "ISF" | "SF" => "fw",
+        "ISR" | "SR" => "rc",
+        _ => "both",
+    }
+}
+
+/// Check that the library type `lib_type` is consistent with the reads being
+/// `paired` or not: the `I` (inward) library types describe read pairs.
+pub(crate) fn check_lib_type(lib_type: &str, paired: bool) -> Result<()> {
+    if lib_type == "auto" || lib_type.starts_with('I') == paired {
+        return Ok(());
+    }
+    bail_with!(
+        ExitKind::InvalidArguments,
+        "library type {} is for {} reads, but {} reads were given.",
+        lib_type,
+        if paired { "unpaired" } else { "paired-end" },
+        if paired { "paired-end" } else { "unpaired" }
+    );
+}
+
+/// Count the reads in the RAD file at `rad_path` all of whose alignments put read
+/// 1 (or the unpaired read) in the forward orientation, and those all of whose
+/// alignments put it in the reverse complement orientation.
+fn count_read_orientations(rad_path: &Path) -> Result<(u64, u64)> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    let map_type_tag = frag_map_type_tag(&rad);
+    let (mut num_fw, mut num_rc) = (0_u64, 0_u64);
+    while let Some(rec) = rad.next_record()? {
+        let mut oris = read1_oris(&rec, map_type_tag).flatten();
+        match oris.next() {
+            Some(fw) if oris.all(|o| o == fw) => {
+                if fw {
+                    num_fw += 1;
+                } else {
+                    num_rc += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((num_fw, num_rc))
+}
+
+/// Infer the library type of `paired` (or unpaired) reads, `num_fw` of which map
+/// only in the forward orientation and `num_rc` only in the reverse complement one.
+fn infer_lib_type(num_fw: u64, num_rc: u64, paired: bool) -> &'static str {
+    let total = (num_fw + num_rc) as f64;
+    let (fw_frac, rc_frac) = if total > 0.0 {
+        (num_fw as f64 / total, num_rc as f64 / total)
+    } else {
+        (0.5, 0.5)
+    };
+    match paired {
+        true if fw_frac >= STRANDED_FRAC => "ISF",
+        true if rc_frac >= STRANDED_FRAC => "ISR",
+        true => "IU",
+        false if fw_frac >= STRANDED_FRAC => "SF",
+        false if rc_frac >= STRANDED_FRAC => "SR",
+        false => "U",
+    }
+}
+
+/// Apply the `--lib-type` `lib_type` to the bulk RAD file at `rad_path`: mappings
+/// inconsistent with the strandedness of the library are removed or, if
+/// `flag_only`, just counted (see `apply_ori_filter`). With `auto`, the library
+/// type is first inferred from the orientation of the reads that map in a single
+/// orientation. The declared and inferred library types, along with these counts,
+/// are recorded under `lib_type` in the mapping summary at `map_info_path`.
+pub(crate) fn apply_lib_type(
+    rad_path: &Path,
+    lib_type: &str,
+    flag_only: bool,
+    map_info_path: &Path,
+) -> Result<()> {
+    let paired = RadReader::from_path(rad_path)?.header.is_paired;
+    let (num_fw, num_rc) = count_read_orientations(rad_path)?;
+    let inferred = infer_lib_type(num_fw, num_rc, paired);
+    let used = if lib_type == "auto" {
+        info!(
+            "inferred library type {} ({} reads map forward, {} reverse complement).",
+            inferred, num_fw, num_rc
+        );
+        inferred
+    } else {
+        if lib_type_ori(inferred) != lib_type_ori(lib_type) {
+            warn!(
+                "the library was declared as {}, but the orientation of its reads suggests {} ({} reads map forward, {} reverse complement).",
+                lib_type, inferred, num_fw, num_rc
+            );
+        }
+        lib_type
+    };
+    map_info::add_map_info_entry(
+        map_info_path,
+        "lib_type",
+        json!({
+            "declared": lib_type,
+            "inferred": inferred,
+            "used": used,
+            "num_fw_reads": num_fw,
+            "num_rc_reads": num_rc,
+        }),
+    )?;
+    apply_ori_filter(rad_path, lib_type_ori(used), flag_only, map_info_path)
+}
+
+/// Whether an alignment in the forward orientation (if `fw`) is consistent
+/// with the expected orientation `expected` (one of `fw`, `rc` or `both`).
+fn is_consistent(expected: &str, fw: bool) -> bool {
+    match expected {
+        "fw" => fw,
+        "rc" => !fw,
+        _ => true,
+    }
+}
+
+/// Count the alignments in the RAD file at `rad_path` that are inconsistent with
+/// the expected orientation `expected` of read 1 (or of the unpaired read; see
+/// `read1_oris`). If `remove` is `true`, the file is rewritten in place without
+/// them, and reads left without any alignment are removed.
+pub(crate) fn filter_rad_orientation(
+    rad_path: &Path,
+    expected: &str,
+    remove: bool,
+) -> Result<OriFilterStats> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    let map_type_tag = frag_map_type_tag(&rad);
+    let tmp_path = PathBuf::from(rad_path.to_string_lossy().into_owned() + ".tmp");
+    let mut writer = if remove {
+        Some(RadWriter::create(&tmp_path, &rad.header)?)
+    } else {
+        None
+    };
+    let mut stats = OriFilterStats::default();
+    while let Some(mut rec) = rad.next_record()? {
+        let num_alns = rec.alns.len();
+        let keep = read1_oris(&rec, map_type_tag)
+            .map(|o| o.is_none_or(|fw| is_consistent(expected, fw)))
+            .collect::<Vec<bool>>();
+        let mut keep = keep.into_iter();
+        rec.alns.retain(|_| keep.next().unwrap_or(true));
+        stats.inconsistent_alns += (num_alns - rec.alns.len()) as u64;
+        if rec.alns.is_empty() && num_alns > 0 {
+            stats.inconsistent_reads += 1;
+        } else if let Some(w) = writer.as_mut() {
+            w.write_record(&rec)?;
+        }
+    }
+    if let Some(w) = writer {
+        w.finish()?;
+        std::fs::rename(&tmp_path, rad_path)?;
+    }
+
+    info!(
+        "{} {} alignments inconsistent with the expected orientation ({}); {} reads had no consistent alignment.",
+        if remove { "removed" } else { "found" },
+        stats.inconsistent_alns,
+        expected,
+        stats.inconsistent_reads
+    );
+    Ok(stats)
+}
+
+/// Apply the `--expected-ori` policy `expected` to the RAD file at `rad_path`:
+/// unless it is `both`, mappings in the other orientation are removed or, if
+/// `flag_only`, just counted. Their number is recorded under
+/// `orientation_filter` in the mapping summary at `map_info_path`.
+pub(crate) fn apply_ori_filter(
+    rad_path: &Path,
+    expected: &str,
+    flag_only: bool,
+    map_info_path: &Path,
+) -> Result<()> {
+    if expected == "both" {
+        return Ok(());
+    }
+    let stats = filter_rad_orientation(rad_path, expected, !flag_only)?;
+    map_info::add_map_info_entry(
+        map_info_path,
+        "orientation_filter",
+        json!({
+            "expected_ori": expected,
+            "mode": if flag_only { "flag" } else { "filter" },
+            "inconsistent_alignments": stats.inconsistent_alns,
+            "inconsistent_reads": stats.inconsistent_reads,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rad::{RadHeader, TagDesc, TagType, TagValue};
+
+    const PAIR: u64 = 4;
+    const FIRST_ORPHAN: u64 = 2;
+
+    /// Write a bulk RAD file with one single-alignment record per `(map type,
+    /// forward)` pair of `reads`.
+    fn write_bulk_rad(path: &Path, reads: &[(u64, bool)]) {
+        let header = RadHeader {
+            is_paired: true,
+            ref_names: vec!["tx1".to_string()],
+            num_chunks: 0,
+            file_tags: vec![],
+            read_tags: vec![TagDesc {
+                name: FRAG_MAP_TYPE_TAG.to_string(),
+                typ: TagType::U8,
+            }],
+            aln_tags: vec![TagDesc {
+                name: "compressed_ori_refid".to_string(),
+                typ: TagType::U32,
+            }],
+            file_tag_values: vec![],
+        };
+        let mut writer = RadWriter::create(path, &header).unwrap();
+        for (map_type, fw) in reads {
+            writer
+                .write_record(&RadRecord {
+                    read_tags: vec![TagValue::U8(*map_type as u8)],
+                    alns: vec![vec![TagValue::U32(if *fw { 0x8000_0000 } else { 0 })]],
+                })
+                .unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn infers_the_library_type_from_the_read_orientations() {
+        assert_eq!(infer_lib_type(90, 10, true), "ISF");
+        assert_eq!(infer_lib_type(10, 90, true), "ISR");
+        assert_eq!(infer_lib_type(50, 50, true), "IU");
+        assert_eq!(infer_lib_type(80, 20, false), "SF");
+        assert_eq!(infer_lib_type(20, 80, false), "SR");
+        assert_eq!(infer_lib_type(0, 0, false), "U");
+    }
+
+    #[test]
+    fn counts_read_2_orphans_in_the_orientation_of_read_1() {
+        let dir = tempfile::tempdir().unwrap();
+        let rad_path = dir.path().join("bulk.rad");
+        // a read 2 orphan mapping in reverse complement puts read 1 forward
+        write_bulk_rad(
+            &rad_path,
+            &[
+                (PAIR, true),
+                (FIRST_ORPHAN, true),
+                (MAPPED_SECOND_ORPHAN, false),
+                (PAIR, false),
+            ],
+        );
+        assert_eq!(count_read_orientations(&rad_path).unwrap(), (3, 1));
+    }
+
+    #[test]
+    fn keeps_read_2_orphans_consistent_with_the_library() {
+        let dir = tempfile::tempdir().unwrap();
+        let rad_path = dir.path().join("bulk.rad");
+        write_bulk_rad(
+            &rad_path,
+            &[
+                (PAIR, false),
+                (PAIR, true),
+                (FIRST_ORPHAN, false),
+                (MAPPED_SECOND_ORPHAN, true),
+                (MAPPED_SECOND_ORPHAN, false),
+            ],
+        );
+        // an ISR library: read 1 maps in reverse complement
+        let stats = filter_rad_orientation(&rad_path, "rc", true).unwrap();
+        assert_eq!(stats.inconsistent_alns, 2);
+        assert_eq!(stats.inconsistent_reads, 2);
+
+        let mut rad = RadReader::from_path(&rad_path).unwrap();
+        let mut kept = Vec::new();
+        while let Some(rec) = rad.next_record().unwrap() {
+            let fw = decode_ref_id(rec.alns[0][0].as_u64().unwrap()).1;
+            kept.push((rec.read_tags[0].as_u64().unwrap(), fw));
+        }
+        assert_eq!(
+            kept,
+            vec![
+                (PAIR, false),
+                (FIRST_ORPHAN, false),
+                (MAPPED_SECOND_ORPHAN, true)
+            ]
+        );
+    }
+}
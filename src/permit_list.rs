@@ -0,0 +1,695 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::geometry;
+use crate::linker_xform;
+use crate::rad::{RadReader, RadWriter, TagDesc, TagValue};
+
+/// The name of the file, written into the output directory by `map-sc` when
+/// `--expected-cells` is given, that lists the barcodes of the retained cells.
+pub(crate) const PERMIT_LIST_FILE: &str = "permit_list.txt";
+
+/// Encode the barcode `bc` as the mappers do, using 2 bits per base
+/// (A = 0, C = 1, G = 2, T = 3) with the first base in the most significant
+/// bits. Returns `None` if `bc` contains any other character.
+fn encode_barcode(bc: &[u8]) -> Option<u64> {
+    let mut v = 0_u64;
+    for c in bc {
+        let code = match c.to_ascii_uppercase() {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => return None,
+        };
+        v = (v << 2) | code;
+    }
+    Some(v)
+}
+
+/// The inverse of `encode_barcode` for a barcode of length `len`.
+pub(crate) fn decode_barcode(mut v: u64, len: usize) -> String {
+    let mut bc = vec![b'A'; len];
+    for c in bc.iter_mut().rev() {
+        *c = b"ACGT"[(v & 3) as usize];
+        v >>= 2;
+    }
+    String::from_utf8(bc).unwrap()
+}
+
+/// The barcodes of an unfiltered permit list (i.e. the list of all barcodes
+/// of a chemistry), encoded as by the mappers.
+pub(crate) struct PermitList {
+    pub barcodes: HashSet<u64>,
+    pub bc_len: usize,
+}
+
+/// Read the unfiltered permit list `path`, which holds one barcode per line
+/// (any further tab-separated columns are ignored). All barcodes must consist
+/// of A, C, G and T only and be of the same length, of at most 32 bases.
+pub(crate) fn read_permit_list(path: &Path) -> Result<PermitList> {
+    let reader = BufReader::new(
+        File::open(path)
+            .with_context(|| format!("could not open permit list {}", path.display()))?,
+    );
+    let mut barcodes = HashSet::new();
+    let mut bc_len = None;
+    for (i, l) in reader.lines().enumerate() {
+        let l = l?;
+        let bc = l.split('\t').next().unwrap_or_default().trim();
+        if bc.is_empty() {
+            continue;
+        }
+        if *bc_len.get_or_insert(bc.len()) != bc.len() {
+            bail!(
+                "barcode {} on line {} of permit list {} has length {}, but previous barcodes have length {}.",
+                bc,
+                i + 1,
+                path.display(),
+                bc.len(),
+                bc_len.unwrap()
+            );
+        }
+        if bc.len() > 32 {
+            bail!(
+                "barcode {} in permit list {} is longer than 32 bases.",
+                bc,
+                path.display()
+            );
+        }
+        match encode_barcode(bc.as_bytes()) {
+            Some(v) => {
+                barcodes.insert(v);
+            }
+            None => bail!(
+                "barcode {} in permit list {} contains characters other than A, C, G, T.",
+                bc,
+                path.display()
+            ),
+        }
+    }
+    match bc_len {
+        Some(bc_len) => Ok(PermitList { barcodes, bc_len }),
+        None => bail!("permit list {} is empty.", path.display()),
+    }
+}
+
+/// The length of the first barcode of the permit list `path`.
+fn first_barcode_len(path: &Path) -> Result<u32> {
+    let reader = BufReader::new(
+        File::open(path)
+            .with_context(|| format!("could not open permit list {}", path.display()))?,
+    );
+    for l in reader.lines() {
+        let l = l?;
+        let bc = l.split('\t').next().unwrap_or_default().trim();
+        if !bc.is_empty() {
+            return Ok(bc.len() as u32);
+        }
+    }
+    bail!("permit list {} is empty.", path.display())
+}
+
+/// Check that the barcodes of the unfiltered permit lists `paths` can be those of
+/// the geometry `geometry`, so that an incompatible list (e.g. the Chromium v3
+/// list given for a BD Rhapsody run) is reported before mapping. A single list
+/// holds whole barcodes, while several lists hold the barcodes of the successive
+/// barcode pieces of the geometry, one list per piece. Only the first barcode of
+/// every list is read; the others are checked when the lists are used.
+pub(crate) fn check_permit_lists(paths: &[PathBuf], geometry: &str) -> Result<()> {
+    let bounds = match paths {
+        [_] => geometry::barcode_len_bounds(geometry).map(|b| vec![b]),
+        _ => geometry::barcode_segment_bounds(geometry),
+    };
+    let Some(bounds) = bounds else {
+        return Ok(());
+    };
+    if bounds.len() != paths.len() {
+        bail!(
+            "{} permit lists were given, but the barcode of geometry {} is made of {} pieces; give either a single list or one list per piece.",
+            paths.len(),
+            geometry,
+            bounds.len()
+        );
+    }
+    for (i, (path, bounds)) in paths.iter().zip(bounds).enumerate() {
+        let len = first_barcode_len(path)?;
+        if len < bounds.0 || bounds.1.is_some_and(|max| len > max) {
+            let piece = if paths.len() > 1 {
+                format!("barcode piece {} of geometry {}", i + 1, geometry)
+            } else {
+                format!("barcodes of geometry {}", geometry)
+            };
+            bail!(
+                "the barcodes in the permit list {} have length {}, but the {} have length {}.",
+                path.display(),
+                len,
+                piece,
+                geometry::format_len_bounds(bounds)
+            );
+        }
+    }
+    Ok(())
+}
+
+impl PermitList {
+    /// Correct the barcode `bc` against the permit list: a barcode in the list is
+    /// kept as is, and one that is a single substitution away from exactly one
+    /// barcode in the list is replaced by that barcode. Returns `None` if the
+    /// barcode can't be corrected unambiguously.
+    fn correct(&self, bc: u64) -> Option<u64> {
+        if self.barcodes.contains(&bc) {
+            return Some(bc);
+        }
+        let mut corrected = None;
+        for i in 0..self.bc_len {
+            let shift = 2 * i;
+            let base = (bc >> shift) & 3;
+            for alt in (0..4).filter(|a| *a != base) {
+                let cand = (bc & !(3 << shift)) | (alt << shift);
+                if self.barcodes.contains(&cand) {
+                    if corrected.is_some() {
+                        return None;
+                    }
+                    corrected = Some(cand);
+                }
+            }
+        }
+        corrected
+    }
+}
+
+/// A piece of the barcode, with the permit list against which it is corrected.
+struct Segment {
+    permit_list: PermitList,
+    /// the number of bases that the piece takes up in the barcode
+    width: usize,
+    /// whether the piece has a variable length, in which case it is padded to
+    /// `width` bases (see `linker_xform::VAR_LEN_PADDING`) and its permitted
+    /// barcode directly precedes the padding, after any extra bases (e.g. the
+    /// diversity insert of the BD Rhapsody enhanced beads)
+    var_len: bool,
+}
+
+impl Segment {
+    /// A piece made of a barcode of `permit_list` only.
+    fn fixed(permit_list: PermitList) -> Self {
+        let width = permit_list.bc_len;
+        Self {
+            permit_list,
+            width,
+            var_len: false,
+        }
+    }
+
+    /// Correct the piece `v` against the permit list (see `PermitList::correct`),
+    /// leaving any extra bases and padding of a variable-length piece as they are.
+    fn correct(&self, v: u64) -> Option<u64> {
+        if !self.var_len {
+            return self.permit_list.correct(v);
+        }
+        // every padding ends with a different base
+        let last = b"ACGT"[(v & 3) as usize];
+        let pad = linker_xform::VAR_LEN_PADDING
+            .iter()
+            .find(|p| p.last() == Some(&last))?
+            .len();
+        if pad + self.permit_list.bc_len > self.width {
+            return None;
+        }
+        let shift = 2 * pad;
+        let mask = u64::MAX >> (64 - 2 * self.permit_list.bc_len);
+        let c = self.permit_list.correct((v >> shift) & mask)?;
+        Some((v & !(mask << shift)) | (c << shift))
+    }
+}
+
+/// Correct the barcode `bc`, the concatenation of the pieces `segments` (in
+/// order), by correcting every piece against its own permit list (see
+/// `Segment::correct`). Returns `None` if any piece can't be corrected
+/// unambiguously.
+fn correct_segments(segments: &[Segment], bc: u64) -> Option<u64> {
+    let mut corrected = 0_u64;
+    let mut shift = 0;
+    for seg in segments.iter().rev() {
+        let bits = 2 * seg.width;
+        let c = seg.correct((bc >> shift) & (u64::MAX >> (64 - bits)))?;
+        corrected |= c << shift;
+        shift += bits;
+    }
+    Some(corrected)
+}
+
+/// The number of reads, out of those recorded in the RAD file, whose barcode
+/// was found in the permit list, corrected, or could not be corrected.
+#[derive(Debug, Default)]
+pub(crate) struct CorrectionStats {
+    pub num_exact: u64,
+    pub num_corrected: u64,
+    pub num_uncorrectable: u64,
+}
+
+/// Select the cells among the barcode counts `counts`, given that about
+/// `expected_cells` cells were captured: as in Cell Ranger (v2), every barcode
+/// with at least a tenth of the count of the barcode at the 99th percentile of
+/// the `expected_cells` most frequent barcodes is retained.
+fn select_cells(counts: &HashMap<u64, u64>, expected_cells: usize) -> Vec<(u64, u64)> {
+    let mut sorted = counts.iter().map(|(b, c)| (*b, *c)).collect::<Vec<_>>();
+    sorted.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let robust_idx = ((expected_cells as f64) * 0.01).round() as usize;
+    let cutoff = match sorted.get(robust_idx.min(sorted.len().saturating_sub(1))) {
+        Some((_, c)) => c / 10,
+        None => return Vec::new(),
+    };
+    sorted.retain(|(_, c)| *c >= cutoff.max(1));
+    sorted
+}
+
+/// The name of the read-level tag in which the barcode of a read, as it was
+/// mapped, is recorded when `--keep-raw-barcodes` is given.
+pub(crate) const RAW_BARCODE_TAG: &str = "rb";
+
+/// The position of the barcode among the read-level tags of `rad`, along with
+/// the length of the barcodes, if it is recorded.
+pub(crate) fn barcode_tag<R: std::io::Read>(
+    rad: &RadReader<R>,
+    rad_path: &Path,
+) -> Result<(usize, Option<usize>)> {
+    let bc_tag = match rad.header.read_tags.iter().position(|t| t.name == "b") {
+        Some(i) => i,
+        None => bail!(
+            "RAD file {} does not record cell barcodes.",
+            rad_path.display()
+        ),
+    };
+    let bc_len = rad
+        .header
+        .file_tags
+        .iter()
+        .position(|t| t.name == "cblen")
+        .and_then(|i| rad.header.file_tag_values.get(i)?.as_u64());
+    Ok((bc_tag, bc_len.map(|l| l as usize)))
+}
+
+/// Rewrite the RAD file at `rad_path` in place, correcting the barcode of every
+/// read against the permit lists of its pieces `segments` (see `correct_segments`). Reads
+/// whose barcode can't be corrected are removed if `drop_uncorrectable`, and kept
+/// as they are otherwise. If `keep_raw`, the barcode of every read as it was
+/// mapped is also recorded in the read-level tag `rb`. Returns the number of
+/// reads of every barcode after correction, along with the correction statistics.
+fn rewrite_barcodes(
+    rad_path: &Path,
+    segments: &[Segment],
+    drop_uncorrectable: bool,
+    keep_raw: bool,
+) -> Result<(HashMap<u64, u64>, CorrectionStats)> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    let (bc_tag, _) = barcode_tag(&rad, rad_path)?;
+    let mut header = rad.header.clone();
+    if keep_raw {
+        if header.read_tags.iter().any(|t| t.name == RAW_BARCODE_TAG) {
+            bail!(
+                "RAD file {} already records raw barcodes.",
+                rad_path.display()
+            );
+        }
+        header.read_tags.push(TagDesc {
+            name: RAW_BARCODE_TAG.to_string(),
+            typ: header.read_tags[bc_tag].typ.clone(),
+        });
+    }
+
+    let tmp_path = PathBuf::from(rad_path.to_string_lossy().into_owned() + ".tmp");
+    let mut writer = RadWriter::create(&tmp_path, &header)?;
+    let mut stats = CorrectionStats::default();
+    let mut counts = HashMap::<u64, u64>::new();
+    while let Some(mut rec) = rad.next_record()? {
+        let bc = match rec.read_tags.get(bc_tag).and_then(|v| v.as_u64()) {
+            Some(bc) => bc,
+            None => bail!("RAD record is missing its barcode"),
+        };
+        let corrected = match correct_segments(segments, bc) {
+            Some(c) => {
+                if c == bc {
+                    stats.num_exact += 1;
+                } else {
+                    stats.num_corrected += 1;
+                }
+                c
+            }
+            None if drop_uncorrectable => {
+                stats.num_uncorrectable += 1;
+                continue;
+            }
+            None => {
+                stats.num_uncorrectable += 1;
+                bc
+            }
+        };
+        if keep_raw {
+            rec.read_tags.push(rec.read_tags[bc_tag].clone());
+        }
+        if corrected != bc {
+            rec.read_tags[bc_tag] = match rec.read_tags[bc_tag] {
+                TagValue::U32(_) => TagValue::U32(corrected as u32),
+                _ => TagValue::U64(corrected),
+            };
+        }
+        *counts.entry(corrected).or_insert(0) += 1;
+        writer.write_record(&rec)?;
+    }
+    writer.finish()?;
+    std::fs::rename(&tmp_path, rad_path)?;
+    Ok((counts, stats))
+}
+
+/// Rewrite the RAD file at `rad_path` in place, correcting the barcode of every
+/// read against the unfiltered permit lists `permit_list_paths`: a single list of
+/// whole barcodes, or one list per barcode piece, against which every piece is
+/// corrected on its own (see `correct_segments`). In the latter case, the length
+/// bounds of the pieces of the geometry (see `geometry::barcode_segment_bounds`),
+/// if known, are given by `segment_bounds`, so that variable-length pieces can be
+/// located in the barcode. Reads whose barcode can't be corrected are removed. If
+/// `keep_raw`, the barcode of every read as it was mapped is also recorded in the
+/// read-level tag `rb`. If `expected_cells` is given, the barcodes of the cells
+/// selected by their number of reads are also written to `permit_list.txt` in
+/// `out_dir`. Returns a summary of the correction.
+pub(crate) fn correct_barcodes(
+    rad_path: &Path,
+    permit_list_paths: &[PathBuf],
+    segment_bounds: Option<&[(u32, Option<u32>)]>,
+    expected_cells: Option<usize>,
+    keep_raw: bool,
+    out_dir: &Path,
+) -> Result<Value> {
+    let mut segments = permit_list_paths
+        .iter()
+        .map(|p| read_permit_list(p).map(Segment::fixed))
+        .collect::<Result<Vec<Segment>>>()?;
+    if let Some(bounds) = segment_bounds.filter(|b| segments.len() > 1 && b.len() == segments.len())
+    {
+        for (seg, bounds) in segments.iter_mut().zip(bounds) {
+            if let (min, Some(max)) = *bounds {
+                if min != max {
+                    // a variable-length piece takes up one more than its maximum length
+                    seg.width = max as usize + 1;
+                    seg.var_len = true;
+                }
+            }
+        }
+    }
+    let paths = permit_list_paths
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    let bc_len = segments.iter().map(|s| s.width).sum::<usize>();
+    if bc_len > 32 {
+        bail!(
+            "the barcodes in the permit lists {} have a total length of {}, but barcodes can be at most 32 bases long.",
+            paths,
+            bc_len
+        );
+    }
+    let rad = RadReader::from_path(rad_path)?;
+    if let (_, Some(l)) = barcode_tag(&rad, rad_path)? {
+        if l != bc_len {
+            bail!(
+                "the barcodes in the permit list {} have length {}, but the barcode length of the geometry is {}.",
+                paths,
+                bc_len,
+                l
+            );
+        }
+    }
+    drop(rad);
+
+    let (counts, stats) = rewrite_barcodes(rad_path, &segments, true, keep_raw)?;
+
+    info!(
+        "barcode correction: {} reads had a permitted barcode, {} were corrected and {} could not be corrected (and were removed).",
+        stats.num_exact, stats.num_corrected, stats.num_uncorrectable
+    );
+
+    let mut summary = json!({
+        "permit_list": paths,
+        "num_permitted_barcodes": segments
+            .iter()
+            .map(|s| s.permit_list.barcodes.len() as u64)
+            .fold(1_u64, u64::saturating_mul),
+        "num_observed_barcodes": counts.len(),
+        "num_exact_reads": stats.num_exact,
+        "num_corrected_reads": stats.num_corrected,
+        "num_uncorrectable_reads": stats.num_uncorrectable,
+        "raw_barcode_tag": keep_raw.then_some(RAW_BARCODE_TAG),
+    });
+    if let Some(expected_cells) = expected_cells {
+        let cells = select_cells(&counts, expected_cells);
+        if cells.is_empty() {
+            warn!("no cells could be selected, since no reads had a permitted barcode.");
+        }
+        let pl_path = out_dir.join(PERMIT_LIST_FILE);
+        let mut out = BufWriter::new(File::create(&pl_path)?);
+        for (bc, count) in &cells {
+            writeln!(out, "{}\t{}", decode_barcode(*bc, bc_len), count)?;
+        }
+        out.flush()?;
+        info!(
+            "selected {} cells (with {} expected); wrote their barcodes to {}.",
+            cells.len(),
+            expected_cells,
+            pl_path.display()
+        );
+        summary["expected_cells"] = json!(expected_cells);
+        summary["num_selected_cells"] = json!(cells.len());
+    }
+    Ok(summary)
+}
+
+/// Rewrite the RAD file at `rad_path` in place, rescuing the barcodes of reads
+/// when no permit list is available: every barcode of at least `min_reads` reads
+/// is taken to be that of a real cell, and a less frequent barcode a single
+/// substitution away from exactly one of them is corrected to it. All other
+/// barcodes are kept as they are. If `keep_raw`, the barcode of every read as it
+/// was mapped is also recorded in the read-level tag `rb`. Returns a summary of
+/// the rescue.
+pub(crate) fn rescue_barcodes(rad_path: &Path, min_reads: u64, keep_raw: bool) -> Result<Value> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    let (bc_tag, bc_len) = barcode_tag(&rad, rad_path)?;
+    let bc_len = match bc_len {
+        Some(l) => l,
+        None => bail!(
+            "RAD file {} does not record the length of its barcodes.",
+            rad_path.display()
+        ),
+    };
+    let mut counts = HashMap::<u64, u64>::new();
+    while let Some(rec) = rad.next_record()? {
+        match rec.read_tags.get(bc_tag).and_then(|v| v.as_u64()) {
+            Some(bc) => *counts.entry(bc).or_insert(0) += 1,
+            None => bail!("RAD record is missing its barcode"),
+        }
+    }
+    drop(rad);
+
+    let frequent = Segment::fixed(PermitList {
+        barcodes: counts
+            .iter()
+            .filter(|(_, c)| **c >= min_reads)
+            .map(|(b, _)| *b)
+            .collect(),
+        bc_len,
+    });
+    if frequent.permit_list.barcodes.is_empty() {
+        warn!(
+            "no barcode has at least {} reads, so no barcodes can be rescued.",
+            min_reads
+        );
+    }
+    let (rescued_counts, stats) =
+        rewrite_barcodes(rad_path, std::slice::from_ref(&frequent), false, keep_raw)?;
+
+    info!(
+        "barcode rescue: {} reads had one of the {} barcodes with at least {} reads, {} were rescued and {} were left as they were.",
+        stats.num_exact,
+        frequent.permit_list.barcodes.len(),
+        min_reads,
+        stats.num_corrected,
+        stats.num_uncorrectable
+    );
+    Ok(json!({
+        "min_reads": min_reads,
+        "num_frequent_barcodes": frequent.permit_list.barcodes.len(),
+        "num_observed_barcodes": counts.len(),
+        "num_barcodes_after_rescue": rescued_counts.len(),
+        "num_frequent_reads": stats.num_exact,
+        "num_rescued_reads": stats.num_corrected,
+        "num_unrescued_reads": stats.num_uncorrectable,
+        "raw_barcode_tag": keep_raw.then_some(RAW_BARCODE_TAG),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rad::{RadHeader, RadRecord, TagType};
+
+    fn permit_list(barcodes: &[&str]) -> PermitList {
+        PermitList {
+            barcodes: barcodes
+                .iter()
+                .map(|b| encode_barcode(b.as_bytes()).unwrap())
+                .collect(),
+            bc_len: barcodes[0].len(),
+        }
+    }
+
+    fn enc(bc: &str) -> u64 {
+        encode_barcode(bc.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn keeps_a_permitted_barcode() {
+        let pl = permit_list(&["ACGTACGT", "TTTTAAAA"]);
+        assert_eq!(pl.correct(enc("ACGTACGT")), Some(enc("ACGTACGT")));
+    }
+
+    #[test]
+    fn corrects_a_single_substitution() {
+        let pl = permit_list(&["ACGTACGT", "TTTTAAAA"]);
+        assert_eq!(pl.correct(enc("ACGTACGA")), Some(enc("ACGTACGT")));
+        assert_eq!(pl.correct(enc("TCGTACGT")), Some(enc("ACGTACGT")));
+        // two substitutions away from every permitted barcode
+        assert_eq!(pl.correct(enc("ACGTACAA")), None);
+    }
+
+    #[test]
+    fn does_not_correct_an_ambiguous_barcode() {
+        // ACGTACGG is a single substitution away from both
+        let pl = permit_list(&["ACGTACGT", "ACGTACGA"]);
+        assert_eq!(pl.correct(enc("ACGTACGG")), None);
+    }
+
+    #[test]
+    fn corrects_every_piece_against_its_own_list() {
+        // three rounds of split-pool barcodes
+        let rounds = [
+            Segment::fixed(permit_list(&["AAAA", "CCCC"])),
+            Segment::fixed(permit_list(&["GGGG", "TTTT"])),
+            Segment::fixed(permit_list(&["ACAC", "AGAG"])),
+        ];
+        assert_eq!(
+            correct_segments(&rounds, enc("CCCCTTTTAGAG")),
+            Some(enc("CCCCTTTTAGAG"))
+        );
+        // a substitution in each round is corrected within that round
+        assert_eq!(
+            correct_segments(&rounds, enc("CCCATTGTTGAG")),
+            Some(enc("CCCCTTTTAGAG"))
+        );
+        // AAAA is permitted in round 1, but not in round 2
+        assert_eq!(correct_segments(&rounds, enc("GGGGAAAAACAC")), None);
+        // ACAG is a single substitution away from both round 3 barcodes
+        assert_eq!(correct_segments(&rounds, enc("AAAAGGGGACAG")), None);
+    }
+
+    #[test]
+    fn corrects_the_barcode_of_a_variable_length_piece() {
+        // a 4-6 base piece, padded to 7 bases, followed by a fixed piece
+        let pieces = [
+            Segment {
+                permit_list: permit_list(&["ACGT", "TTTT"]),
+                width: 7,
+                var_len: true,
+            },
+            Segment::fixed(permit_list(&["GGGG"])),
+        ];
+        // 1 extra base before the barcode, padded with AC
+        assert_eq!(
+            correct_segments(&pieces, enc("GACGAACGGGG")),
+            Some(enc("GACGTACGGGG"))
+        );
+        // no extra bases, padded with AAG
+        assert_eq!(
+            correct_segments(&pieces, enc("TTTTAAGGGGG")),
+            Some(enc("TTTTAAGGGGG"))
+        );
+        // 2 extra bases, padded with A
+        assert_eq!(
+            correct_segments(&pieces, enc("CCTTATAGGGC")),
+            Some(enc("CCTTTTAGGGG"))
+        );
+    }
+
+    #[test]
+    fn selects_cells_with_the_cell_ranger_v2_cutoff() {
+        let counts = [1000, 900, 500, 95, 90, 89, 10]
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (i as u64, *c as u64))
+            .collect::<HashMap<u64, u64>>();
+        // with 100 expected cells, the 99th percentile is the second barcode,
+        // and a tenth of its count (90) is the cutoff
+        let cells = select_cells(&counts, 100);
+        assert_eq!(cells, vec![(0, 1000), (1, 900), (2, 500), (3, 95), (4, 90)]);
+        assert!(select_cells(&HashMap::new(), 100).is_empty());
+    }
+
+    #[test]
+    fn rescues_barcodes_near_frequent_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let rad_path = dir.path().join("map.rad");
+        let header = RadHeader {
+            is_paired: false,
+            ref_names: vec!["tx1".to_string()],
+            num_chunks: 0,
+            file_tags: vec![TagDesc {
+                name: "cblen".to_string(),
+                typ: TagType::U16,
+            }],
+            read_tags: vec![TagDesc {
+                name: "b".to_string(),
+                typ: TagType::U32,
+            }],
+            aln_tags: vec![],
+            file_tag_values: vec![TagValue::U16(8)],
+        };
+        let mut writer = RadWriter::create(&rad_path, &header).unwrap();
+        let barcodes = ["ACGTACGT"; 5].into_iter().chain(["ACGTACGA", "TTTTAAAA"]);
+        for bc in barcodes {
+            writer
+                .write_record(&RadRecord {
+                    read_tags: vec![TagValue::U32(enc(bc) as u32)],
+                    alns: vec![],
+                })
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let summary = rescue_barcodes(&rad_path, 3, true).unwrap();
+        assert_eq!(summary["num_frequent_barcodes"], 1);
+        assert_eq!(summary["num_rescued_reads"], 1);
+        assert_eq!(summary["num_unrescued_reads"], 1);
+
+        let mut rad = RadReader::from_path(&rad_path).unwrap();
+        let mut rewritten = Vec::new();
+        while let Some(rec) = rad.next_record().unwrap() {
+            let bc = rec.read_tags[0].as_u64().unwrap();
+            let raw = rec.read_tags[1].as_u64().unwrap();
+            rewritten.push((decode_barcode(bc, 8), decode_barcode(raw, 8)));
+        }
+        assert_eq!(
+            rewritten[5],
+            ("ACGTACGT".to_string(), "ACGTACGA".to_string())
+        );
+        assert_eq!(
+            rewritten[6],
+            ("TTTTAAAA".to_string(), "TTTTAAAA".to_string())
+        );
+    }
+}
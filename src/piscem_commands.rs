@@ -4,6 +4,8 @@ use std::ffi::CString;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use crate::exit_code::{bail_with, ExitKind};
+
 trait DefaultMappingParams {
     const MAX_EC_CARD: u32;
     const MAX_HIT_OCC: u32;
@@ -40,6 +42,164 @@ pub trait AsArgv {
     fn as_argv(&self) -> Result<Vec<CString>>;
 }
 
+/// Parse the options of the command `command` from the arguments `args` (which
+/// don't include the program and command names) exactly as the piscem executable
+/// parses its command line, so that the bindings (and commands that run other
+/// commands) get the same defaults and checks.
+pub(crate) fn parse_opts<T: Args + clap::FromArgMatches>(
+    command: &'static str,
+    args: Vec<String>,
+) -> Result<T> {
+    let cmd = T::augment_args(clap::Command::new(command).no_binary_name(true));
+    match cmd
+        .try_get_matches_from(args)
+        .and_then(|m| T::from_arg_matches(&m))
+    {
+        Ok(opts) => Ok(opts),
+        Err(e) => bail_with!(ExitKind::InvalidArguments, "{}", e.render()),
+    }
+}
+
+/// The options of the command `command` with their defaults, parsed from the
+/// arguments `required`, which give placeholders for the required options (to
+/// be replaced by the caller).
+fn defaults<T: Args + clap::FromArgMatches>(command: &'static str, required: &[&str]) -> T {
+    parse_opts(command, required.iter().map(|a| a.to_string()).collect())
+        .unwrap_or_else(|e| panic!("the defaults of {} are invalid: {:#}", command, e))
+}
+
+fn to_strings<S: AsRef<str>>(items: &[S]) -> Vec<String> {
+    items.iter().map(|s| s.as_ref().to_string()).collect()
+}
+
+impl BuildOpts {
+    /// The options of a build of an index with the prefix `output` over the
+    /// reference FASTA files `ref_seqs` with `threads` threads, with all other
+    /// options at the defaults of `piscem build` (the k-mer and minimizer lengths
+    /// among them).
+    pub fn new<S: AsRef<str>>(ref_seqs: &[S], output: impl Into<PathBuf>, threads: usize) -> Self {
+        let mut opts: Self = defaults(
+            "build",
+            &[
+                "--ref-seqs",
+                "ref.fa",
+                "--output",
+                "index",
+                "--threads",
+                "1",
+            ],
+        );
+        opts.ref_seqs = Some(to_strings(ref_seqs));
+        opts.output = output.into();
+        opts.threads = threads;
+        opts
+    }
+}
+
+impl MapSCOpts {
+    /// The options of the mapping of the single-cell reads `read1` and `read2`,
+    /// of the geometry `geometry`, against the index with the prefix `index` into
+    /// the output directory `output`, with all other options at the defaults of
+    /// `piscem map-sc`.
+    pub fn new<S: AsRef<str>>(
+        index: &str,
+        geometry: &str,
+        read1: &[S],
+        read2: &[S],
+        output: impl Into<PathBuf>,
+    ) -> Self {
+        let mut opts: Self = defaults(
+            "map-sc",
+            &[
+                "--index",
+                "index",
+                "--geometry",
+                "chromium_v3",
+                "-1",
+                "r1.fq",
+                "-2",
+                "r2.fq",
+                "--output",
+                "out",
+            ],
+        );
+        opts.index = index.to_string();
+        opts.geometry = geometry.to_string();
+        opts.read1 = to_strings(read1);
+        opts.read2 = to_strings(read2);
+        opts.output = output.into();
+        opts
+    }
+}
+
+impl MapSCAtacOpts {
+    /// The options of the mapping of the single-cell ATAC-seq read pairs `read1`
+    /// and `read2`, with their barcodes in the files `barcode`, against the index
+    /// with the prefix `index` into the output directory `output`, with all other
+    /// options at the defaults of `piscem map-sc-atac`.
+    pub fn new<S: AsRef<str>>(
+        index: &str,
+        read1: &[S],
+        read2: &[S],
+        barcode: &[S],
+        output: impl Into<PathBuf>,
+    ) -> Self {
+        let mut opts: Self = defaults(
+            "map-sc-atac",
+            &[
+                "--index",
+                "index",
+                "-1",
+                "r1.fq",
+                "-2",
+                "r2.fq",
+                "--barcode",
+                "bc.fq",
+                "--output",
+                "out",
+            ],
+        );
+        opts.index = index.to_string();
+        opts.read1 = Some(to_strings(read1));
+        opts.read2 = Some(to_strings(read2));
+        opts.barcode = Some(to_strings(barcode));
+        opts.output = output.into();
+        opts
+    }
+}
+
+impl MapBulkOpts {
+    /// The options of the mapping of the paired-end reads `read1` and `read2`
+    /// against the index with the prefix `index`, with the output stem `output`
+    /// and all other options at the defaults of `piscem map-bulk`.
+    pub fn paired<S: AsRef<str>>(
+        index: &str,
+        read1: &[S],
+        read2: &[S],
+        output: impl Into<PathBuf>,
+    ) -> Self {
+        let mut opts = Self::unpaired::<&str>(index, &[], output);
+        opts.reads = None;
+        opts.read1 = Some(to_strings(read1));
+        opts.read2 = Some(to_strings(read2));
+        opts
+    }
+
+    /// The options of the mapping of the unpaired reads `reads` against the index
+    /// with the prefix `index`, with the output stem `output` and all other
+    /// options at the defaults of `piscem map-bulk`.
+    pub fn unpaired<S: AsRef<str>>(index: &str, reads: &[S], output: impl Into<PathBuf>) -> Self {
+        let mut opts: Self = defaults(
+            "map-bulk",
+            &["--index", "index", "-r", "reads.fq", "--output", "out"],
+        );
+        opts.index = index.to_string();
+        opts.reads = Some(to_strings(reads));
+        opts.output = output.into();
+        opts
+    }
+}
+
 fn klen_is_good(s: &str) -> Result<usize> {
     let k: usize = s
         .parse()
@@ -53,14 +213,51 @@ fn klen_is_good(s: &str) -> Result<usize> {
     }
 }
 
+fn bucket_load_is_good(s: &str) -> Result<f64> {
+    let c: f64 = s
+        .parse()
+        .map_err(|_| anyhow!("`{s}` can't be parsed as a number"))?;
+    if !(c.is_finite() && c >= 1.0) {
+        bail!("bucket load factor = {c} must be >= 1.0");
+    }
+    Ok(c)
+}
+
+fn adapter_is_good(s: &str) -> Result<String> {
+    if s.is_empty() || !s.bytes().all(|c| b"ACGTacgt".contains(&c)) {
+        bail!("adapter `{s}` must be a non-empty sequence of A, C, G and T");
+    }
+    Ok(s.to_ascii_uppercase())
+}
+
+fn proportion_is_good(s: &str) -> Result<f64> {
+    let f: f64 = s
+        .parse()
+        .map_err(|_| anyhow!("`{s}` can't be parsed as a number"))?;
+    if !(0.0..=1.0).contains(&f) {
+        bail!("proportion = {f} must be in [0, 1]");
+    }
+    Ok(f)
+}
+
+fn fraction_is_good(s: &str) -> Result<f64> {
+    let f: f64 = s
+        .parse()
+        .map_err(|_| anyhow!("`{s}` can't be parsed as a number"))?;
+    if !(f > 0.0 && f <= 1.0) {
+        bail!("fraction = {f} must be in (0, 1]");
+    }
+    Ok(f)
+}
+
 #[derive(Args, Clone, Debug)]
 #[command(arg_required_else_help = true)]
 #[command(group(
     ArgGroup::new("ref-input")
     .required(true)
-    .args(&["ref_seqs", "ref_lists", "ref_dirs"]),
+    .args(&["ref_seqs", "ref_lists", "ref_dirs", "probe_set", "genome"]),
 ))]
-pub(crate) struct BuildOpts {
+pub struct BuildOpts {
     /// ',' separated list of reference FASTA files
     #[arg(short = 's', long, help_heading = "Input", value_delimiter = ',')]
     pub ref_seqs: Option<Vec<String>>,
@@ -74,6 +271,65 @@ pub(crate) struct BuildOpts {
     #[arg(short = 'd', long, help_heading = "Input", value_delimiter = ',')]
     pub ref_dirs: Option<Vec<String>>,
 
+    /// 10x Flex probe set CSV file; the probe sequences will be indexed, and a
+    /// probe to gene mapping will be written alongside the index.
+    #[arg(long, help_heading = "Input")]
+    pub probe_set: Option<PathBuf>,
+
+    /// genome FASTA file from which to build an augmented (splici or spliceu)
+    /// transcriptome, using the annotation given with `--gtf`; the reference and its
+    /// t2g files are written alongside the index and then indexed.
+    #[arg(long, help_heading = "Input", requires = "gtf")]
+    pub genome: Option<PathBuf>,
+
+    /// GTF file annotating the transcripts of the `--genome`, or of the
+    /// transcriptome given with `--ref-seqs`, `--ref-lists` or `--ref-dirs`; the
+    /// 3-column and 2-column t2g files of the references are written alongside the
+    /// index.
+    #[arg(long, help_heading = "Input", conflicts_with = "probe_set")]
+    pub gtf: Option<PathBuf>,
+
+    /// the type of augmented transcriptome to build from the `--genome`: spliced
+    /// transcripts with either their intronic regions (splici) or their unspliced
+    /// gene bodies (spliceu).
+    #[arg(long, help_heading = "Input", default_value = "splici", value_parser = clap::builder::PossibleValuesParser::new(["splici", "spliceu"]))]
+    pub ref_type: String,
+
+    /// the read length of the data to be mapped, which determines the length of the
+    /// flanks added to the intronic regions of a splici reference (read length minus
+    /// `--flank-trim-length`).
+    #[arg(long, help_heading = "Input")]
+    pub read_length: Option<u64>,
+
+    /// the number of bases subtracted from `--read-length` to obtain the flank length
+    #[arg(long, help_heading = "Input", default_value_t = 5)]
+    pub flank_trim_length: u64,
+
+    /// how to handle duplicate references: fail if two references have the same
+    /// name (error), keep only the first of the references with the same name or
+    /// sequence (keep-first), or rename the later ones to `<name>_dup<i>` (rename).
+    #[arg(long, help_heading = "Input", default_value = "error", value_parser = clap::builder::PossibleValuesParser::new(["error", "keep-first", "rename"]))]
+    pub dedup_policy: String,
+
+    /// how to handle the characters other than ACGT (IUPAC ambiguity codes,
+    /// including N) in the references: leave them, so that the references are
+    /// split at them (split), replace each by a random base among those it stands
+    /// for (random), or replace each by N (mask).
+    #[arg(long, help_heading = "Input", default_value = "split", value_parser = clap::builder::PossibleValuesParser::new(["split", "random", "mask"]))]
+    pub ambig_policy: String,
+
+    /// drop references (or, with `--max-n-run`, pieces of references) shorter than
+    /// this many bases
+    #[arg(long, help_heading = "Input", default_value_t = 0)]
+    pub min_ref_length: usize,
+
+    /// cut references at every run of more than this many Ns, and index each
+    /// remaining piece as a reference of its own, named `<name>:<start>-<end>`;
+    /// without it, the cDBG builder splits the references at every N, but they are
+    /// indexed whole.
+    #[arg(long, help_heading = "Input")]
+    pub max_n_run: Option<usize>,
+
     /// length of k-mer to use, must be <= 31 and odd
     #[arg(short, long, help_heading = "Index Construction Parameters", default_value_t = 31, value_parser = klen_is_good)]
     pub klen: usize,
@@ -87,7 +343,7 @@ pub(crate) struct BuildOpts {
     )]
     pub mlen: usize,
 
-    /// number of threads to use
+    /// number of threads to use (0 uses all available logical CPUs)
     #[arg(short, long, help_heading = "Index Construction Parameters")]
     pub threads: usize,
 
@@ -100,11 +356,31 @@ pub(crate) struct BuildOpts {
     #[arg(long, help_heading = "Indexing Details")]
     pub keep_intermediate_dbg: bool,
 
-    /// working directory where temporary files should be placed.
-    #[arg(short = 'w', long, help_heading = "Indexing Details", default_value_os_t = PathBuf::from("./workdir.noindex"))]
-    pub work_dir: PathBuf,
+    /// working directory where temporary files should be placed; by default, a
+    /// unique directory is created in the temporary directory of the system
+    /// (`$TMPDIR`, or /tmp) and removed once the build ends, however it ends.
+    #[arg(short = 'w', long, help_heading = "Indexing Details")]
+    pub work_dir: Option<PathBuf>,
+
+    /// soft limit on the memory (in GB) used during index construction; it is passed to
+    /// both cuttlefish (and so to KMC) and the SSHash builder, which resort to more
+    /// disk-based processing in the work directory to stay within it.
+    #[arg(long, help_heading = "Indexing Details", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_memory: Option<u64>,
+
+    /// resume an interrupted build with the same output and work directory, skipping
+    /// the phases (cDBG construction, index construction, poison table construction)
+    /// that it completed. The work directory must be given with `--work-dir`.
+    #[arg(
+        long,
+        help_heading = "Indexing Details",
+        conflicts_with = "overwrite",
+        requires = "work_dir"
+    )]
+    pub resume: bool,
 
-    /// overwite an existing index if the output path is the same.
+    /// overwrite an existing index with the same output prefix, removing all of its
+    /// files (and those written alongside it) before building.
     #[arg(long, help_heading = "Indexing Details")]
     pub overwrite: bool,
 
@@ -118,28 +394,400 @@ pub(crate) struct BuildOpts {
     #[arg(long, value_delimiter = ',')]
     pub decoy_paths: Option<Vec<PathBuf>>,
 
+    /// genome FASTA file from which to derive decoy sequences (in addition to any
+    /// `--decoy-paths`); with `--gtf`, the exons it annotates are masked in them.
+    #[arg(long)]
+    pub decoy_genome: Option<PathBuf>,
+
     /// index construction seed (seed value passed to SSHash index construction; useful if empty
     /// buckets occur).
     #[arg(
         long = "seed",
-        help_heading = "Index Construction Parameters",
+        help_heading = "Advanced Index Construction",
         default_value_t = 1
     )]
     pub seed: u64,
+
+    /// the (log2 of the) size of the minimizer buckets above which SSHash indexes the
+    /// k-mers of a bucket with separate minimal perfect hash functions (SSHash's `l`).
+    #[arg(
+        long,
+        help_heading = "Advanced Index Construction",
+        default_value_t = 6,
+        value_parser = clap::value_parser!(u64).range(1..32)
+    )]
+    pub skew_threshold: u64,
+
+    /// the average load of the buckets of the minimal perfect hash functions built by
+    /// SSHash (SSHash's `c`); larger values build the index faster, at the cost of a
+    /// larger index. Values between 3.0 and 10.0 are reasonable.
+    #[arg(
+        long,
+        help_heading = "Advanced Index Construction",
+        default_value_t = 3.0,
+        value_parser = bucket_load_is_good
+    )]
+    pub bucket_load_factor: f64,
+
+    /// after building the SSHash dictionary, check that every k-mer of the reference
+    /// can be looked up in it (this is slow, and meant for debugging).
+    #[arg(long, help_heading = "Advanced Index Construction")]
+    pub check_dict: bool,
 }
 
 #[derive(Args, Clone, Debug)]
-pub(crate) struct MapSCOpts {
+pub struct InspectOpts {
     /// input index prefix
     #[arg(short, long, help_heading = "Input")]
     pub index: String,
 
-    /// list available geometries supported by the underlying `pesc-sc` mapper
-    // #[arg(long, help_heading = "Advanced")]
-    // pub list_geometries: bool,
+    /// print the statistics as JSON rather than as text
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct DumpUnitigsOpts {
+    /// input index prefix; the index must have been built with
+    /// `--keep-intermediate-dbg`
+    #[arg(short, long, help_heading = "Input")]
+    pub index: String,
+
+    /// output FASTA file; the unitigs are written to stdout if it is not given
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// annotate each unitig with the references it occurs in
+    #[arg(long)]
+    pub colors: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct DumpRefsOpts {
+    /// input index prefix; the index must have been built with
+    /// `--keep-intermediate-dbg`
+    #[arg(short, long, help_heading = "Input")]
+    pub index: String,
+
+    /// output FASTA file; the references are written to stdout if it is not given
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// the number of bases per line of the FASTA output (0 writes each reference
+    /// on a single line)
+    #[arg(long, default_value_t = 80)]
+    pub line_width: usize,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct DumpEctabOpts {
+    /// input index prefix; the index must have been built with
+    /// `--keep-intermediate-dbg`
+    #[arg(short, long, help_heading = "Input")]
+    pub index: String,
+
+    /// output TSV file; the table is written to stdout if it is not given
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// also write the equivalence class of each unitig to this TSV file
+    #[arg(long)]
+    pub unitigs: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+#[command(group(
+        ArgGroup::new("queries")
+        .required(true)
+        .multiple(true)
+        .args(["seqs", "fasta"])
+))]
+pub struct QueryOpts {
+    /// input index prefix; the index must have been built with
+    /// `--keep-intermediate-dbg`
+    #[arg(short, long, help_heading = "Input")]
+    pub index: String,
+
+    /// the k-mers or sequences to look up (every k-mer of a sequence is looked up)
+    pub seqs: Vec<String>,
+
+    /// FASTA (or FASTQ) file of sequences to look up
+    #[arg(short, long, help_heading = "Input")]
+    pub fasta: Option<PathBuf>,
 
-    /// geometry of barcode, umi and read
+    /// output TSV file; the lookups are written to stdout if it is not given
     #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct RadInspectOpts {
+    /// the RAD file written by a mapper, or the output directory of `map-sc` or
+    /// `map-sc-atac` (whose `map.rad` is inspected)
+    pub input: PathBuf,
+
+    /// print the summary as JSON rather than as text
+    #[arg(long)]
+    pub json: bool,
+
+    /// the number of references with the most alignments to list in the text
+    /// output (0 lists every reference with alignments)
+    #[arg(long, default_value_t = 20)]
+    pub top: usize,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct RadToSamOpts {
+    /// the RAD file written by a mapper, or the output directory of `map-sc` or
+    /// `map-sc-atac` (whose `map.rad` is converted)
+    pub input: PathBuf,
+
+    /// prefix of the index the reads were mapped against, whose reference names
+    /// and lengths are used for the header
+    #[arg(short, long, help_heading = "Input")]
+    pub index: String,
+
+    /// output file; the records are written to stdout if it is not given
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// the output format [default: bam if the output file ends in `.bam`, and
+    /// sam otherwise]
+    #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(["sam", "bam"]))]
+    pub output_format: Option<String>,
+
+    /// number of threads to use to compress BAM output.
+    #[arg(long, default_value_t = 4)]
+    pub compression_threads: usize,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct RadMergeOpts {
+    /// the RAD files to merge (or the output directories of `map-sc` or
+    /// `map-sc-atac`, or the output stems of `map-bulk`), mapped against the same
+    /// index
+    #[arg(required = true, num_args = 2..)]
+    pub inputs: Vec<PathBuf>,
+
+    /// the output directory (if the inputs are output directories) or output stem
+    /// of the merged RAD file and mapping summary
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// prefix of the index the inputs were mapped against; if given, the references
+    /// of the inputs are checked against those of the index
+    #[arg(short, long, help_heading = "Input")]
+    pub index: Option<String>,
+
+    /// comma-separated labels of the inputs; if given, the input each record comes
+    /// from is recorded in the read-level tag `rg` of the merged RAD file
+    #[arg(long, value_delimiter = ',')]
+    pub labels: Option<Vec<String>>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct QuantBulkOpts {
+    /// the RAD file written by `map-bulk`, or the output stem given to it
+    pub input: PathBuf,
+
+    /// prefix of the index the reads were mapped against, whose reference lengths
+    /// are used to compute the effective lengths
+    #[arg(short, long, help_heading = "Input")]
+    pub index: String,
+
+    /// the file to which the abundances are written, in the format of salmon's
+    /// `quant.sf`
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// the mean fragment length used to compute the effective lengths [default: the
+    /// mean length of the fragments recorded in the RAD file, or 250 if there are none]
+    #[arg(long)]
+    pub frag_len_mean: Option<f64>,
+
+    /// the maximum number of rounds of the EM.
+    #[arg(long, default_value_t = 10000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_em_rounds: u64,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ClassifyOpts {
+    /// the RAD file written by `map-bulk` (against an index over several genomes),
+    /// or the output stem given to it
+    pub input: PathBuf,
+
+    /// a file mapping every reference of the index to its taxid, with a reference
+    /// name and a taxid (separated by whitespace) per line
+    #[arg(long, help_heading = "Taxonomy")]
+    pub seq2taxid: PathBuf,
+
+    /// the `nodes.dmp` file of an NCBI-style taxonomy, giving the parent and rank of
+    /// every taxon; without it, all taxa are children of the root
+    #[arg(long, help_heading = "Taxonomy")]
+    pub nodes: Option<PathBuf>,
+
+    /// the `names.dmp` file of an NCBI-style taxonomy, giving the names of the taxa
+    /// in the report
+    #[arg(long, help_heading = "Taxonomy")]
+    pub names: Option<PathBuf>,
+
+    /// the output prefix; the classifications are written to
+    /// `<output>.classifications.tsv` and the report to `<output>.report.txt`
+    #[arg(short, long)]
+    pub output: PathBuf,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ValidateIndexOpts {
+    /// input index prefix
+    #[arg(short, long, help_heading = "Input")]
+    pub index: String,
+}
+
+#[derive(Args, Clone, Debug)]
+#[command(group(
+        ArgGroup::new("read_source")
+        .required(true)
+        .args(["read1", "reads"])
+))]
+pub struct BenchOpts {
+    /// input index prefix
+    #[arg(short, long, help_heading = "Input")]
+    pub index: String,
+
+    /// path to a comma-separated list of read 1 files
+    #[arg(
+        short = '1',
+        long,
+        help_heading = "Input",
+        value_delimiter = ',',
+        requires = "read2"
+    )]
+    pub read1: Option<Vec<String>>,
+
+    /// path to a ',' separated list of read 2 files
+    #[arg(
+        short = '2',
+        long,
+        help_heading = "Input",
+        value_delimiter = ',',
+        requires = "read1"
+    )]
+    pub read2: Option<Vec<String>>,
+
+    /// path to a ',' separated list of read unpaired read files
+    #[arg(short = 'r', long, help_heading = "Input", value_delimiter = ',', conflicts_with_all = ["read1", "read2"])]
+    pub reads: Option<Vec<String>>,
+
+    /// the number of reads (or read pairs), from the start of the read files, to map
+    /// in each run
+    #[arg(short, long, default_value_t = 1_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub num_reads: u64,
+
+    /// a ',' separated list of the numbers of threads to map the reads with, one
+    /// run each (0 uses all available logical CPUs)
+    #[arg(short, long, value_delimiter = ',', default_values_t = [1, 2, 4, 8, 16])]
+    pub threads: Vec<usize>,
+
+    /// print the measurements as JSON rather than as a table
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct SelftestOpts {
+    /// directory to build the index and write the mappings into, which are then
+    /// kept [default: a temporary directory, removed afterwards]
+    #[arg(long)]
+    pub work_dir: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct PrefetchIndexOpts {
+    /// input index prefix
+    #[arg(short, long, help_heading = "Input")]
+    pub index: String,
+
+    /// only ask the kernel to read the index files into the page cache in the
+    /// background (with `posix_fadvise`), rather than reading them before returning
+    #[arg(long)]
+    pub advise: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct CleanOpts {
+    /// prefix of the index to remove, along with the files written alongside it
+    /// by `build`
+    pub prefix: String,
+
+    /// working directory given to the build of the index with `--work-dir`, from
+    /// which the markers of an interrupted build are removed.
+    #[arg(short = 'w', long)]
+    pub work_dir: Option<PathBuf>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct FetchIndexOpts {
+    /// the name of the index to fetch, as listed by `--list`
+    #[arg(required_unless_present = "list")]
+    pub name: Option<String>,
+
+    /// URL (or path) of the index registry, a JSON file listing the available indexes
+    #[arg(long, env = "PISCEM_INDEX_REGISTRY")]
+    pub registry: String,
+
+    /// list the indexes available from the registry and exit
+    #[arg(long)]
+    pub list: bool,
+
+    /// directory in which fetched indexes are cached [default: $XDG_CACHE_HOME/piscem/indexes
+    /// or ~/.cache/piscem/indexes]
+    #[arg(long, env = "PISCEM_INDEX_CACHE")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// download and unpack the index again, even if it is already in the cache
+    #[arg(long)]
+    pub force: bool,
+
+    /// the number of times a download is retried if it fails to start
+    #[arg(long, default_value_t = 5)]
+    pub url_retries: u32,
+
+    /// the number of seconds to wait between retries of a download; by default, the
+    /// wait starts at one second and doubles with every retry.
+    #[arg(long)]
+    pub url_retry_delay: Option<u64>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct MapSCOpts {
+    /// input index prefix
+    #[arg(
+        short,
+        long,
+        help_heading = "Input",
+        required_unless_present = "list_geometries",
+        default_value = "",
+        hide_default_value = true
+    )]
+    pub index: String,
+
+    /// list the named geometries (those supported by the underlying `pesc-sc` mapper
+    /// and the presets resolved by piscem) with their barcode and UMI lengths, as a
+    /// table or as JSON, and exit.
+    #[arg(long, help_heading = "Advanced", num_args = 0..=1, default_missing_value = "table", value_parser = clap::builder::PossibleValuesParser::new(["table", "json"]))]
+    pub list_geometries: Option<String>,
+
+    /// geometry of barcode, umi and read; either the name of a known chemistry
+    /// (e.g. chromium_v3, bd_rhapsody) or a custom geometry description. If `auto`,
+    /// the chemistry is detected from the first read pairs of the input.
+    #[arg(
+        short,
+        long,
+        required_unless_present = "list_geometries",
+        default_value = "",
+        hide_default_value = true
+    )]
     pub geometry: String,
 
     /// path to a ',' separated list of read 1 files
@@ -148,7 +796,7 @@ pub(crate) struct MapSCOpts {
         long,
         help_heading = "Input",
         value_delimiter = ',',
-        required = true
+        required_unless_present_any = ["interleaved", "list_geometries"]
     )]
     pub read1: Vec<String>,
 
@@ -158,18 +806,237 @@ pub(crate) struct MapSCOpts {
         long,
         help_heading = "Input",
         value_delimiter = ',',
-        required = true
+        required_unless_present_any = ["interleaved", "list_geometries"]
     )]
     pub read2: Vec<String>,
 
-    /// number of threads to use
+    /// path to a ',' separated list of interleaved read files, in which every
+    /// read 1 record is immediately followed by its mate
+    #[arg(long, help_heading = "Input", value_delimiter = ',', conflicts_with_all = ["read1", "read2"])]
+    pub interleaved: Option<Vec<String>>,
+
+    /// before mapping, check that the first N (100000 if no value is given) records of
+    /// every pair of read 1 and read 2 files have matching names and that neither
+    /// file runs out of records first.
+    #[arg(long, help_heading = "Input", value_name = "N", num_args = 0..=1, default_missing_value = "100000")]
+    pub check_pairing: Option<usize>,
+
+    /// number of threads to use (0 uses all available logical CPUs)
     #[arg(short, long, default_value_t = 16)]
     pub threads: usize,
 
+    /// skip checking the files of the index against the checksums recorded when it
+    /// was built.
+    #[arg(long, help_heading = "Input")]
+    pub no_verify: bool,
+
+    /// the number of threads used to decompress each list of gzipped read files before
+    /// they are passed to the mapper (BGZF files are decompressed in parallel, other
+    /// gzip files with one thread per list); 0 leaves decompression to the mapper.
+    #[arg(long, default_value_t = 0)]
+    pub decompression_threads: usize,
+
+    /// the number of times the download of reads given as a URL is retried if it fails to start
+    #[arg(long, help_heading = "Input", default_value_t = 5)]
+    pub url_retries: u32,
+
+    /// the number of seconds to wait between retries of a download; by default, the
+    /// wait starts at one second and doubles with every retry.
+    #[arg(long, help_heading = "Input")]
+    pub url_retry_delay: Option<u64>,
+
+    /// map only a random fraction (in (0, 1]) of the reads (or read pairs), e.g. to
+    /// quickly estimate the mapping rate or to build a saturation curve.
+    #[arg(long, help_heading = "Read Preprocessing", value_parser = fraction_is_good)]
+    pub subsample_fraction: Option<f64>,
+
+    /// the seed of the random choice of reads by --subsample-fraction; the same seed
+    /// selects the same reads.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub subsample_seed: u64,
+
+    /// the number of bases trimmed from the start of every read 1 (or unpaired read)
+    /// before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_front1: usize,
+
+    /// the number of bases trimmed from the end of every read 1 (or unpaired read)
+    /// before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_tail1: usize,
+
+    /// the number of bases trimmed from the start of every read 2 before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_front2: usize,
+
+    /// the number of bases trimmed from the end of every read 2 before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_tail2: usize,
+
+    /// clip the 3' adapters of a library preparation kit (TruSeq or Nextera) from the
+    /// reads before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", value_parser = clap::builder::PossibleValuesParser::new(["truseq", "nextera"]))]
+    pub adapter_preset: Option<String>,
+
+    /// a ',' separated list of 3' adapter sequences to clip from every read 2 before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", value_delimiter = ',', value_parser = adapter_is_good)]
+    pub adapter2: Vec<String>,
+
+    /// clip polyA tails (of at least --clip-poly-min-len bases) from every read 2 before mapping.
+    #[arg(long, help_heading = "Read Preprocessing")]
+    pub clip_polya: bool,
+
+    /// clip polyG tails (of at least --clip-poly-min-len bases), an artifact of two-color
+    /// sequencing chemistries, from every read 2 before mapping.
+    #[arg(long, help_heading = "Read Preprocessing")]
+    pub clip_polyg: bool,
+
+    /// the minimum length of a homopolymer tail to be clipped by --clip-polya and --clip-polyg.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub clip_poly_min_len: u64,
+
+    /// skip reads 2 (along with their read 1) whose mean base quality, after any
+    /// trimming and clipping, is below this value.
+    #[arg(long, help_heading = "Read Preprocessing")]
+    pub min_mean_qual: Option<f64>,
+
+    /// skip reads 2 (along with their read 1) in which more than this fraction of the
+    /// bases, after any trimming and clipping, have a quality below 20.
+    #[arg(long, help_heading = "Read Preprocessing", value_parser = proportion_is_good)]
+    pub max_low_qual_frac: Option<f64>,
+
+    /// skip reads 2 (along with their read 1) shorter than N bases after any trimming and
+    /// clipping; if no value is given, reads shorter than the k-mer length of the index.
+    #[arg(long, help_heading = "Read Preprocessing", value_name = "N", num_args = 0..=1, default_missing_value = "0")]
+    pub min_read_len: Option<usize>,
+
     /// path to output directory
-    #[arg(short, long)]
+    #[arg(
+        short,
+        long,
+        required_unless_present = "list_geometries",
+        default_value = "",
+        hide_default_value = true
+    )]
     pub output: PathBuf,
 
+    /// the number of mismatches allowed in each fixed (linker or anchor) sequence of a
+    /// complex geometry, e.g. the linkers between the barcode segments of SPLiT-seq reads.
+    #[arg(long, default_value_t = 0)]
+    pub linker_mismatches: u32,
+
+    /// what to do with reads whose barcode or UMI contains Ns: `discard` them, `correct`
+    /// a single N (in each of the barcode and the UMI) to an A and discard reads with
+    /// more, or `keep` them all, with every N replaced with an A. If not given, the
+    /// mapper's own handling applies.
+    #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(["discard", "correct", "keep"]))]
+    pub bc_n_policy: Option<String>,
+
+    /// tab-separated file with a `barcode` column followed by well/plate annotations;
+    /// it is validated and copied into the output directory as `plate_metadata.tsv`.
+    #[arg(long, help_heading = "Input")]
+    pub plate_metadata: Option<PathBuf>,
+
+    /// 3-column (reference, gene, splicing status) t2g file for a splici/spliceu index;
+    /// if provided, the number of spliced, unspliced and ambiguous reads (and equivalence
+    /// classes) is recorded in `map_info.json`.
+    #[arg(long, help_heading = "Input")]
+    pub usa_t2g: Option<PathBuf>,
+
+    /// with `--usa-t2g`, also record the splicing status of every mapped read in the
+    /// read-level tag `us` of the RAD file (0 for spliced, 1 for unspliced and 2 for
+    /// ambiguous reads).
+    #[arg(long, requires = "usa_t2g", help_heading = "Input")]
+    pub usa_tag: bool,
+
+    /// unfiltered permit list (one barcode per line) of the chemistry; if provided,
+    /// the barcode of every mapped read is corrected against it (allowing one
+    /// substitution) and reads whose barcode can't be corrected are removed. For a
+    /// barcode made of several pieces (e.g. the rounds of a split-pool barcode), one
+    /// list per piece may be given instead (separated by commas, in the order of the
+    /// pieces), and every piece is then corrected against its own list.
+    #[arg(long, help_heading = "Input", value_delimiter = ',')]
+    pub unfiltered_pl: Vec<PathBuf>,
+
+    /// the expected number of cells; if provided with `--unfiltered-pl`, the barcodes
+    /// of the cells selected by their number of reads are written to `permit_list.txt`
+    /// in the output directory.
+    #[arg(long, requires = "unfiltered_pl", help_heading = "Input")]
+    pub expected_cells: Option<usize>,
+
+    /// without a permit list, rescue the barcodes of mapped reads that are a single
+    /// substitution away from exactly one barcode with at least `--rescue-min-reads`
+    /// reads, correcting them to that barcode.
+    #[arg(long, conflicts_with = "unfiltered_pl", help_heading = "Input")]
+    pub rescue_barcodes: bool,
+
+    /// the minimum number of reads of a barcode for other barcodes to be rescued to it
+    /// by `--rescue-barcodes`.
+    #[arg(long, requires = "rescue_barcodes", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..), help_heading = "Input")]
+    pub rescue_min_reads: u64,
+
+    /// when barcodes are corrected (with `--unfiltered-pl` or `--rescue-barcodes`),
+    /// also record the barcode of every read as it was mapped in the read-level tag
+    /// `rb` of the RAD file.
+    #[arg(long, help_heading = "Input")]
+    pub keep_raw_barcodes: bool,
+
+    /// tab-separated file mapping barcode prefixes to sample names; the mappings of
+    /// each sample are also written to their own directory (named after the sample)
+    /// in the output directory.
+    #[arg(long, help_heading = "Input")]
+    pub demux_map: Option<PathBuf>,
+
+    /// match the barcodes of `--demux-map` against the end of the barcodes instead of
+    /// their start, e.g. to demultiplex `10x_flex_v1` reads by the probe barcode that
+    /// is appended to the cell barcode.
+    #[arg(long, requires = "demux_map", help_heading = "Input")]
+    pub demux_suffix: bool,
+
+    /// file listing (one per line) the only references to which mappings should be
+    /// reported; mappings to all other references are removed from the output after
+    /// mapping (reads are not mapped again to the remaining references).
+    #[arg(long, help_heading = "Input")]
+    pub restrict_refs: Option<PathBuf>,
+
+    /// file listing (one per line) references to which mappings should not be
+    /// reported; mappings to these references are removed from the output after
+    /// mapping (reads are not mapped again to the remaining references).
+    #[arg(long, help_heading = "Input")]
+    pub exclude_refs: Option<PathBuf>,
+
+    /// the expected orientation of reads 2 with respect to the references; mappings in
+    /// the other orientation are removed from the output (or only counted, with
+    /// `--flag-ori`).
+    #[arg(long, default_value = "both", value_parser = clap::builder::PossibleValuesParser::new(["fw", "rc", "both"]))]
+    pub expected_ori: String,
+
+    /// count the mappings inconsistent with `--expected-ori` in the mapping summary
+    /// instead of removing them.
+    #[arg(long)]
+    pub flag_ori: bool,
+
+    /// write a self-contained HTML report (`qc_report.html` in the output directory)
+    /// summarizing the mapping run.
+    #[arg(long)]
+    pub qc_report: bool,
+
+    /// write the number of reads of every barcode to `barcode_frequencies.tsv` in the
+    /// output directory, and estimate the number of cells from the knee of the
+    /// barcode rank plot.
+    #[arg(long)]
+    pub barcode_freqs: bool,
+
+    /// also write the mappings in SAM or BAM format (to `map.sam` or `map.bam` in the
+    /// output directory), with the barcode and UMI of each read in its CB/CR and UB/UR
+    /// tags.
+    #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(["sam", "bam"]))]
+    pub output_format: Option<String>,
+
+    /// number of threads to use to compress BAM output.
+    #[arg(long, default_value_t = 4)]
+    pub compression_threads: usize,
+
     /// do not consider poison k-mers, even if the underlying index contains them.
     /// In this case, the mapping results will be identical to those obtained as if
     /// no poison table was added to the index.
@@ -221,9 +1088,9 @@ pub(crate) struct MapSCOpts {
 #[command(group(
         ArgGroup::new("read_source")
         .required(true)
-        .args(["read1", "reads"])
+        .args(["read1", "reads", "interleaved"])
 ))]
-pub(crate) struct MapBulkOpts {
+pub struct MapBulkOpts {
     /// input index prefix
     #[arg(short, long, help_heading = "Input")]
     pub index: String,
@@ -252,14 +1119,186 @@ pub(crate) struct MapBulkOpts {
     #[arg(short = 'r', long, help_heading = "Input", value_delimiter = ',', conflicts_with_all = ["read1", "read2"])]
     pub reads: Option<Vec<String>>,
 
-    /// number of threads to use
+    /// path to a ',' separated list of interleaved read files, in which every
+    /// read 1 record is immediately followed by its mate
+    #[arg(long, help_heading = "Input", value_delimiter = ',', conflicts_with_all = ["read1", "read2", "reads"])]
+    pub interleaved: Option<Vec<String>>,
+
+    /// a ',' separated list of read group labels, one per read file (or pair of
+    /// files); the reads of each label are mapped in turn, and the label of every
+    /// read is recorded in the RAD file.
+    #[arg(long, help_heading = "Input", value_delimiter = ',')]
+    pub read_groups: Option<Vec<String>>,
+
+    /// before mapping, check that the first N (100000 if no value is given) records of
+    /// every pair of read 1 and read 2 files have matching names and that neither
+    /// file runs out of records first.
+    #[arg(long, help_heading = "Input", value_name = "N", num_args = 0..=1, default_missing_value = "100000")]
+    pub check_pairing: Option<usize>,
+
+    /// number of threads to use (0 uses all available logical CPUs)
     #[arg(short, long, default_value_t = 16)]
     pub threads: usize,
 
+    /// skip checking the files of the index against the checksums recorded when it
+    /// was built.
+    #[arg(long, help_heading = "Input")]
+    pub no_verify: bool,
+
+    /// the number of threads used to decompress each list of gzipped read files before
+    /// they are passed to the mapper (BGZF files are decompressed in parallel, other
+    /// gzip files with one thread per list); 0 leaves decompression to the mapper.
+    #[arg(long, default_value_t = 0)]
+    pub decompression_threads: usize,
+
+    /// the number of times the download of reads given as a URL is retried if it fails to start
+    #[arg(long, help_heading = "Input", default_value_t = 5)]
+    pub url_retries: u32,
+
+    /// the number of seconds to wait between retries of a download; by default, the
+    /// wait starts at one second and doubles with every retry.
+    #[arg(long, help_heading = "Input")]
+    pub url_retry_delay: Option<u64>,
+
+    /// map only a random fraction (in (0, 1]) of the reads (or read pairs), e.g. to
+    /// quickly estimate the mapping rate or to build a saturation curve.
+    #[arg(long, help_heading = "Read Preprocessing", value_parser = fraction_is_good)]
+    pub subsample_fraction: Option<f64>,
+
+    /// the seed of the random choice of reads by --subsample-fraction; the same seed
+    /// selects the same reads.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub subsample_seed: u64,
+
+    /// the number of bases trimmed from the start of every read 1 (or unpaired read)
+    /// before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_front1: usize,
+
+    /// the number of bases trimmed from the end of every read 1 (or unpaired read)
+    /// before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_tail1: usize,
+
+    /// the number of bases trimmed from the start of every read 2 before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_front2: usize,
+
+    /// the number of bases trimmed from the end of every read 2 before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_tail2: usize,
+
+    /// clip the 3' adapters of a library preparation kit (TruSeq or Nextera) from the
+    /// reads before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", value_parser = clap::builder::PossibleValuesParser::new(["truseq", "nextera"]))]
+    pub adapter_preset: Option<String>,
+
+    /// a ',' separated list of 3' adapter sequences to clip from every read 1 (or
+    /// unpaired read) before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", value_delimiter = ',', value_parser = adapter_is_good)]
+    pub adapter1: Vec<String>,
+
+    /// a ',' separated list of 3' adapter sequences to clip from every read before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", value_delimiter = ',', value_parser = adapter_is_good)]
+    pub adapter2: Vec<String>,
+
+    /// clip polyA tails (of at least --clip-poly-min-len bases) from every read before mapping.
+    #[arg(long, help_heading = "Read Preprocessing")]
+    pub clip_polya: bool,
+
+    /// clip polyG tails (of at least --clip-poly-min-len bases), an artifact of two-color
+    /// sequencing chemistries, from every read before mapping.
+    #[arg(long, help_heading = "Read Preprocessing")]
+    pub clip_polyg: bool,
+
+    /// the minimum length of a homopolymer tail to be clipped by --clip-polya and --clip-polyg.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub clip_poly_min_len: u64,
+
+    /// skip reads (along with their mate, if any) whose mean base quality, after any
+    /// trimming and clipping, is below this value.
+    #[arg(long, help_heading = "Read Preprocessing")]
+    pub min_mean_qual: Option<f64>,
+
+    /// skip reads (along with their mate, if any) in which more than this fraction of
+    /// the bases, after any trimming and clipping, have a quality below 20.
+    #[arg(long, help_heading = "Read Preprocessing", value_parser = proportion_is_good)]
+    pub max_low_qual_frac: Option<f64>,
+
+    /// skip reads (along with their mate, if any) shorter than N bases after any trimming
+    /// and clipping; if no value is given, reads shorter than the k-mer length of the index.
+    #[arg(long, help_heading = "Read Preprocessing", value_name = "N", num_args = 0..=1, default_missing_value = "0")]
+    pub min_read_len: Option<usize>,
+
     /// path to output directory
     #[arg(short, long)]
     pub output: PathBuf,
 
+    /// file listing (one per line) the only references to which mappings should be
+    /// reported; mappings to all other references are removed from the output after
+    /// mapping (reads are not mapped again to the remaining references).
+    #[arg(long, help_heading = "Input")]
+    pub restrict_refs: Option<PathBuf>,
+
+    /// file listing (one per line) references to which mappings should not be
+    /// reported; mappings to these references are removed from the output after
+    /// mapping (reads are not mapped again to the remaining references).
+    #[arg(long, help_heading = "Input")]
+    pub exclude_refs: Option<PathBuf>,
+
+    /// the expected orientation of the reads (read 1 for paired-end reads) with respect to the references; mappings in
+    /// the other orientation are removed from the output (or only counted, with
+    /// `--flag-ori`).
+    #[arg(long, default_value = "both", value_parser = clap::builder::PossibleValuesParser::new(["fw", "rc", "both"]))]
+    pub expected_ori: String,
+
+    /// the library type, in salmon's notation; mappings inconsistent with its
+    /// strandedness are removed from the output (or only counted, with `--flag-ori`).
+    /// With `auto`, the library type is inferred from the mappings.
+    #[arg(long, conflicts_with = "expected_ori", value_parser = clap::builder::PossibleValuesParser::new(["ISR", "ISF", "IU", "SR", "SF", "U", "auto"]))]
+    pub lib_type: Option<String>,
+
+    /// count the mappings inconsistent with `--expected-ori` (or `--lib-type`) in the
+    /// mapping summary instead of removing them.
+    #[arg(long)]
+    pub flag_ori: bool,
+
+    /// write a self-contained HTML report (`<output>.qc_report.html`) summarizing
+    /// the mapping run.
+    #[arg(long)]
+    pub qc_report: bool,
+
+    /// also write the mappings in SAM or BAM format (to `<output>.sam` or
+    /// `<output>.bam`), with a header listing the references of the index.
+    #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(["sam", "bam"]))]
+    pub output_format: Option<String>,
+
+    /// also write the mappings in SAM format; equivalent to `--output-format sam`.
+    #[arg(long, conflicts_with = "output_format")]
+    pub sam: bool,
+
+    /// also write the equivalence classes of the mapped fragments (the sets of
+    /// references they map to, and their counts) to `<output>.eq_classes.txt`, in
+    /// the format of salmon's `eq_classes.txt`.
+    #[arg(long)]
+    pub write_eqclasses: bool,
+
+    /// also quantify the references by an EM over the equivalence classes of the
+    /// mapped fragments, writing their abundances to `<output>.quant.sf` (in the
+    /// format of salmon's `quant.sf`).
+    #[arg(long)]
+    pub quant: bool,
+
+    /// also write the per-base coverage of the references by the mapped fragments
+    /// to `<PREFIX>.bedGraph`, along with the reference lengths in
+    /// `<PREFIX>.chrom.sizes` (for conversion to bigWig with `bedGraphToBigWig`).
+    #[arg(long, value_name = "PREFIX")]
+    pub coverage_out: Option<PathBuf>,
+
+    /// number of threads to use to compress BAM output.
+    #[arg(long, default_value_t = 4)]
+    pub compression_threads: usize,
+
     /// do not consider poison k-mers, even if the underlying index contains them.
     /// In this case, the mapping results will be identical to those obtained as if
     /// no poison table was added to the index.
@@ -286,7 +1325,6 @@ pub(crate) struct MapBulkOpts {
     #[arg(
         long,
         short,
-        requires = "check_ambig_hits",
         default_value_t = DefaultParams::MAX_EC_CARD,
         conflicts_with = "ignore_ambig_hits",
         help_heading = "Advanced options"
@@ -322,7 +1360,7 @@ impl AsArgv for MapSCOpts {
             for s in idx_suffixes {
                 let req_file = idx_path.with_extension(s);
                 if !req_file.exists() {
-                    bail!("To load the index with the specified prefix {}, piscem expects the file {} to exist, but it does not!", &self.index, req_file.display());
+                    bail_with!(ExitKind::MissingIndexComponent, "To load the index with the specified prefix {}, piscem expects the file {} to exist, but it does not!", &self.index, req_file.display());
                 }
             }
         }
@@ -418,7 +1456,7 @@ impl AsArgv for MapBulkOpts {
             for s in idx_suffixes {
                 let req_file = idx_path.with_extension(s);
                 if !req_file.exists() {
-                    bail!("To load the index with the specified prefix {}, piscem expects the file {} to exist, but it does not!", &self.index, req_file.display());
+                    bail_with!(ExitKind::MissingIndexComponent, "To load the index with the specified prefix {}, piscem expects the file {} to exist, but it does not!", &self.index, req_file.display());
                 }
             }
         }
@@ -487,7 +1525,12 @@ impl AsArgv for MapBulkOpts {
 }
 
 #[derive(Args, Clone, Debug)]
-pub(crate) struct MapSCAtacOpts {
+#[command(group(
+        ArgGroup::new("read_source")
+        .required(true)
+        .args(["read1", "reads"])
+))]
+pub struct MapSCAtacOpts {
     /// input index prefix
     #[arg(short, long, help_heading = "Input")]
     pub index: String,
@@ -515,20 +1558,110 @@ pub(crate) struct MapSCAtacOpts {
     #[arg(short = 'r', long, help_heading = "Input", value_delimiter = ',', conflicts_with_all = ["read1", "read2"])]
     pub reads: Option<Vec<String>>,
 
-    /// path to a ',' separated list of read 2 files
+    /// path to a ',' separated list of barcode files, one for each read (pair) file
     #[arg(
         short = 'b',
         long,
         help_heading = "Input",
         value_delimiter = ',',
-        requires = "read1"
+        required = true
     )]
     pub barcode: Option<Vec<String>>,
 
-    /// number of threads to use
+    /// number of threads to use (0 uses all available logical CPUs)
     #[arg(short, long, default_value_t = 16)]
     pub threads: usize,
 
+    /// skip checking the files of the index against the checksums recorded when it
+    /// was built.
+    #[arg(long, help_heading = "Input")]
+    pub no_verify: bool,
+
+    /// the number of threads used to decompress each list of gzipped read files before
+    /// they are passed to the mapper (BGZF files are decompressed in parallel, other
+    /// gzip files with one thread per list); 0 leaves decompression to the mapper.
+    #[arg(long, default_value_t = 0)]
+    pub decompression_threads: usize,
+
+    /// the number of times the download of reads given as a URL is retried if it fails to start
+    #[arg(long, help_heading = "Input", default_value_t = 5)]
+    pub url_retries: u32,
+
+    /// the number of seconds to wait between retries of a download; by default, the
+    /// wait starts at one second and doubles with every retry.
+    #[arg(long, help_heading = "Input")]
+    pub url_retry_delay: Option<u64>,
+
+    /// map only a random fraction (in (0, 1]) of the reads (or read pairs), e.g. to
+    /// quickly estimate the mapping rate or to build a saturation curve.
+    #[arg(long, help_heading = "Read Preprocessing", value_parser = fraction_is_good)]
+    pub subsample_fraction: Option<f64>,
+
+    /// the seed of the random choice of reads by --subsample-fraction; the same seed
+    /// selects the same reads.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub subsample_seed: u64,
+
+    /// the number of bases trimmed from the start of every read 1 (or unpaired read)
+    /// before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_front1: usize,
+
+    /// the number of bases trimmed from the end of every read 1 (or unpaired read)
+    /// before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_tail1: usize,
+
+    /// the number of bases trimmed from the start of every read 2 before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_front2: usize,
+
+    /// the number of bases trimmed from the end of every read 2 before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 0)]
+    pub trim_tail2: usize,
+
+    /// clip the 3' adapters of a library preparation kit (TruSeq or Nextera) from the
+    /// reads before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", value_parser = clap::builder::PossibleValuesParser::new(["truseq", "nextera"]))]
+    pub adapter_preset: Option<String>,
+
+    /// a ',' separated list of 3' adapter sequences to clip from every read 1 (or
+    /// unpaired read) before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", value_delimiter = ',', value_parser = adapter_is_good)]
+    pub adapter1: Vec<String>,
+
+    /// a ',' separated list of 3' adapter sequences to clip from every read before mapping.
+    #[arg(long, help_heading = "Read Preprocessing", value_delimiter = ',', value_parser = adapter_is_good)]
+    pub adapter2: Vec<String>,
+
+    /// clip polyA tails (of at least --clip-poly-min-len bases) from every read before mapping.
+    #[arg(long, help_heading = "Read Preprocessing")]
+    pub clip_polya: bool,
+
+    /// clip polyG tails (of at least --clip-poly-min-len bases), an artifact of two-color
+    /// sequencing chemistries, from every read before mapping.
+    #[arg(long, help_heading = "Read Preprocessing")]
+    pub clip_polyg: bool,
+
+    /// the minimum length of a homopolymer tail to be clipped by --clip-polya and --clip-polyg.
+    #[arg(long, help_heading = "Read Preprocessing", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub clip_poly_min_len: u64,
+
+    /// skip reads (along with their mate, if any) whose mean base quality, after any
+    /// trimming and clipping, is below this value.
+    #[arg(long, help_heading = "Read Preprocessing")]
+    pub min_mean_qual: Option<f64>,
+
+    /// skip reads (along with their mate, if any) in which more than this fraction of
+    /// the bases, after any trimming and clipping, have a quality below 20.
+    #[arg(long, help_heading = "Read Preprocessing", value_parser = proportion_is_good)]
+    pub max_low_qual_frac: Option<f64>,
+
+    /// skip reads (along with their mate, if any) shorter than N bases after any trimming
+    /// and clipping; if no value is given, reads shorter than the k-mer length of the index.
+    #[arg(long, help_heading = "Read Preprocessing", value_name = "N", num_args = 0..=1, default_missing_value = "0")]
+    pub min_read_len: Option<usize>,
+
     /// path to output directory
     #[arg(short, long)]
     pub output: PathBuf,
@@ -561,6 +1694,15 @@ pub(crate) struct MapSCAtacOpts {
     #[arg(long)]
     pub bed_format: bool,
 
+    /// also write the mappings in BAM format (to `map.bam` in the output directory),
+    /// with a header listing the references of the index.
+    #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(["bam"]), conflicts_with_all = ["sam_format", "bed_format"])]
+    pub output_format: Option<String>,
+
+    /// number of threads to use to compress BAM output.
+    #[arg(long, default_value_t = 4)]
+    pub compression_threads: usize,
+
     /// use chromosomes as color
     #[arg(long)]
     pub use_chr: bool,
@@ -581,6 +1723,40 @@ pub(crate) struct MapSCAtacOpts {
     #[arg(long)]
     pub no_tn5_shift: bool,
 
+    /// also write the fragments of the uniquely mapped read pairs, sorted by position,
+    /// to `fragments.tsv.gz` in the output directory (a bgzip compressed, tabix
+    /// indexed, 10x-style fragment file).
+    #[arg(long)]
+    pub write_fragments: bool,
+
+    /// collapse fragments with the same barcode, start and end into a single line
+    /// of the fragment file (implies --write-fragments), and write the duplication
+    /// statistics of every barcode to `fragment_duplication.tsv`.
+    #[arg(long)]
+    pub dedup_fragments: bool,
+
+    /// also write the QC metrics of every barcode (its number of distinct fragments,
+    /// and the fraction of those on a mitochondrial reference or in a blacklisted
+    /// region) to `barcode_stats.tsv` in the output directory.
+    #[arg(long)]
+    pub barcode_stats: bool,
+
+    /// a ',' separated list of the names of the mitochondrial references, for
+    /// --barcode-stats.
+    #[arg(long, value_delimiter = ',', default_value = "chrM,chrMT,MT,M")]
+    pub mito_refs: Vec<String>,
+
+    /// a BED file (possibly gzipped) of blacklisted regions, fragments overlapping
+    /// which are counted by --barcode-stats.
+    #[arg(long, requires = "barcode_stats")]
+    pub blacklist: Option<PathBuf>,
+
+    /// also write a sparse barcode × bin matrix (in Matrix Market format) of the
+    /// number of Tn5 insertions of every barcode in every bin of --bin-size bases,
+    /// to the `bin_matrix` directory of the output directory.
+    #[arg(long)]
+    pub bin_matrix: bool,
+
     /// Check if any mapping kmer exist for a mate which is not mapped,
     /// but there exists mapping for the other read.
     /// If set to true and a mapping kmer exists, then the pair would not be mapped (default false)
@@ -636,7 +1812,7 @@ impl AsArgv for MapSCAtacOpts {
             for s in idx_suffixes {
                 let req_file = idx_path.with_extension(s);
                 if !req_file.exists() {
-                    bail!("To load the index with the specified prefix {}, piscem expects the file {} to exist, but it does not!", &self.index, req_file.display());
+                    bail_with!(ExitKind::MissingIndexComponent, "To load the index with the specified prefix {}, piscem expects the file {} to exist, but it does not!", &self.index, req_file.display());
                 }
             }
         }
@@ -672,7 +1848,10 @@ impl AsArgv for MapSCAtacOpts {
         //     args.push(CString::new("-b").unwrap());
         //     args.push(CString::new(b_string.as_str()).unwrap());
         // }
-        let b_string = self.barcode.as_ref().unwrap().clone().join(",");
+        let b_string = match self.barcode {
+            Some(ref b) => b.join(","),
+            None => bail!("barcode files must be provided to map scATAC-seq reads."),
+        };
         if let Some(ref unpaired_reads) = &self.reads {
             let r_string = unpaired_reads.clone().join(",");
             args.push(CString::new("-r").unwrap());
@@ -758,3 +1937,35 @@ impl AsArgv for MapSCAtacOpts {
         Ok(args)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constructors_use_the_command_line_defaults() {
+        let build = BuildOpts::new(&["a.fa", "b,c.fa"], "idx", 8);
+        assert_eq!(
+            build.ref_seqs,
+            Some(vec!["a.fa".to_string(), "b,c.fa".to_string()])
+        );
+        assert_eq!(build.output, PathBuf::from("idx"));
+        assert_eq!((build.klen, build.mlen, build.threads), (31, 19, 8));
+
+        let sc = MapSCOpts::new("idx", "chromium_v2", &["r1.fq"], &["r2.fq"], "out");
+        assert_eq!(sc.geometry, "chromium_v2");
+        assert_eq!((sc.read1.len(), sc.read2.len()), (1, 1));
+        assert_eq!(sc.max_ec_card, DefaultParams::MAX_EC_CARD);
+
+        let atac = MapSCAtacOpts::new("idx", &["r1.fq"], &["r2.fq"], &["bc.fq"], "out");
+        assert_eq!(atac.barcode, Some(vec!["bc.fq".to_string()]));
+        assert_eq!(atac.threads, 16);
+
+        let paired = MapBulkOpts::paired("idx", &["r1.fq"], &["r2.fq"], "out");
+        assert!(paired.reads.is_none());
+        assert_eq!(paired.read2, Some(vec!["r2.fq".to_string()]));
+        let unpaired = MapBulkOpts::unpaired("idx", &["r.fq"], "out");
+        assert!(unpaired.read1.is_none());
+        assert_eq!(unpaired.reads, Some(vec!["r.fq".to_string()]));
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use tracing::info;
+
+/// The name of the sidecar file, written into the mapping output directory,
+/// that holds the barcode to well/plate annotations.
+pub(crate) const PLATE_METADATA_FILE: &str = "plate_metadata.tsv";
+
+/// Validate the barcode to well/plate metadata in the tab-separated file
+/// `metadata`, and write it into the output directory `out_dir` so that the
+/// plate layout stays attached to the mapping results. The first line is a
+/// header whose first column must be `barcode`; every following line holds a
+/// barcode followed by its annotations (e.g. well and plate). All barcodes
+/// must be distinct and of the same length. Returns the number of barcodes
+/// annotated.
+pub(crate) fn write_plate_metadata(metadata: &Path, out_dir: &Path) -> Result<usize> {
+    let reader =
+        BufReader::new(File::open(metadata).with_context(|| {
+            format!("could not open plate metadata file {}", metadata.display())
+        })?);
+    let mut lines = reader.lines();
+
+    let header = match lines.next() {
+        Some(l) => l?,
+        None => bail!("plate metadata file {} is empty.", metadata.display()),
+    };
+    let ncols = header.split('\t').count();
+    if header.split('\t').next() != Some("barcode") || ncols < 2 {
+        bail!(
+            "the header of plate metadata file {} must start with a `barcode` column followed by at least one annotation column.",
+            metadata.display()
+        );
+    }
+
+    let mut records = vec![header];
+    let mut seen = HashSet::new();
+    let mut bc_len = None;
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != ncols {
+            bail!(
+                "line {} of plate metadata file {} has {} columns, but the header has {}.",
+                i + 2,
+                metadata.display(),
+                fields.len(),
+                ncols
+            );
+        }
+        let bc = fields[0];
+        if !bc.bytes().all(|b| matches!(b, b'A' | b'C' | b'G' | b'T')) {
+            bail!(
+                "barcode {} in plate metadata file {} contains characters other than A, C, G, T.",
+                bc,
+                metadata.display()
+            );
+        }
+        match bc_len {
+            None => bc_len = Some(bc.len()),
+            Some(l) if l != bc.len() => {
+                bail!(
+                    "barcode {} in plate metadata file {} has length {}, but previous barcodes have length {}.",
+                    bc,
+                    metadata.display(),
+                    bc.len(),
+                    l
+                );
+            }
+            _ => {}
+        }
+        if !seen.insert(bc.to_string()) {
+            bail!(
+                "barcode {} appears more than once in plate metadata file {}.",
+                bc,
+                metadata.display()
+            );
+        }
+        records.push(line);
+    }
+
+    std::fs::create_dir_all(out_dir)?;
+    let out_path = out_dir.join(PLATE_METADATA_FILE);
+    let mut out = BufWriter::new(File::create(&out_path)?);
+    for r in &records {
+        writeln!(out, "{}", r)?;
+    }
+    info!(
+        "wrote plate metadata for {} barcodes to {}.",
+        seen.len(),
+        out_path.display()
+    );
+    Ok(seen.len())
+}
@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::Read;
+use std::time::Instant;
+use tracing::{info, warn};
+
+use crate::exit_code::{bail_with, ExitKind};
+use crate::inspect::{self, INDEX_COMPONENTS};
+use crate::piscem_commands::PrefetchIndexOpts;
+
+/// The size of the blocks in which index files are read.
+const BLOCK_SIZE: usize = 4 << 20;
+
+/// Ask the kernel to read the whole file `f` into the page cache in the
+/// background. Returns whether the hint could be given on this platform.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn advise_willneed(f: &File) -> Result<bool> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: the file descriptor is valid for the lifetime of `f`
+    let ret = unsafe { libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_WILLNEED) };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret).into());
+    }
+    Ok(true)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn advise_willneed(_f: &File) -> Result<bool> {
+    Ok(false)
+}
+
+/// Read the file `f` sequentially to its end, discarding its contents, so that
+/// it ends up in the page cache. Returns the number of bytes read.
+fn read_through(f: &mut File) -> Result<u64> {
+    let mut buf = vec![0_u8; BLOCK_SIZE];
+    let mut total = 0_u64;
+    loop {
+        match f.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => total += n as u64,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Bring the components of the index given in `opts` into the page cache, so that
+/// the mapper does not stall on page faults when loading it from a network file
+/// system. Every component present on disk is read sequentially; with `--advise`,
+/// the kernel is only asked to read the files in the background, and the command
+/// returns right away. Returns a summary of the prefetched files.
+pub(crate) fn prefetch_index(opts: &PrefetchIndexOpts) -> Result<Value> {
+    let index = &opts.index;
+    if let Some(c) = INDEX_COMPONENTS
+        .iter()
+        .find(|c| c.required && !inspect::component_path(index, c.suffix).exists())
+    {
+        bail_with!(
+            ExitKind::MissingIndexComponent,
+            "no index with the prefix {} was found; the file {} does not exist.",
+            index,
+            inspect::component_path(index, c.suffix).display()
+        );
+    }
+
+    let start = Instant::now();
+    let mut advise = opts.advise;
+    let mut files = Vec::new();
+    let mut total_bytes = 0_u64;
+    for c in INDEX_COMPONENTS {
+        let path = inspect::component_path(index, c.suffix);
+        if !path.exists() {
+            continue;
+        }
+        let mut f =
+            File::open(&path).with_context(|| format!("could not open {}", path.display()))?;
+        let size = f.metadata()?.len();
+        if advise && !advise_willneed(&f)? {
+            warn!("read-ahead hints are not supported on this platform; reading the index files instead.");
+            advise = false;
+        }
+        if !advise {
+            let n = read_through(&mut f)
+                .with_context(|| format!("could not read {}", path.display()))?;
+            if n != size {
+                warn!(
+                    "read {} bytes of {}, whose size is {} bytes; it may have changed while being read.",
+                    n,
+                    path.display(),
+                    size
+                );
+            }
+        }
+        info!(
+            "prefetched the {} ({}, {} bytes).",
+            c.description,
+            path.display(),
+            size
+        );
+        total_bytes += size;
+        files.push(json!({ "path": path, "size": size }));
+    }
+
+    let secs = start.elapsed().as_secs_f64();
+    if advise {
+        info!(
+            "asked the kernel to read {} index files ({} bytes) in the background.",
+            files.len(),
+            total_bytes
+        );
+    } else {
+        info!(
+            "read {} index files ({} bytes) in {:.1}s ({:.1} MiB/s).",
+            files.len(),
+            total_bytes,
+            secs,
+            total_bytes as f64 / (1 << 20) as f64 / secs.max(1e-9)
+        );
+    }
+    Ok(json!({
+        "index": index,
+        "files": files,
+        "num_bytes": total_bytes,
+        "advise_only": advise,
+        "elapsed_secs": secs,
+    }))
+}
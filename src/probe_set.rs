@@ -0,0 +1,96 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// The path of the FASTA file of probe sequences written by `write_probe_set_refs`
+/// for the stem `out_stem`.
+pub(crate) fn probe_set_fasta_path(out_stem: &Path) -> PathBuf {
+    PathBuf::from(out_stem.to_string_lossy().into_owned() + ".probes.fa")
+}
+
+/// Convert the 10x Flex probe set CSV file `probe_set` into a FASTA file
+/// containing the probe sequences, and a 2-column file mapping each probe
+/// to its gene. The files are written next to the index with the stem
+/// `out_stem`. Lines starting with `#` hold metadata and are skipped, as are
+/// probes whose `included` column is `FALSE`. Returns the path of the FASTA file.
+pub(crate) fn write_probe_set_refs(probe_set: &Path, out_stem: &Path) -> Result<PathBuf> {
+    let reader = BufReader::new(
+        File::open(probe_set)
+            .with_context(|| format!("could not open probe set {}", probe_set.display()))?,
+    );
+
+    let fasta = probe_set_fasta_path(out_stem);
+    let t2g = PathBuf::from(out_stem.to_string_lossy().into_owned() + ".probes_t2g.tsv");
+    if let Some(parent_path) = fasta.parent() {
+        if !parent_path.as_os_str().is_empty() && !parent_path.exists() {
+            std::fs::create_dir_all(parent_path)?;
+        }
+    }
+    let mut fasta_out = BufWriter::new(File::create(&fasta)?);
+    let mut t2g_out = BufWriter::new(File::create(&t2g)?);
+
+    let mut columns: Option<(usize, usize, usize, Option<usize>)> = None;
+    let mut num_probes = 0_usize;
+    let mut num_excluded = 0_usize;
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.trim_end().split(',').collect();
+        match columns {
+            None => {
+                // this is the header line
+                let col = |name: &str| fields.iter().position(|f| *f == name);
+                match (col("gene_id"), col("probe_seq"), col("probe_id")) {
+                    (Some(g), Some(s), Some(p)) => {
+                        columns = Some((g, s, p, col("included")));
+                    }
+                    _ => {
+                        bail!("the header of probe set {} must contain the columns gene_id, probe_seq and probe_id.",
+                            probe_set.display());
+                    }
+                }
+            }
+            Some((gene_col, seq_col, probe_col, included_col)) => {
+                let get = |c: usize| {
+                    fields.get(c).copied().with_context(|| {
+                        format!(
+                            "line {} of probe set {} has too few columns",
+                            line_num + 1,
+                            probe_set.display()
+                        )
+                    })
+                };
+                if let Some(ic) = included_col {
+                    if get(ic)?.eq_ignore_ascii_case("false") {
+                        num_excluded += 1;
+                        continue;
+                    }
+                }
+                let probe_id = get(probe_col)?;
+                writeln!(fasta_out, ">{}\n{}", probe_id, get(seq_col)?)?;
+                writeln!(t2g_out, "{}\t{}", probe_id, get(gene_col)?)?;
+                num_probes += 1;
+            }
+        }
+    }
+
+    if num_probes == 0 {
+        bail!(
+            "probe set {} did not contain any (included) probes.",
+            probe_set.display()
+        );
+    }
+    info!(
+        "wrote {} probes ({} excluded) from probe set {} to {}; probe to gene mapping written to {}.",
+        num_probes,
+        num_excluded,
+        probe_set.display(),
+        fasta.display(),
+        t2g.display()
+    );
+    Ok(fasta)
+}
@@ -0,0 +1,220 @@
+// The code generated by pyo3 0.22 checks its own `gil-refs` feature (in
+// `create_exception!`), which is unknown to this crate, and converts the errors
+// of every `#[pyfunction]` into `PyErr` even when they already are; neither can
+// be allowed on the items themselves.
+#![allow(unexpected_cfgs, clippy::useless_conversion)]
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyList, PyTuple};
+use serde_json::Value;
+
+use crate::piscem_commands::parse_opts;
+use crate::{
+    BuildConfig, LogLevel, MapBulkConfig, MapSCAtacConfig, MapSCConfig,
+    MapSummary as RustMapSummary, RunOptions,
+};
+
+create_exception!(
+    piscem,
+    PiscemError,
+    PyException,
+    "A failure of piscem; its arguments are the error message and the exit code the piscem executable would report."
+);
+
+/// Turn an error of piscem into a `PiscemError` with the message and exit code
+/// of the error.
+fn to_py_err(e: anyhow::Error) -> PyErr {
+    PiscemError::new_err((format!("{:#}", e), crate::exit_code(&e)))
+}
+
+/// The command line arguments corresponding to the keyword arguments `kwargs`:
+/// `ref_seqs=["a.fa", "b.fa"]` becomes `--ref-seqs a.fa,b.fa`, `threads=8`
+/// becomes `--threads 8`, and `overwrite=True` becomes `--overwrite`. Arguments
+/// that are `False` or `None` are left out.
+fn kwargs_to_argv(kwargs: Option<&Bound<'_, PyDict>>) -> PyResult<Vec<String>> {
+    let mut argv = Vec::new();
+    let Some(kwargs) = kwargs else {
+        return Ok(argv);
+    };
+    for (key, value) in kwargs.iter() {
+        let flag = format!("--{}", key.extract::<String>()?.replace('_', "-"));
+        // a bool is also an int, so it must be checked first
+        if let Ok(b) = value.downcast::<PyBool>() {
+            if b.is_true() {
+                argv.push(flag);
+            }
+            continue;
+        }
+        if value.is_none() {
+            continue;
+        }
+        let items = if let Ok(list) = value.downcast::<PyList>() {
+            list.iter().collect::<Vec<_>>()
+        } else if let Ok(tuple) = value.downcast::<PyTuple>() {
+            tuple.iter().collect::<Vec<_>>()
+        } else {
+            vec![value]
+        };
+        let values = items
+            .iter()
+            .map(|v| v.str().map(|s| s.to_string()))
+            .collect::<PyResult<Vec<String>>>()?;
+        argv.push(flag);
+        argv.push(values.join(","));
+    }
+    Ok(argv)
+}
+
+/// The options of a run with the `quiet` and `dry_run` arguments of the Python
+/// functions; being quiet corresponds to the `warn` log level.
+fn run_options(quiet: bool, dry_run: bool) -> RunOptions {
+    RunOptions {
+        log_level: if quiet {
+            LogLevel::Warn
+        } else {
+            LogLevel::Info
+        },
+        dry_run,
+    }
+}
+
+/// Convert the JSON value `value` into the corresponding Python object.
+fn json_to_py(py: Python<'_>, value: &Value) -> PyResult<PyObject> {
+    let json = py.import_bound("json")?;
+    Ok(json.call_method1("loads", (value.to_string(),))?.unbind())
+}
+
+/// The outcome of `piscem.build()`.
+#[pyclass(name = "IndexInfo", module = "piscem", get_all)]
+struct IndexInfo {
+    /// the prefix of the index
+    prefix: String,
+    /// the k-mer length of the index
+    k: usize,
+    /// the minimizer length of the index
+    m: usize,
+    /// the number of references indexed (`None` for a dry run)
+    num_refs: Option<u64>,
+    /// the provenance of the index (the contents of `index_info.json`), as a dict
+    provenance: PyObject,
+}
+
+/// The outcome of `piscem.map_sc()`, `piscem.map_sc_atac()` and `piscem.map_bulk()`.
+#[pyclass(name = "MapSummary", module = "piscem", get_all)]
+struct MapSummary {
+    /// the output directory (or, for bulk reads, the output stem)
+    output: String,
+    /// the number of reads (or read pairs) processed (`None` for a dry run)
+    num_reads: Option<u64>,
+    /// the number of reads (or read pairs) mapped (`None` for a dry run)
+    num_mapped: Option<u64>,
+    /// the percentage of the reads that were mapped (`None` for a dry run)
+    percent_mapped: Option<f64>,
+    /// the mapping summary (the contents of `map_info.json`), as a dict
+    map_info: PyObject,
+}
+
+impl MapSummary {
+    fn from_rust(py: Python<'_>, summary: RustMapSummary) -> PyResult<Self> {
+        Ok(Self {
+            output: summary.output.to_string_lossy().into_owned(),
+            num_reads: summary.num_reads,
+            num_mapped: summary.num_mapped,
+            percent_mapped: summary.percent_mapped,
+            map_info: json_to_py(py, &summary.map_info)?,
+        })
+    }
+}
+
+/// Build an index. The keyword arguments are the options of `piscem build`, with
+/// `_` in place of `-` (e.g. `piscem.build(ref_seqs=["tx.fa"], output="idx", threads=8)`).
+#[pyfunction]
+#[pyo3(signature = (quiet = false, dry_run = false, **kwargs))]
+fn build(
+    py: Python<'_>,
+    quiet: bool,
+    dry_run: bool,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<IndexInfo> {
+    let config = parse_opts::<BuildConfig>("build", kwargs_to_argv(kwargs)?).map_err(to_py_err)?;
+    let run = run_options(quiet, dry_run);
+    let info = py
+        .allow_threads(|| crate::build_index(config, &run))
+        .map_err(to_py_err)?;
+    Ok(IndexInfo {
+        prefix: info.prefix.to_string_lossy().into_owned(),
+        k: info.k,
+        m: info.m,
+        num_refs: info.num_refs,
+        provenance: json_to_py(py, &info.provenance)?,
+    })
+}
+
+/// Map single-cell reads. The keyword arguments are the options of `piscem map-sc`.
+#[pyfunction]
+#[pyo3(signature = (quiet = false, dry_run = false, **kwargs))]
+fn map_sc(
+    py: Python<'_>,
+    quiet: bool,
+    dry_run: bool,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<MapSummary> {
+    let config = parse_opts::<MapSCConfig>("map-sc", kwargs_to_argv(kwargs)?).map_err(to_py_err)?;
+    let run = run_options(quiet, dry_run);
+    let summary = py
+        .allow_threads(|| crate::map_sc(config, &run))
+        .map_err(to_py_err)?;
+    MapSummary::from_rust(py, summary)
+}
+
+/// Map single-cell ATAC-seq reads. The keyword arguments are the options of
+/// `piscem map-sc-atac`.
+#[pyfunction]
+#[pyo3(signature = (quiet = false, dry_run = false, **kwargs))]
+fn map_sc_atac(
+    py: Python<'_>,
+    quiet: bool,
+    dry_run: bool,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<MapSummary> {
+    let config =
+        parse_opts::<MapSCAtacConfig>("map-sc-atac", kwargs_to_argv(kwargs)?).map_err(to_py_err)?;
+    let run = run_options(quiet, dry_run);
+    let summary = py
+        .allow_threads(|| crate::map_sc_atac(config, &run))
+        .map_err(to_py_err)?;
+    MapSummary::from_rust(py, summary)
+}
+
+/// Map bulk reads. The keyword arguments are the options of `piscem map-bulk`.
+#[pyfunction]
+#[pyo3(signature = (quiet = false, dry_run = false, **kwargs))]
+fn map_bulk(
+    py: Python<'_>,
+    quiet: bool,
+    dry_run: bool,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<MapSummary> {
+    let config =
+        parse_opts::<MapBulkConfig>("map-bulk", kwargs_to_argv(kwargs)?).map_err(to_py_err)?;
+    let run = run_options(quiet, dry_run);
+    let summary = py
+        .allow_threads(|| crate::map_bulk(config, &run))
+        .map_err(to_py_err)?;
+    MapSummary::from_rust(py, summary)
+}
+
+/// Indexing and mapping to compacted colored de Bruijn graphs.
+#[pymodule]
+fn piscem(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(build, m)?)?;
+    m.add_function(wrap_pyfunction!(map_sc, m)?)?;
+    m.add_function(wrap_pyfunction!(map_sc_atac, m)?)?;
+    m.add_function(wrap_pyfunction!(map_bulk, m)?)?;
+    m.add_class::<IndexInfo>()?;
+    m.add_class::<MapSummary>()?;
+    m.add("PiscemError", m.py().get_type_bound::<PiscemError>())?;
+    Ok(())
+}
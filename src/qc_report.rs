@@ -0,0 +1,274 @@
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::map_info;
+use crate::rad::RadReader;
+
+/// The name of the QC report written into the output directory by `map-sc`.
+pub(crate) const SC_QC_REPORT_FILE: &str = "qc_report.html";
+
+/// The path of the QC report written by `map-bulk` for the output stem `output`.
+pub(crate) fn bulk_qc_report_path(output: &Path) -> PathBuf {
+    PathBuf::from(output.to_string_lossy().into_owned() + ".qc_report.html")
+}
+
+/// Fragment lengths above this are collected in the last histogram bin.
+const MAX_FRAG_LEN: usize = 1000;
+
+const PLOT_WIDTH: f64 = 600.0;
+const PLOT_HEIGHT: f64 = 300.0;
+const PLOT_MARGIN: f64 = 50.0;
+
+/// The metrics collected from a RAD file for the report.
+#[derive(Debug, Default)]
+struct RadMetrics {
+    /// the number of mapped reads for each barcode, sorted in decreasing order
+    barcode_counts: Vec<u64>,
+    /// the number of mapped fragments of each length (of their first alignment)
+    frag_len_hist: Vec<u64>,
+}
+
+/// Collect the barcode counts (if the reads carry a barcode tag `b`) and the
+/// fragment length histogram (if the alignments carry a tag `frag_len`) from
+/// the RAD file at `rad_path`.
+fn collect_rad_metrics(rad_path: &Path) -> Result<RadMetrics> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    let bc_tag = rad.header.read_tags.iter().position(|t| t.name == "b");
+    let frag_len_tag = rad
+        .header
+        .aln_tags
+        .iter()
+        .position(|t| t.name == "frag_len");
+
+    let mut bc_counts = HashMap::<u64, u64>::new();
+    let mut frag_len_hist = vec![0_u64; MAX_FRAG_LEN + 1];
+    while let Some(rec) = rad.next_record()? {
+        if let Some(bc) = bc_tag.and_then(|i| rec.read_tags.get(i)?.as_u64()) {
+            *bc_counts.entry(bc).or_default() += 1;
+        }
+        if let Some(fl) = frag_len_tag.and_then(|i| rec.alns.first()?.get(i)?.as_u64()) {
+            // unpaired (or orphaned) fragments have no length
+            if fl > 0 {
+                frag_len_hist[(fl as usize).min(MAX_FRAG_LEN)] += 1;
+            }
+        }
+    }
+
+    let mut barcode_counts = bc_counts.into_values().collect::<Vec<u64>>();
+    barcode_counts.sort_unstable_by(|a, b| b.cmp(a));
+    if frag_len_hist.iter().all(|c| *c == 0) {
+        frag_len_hist.clear();
+    }
+    Ok(RadMetrics {
+        barcode_counts,
+        frag_len_hist,
+    })
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Flatten the (possibly nested) JSON object `obj` into rows of the metrics table,
+/// joining the keys of nested objects with `.`.
+fn flatten_metrics(prefix: &str, obj: &Map<String, Value>, rows: &mut Vec<(String, String)>) {
+    for (k, v) in obj {
+        let key = if prefix.is_empty() {
+            k.clone()
+        } else {
+            format!("{}.{}", prefix, k)
+        };
+        match v {
+            Value::Object(o) => flatten_metrics(&key, o, rows),
+            Value::String(s) => rows.push((key, s.clone())),
+            _ => rows.push((key, v.to_string())),
+        }
+    }
+}
+
+/// Render the `points` (in data coordinates) as an SVG line plot, with logarithmic
+/// axes if `log_scale` is set.
+fn svg_line_plot(points: &[(f64, f64)], x_label: &str, y_label: &str, log_scale: bool) -> String {
+    let tr = |v: f64| if log_scale { v.max(1.0).log10() } else { v };
+    let x_max = points.iter().map(|p| tr(p.0)).fold(0.0, f64::max).max(1e-9);
+    let y_max = points.iter().map(|p| tr(p.1)).fold(0.0, f64::max).max(1e-9);
+    let to_px = |(x, y): (f64, f64)| {
+        (
+            PLOT_MARGIN + tr(x) / x_max * (PLOT_WIDTH - 2.0 * PLOT_MARGIN),
+            PLOT_HEIGHT - PLOT_MARGIN - tr(y) / y_max * (PLOT_HEIGHT - 2.0 * PLOT_MARGIN),
+        )
+    };
+
+    let mut path = String::new();
+    for (i, p) in points.iter().enumerate() {
+        let (px, py) = to_px(*p);
+        let _ = write!(
+            path,
+            "{}{:.1},{:.1}",
+            if i == 0 { "M" } else { " L" },
+            px,
+            py
+        );
+    }
+    let axis_max = |m: f64| if log_scale { 10_f64.powf(m) } else { m };
+    format!(
+        r#"<svg width="{w}" height="{h}" xmlns="http://www.w3.org/2000/svg">
+<line x1="{m}" y1="{b}" x2="{r}" y2="{b}" stroke="black"/>
+<line x1="{m}" y1="{m}" x2="{m}" y2="{b}" stroke="black"/>
+<path d="{path}" fill="none" stroke="steelblue" stroke-width="2"/>
+<text x="{cx}" y="{xl}" text-anchor="middle">{x_label}</text>
+<text x="15" y="{cy}" text-anchor="middle" transform="rotate(-90 15 {cy})">{y_label}</text>
+<text x="{r}" y="{xt}" text-anchor="end">{x_max:.0}</text>
+<text x="{yt}" y="{m}" text-anchor="end">{y_max:.0}</text>
+</svg>"#,
+        w = PLOT_WIDTH,
+        h = PLOT_HEIGHT,
+        m = PLOT_MARGIN,
+        b = PLOT_HEIGHT - PLOT_MARGIN,
+        r = PLOT_WIDTH - PLOT_MARGIN,
+        cx = PLOT_WIDTH / 2.0,
+        cy = PLOT_HEIGHT / 2.0,
+        xl = PLOT_HEIGHT - 10.0,
+        xt = PLOT_HEIGHT - PLOT_MARGIN + 15.0,
+        yt = PLOT_MARGIN - 5.0,
+        x_max = axis_max(x_max),
+        y_max = axis_max(y_max),
+    )
+}
+
+/// Render the fraction `mapped` of `total` as a horizontal SVG bar.
+fn svg_mapping_rate(mapped: f64, total: f64) -> String {
+    let frac = if total > 0.0 { mapped / total } else { 0.0 };
+    let w = frac * (PLOT_WIDTH - 2.0 * PLOT_MARGIN);
+    format!(
+        r#"<svg width="{pw}" height="50" xmlns="http://www.w3.org/2000/svg">
+<rect x="{m}" y="10" width="{full}" height="25" fill="lightgray"/>
+<rect x="{m}" y="10" width="{w:.1}" height="25" fill="steelblue"/>
+<text x="{tx}" y="28">{pct:.2}% mapped</text>
+</svg>"#,
+        pw = PLOT_WIDTH,
+        m = PLOT_MARGIN,
+        full = PLOT_WIDTH - 2.0 * PLOT_MARGIN,
+        tx = PLOT_MARGIN + 5.0,
+        pct = frac * 100.0,
+    )
+}
+
+/// Write a self-contained HTML report, titled `title`, summarizing the mapping run
+/// whose summary is in `map_info_path` and whose mappings are in `rad_path`. The
+/// report embeds (as inline SVG) the mapping rate, the barcode knee plot (for
+/// single-cell runs) and the fragment length distribution (for paired-end runs),
+/// along with every metric of the mapping summary and the list of input files
+/// in `input_files`.
+pub(crate) fn write_qc_report(
+    report_path: &Path,
+    title: &str,
+    map_info_path: &Path,
+    rad_path: &Path,
+    input_files: &[String],
+) -> Result<()> {
+    let map_info = map_info::read_map_info(map_info_path)?;
+    let metrics = collect_rad_metrics(rad_path)?;
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{t}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; }}
+td, th {{ border: 1px solid #ccc; padding: 4px 8px; text-align: left; }}
+</style>
+</head>
+<body>
+<h1>{t}</h1>
+<p>piscem version {v}</p>
+"#,
+        t = escape_html(title),
+        v = clap::crate_version!(),
+    );
+
+    let num = |k: &str| map_info.get(k).and_then(|v| v.as_f64());
+    if let (Some(total), Some(mapped)) = (num("num_reads"), num("num_mapped")) {
+        let _ = write!(
+            html,
+            "<h2>Mapping rate</h2>\n{}\n",
+            svg_mapping_rate(mapped, total)
+        );
+    }
+
+    if !metrics.barcode_counts.is_empty() {
+        let points = metrics
+            .barcode_counts
+            .iter()
+            .enumerate()
+            .map(|(i, c)| ((i + 1) as f64, *c as f64))
+            .collect::<Vec<(f64, f64)>>();
+        let _ = write!(
+            html,
+            "<h2>Barcode knee plot</h2>\n<p>{} distinct barcodes with mapped reads.</p>\n{}\n",
+            metrics.barcode_counts.len(),
+            svg_line_plot(&points, "barcode rank", "mapped reads", true)
+        );
+    }
+
+    if !metrics.frag_len_hist.is_empty() {
+        let points = metrics
+            .frag_len_hist
+            .iter()
+            .enumerate()
+            .map(|(l, c)| (l as f64, *c as f64))
+            .collect::<Vec<(f64, f64)>>();
+        let _ = write!(
+            html,
+            "<h2>Fragment length distribution</h2>\n<p>Fragments longer than {} are counted in the last bin.</p>\n{}\n",
+            MAX_FRAG_LEN,
+            svg_line_plot(&points, "fragment length", "fragments", false)
+        );
+    }
+
+    let mut rows = Vec::new();
+    flatten_metrics("", &map_info, &mut rows);
+    if !rows.is_empty() {
+        html.push_str("<h2>Mapping summary</h2>\n<table>\n");
+        for (k, v) in rows {
+            let _ = writeln!(
+                html,
+                "<tr><th>{}</th><td>{}</td></tr>",
+                escape_html(&k),
+                escape_html(&v)
+            );
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("<h2>Input files</h2>\n<table>\n<tr><th>file</th><th>size (bytes)</th></tr>\n");
+    for f in input_files {
+        let size = std::fs::metadata(f)
+            .map(|m| m.len().to_string())
+            .unwrap_or_else(|_| String::from("unknown"));
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            escape_html(f),
+            size
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    std::fs::write(report_path, html)
+        .with_context(|| format!("could not write QC report {}", report_path.display()))?;
+    info!("wrote QC report to {}.", report_path.display());
+    Ok(())
+}
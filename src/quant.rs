@@ -0,0 +1,245 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::eq_classes::{self, EqClasses};
+use crate::exit_code::{bail_with, ExitKind};
+use crate::piscem_commands::QuantBulkOpts;
+use crate::refinfo::{self, RefInfo};
+use crate::{inspect, rad};
+
+/// The mean fragment length assumed when the RAD file records none (e.g. for
+/// single-end reads), as in salmon.
+const DEFAULT_FRAG_LEN: f64 = 250.0;
+
+/// The EM stops once no abundance above `MIN_ABUNDANCE` changes by more than
+/// this fraction between two rounds.
+const REL_DIFF_TOLERANCE: f64 = 0.01;
+
+/// Abundances below this are not considered by the convergence check, and are
+/// set to 0 once the EM has finished.
+const MIN_ABUNDANCE: f64 = 1e-8;
+
+/// The number of rounds the EM runs before it checks for convergence.
+const MIN_EM_ROUNDS: usize = 50;
+
+/// The maximum number of rounds of the EM run by `map-bulk --quant`.
+pub(crate) const MAX_EM_ROUNDS: usize = 10000;
+
+/// The path of the quantification written by `map-bulk --quant` for the output
+/// stem `output`.
+pub(crate) fn bulk_quant_path(output: &Path) -> PathBuf {
+    PathBuf::from(output.to_string_lossy().into_owned() + ".quant.sf")
+}
+
+/// The effective length of a reference of length `len` for fragments of mean
+/// length `frag_len`, i.e. the number of positions at which such a fragment can
+/// start. References shorter than the fragments keep their length.
+fn effective_len(len: u64, frag_len: f64) -> f64 {
+    let len = len as f64;
+    if len > frag_len {
+        len - frag_len + 1.0
+    } else {
+        len.max(1.0)
+    }
+}
+
+/// Estimate the number of fragments from each reference by running an EM over
+/// the equivalence classes `ecs`, given the effective lengths `eff_lens` of the
+/// references, for at most `max_rounds` rounds. Returns the abundances, the
+/// number of rounds run and whether the EM converged.
+fn run_em(ecs: &EqClasses, eff_lens: &[f64], max_rounds: usize) -> (Vec<f64>, usize, bool) {
+    let n = eff_lens.len();
+    let total = ecs.num_fragments() as f64;
+    let mut alphas = vec![total / n.max(1) as f64; n];
+    let mut next = vec![0.0; n];
+    let mut rounds = 0;
+    let mut converged = false;
+    while rounds < max_rounds {
+        next.iter_mut().for_each(|a| *a = 0.0);
+        for (refs, count) in &ecs.classes {
+            let denom = refs
+                .iter()
+                .map(|&r| alphas[r as usize] / eff_lens[r as usize])
+                .sum::<f64>();
+            if denom <= 0.0 {
+                continue;
+            }
+            for &r in refs {
+                let r = r as usize;
+                next[r] += *count as f64 * (alphas[r] / eff_lens[r]) / denom;
+            }
+        }
+        rounds += 1;
+        converged = rounds >= MIN_EM_ROUNDS
+            && alphas
+                .iter()
+                .zip(next.iter())
+                .all(|(a, b)| *b <= MIN_ABUNDANCE || ((a - b).abs() / b) <= REL_DIFF_TOLERANCE);
+        std::mem::swap(&mut alphas, &mut next);
+        if converged {
+            break;
+        }
+    }
+    alphas
+        .iter_mut()
+        .filter(|a| **a < MIN_ABUNDANCE)
+        .for_each(|a| *a = 0.0);
+    (alphas, rounds, converged)
+}
+
+/// Write the abundances `alphas` of the references `refs`, with the effective
+/// lengths `eff_lens`, to `path` in the format of salmon's `quant.sf`.
+fn write_quant_sf(path: &Path, refs: &RefInfo, eff_lens: &[f64], alphas: &[f64]) -> Result<()> {
+    let rates = alphas
+        .iter()
+        .zip(eff_lens.iter())
+        .map(|(a, l)| a / l)
+        .collect::<Vec<f64>>();
+    let rate_sum = rates.iter().sum::<f64>();
+    let f = File::create(path).with_context(|| format!("could not create {}", path.display()))?;
+    let mut out = BufWriter::new(f);
+    writeln!(out, "Name\tLength\tEffectiveLength\tTPM\tNumReads")?;
+    for (i, name) in refs.names.iter().enumerate() {
+        let tpm = if rate_sum > 0.0 {
+            rates[i] / rate_sum * 1e6
+        } else {
+            0.0
+        };
+        writeln!(
+            out,
+            "{}\t{}\t{:.3}\t{:.6}\t{:.3}",
+            name, refs.lengths[i], eff_lens[i], tpm, alphas[i]
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Quantify the references of the index with the prefix `index` from the
+/// fragments in the bulk RAD file at `rad_path`, by an EM over their equivalence
+/// classes, and write the abundances to `quant_path` (see `write_quant_sf`).
+/// The effective lengths of the references are computed for fragments of mean
+/// length `frag_len`, or else of the mean length recorded in the RAD file.
+/// Returns a summary of the quantification.
+pub(crate) fn quantify(
+    rad_path: &Path,
+    index: &str,
+    quant_path: &Path,
+    frag_len: Option<f64>,
+    max_rounds: usize,
+) -> Result<Value> {
+    let refs = refinfo::read_refinfo(&inspect::component_path(index, ".refinfo"))?;
+    let ecs = eq_classes::collect_eq_classes(rad_path)?;
+    if ecs.ref_names != refs.names {
+        bail!(
+            "the references of RAD file {} do not match those of the index.",
+            rad_path.display()
+        );
+    }
+    let frag_len = frag_len.or(ecs.mean_frag_len).unwrap_or(DEFAULT_FRAG_LEN);
+    let eff_lens = refs
+        .lengths
+        .iter()
+        .map(|l| effective_len(*l, frag_len))
+        .collect::<Vec<f64>>();
+
+    let (alphas, rounds, converged) = run_em(&ecs, &eff_lens, max_rounds);
+    if !converged {
+        warn!(
+            "the EM did not converge within {} rounds; the abundances may not be accurate.",
+            max_rounds
+        );
+    }
+    write_quant_sf(quant_path, &refs, &eff_lens, &alphas)?;
+    info!(
+        "quantified {} fragments in {} equivalence classes ({} EM rounds); wrote the abundances to {}.",
+        ecs.num_fragments(),
+        ecs.classes.len(),
+        rounds,
+        quant_path.display()
+    );
+    Ok(json!({
+        "file": quant_path.display().to_string(),
+        "num_fragments": ecs.num_fragments(),
+        "num_eq_classes": ecs.classes.len(),
+        "mean_frag_len": frag_len,
+        "num_em_rounds": rounds,
+        "converged": converged,
+    }))
+}
+
+/// Quantify the references from the bulk RAD file given in `opts` (or the RAD
+/// file of the `map-bulk` output stem given there), as described by `opts`.
+pub(crate) fn quant_bulk(opts: &QuantBulkOpts) -> Result<Value> {
+    if opts.frag_len_mean.is_some_and(|l| l.is_nan() || l <= 0.0) {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "the mean fragment length must be greater than 0."
+        );
+    }
+    let stem_rad = rad::bulk_rad_path(&opts.input);
+    let rad_path = if !opts.input.exists() && stem_rad.exists() {
+        stem_rad
+    } else {
+        rad::resolve_rad_path(&opts.input)?
+    };
+    quantify(
+        &rad_path,
+        &opts.index,
+        &opts.output,
+        opts.frag_len_mean,
+        opts.max_em_rounds as usize,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eq_classes(classes: Vec<(Vec<u32>, u64)>) -> EqClasses {
+        EqClasses {
+            ref_names: vec!["tx1".to_string(), "tx2".to_string()],
+            classes,
+            mean_frag_len: None,
+        }
+    }
+
+    #[test]
+    fn splits_a_shared_class_by_abundance() {
+        let ecs = eq_classes(vec![(vec![0], 30), (vec![1], 10), (vec![0, 1], 60)]);
+        // with equal lengths, the shared fragments follow the unique ones: the
+        // fixed point a0 = 30 + 60 a0 / 100 gives a0 = 75
+        let (alphas, rounds, converged) = run_em(&ecs, &[1000.0, 1000.0], MAX_EM_ROUNDS);
+        assert!(converged);
+        assert!(rounds < MAX_EM_ROUNDS);
+        assert!((alphas[0] - 75.0).abs() < 0.5, "{:?}", alphas);
+        assert!((alphas[1] - 25.0).abs() < 0.5, "{:?}", alphas);
+
+        // when the second reference is half as long, a1 = 10 + 120 a1 / (100 + a1),
+        // i.e. a1^2 - 30 a1 - 1000 = 0, gives a1 = 50
+        let (alphas, _, converged) = run_em(&ecs, &[1000.0, 500.0], MAX_EM_ROUNDS);
+        assert!(converged);
+        assert!((alphas[0] - 50.0).abs() < 0.5, "{:?}", alphas);
+        assert!((alphas[1] - 50.0).abs() < 0.5, "{:?}", alphas);
+        assert!((alphas.iter().sum::<f64>() - 100.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn gives_no_abundance_to_unsupported_references() {
+        let ecs = eq_classes(vec![(vec![0], 40)]);
+        let (alphas, _, _) = run_em(&ecs, &[1000.0, 1000.0], MAX_EM_ROUNDS);
+        assert_eq!(alphas, vec![40.0, 0.0]);
+    }
+
+    #[test]
+    fn keeps_the_length_of_references_shorter_than_the_fragments() {
+        assert_eq!(effective_len(1000, 250.0), 751.0);
+        assert_eq!(effective_len(100, 250.0), 100.0);
+        assert_eq!(effective_len(250, 250.0), 250.0);
+        assert_eq!(effective_len(0, 250.0), 1.0);
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{bail, Context, Result};
+use needletail::parse_fastx_file;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use tracing::info;
+
+use crate::dump_unitigs::{self, SEGMENTS_SUFFIX, TILINGS_SUFFIX};
+use crate::exit_code::{bail_with, ExitKind};
+use crate::piscem_commands::QueryOpts;
+use crate::{index_info, inspect, map_info};
+
+/// The 2-bit code of the nucleotide `c`, if it is one of ACGT (in either case).
+fn nuc_code(c: u8) -> Option<u64> {
+    match c {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+/// The forward and reverse-complement 2-bit encodings of every k-mer of `seq`,
+/// by position; k-mers with a base other than ACGT are `None`.
+fn encode_kmers(seq: &[u8], k: usize) -> Vec<Option<(u64, u64)>> {
+    let mask = if k == 32 {
+        u64::MAX
+    } else {
+        (1 << (2 * k)) - 1
+    };
+    let (mut fw, mut rc, mut valid) = (0_u64, 0_u64, 0_usize);
+    let mut kmers = Vec::with_capacity(seq.len().saturating_sub(k - 1));
+    for (i, &c) in seq.iter().enumerate() {
+        match nuc_code(c) {
+            Some(c) => {
+                fw = ((fw << 2) | c) & mask;
+                rc = (rc >> 2) | ((3 - c) << (2 * (k - 1)));
+                valid += 1;
+            }
+            None => valid = 0,
+        }
+        if i + 1 >= k {
+            kmers.push((valid >= k).then_some((fw, rc)));
+        }
+    }
+    kmers
+}
+
+/// The k-mer length of the index with the prefix `index`, from its k-mer
+/// dictionary or, failing that, from its build provenance.
+pub(crate) fn index_k(index: &str) -> Result<usize> {
+    if let Some(p) = inspect::read_dict_params(&inspect::component_path(index, ".sshash"))
+        .ok()
+        .flatten()
+    {
+        return Ok(p.k as usize);
+    }
+    let prov = map_info::read_map_info(&inspect::component_path(
+        index,
+        index_info::INDEX_INFO_SUFFIX,
+    ))?;
+    match prov.get("parameters").and_then(|p| p.get("k")?.as_u64()) {
+        Some(k) => Ok(k as usize),
+        None => bail_with!(
+            ExitKind::MissingIndexComponent,
+            "could not determine the k-mer length of index {}.",
+            index
+        ),
+    }
+}
+
+/// Where a k-mer occurs in the compacted de Bruijn graph.
+#[derive(Clone, Copy)]
+struct Hit {
+    unitig: u64,
+    /// the position of the k-mer in the unitig
+    offset: usize,
+    /// the forward encoding of the k-mer at `offset` in the unitig
+    unitig_kmer: u64,
+}
+
+/// Look up the k-mers of the sequences given in `opts` (on the command line or in a
+/// FASTA file) in the index given there. For every k-mer of every sequence, report
+/// whether it is in the index and, if so, the unitig of the compacted de Bruijn
+/// graph it lies on, its offset in the unitig, its orientation relative to the
+/// unitig and the references the unitig occurs in. The graph is read from the
+/// files kept by `build --keep-intermediate-dbg` (see `dump_unitigs`). Returns a
+/// summary of the lookups.
+pub(crate) fn query(opts: &QueryOpts) -> Result<Value> {
+    let seg_path = inspect::component_path(&opts.index, SEGMENTS_SUFFIX);
+    let seq_path = inspect::component_path(&opts.index, TILINGS_SUFFIX);
+    for path in [&seg_path, &seq_path] {
+        if !path.exists() {
+            bail_with!(
+                ExitKind::MissingIndexComponent,
+                "the compacted de Bruijn graph of index {} was not found ({} does not exist); it is only kept if the index is built with --keep-intermediate-dbg.",
+                opts.index,
+                path.display()
+            );
+        }
+    }
+    let k = index_k(&opts.index)?;
+
+    let mut queries = opts
+        .seqs
+        .iter()
+        .enumerate()
+        .map(|(i, s)| (format!("query{}", i + 1), s.as_bytes().to_vec()))
+        .collect::<Vec<(String, Vec<u8>)>>();
+    if let Some(ref fasta) = opts.fasta {
+        let mut reader = parse_fastx_file(fasta)
+            .with_context(|| format!("could not open query file {}", fasta.display()))?;
+        while let Some(rec) = reader.next() {
+            let rec = rec.with_context(|| format!("could not parse {}", fasta.display()))?;
+            let name = String::from_utf8_lossy(rec.id());
+            let name = name.split_ascii_whitespace().next().unwrap_or_default();
+            queries.push((name.to_string(), rec.seq().to_vec()));
+        }
+    }
+    if let Some((name, _)) = queries.iter().find(|(_, s)| s.len() < k) {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "query {} is shorter than the k-mer length of the index ({}).",
+            name,
+            k
+        );
+    }
+    let kmers = queries
+        .iter()
+        .map(|(_, s)| encode_kmers(s, k))
+        .collect::<Vec<Vec<Option<(u64, u64)>>>>();
+    let mut hits = kmers
+        .iter()
+        .flatten()
+        .flatten()
+        .map(|&(fw, rc)| (fw.min(rc), None))
+        .collect::<HashMap<u64, Option<Hit>>>();
+
+    // a single pass over the unitigs finds every queried k-mer
+    let f =
+        File::open(&seg_path).with_context(|| format!("could not open {}", seg_path.display()))?;
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        let Some((id, seq)) = line.split_once('\t') else {
+            continue;
+        };
+        let Ok(unitig) = id.parse::<u64>() else {
+            bail!("{} is not a valid unitig id in {}.", id, seg_path.display());
+        };
+        for (offset, kmer) in encode_kmers(seq.as_bytes(), k).into_iter().enumerate() {
+            let Some((fw, rc)) = kmer else {
+                continue;
+            };
+            if let Some(hit @ None) = hits.get_mut(&fw.min(rc)) {
+                *hit = Some(Hit {
+                    unitig,
+                    offset,
+                    unitig_kmer: fw,
+                });
+            }
+        }
+    }
+    let hit_unitigs = hits
+        .values()
+        .flatten()
+        .map(|h| h.unitig)
+        .collect::<HashSet<u64>>();
+    let colors = dump_unitigs::read_colors(&seq_path, Some(&hit_unitigs))?;
+
+    let mut out: Box<dyn Write> = match opts.output {
+        Some(ref p) => Box::new(BufWriter::new(
+            File::create(p).with_context(|| format!("could not create {}", p.display()))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    writeln!(
+        out,
+        "query\tpos\tkmer\tfound\tunitig\toffset\torientation\trefs"
+    )?;
+    let (mut num_kmers, mut num_found) = (0_u64, 0_u64);
+    for ((name, seq), kmers) in queries.iter().zip(kmers.iter()) {
+        for (pos, kmer) in kmers.iter().enumerate() {
+            let kmer_seq = String::from_utf8_lossy(&seq[pos..pos + k]).to_ascii_uppercase();
+            num_kmers += 1;
+            let hit = kmer.and_then(|(fw, rc)| Some((fw, hits.get(&fw.min(rc)).copied()??)));
+            match hit {
+                Some((fw, h)) => {
+                    num_found += 1;
+                    writeln!(
+                        out,
+                        "{}\t{}\t{}\t1\t{}\t{}\t{}\t{}",
+                        name,
+                        pos,
+                        kmer_seq,
+                        h.unitig,
+                        h.offset,
+                        if fw == h.unitig_kmer { '+' } else { '-' },
+                        colors.ref_names(h.unitig).join(",")
+                    )?;
+                }
+                None => writeln!(out, "{}\t{}\t{}\t0\t.\t.\t.\t.", name, pos, kmer_seq)?,
+            }
+        }
+    }
+    out.flush()?;
+
+    info!(
+        "{} of the {} queried k-mers are in the index.",
+        num_found, num_kmers
+    );
+    Ok(json!({
+        "k": k,
+        "num_queries": queries.len(),
+        "num_kmers": num_kmers,
+        "num_found": num_found,
+    }))
+}
@@ -0,0 +1,599 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::exit_code::{bail_with, ExitKind};
+
+/// The name of the RAD file written into the output directory by `map-sc`.
+pub(crate) const SC_RAD_FILE: &str = "map.rad";
+
+/// The path of the RAD file written by `map-bulk` for the output stem `output`.
+pub(crate) fn bulk_rad_path(output: &Path) -> PathBuf {
+    PathBuf::from(output.to_string_lossy().into_owned() + ".rad")
+}
+
+/// The RAD file given as `path` on the command line: the file itself, or the
+/// `map.rad` file of a `map-sc` or `map-sc-atac` output directory.
+pub(crate) fn resolve_rad_path(path: &Path) -> Result<PathBuf> {
+    let path = if path.is_dir() {
+        path.join(SC_RAD_FILE)
+    } else {
+        path.to_path_buf()
+    };
+    if !path.exists() {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "the RAD file {} does not exist.",
+            path.display()
+        );
+    }
+    Ok(path)
+}
+
+/// The number of records written per chunk by `RadWriter`.
+const RECORDS_PER_CHUNK: u32 = 5000;
+
+/// The type of a tag in a RAD file, as described in the tag sections
+/// of the header.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TagType {
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    F32,
+    F64,
+    /// an array whose length is stored with the first type and
+    /// whose elements have the second type
+    Array(Box<TagType>, Box<TagType>),
+    String,
+}
+
+impl std::fmt::Display for TagType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TagType::Bool => write!(f, "bool"),
+            TagType::U8 => write!(f, "u8"),
+            TagType::U16 => write!(f, "u16"),
+            TagType::U32 => write!(f, "u32"),
+            TagType::U64 => write!(f, "u64"),
+            TagType::F32 => write!(f, "f32"),
+            TagType::F64 => write!(f, "f64"),
+            TagType::Array(len_type, elem_type) => write!(f, "array<{}, {}>", len_type, elem_type),
+            TagType::String => write!(f, "string"),
+        }
+    }
+}
+
+/// The value of a tag read from a RAD file.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TagValue {
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Array(Vec<TagValue>),
+    String(String),
+}
+
+impl TagValue {
+    /// Returns the value as a `u64` if it is an unsigned integer (or bool).
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            TagValue::Bool(v) => Some(v as u64),
+            TagValue::U8(v) => Some(v as u64),
+            TagValue::U16(v) => Some(v as u64),
+            TagValue::U32(v) => Some(v as u64),
+            TagValue::U64(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// The description (name and type) of a tag in a RAD file.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TagDesc {
+    pub name: String,
+    pub typ: TagType,
+}
+
+/// The header of a RAD file, along with the tag descriptions
+/// and the values of the file-level tags.
+#[derive(Clone, Debug)]
+pub(crate) struct RadHeader {
+    pub is_paired: bool,
+    pub ref_names: Vec<String>,
+    /// the number of chunks in the file; 0 if this was not known
+    /// when the file was written.
+    pub num_chunks: u64,
+    pub file_tags: Vec<TagDesc>,
+    pub read_tags: Vec<TagDesc>,
+    pub aln_tags: Vec<TagDesc>,
+    pub file_tag_values: Vec<TagValue>,
+}
+
+/// A single mapped read (or fragment) and its alignments.
+#[derive(Clone, Debug)]
+pub(crate) struct RadRecord {
+    /// the values of the read-level tags, in the order of `RadHeader::read_tags`
+    pub read_tags: Vec<TagValue>,
+    /// for each alignment, the values of the alignment-level tags, in the order
+    /// of `RadHeader::aln_tags`
+    pub alns: Vec<Vec<TagValue>>,
+}
+
+/// The mappers record the reference id of an alignment along with its
+/// orientation in the high bit of a single `u32`. Returns the reference
+/// id and whether the alignment is in the forward orientation.
+pub(crate) fn decode_ref_id(v: u64) -> (u32, bool) {
+    let v = v as u32;
+    (v & 0x7fff_ffff, (v & 0x8000_0000) != 0)
+}
+
+impl RadRecord {
+    /// Returns the reference ids of the alignments of this record,
+    /// which are stored in the first alignment-level tag.
+    pub fn ref_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.alns
+            .iter()
+            .filter_map(|a| a.first().and_then(|v| v.as_u64()))
+            .map(|v| decode_ref_id(v).0)
+    }
+}
+
+fn read_u8<R: Read>(r: &mut R) -> Result<u8> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u16<R: Read>(r: &mut R) -> Result<u16> {
+    let mut b = [0u8; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_le_bytes(b))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+fn read_string<R: Read>(r: &mut R) -> Result<String> {
+    let len = read_u16(r)? as usize;
+    let mut b = vec![0u8; len];
+    r.read_exact(&mut b)?;
+    Ok(String::from_utf8_lossy(&b).into_owned())
+}
+
+fn read_tag_type<R: Read>(r: &mut R) -> Result<TagType> {
+    let t = read_u8(r)?;
+    Ok(match t {
+        0 => TagType::Bool,
+        1 => TagType::U8,
+        2 => TagType::U16,
+        3 => TagType::U32,
+        4 => TagType::U64,
+        5 => TagType::F32,
+        6 => TagType::F64,
+        7 => {
+            let len_type = read_tag_type(r)?;
+            let elem_type = read_tag_type(r)?;
+            TagType::Array(Box::new(len_type), Box::new(elem_type))
+        }
+        8 => TagType::String,
+        _ => bail!("unknown RAD tag type id {}", t),
+    })
+}
+
+fn read_tag_value<R: Read>(r: &mut R, typ: &TagType) -> Result<TagValue> {
+    Ok(match typ {
+        TagType::Bool => TagValue::Bool(read_u8(r)? != 0),
+        TagType::U8 => TagValue::U8(read_u8(r)?),
+        TagType::U16 => TagValue::U16(read_u16(r)?),
+        TagType::U32 => TagValue::U32(read_u32(r)?),
+        TagType::U64 => TagValue::U64(read_u64(r)?),
+        TagType::F32 => TagValue::F32(f32::from_bits(read_u32(r)?)),
+        TagType::F64 => TagValue::F64(f64::from_bits(read_u64(r)?)),
+        TagType::Array(len_type, elem_type) => {
+            let len = match read_tag_value(r, len_type)?.as_u64() {
+                Some(l) => l as usize,
+                None => bail!("RAD array length must have an integer type"),
+            };
+            let mut v = Vec::with_capacity(len);
+            for _ in 0..len {
+                v.push(read_tag_value(r, elem_type)?);
+            }
+            TagValue::Array(v)
+        }
+        TagType::String => TagValue::String(read_string(r)?),
+    })
+}
+
+fn read_tag_descs<R: Read>(r: &mut R) -> Result<Vec<TagDesc>> {
+    let n = read_u16(r)?;
+    let mut descs = Vec::with_capacity(n as usize);
+    for _ in 0..n {
+        let name = read_string(r)?;
+        let typ = read_tag_type(r)?;
+        descs.push(TagDesc { name, typ });
+    }
+    Ok(descs)
+}
+
+/// A streaming reader over the records of a RAD file. Since RAD files
+/// are self-describing, this works for the output of any of the mappers.
+pub(crate) struct RadReader<R: Read> {
+    reader: R,
+    pub header: RadHeader,
+    remaining_in_chunk: u32,
+    chunks_read: u64,
+}
+
+impl RadReader<BufReader<File>> {
+    /// Open the RAD file at `path` and read its header.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let f = File::open(path)
+            .with_context(|| format!("could not open RAD file {}", path.display()))?;
+        RadReader::new(BufReader::new(f))
+            .with_context(|| format!("could not read the header of RAD file {}", path.display()))
+    }
+}
+
+impl<R: Read> RadReader<R> {
+    /// Read the header from `reader` and return a reader positioned
+    /// at the first chunk.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let is_paired = read_u8(&mut reader)? != 0;
+        let ref_count = read_u64(&mut reader)?;
+        let mut ref_names = Vec::with_capacity(ref_count as usize);
+        for _ in 0..ref_count {
+            ref_names.push(read_string(&mut reader)?);
+        }
+        let num_chunks = read_u64(&mut reader)?;
+        let file_tags = read_tag_descs(&mut reader)?;
+        let read_tags = read_tag_descs(&mut reader)?;
+        let aln_tags = read_tag_descs(&mut reader)?;
+        let mut file_tag_values = Vec::with_capacity(file_tags.len());
+        for t in &file_tags {
+            file_tag_values.push(read_tag_value(&mut reader, &t.typ)?);
+        }
+        Ok(Self {
+            reader,
+            header: RadHeader {
+                is_paired,
+                ref_names,
+                num_chunks,
+                file_tags,
+                read_tags,
+                aln_tags,
+                file_tag_values,
+            },
+            remaining_in_chunk: 0,
+            chunks_read: 0,
+        })
+    }
+
+    /// Move to the next non-empty chunk, returning `false` if there are no more chunks.
+    fn next_chunk(&mut self) -> Result<bool> {
+        loop {
+            if self.header.num_chunks > 0 && self.chunks_read >= self.header.num_chunks {
+                return Ok(false);
+            }
+            // the number of bytes in the chunk; we don't need it since
+            // we parse the records themselves.
+            let mut b = [0u8; 4];
+            match self.reader.read_exact(&mut b) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+            self.remaining_in_chunk = read_u32(&mut self.reader)?;
+            self.chunks_read += 1;
+            if self.remaining_in_chunk > 0 {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// The number of chunks read so far, including empty ones.
+    pub fn chunks_read(&self) -> u64 {
+        self.chunks_read
+    }
+
+    /// Read the next record, returning `None` once all records have been read.
+    pub fn next_record(&mut self) -> Result<Option<RadRecord>> {
+        if self.remaining_in_chunk == 0 && !self.next_chunk()? {
+            return Ok(None);
+        }
+        self.remaining_in_chunk -= 1;
+
+        let num_alns = read_u32(&mut self.reader)?;
+        let mut read_tags = Vec::with_capacity(self.header.read_tags.len());
+        for t in &self.header.read_tags {
+            read_tags.push(read_tag_value(&mut self.reader, &t.typ)?);
+        }
+        let mut alns = Vec::with_capacity(num_alns as usize);
+        for _ in 0..num_alns {
+            let mut aln = Vec::with_capacity(self.header.aln_tags.len());
+            for t in &self.header.aln_tags {
+                aln.push(read_tag_value(&mut self.reader, &t.typ)?);
+            }
+            alns.push(aln);
+        }
+        Ok(Some(RadRecord { read_tags, alns }))
+    }
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> Result<()> {
+    w.write_all(&(s.len() as u16).to_le_bytes())?;
+    w.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn write_tag_type<W: Write>(w: &mut W, typ: &TagType) -> Result<()> {
+    match typ {
+        TagType::Bool => w.write_all(&[0])?,
+        TagType::U8 => w.write_all(&[1])?,
+        TagType::U16 => w.write_all(&[2])?,
+        TagType::U32 => w.write_all(&[3])?,
+        TagType::U64 => w.write_all(&[4])?,
+        TagType::F32 => w.write_all(&[5])?,
+        TagType::F64 => w.write_all(&[6])?,
+        TagType::Array(len_type, elem_type) => {
+            w.write_all(&[7])?;
+            write_tag_type(w, len_type)?;
+            write_tag_type(w, elem_type)?;
+        }
+        TagType::String => w.write_all(&[8])?,
+    }
+    Ok(())
+}
+
+fn write_tag_value<W: Write>(w: &mut W, v: &TagValue, typ: &TagType) -> Result<()> {
+    match (v, typ) {
+        (TagValue::Bool(x), TagType::Bool) => w.write_all(&[*x as u8])?,
+        (TagValue::U8(x), TagType::U8) => w.write_all(&[*x])?,
+        (TagValue::U16(x), TagType::U16) => w.write_all(&x.to_le_bytes())?,
+        (TagValue::U32(x), TagType::U32) => w.write_all(&x.to_le_bytes())?,
+        (TagValue::U64(x), TagType::U64) => w.write_all(&x.to_le_bytes())?,
+        (TagValue::F32(x), TagType::F32) => w.write_all(&x.to_le_bytes())?,
+        (TagValue::F64(x), TagType::F64) => w.write_all(&x.to_le_bytes())?,
+        (TagValue::Array(xs), TagType::Array(len_type, elem_type)) => {
+            let len = xs.len() as u64;
+            let len_value = match **len_type {
+                TagType::U8 => TagValue::U8(len as u8),
+                TagType::U16 => TagValue::U16(len as u16),
+                TagType::U32 => TagValue::U32(len as u32),
+                TagType::U64 => TagValue::U64(len),
+                _ => bail!("RAD array length must have an integer type"),
+            };
+            write_tag_value(w, &len_value, len_type)?;
+            for x in xs {
+                write_tag_value(w, x, elem_type)?;
+            }
+        }
+        (TagValue::String(x), TagType::String) => write_string(w, x)?,
+        _ => bail!("RAD tag value {:?} does not have type {:?}", v, typ),
+    }
+    Ok(())
+}
+
+fn write_tag_descs<W: Write>(w: &mut W, descs: &[TagDesc]) -> Result<()> {
+    w.write_all(&(descs.len() as u16).to_le_bytes())?;
+    for d in descs {
+        write_string(w, &d.name)?;
+        write_tag_type(w, &d.typ)?;
+    }
+    Ok(())
+}
+
+/// Writes records to a RAD file, grouping them into chunks. The number of
+/// chunks in the header is filled in by `finish`.
+pub(crate) struct RadWriter {
+    writer: BufWriter<File>,
+    header: RadHeader,
+    num_chunks_offset: u64,
+    num_chunks: u64,
+    chunk_buf: Vec<u8>,
+    chunk_records: u32,
+}
+
+impl RadWriter {
+    /// Create the RAD file at `path` and write `header` to it.
+    pub fn create(path: &Path, header: &RadHeader) -> Result<Self> {
+        let f = File::create(path)
+            .with_context(|| format!("could not create RAD file {}", path.display()))?;
+        let mut writer = BufWriter::new(f);
+        writer.write_all(&[header.is_paired as u8])?;
+        writer.write_all(&(header.ref_names.len() as u64).to_le_bytes())?;
+        let mut num_chunks_offset = 9_u64;
+        for n in &header.ref_names {
+            write_string(&mut writer, n)?;
+            num_chunks_offset += 2 + n.len() as u64;
+        }
+        writer.write_all(&0_u64.to_le_bytes())?;
+        write_tag_descs(&mut writer, &header.file_tags)?;
+        write_tag_descs(&mut writer, &header.read_tags)?;
+        write_tag_descs(&mut writer, &header.aln_tags)?;
+        for (v, d) in header.file_tag_values.iter().zip(header.file_tags.iter()) {
+            write_tag_value(&mut writer, v, &d.typ)?;
+        }
+        Ok(Self {
+            writer,
+            header: header.clone(),
+            num_chunks_offset,
+            num_chunks: 0,
+            chunk_buf: Vec::new(),
+            chunk_records: 0,
+        })
+    }
+
+    /// Append `rec`, whose tags must match those of the header.
+    pub fn write_record(&mut self, rec: &RadRecord) -> Result<()> {
+        let buf = &mut self.chunk_buf;
+        buf.write_all(&(rec.alns.len() as u32).to_le_bytes())?;
+        for (v, d) in rec.read_tags.iter().zip(self.header.read_tags.iter()) {
+            write_tag_value(buf, v, &d.typ)?;
+        }
+        for aln in &rec.alns {
+            for (v, d) in aln.iter().zip(self.header.aln_tags.iter()) {
+                write_tag_value(buf, v, &d.typ)?;
+            }
+        }
+        self.chunk_records += 1;
+        if self.chunk_records >= RECORDS_PER_CHUNK {
+            self.flush_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn flush_chunk(&mut self) -> Result<()> {
+        if self.chunk_records == 0 {
+            return Ok(());
+        }
+        // the number of bytes in a chunk includes its own 8 byte header
+        let nbytes = (self.chunk_buf.len() + 8) as u32;
+        self.writer.write_all(&nbytes.to_le_bytes())?;
+        self.writer.write_all(&self.chunk_records.to_le_bytes())?;
+        self.writer.write_all(&self.chunk_buf)?;
+        self.chunk_buf.clear();
+        self.chunk_records = 0;
+        self.num_chunks += 1;
+        Ok(())
+    }
+
+    /// Write out any pending records and record the number of chunks in
+    /// the header. Returns the number of chunks written.
+    pub fn finish(mut self) -> Result<u64> {
+        self.flush_chunk()?;
+        self.writer.flush()?;
+        let mut f = self.writer.into_inner()?;
+        f.seek(SeekFrom::Start(self.num_chunks_offset))?;
+        f.write_all(&self.num_chunks.to_le_bytes())?;
+        Ok(self.num_chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> RadHeader {
+        RadHeader {
+            is_paired: false,
+            ref_names: vec!["tx1".to_string(), "tx2".to_string()],
+            num_chunks: 0,
+            file_tags: vec![
+                TagDesc {
+                    name: "cblen".to_string(),
+                    typ: TagType::U16,
+                },
+                TagDesc {
+                    name: "ref_lengths".to_string(),
+                    typ: TagType::Array(Box::new(TagType::U32), Box::new(TagType::U32)),
+                },
+            ],
+            read_tags: vec![
+                TagDesc {
+                    name: "b".to_string(),
+                    typ: TagType::U32,
+                },
+                TagDesc {
+                    name: "u".to_string(),
+                    typ: TagType::U64,
+                },
+            ],
+            aln_tags: vec![TagDesc {
+                name: "compressed_ori_refid".to_string(),
+                typ: TagType::U32,
+            }],
+            file_tag_values: vec![
+                TagValue::U16(16),
+                TagValue::Array(vec![TagValue::U32(1500), TagValue::U32(800)]),
+            ],
+        }
+    }
+
+    fn record(i: u32) -> RadRecord {
+        RadRecord {
+            read_tags: vec![TagValue::U32(i), TagValue::U64(u64::from(i) * 7)],
+            alns: (0..(i % 3))
+                .map(|j| vec![TagValue::U32(j | 0x8000_0000)])
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn round_trips_through_writer_and_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.rad");
+        let num_records = RECORDS_PER_CHUNK * 2 + 17;
+        let mut writer = RadWriter::create(&path, &header()).unwrap();
+        for i in 0..num_records {
+            writer.write_record(&record(i)).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), 3);
+
+        let mut reader = RadReader::from_path(&path).unwrap();
+        let expected = header();
+        assert!(!reader.header.is_paired);
+        assert_eq!(reader.header.ref_names, expected.ref_names);
+        assert_eq!(reader.header.num_chunks, 3);
+        assert_eq!(reader.header.file_tags, expected.file_tags);
+        assert_eq!(reader.header.read_tags, expected.read_tags);
+        assert_eq!(reader.header.aln_tags, expected.aln_tags);
+        assert_eq!(reader.header.file_tag_values, expected.file_tag_values);
+        for i in 0..num_records {
+            let rec = reader.next_record().unwrap().unwrap();
+            let expected = record(i);
+            assert_eq!(rec.read_tags, expected.read_tags);
+            assert_eq!(rec.alns, expected.alns);
+        }
+        assert!(reader.next_record().unwrap().is_none());
+        assert_eq!(reader.chunks_read(), 3);
+    }
+
+    #[test]
+    fn chunk_sizes_include_the_chunk_header() {
+        let dir = tempfile::tempdir().unwrap();
+        // a file without records holds the header alone
+        let empty = dir.path().join("empty.rad");
+        RadWriter::create(&empty, &header())
+            .unwrap()
+            .finish()
+            .unwrap();
+        let header_len = std::fs::metadata(&empty).unwrap().len() as usize;
+
+        let path = dir.path().join("map.rad");
+        let num_records = RECORDS_PER_CHUNK + 1;
+        let mut writer = RadWriter::create(&path, &header()).unwrap();
+        for i in 0..num_records {
+            writer.write_record(&record(i)).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        let le_u32 = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let mut offset = header_len;
+        let mut chunk_records = Vec::new();
+        while offset < bytes.len() {
+            let nbytes = le_u32(offset) as usize;
+            chunk_records.push(le_u32(offset + 4));
+            offset += nbytes;
+        }
+        assert_eq!(offset, bytes.len());
+        assert_eq!(chunk_records, vec![RECORDS_PER_CHUNK, 1]);
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::path::Path;
+
+use crate::rad::{self, RadReader, TagDesc, TagValue};
+
+/// Convert the RAD tag value `v` into the corresponding JSON value.
+fn tag_value_to_json(v: &TagValue) -> Value {
+    match v {
+        TagValue::Bool(x) => json!(x),
+        TagValue::U8(x) => json!(x),
+        TagValue::U16(x) => json!(x),
+        TagValue::U32(x) => json!(x),
+        TagValue::U64(x) => json!(x),
+        TagValue::F32(x) => json!(x),
+        TagValue::F64(x) => json!(x),
+        TagValue::Array(xs) => Value::Array(xs.iter().map(tag_value_to_json).collect()),
+        TagValue::String(x) => json!(x),
+    }
+}
+
+fn tag_descs_to_json(descs: &[TagDesc]) -> Value {
+    descs
+        .iter()
+        .map(|d| json!({ "name": d.name, "type": d.typ.to_string() }))
+        .collect()
+}
+
+/// Read the RAD file at `path` (or the `map.rad` file of the output directory
+/// `path`) in full and summarize it: its header, tag schema, the number of
+/// chunks, records and alignments, and the number of alignments to each
+/// reference that has any.
+pub(crate) fn summarize_rad(path: &Path) -> Result<Value> {
+    let path = rad::resolve_rad_path(path)?;
+    let mut rad = RadReader::from_path(&path)?;
+
+    let mut ref_hits = vec![0_u64; rad.header.ref_names.len()];
+    let mut num_records = 0_u64;
+    let mut num_alignments = 0_u64;
+    while let Some(rec) = rad
+        .next_record()
+        .with_context(|| format!("could not read a record of RAD file {}", path.display()))?
+    {
+        num_records += 1;
+        num_alignments += rec.alns.len() as u64;
+        for r in rec.ref_ids() {
+            if let Some(h) = ref_hits.get_mut(r as usize) {
+                *h += 1;
+            }
+        }
+    }
+
+    let header = &rad.header;
+    let file_tags = header
+        .file_tags
+        .iter()
+        .zip(header.file_tag_values.iter())
+        .map(|(d, v)| {
+            json!({
+                "name": d.name,
+                "type": d.typ.to_string(),
+                "value": tag_value_to_json(v),
+            })
+        })
+        .collect::<Vec<Value>>();
+    let hits = ref_hits
+        .iter()
+        .enumerate()
+        .filter(|(_, h)| **h > 0)
+        .map(|(i, h)| json!({ "name": header.ref_names[i], "hits": h }))
+        .collect::<Vec<Value>>();
+
+    Ok(json!({
+        "path": path.display().to_string(),
+        "is_paired": header.is_paired,
+        "num_refs": header.ref_names.len(),
+        // 0 if the mapper didn't record the number of chunks in the header
+        "header_num_chunks": header.num_chunks,
+        "num_chunks": rad.chunks_read(),
+        "num_records": num_records,
+        "num_alignments": num_alignments,
+        "file_tags": file_tags,
+        "read_tags": tag_descs_to_json(&header.read_tags),
+        "aln_tags": tag_descs_to_json(&header.aln_tags),
+        "num_refs_hit": hits.len(),
+        "ref_hits": hits,
+    }))
+}
+
+/// Render the RAD file summary `summary`, as returned by `summarize_rad`, as
+/// text, listing the `top` references with the most alignments (all of them if
+/// `top` is 0).
+pub(crate) fn format_summary(summary: &Value, top: usize) -> String {
+    let val = |k: &str| match summary.get(k) {
+        Some(Value::Null) | None => String::from("unknown"),
+        Some(Value::String(s)) => s.clone(),
+        Some(v) => v.to_string(),
+    };
+    let mut out = String::new();
+    for (k, label) in [
+        ("path", "RAD file"),
+        ("is_paired", "paired-end"),
+        ("num_refs", "number of references"),
+        ("header_num_chunks", "chunks (header)"),
+        ("num_chunks", "chunks (read)"),
+        ("num_records", "number of records"),
+        ("num_alignments", "number of alignments"),
+        ("num_refs_hit", "references with hits"),
+    ] {
+        out += &format!("{:<26}{}\n", label, val(k));
+    }
+
+    for (k, label) in [
+        ("file_tags", "file-level tags"),
+        ("read_tags", "read-level tags"),
+        ("aln_tags", "alignment-level tags"),
+    ] {
+        out += &format!("{}:\n", label);
+        for t in summary
+            .get(k)
+            .and_then(|t| t.as_array())
+            .into_iter()
+            .flatten()
+        {
+            let name = t.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+            let typ = t.get("type").and_then(|n| n.as_str()).unwrap_or_default();
+            match t.get("value") {
+                Some(v) => out += &format!("  {:<24}{:<24}{}\n", name, typ, v),
+                None => out += &format!("  {:<24}{}\n", name, typ),
+            }
+        }
+    }
+
+    let mut hits = summary
+        .get("ref_hits")
+        .and_then(|h| h.as_array())
+        .map(|h| {
+            h.iter()
+                .map(|r| {
+                    (
+                        r.get("name").and_then(|n| n.as_str()).unwrap_or_default(),
+                        r.get("hits").and_then(|n| n.as_u64()).unwrap_or_default(),
+                    )
+                })
+                .collect::<Vec<(&str, u64)>>()
+        })
+        .unwrap_or_default();
+    hits.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    if top > 0 && hits.len() > top {
+        out += &format!("alignments per reference (top {}):\n", top);
+        hits.truncate(top);
+    } else {
+        out += "alignments per reference:\n";
+    }
+    for (name, h) in hits {
+        out += &format!("  {:<50}{}\n", name, h);
+    }
+    out
+}
@@ -0,0 +1,201 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::exit_code::{bail_with, ExitKind};
+use crate::piscem_commands::RadMergeOpts;
+use crate::rad::{self, RadHeader, RadReader};
+use crate::{inspect, map_info, read_groups, refinfo};
+
+/// A RAD file to merge, along with the mapping summary written next to it.
+struct MergeInput {
+    rad: PathBuf,
+    map_info: Option<PathBuf>,
+    is_dir: bool,
+}
+
+impl MergeInput {
+    /// The RAD file given as `path` on the command line: the `map.rad` file of a
+    /// `map-sc` or `map-sc-atac` output directory, the RAD file of a `map-bulk`
+    /// output stem, or the file itself.
+    fn resolve(path: &Path) -> Result<Self> {
+        let stem_rad = rad::bulk_rad_path(path);
+        Ok(if path.is_dir() {
+            Self {
+                rad: rad::resolve_rad_path(path)?,
+                map_info: Some(path.join(map_info::SC_MAP_INFO_FILE)),
+                is_dir: true,
+            }
+        } else if !path.exists() && stem_rad.exists() {
+            Self {
+                rad: stem_rad,
+                map_info: Some(map_info::bulk_map_info_path(path)),
+                is_dir: false,
+            }
+        } else {
+            Self {
+                rad: rad::resolve_rad_path(path)?,
+                map_info: None,
+                is_dir: false,
+            }
+        })
+    }
+}
+
+/// The SHA-256 digest of the reference names `names`, in order, as a lowercase
+/// hexadecimal string.
+fn ref_names_digest(names: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    for name in names {
+        hasher.update(name.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Check that the header `h` of the RAD file `path` can be merged with the header
+/// `first` of the RAD file `first_path`: both must be of the same kind of reads,
+/// have the same tags (and file-level tag values) and references with the same
+/// digest.
+fn check_compatible(
+    h: &RadHeader,
+    path: &Path,
+    first: &RadHeader,
+    first_path: &Path,
+) -> Result<()> {
+    let mismatch = if h.is_paired != first.is_paired {
+        Some("paired-end status")
+    } else if h.read_tags != first.read_tags || h.aln_tags != first.aln_tags {
+        Some("read or alignment tags")
+    } else if h.file_tags != first.file_tags || h.file_tag_values != first.file_tag_values {
+        Some("file-level tags")
+    } else if ref_names_digest(&h.ref_names) != ref_names_digest(&first.ref_names) {
+        Some("references")
+    } else {
+        None
+    };
+    if let Some(what) = mismatch {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "RAD file {} does not have the same {} as {}; only files mapped against the same index, with the same options, can be merged.",
+            path.display(),
+            what,
+            first_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Merge the RAD files given in `opts` into one, after checking that they were
+/// mapped against the same index (and, if the index is given, against that index).
+/// If the inputs are output directories, the merged RAD file and mapping summary
+/// are written into the output directory; otherwise, they are written to the
+/// output stem. The mapping summaries of the inputs, where present, are merged
+/// as those of read groups (see `read_groups::merge_map_infos`). Returns a
+/// summary of the merge, which is also recorded under `rad_merge` in the merged
+/// mapping summary.
+pub(crate) fn rad_merge(opts: &RadMergeOpts) -> Result<Value> {
+    let inputs = opts
+        .inputs
+        .iter()
+        .map(|p| MergeInput::resolve(p))
+        .collect::<Result<Vec<MergeInput>>>()?;
+    let out_dir = inputs.iter().all(|i| i.is_dir);
+    if !out_dir && inputs.iter().any(|i| i.is_dir) {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "output directories of `map-sc` or `map-sc-atac` can't be merged with other RAD files."
+        );
+    }
+    if let Some(ref labels) = opts.labels {
+        let groups = read_groups::group_files(labels, inputs.len())?;
+        if groups.len() != labels.len() {
+            bail_with!(
+                ExitKind::InvalidArguments,
+                "the labels of the inputs must be distinct."
+            );
+        }
+    }
+
+    let first = RadReader::from_path(&inputs[0].rad)?.header;
+    for input in &inputs[1..] {
+        let h = RadReader::from_path(&input.rad)?.header;
+        check_compatible(&h, &input.rad, &first, &inputs[0].rad)?;
+    }
+    let digest = ref_names_digest(&first.ref_names);
+    if let Some(ref index) = opts.index {
+        let refs = refinfo::read_refinfo(&inspect::component_path(index, ".refinfo"))?;
+        if ref_names_digest(&refs.names) != digest {
+            bail_with!(
+                ExitKind::InvalidArguments,
+                "the references of the RAD files are not those of index {}.",
+                index
+            );
+        }
+    }
+
+    let (out_rad, out_info) = if out_dir {
+        std::fs::create_dir_all(&opts.output)
+            .with_context(|| format!("could not create {}", opts.output.display()))?;
+        (
+            opts.output.join(rad::SC_RAD_FILE),
+            opts.output.join(map_info::SC_MAP_INFO_FILE),
+        )
+    } else {
+        (
+            rad::bulk_rad_path(&opts.output),
+            map_info::bulk_map_info_path(&opts.output),
+        )
+    };
+    if let Ok(out) = out_rad.canonicalize() {
+        if inputs
+            .iter()
+            .any(|i| i.rad.canonicalize().is_ok_and(|p| p == out))
+        {
+            bail_with!(
+                ExitKind::InvalidArguments,
+                "the merged RAD file {} would overwrite one of the inputs.",
+                out_rad.display()
+            );
+        }
+    }
+
+    let rads = inputs
+        .iter()
+        .map(|i| i.rad.clone())
+        .collect::<Vec<PathBuf>>();
+    let num_records = read_groups::merge_rad_files(&rads, opts.labels.as_deref(), &out_rad)?;
+    let summary = json!({
+        "inputs": opts.inputs,
+        "num_records": num_records,
+        "ref_names_sha256": digest,
+        "labels": opts.labels,
+    });
+    let infos = inputs
+        .iter()
+        .filter_map(|i| i.map_info.clone().filter(|p| p.exists()))
+        .collect::<Vec<PathBuf>>();
+    if infos.len() == inputs.len() {
+        let labels = opts.labels.clone().unwrap_or_else(|| {
+            opts.inputs
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect()
+        });
+        read_groups::merge_map_infos(&infos, &labels, &num_records, "merged_inputs", &out_info)?;
+        map_info::add_map_info_entry(&out_info, "rad_merge", summary.clone())?;
+    }
+    info!(
+        "merged {} RAD files ({} records) into {}.",
+        inputs.len(),
+        num_records.iter().sum::<u64>(),
+        out_rad.display()
+    );
+    Ok(summary)
+}
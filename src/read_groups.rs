@@ -0,0 +1,175 @@
+use anyhow::{bail, Result};
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+
+use crate::exit_code::{bail_with, ExitKind};
+use crate::map_info;
+use crate::rad::{RadReader, RadWriter, TagDesc, TagType, TagValue};
+
+/// The read-level tag holding the index of the read group of a record, in the
+/// labels of the `read_groups` file-level tag.
+pub(crate) const READ_GROUP_TAG: &str = "rg";
+
+/// The file-level tag holding the labels of the read groups.
+pub(crate) const READ_GROUPS_FILE_TAG: &str = "read_groups";
+
+/// Group the `num_files` read files (or pairs of files) by their labels `labels`,
+/// one per file. Returns the distinct labels, in the order in which they are first
+/// given, along with the indices of the files of each.
+pub(crate) fn group_files(
+    labels: &[String],
+    num_files: usize,
+) -> Result<Vec<(String, Vec<usize>)>> {
+    if labels.len() != num_files {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "the number of read group labels ({}) must match the number of read files ({}).",
+            labels.len(),
+            num_files
+        );
+    }
+    if labels.iter().any(|l| l.is_empty()) {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "read group labels must not be empty."
+        );
+    }
+    let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+    for (i, label) in labels.iter().enumerate() {
+        match groups.iter_mut().find(|(l, _)| l == label) {
+            Some((_, files)) => files.push(i),
+            None => groups.push((label.clone(), vec![i])),
+        }
+    }
+    if groups.len() > u16::MAX as usize {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "at most {} read groups can be given.",
+            u16::MAX
+        );
+    }
+    Ok(groups)
+}
+
+/// The output stem of the mapping of the `i`-th read group, for the output stem `output`.
+pub(crate) fn part_stem(output: &Path, i: usize) -> PathBuf {
+    PathBuf::from(output.to_string_lossy().into_owned() + &format!(".rg{}", i))
+}
+
+/// Merge the RAD files `parts`, which must have the same references and tags,
+/// into the RAD file `out`. If `labels` are given, the index of the part each
+/// record comes from is recorded in the read-level `rg` tag, and the labels of
+/// the parts in the file-level `read_groups` tag. Returns the number of records
+/// of each part.
+pub(crate) fn merge_rad_files(
+    parts: &[PathBuf],
+    labels: Option<&[String]>,
+    out: &Path,
+) -> Result<Vec<u64>> {
+    let Some(first) = parts.first() else {
+        bail!("there are no RAD files to merge.");
+    };
+    let mut header = RadReader::from_path(first)?.header;
+    let num_read_tags = header.read_tags.len();
+    if let Some(labels) = labels {
+        if header.read_tags.iter().any(|t| t.name == READ_GROUP_TAG) {
+            bail!("RAD file {} already records read groups.", first.display());
+        }
+        header.read_tags.push(TagDesc {
+            name: READ_GROUP_TAG.to_string(),
+            typ: TagType::U16,
+        });
+        header.file_tags.push(TagDesc {
+            name: READ_GROUPS_FILE_TAG.to_string(),
+            typ: TagType::Array(Box::new(TagType::U16), Box::new(TagType::String)),
+        });
+        header.file_tag_values.push(TagValue::Array(
+            labels.iter().map(|l| TagValue::String(l.clone())).collect(),
+        ));
+    }
+    header.num_chunks = 0;
+
+    let mut writer = RadWriter::create(out, &header)?;
+    let mut num_records = Vec::with_capacity(parts.len());
+    for (i, part) in parts.iter().enumerate() {
+        let mut rad = RadReader::from_path(part)?;
+        let h = &rad.header;
+        if h.ref_names != header.ref_names
+            || h.is_paired != header.is_paired
+            || h.aln_tags != header.aln_tags
+            || h.read_tags[..] != header.read_tags[..num_read_tags]
+        {
+            bail!(
+                "RAD file {} does not have the same references and tags as {}.",
+                part.display(),
+                first.display()
+            );
+        }
+        let mut n = 0_u64;
+        while let Some(mut rec) = rad.next_record()? {
+            if labels.is_some() {
+                rec.read_tags.push(TagValue::U16(i as u16));
+            }
+            writer.write_record(&rec)?;
+            n += 1;
+        }
+        num_records.push(n);
+    }
+    writer.finish()?;
+    Ok(num_records)
+}
+
+/// Merge the mapping summaries `parts` of the mappings labelled `labels`, whose
+/// RAD files hold `num_records` records, into the mapping summary at `out`. The
+/// counts reported by the mapper for every part are summed (and the mapping rate
+/// is recomputed from them), and the summary of each part is recorded under `key`.
+pub(crate) fn merge_map_infos(
+    parts: &[PathBuf],
+    labels: &[String],
+    num_records: &[u64],
+    key: &str,
+    out: &Path,
+) -> Result<()> {
+    let infos = parts
+        .iter()
+        .map(|p| map_info::read_map_info(p))
+        .collect::<Result<Vec<Map<String, Value>>>>()?;
+    let mut merged = infos.first().cloned().unwrap_or_default();
+    for (key, value) in merged.iter_mut() {
+        if value.is_u64() {
+            let total = infos
+                .iter()
+                .map(|i| i.get(key).and_then(|v| v.as_u64()))
+                .sum::<Option<u64>>();
+            if let Some(t) = total {
+                *value = json!(t);
+            }
+        }
+    }
+    let num = |k: &str| merged.get(k).and_then(|v| v.as_u64());
+    if let (Some(total), Some(mapped), true) = (
+        num("num_reads"),
+        num("num_mapped"),
+        merged.contains_key("percent_mapped"),
+    ) {
+        let pct = if total > 0 {
+            mapped as f64 / total as f64 * 100.0
+        } else {
+            0.0
+        };
+        merged.insert("percent_mapped".to_string(), json!(pct));
+    }
+    merged.insert(
+        key.to_string(),
+        Value::Array(
+            labels
+                .iter()
+                .zip(num_records.iter())
+                .zip(infos)
+                .map(|((l, n), i)| json!({ "label": l, "num_records": n, "map_info": i }))
+                .collect(),
+        ),
+    );
+    std::fs::write(out, serde_json::to_string_pretty(&merged)?)?;
+    Ok(())
+}
@@ -0,0 +1,802 @@
+use anyhow::{bail, Context, Result};
+use needletail::parse_fastx_file;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::thread;
+use tempfile::TempDir;
+use tracing::info;
+
+use crate::geometry::{self, TagPiece};
+use crate::{fifo_reads, inspect};
+use crate::{MapBulkConfig, MapSCAtacConfig, MapSCConfig};
+
+/// The 3' adapters of the adapter presets, for read 1 and read 2.
+const ADAPTER_PRESETS: &[(&str, &str, &str)] = &[
+    (
+        "truseq",
+        "AGATCGGAAGAGCACACGTCTGAACTCCAGTCA",
+        "AGATCGGAAGAGCGTCGTGTAGGGAAAGAGTGT",
+    ),
+    ("nextera", "CTGTCTCTTATACACATCT", "CTGTCTCTTATACACATCT"),
+];
+
+/// The minimum number of bases of an adapter that must overlap the end of a read
+/// for it to be clipped.
+const MIN_ADAPTER_OVERLAP: usize = 3;
+
+/// The maximum fraction of mismatches between a read and an adapter it overlaps.
+const ADAPTER_ERROR_RATE: f64 = 0.1;
+
+/// The 3' adapters for read 1 and read 2 of the preset `preset`, if any, and
+/// those given explicitly (`adapter1` and `adapter2`).
+fn adapters(preset: Option<&str>, adapter1: &[String], adapter2: &[String]) -> [Vec<Vec<u8>>; 2] {
+    let mut adapters = [
+        adapter1
+            .iter()
+            .map(|a| a.as_bytes().to_vec())
+            .collect::<Vec<_>>(),
+        adapter2
+            .iter()
+            .map(|a| a.as_bytes().to_vec())
+            .collect::<Vec<_>>(),
+    ];
+    if let Some(&(_, a1, a2)) = ADAPTER_PRESETS.iter().find(|p| Some(p.0) == preset) {
+        adapters[0].push(a1.as_bytes().to_vec());
+        adapters[1].push(a2.as_bytes().to_vec());
+    }
+    adapters
+}
+
+/// The position in `seq` at which the 3' adapter `adapter` starts, if any. The
+/// adapter may extend past the end of the read, so that its first bases at the end
+/// of a read are found too, as long as at least `MIN_ADAPTER_OVERLAP` bases overlap;
+/// up to `ADAPTER_ERROR_RATE` of the overlapping bases may mismatch.
+fn find_adapter(seq: &[u8], adapter: &[u8]) -> Option<usize> {
+    for pos in 0..seq.len() {
+        let overlap = (seq.len() - pos).min(adapter.len());
+        if overlap < MIN_ADAPTER_OVERLAP {
+            break;
+        }
+        let max_mismatches = (overlap as f64 * ADAPTER_ERROR_RATE) as usize;
+        let mismatches = seq[pos..pos + overlap]
+            .iter()
+            .zip(adapter)
+            .filter(|(a, b)| !a.eq_ignore_ascii_case(b))
+            .count();
+        if mismatches <= max_mismatches {
+            return Some(pos);
+        }
+    }
+    None
+}
+
+/// The bases of the homopolymer tails to clip from read 1 and read 2: polyA
+/// and/or polyG, if `polya` and `polyg` (respectively) are set, but only from
+/// read 2 if `read1` is not set.
+fn poly_tails(polya: bool, polyg: bool, read1: bool) -> [Vec<u8>; 2] {
+    let bases = [(polya, b'A'), (polyg, b'G')]
+        .iter()
+        .filter(|(clip, _)| *clip)
+        .map(|(_, b)| *b)
+        .collect::<Vec<u8>>();
+    [if read1 { bases.clone() } else { Vec::new() }, bases]
+}
+
+/// The length of `seq` once any tails of at least `min_len` repeats of one of
+/// `bases` are removed from its end.
+fn clip_poly_tails(seq: &[u8], bases: &[u8], min_len: usize) -> usize {
+    let mut end = seq.len();
+    loop {
+        let clipped = bases.iter().find_map(|b| {
+            let run = seq[..end]
+                .iter()
+                .rev()
+                .take_while(|c| c.eq_ignore_ascii_case(b))
+                .count();
+            (run >= min_len).then_some(run)
+        });
+        match clipped {
+            Some(run) => end -= run,
+            None => return end,
+        }
+    }
+}
+
+/// Bases with a quality below this are counted as low quality by the quality filter.
+const LOW_QUALITY: u8 = 20;
+
+/// The offset of the quality scores of FASTQ records.
+const PHRED_OFFSET: u8 = 33;
+
+/// What the records of a list of read files hold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ReadKind {
+    /// read 1 (or unpaired reads)
+    Mate1,
+    Mate2,
+    /// the barcodes of single-cell ATAC-seq reads, which are passed on as they are
+    Barcode,
+}
+
+/// What is done with a read whose barcode or UMI contains Ns.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum NPolicy {
+    /// the read is discarded
+    Discard,
+    /// a single N in the barcode or the UMI is replaced with an A; reads with
+    /// more Ns are discarded
+    Correct,
+    /// all Ns are replaced with As, so that the read is kept
+    Keep,
+}
+
+/// The `--bc-n-policy` applied to the barcode and UMI pieces `pieces` of the reads.
+#[derive(Clone, Debug)]
+pub(crate) struct BarcodeNs {
+    pub policy: NPolicy,
+    pub pieces: Vec<TagPiece>,
+}
+
+impl BarcodeNs {
+    /// The policy `policy` (`discard`, `correct` or `keep`) for the reads of the
+    /// geometry `geometry`, which must be a simple geometry, i.e. one whose
+    /// barcodes and UMIs are at fixed positions.
+    pub fn new(policy: &str, geometry: &str) -> Result<Self> {
+        let policy = match policy {
+            "discard" => NPolicy::Discard,
+            "correct" => NPolicy::Correct,
+            "keep" => NPolicy::Keep,
+            _ => bail!("unknown barcode N policy {}.", policy),
+        };
+        match geometry::fixed_tag_pieces(geometry) {
+            Some(pieces) => Ok(Self { policy, pieces }),
+            None => bail!(
+                "the positions of the barcode and UMI of geometry {} are not fixed.",
+                geometry
+            ),
+        }
+    }
+
+    /// Apply the policy to the sequences `seqs` of a fragment, whose kinds are
+    /// `kinds`. Returns `None` if the fragment is to be discarded, and otherwise
+    /// whether any N was replaced.
+    fn apply(&self, seqs: &mut [Cow<[u8]>], kinds: &[ReadKind]) -> Option<bool> {
+        let mut num_ns = [0_usize; 2];
+        for p in &self.pieces {
+            if let Some(seq) = mate_seq(seqs, kinds, p.mate) {
+                let end = (p.start + p.len).min(seq.len());
+                let start = p.start.min(end);
+                num_ns[p.is_umi as usize] += seq[start..end]
+                    .iter()
+                    .filter(|c| c.eq_ignore_ascii_case(&b'N'))
+                    .count();
+            }
+        }
+        if num_ns == [0, 0] {
+            return Some(false);
+        }
+        match self.policy {
+            NPolicy::Discard => return None,
+            NPolicy::Correct if num_ns.iter().any(|n| *n > 1) => return None,
+            _ => {}
+        }
+        for p in &self.pieces {
+            let idx = match kinds.iter().position(|k| *k == mate_kind(p.mate)) {
+                Some(i) => i,
+                None => continue,
+            };
+            let seq = seqs[idx].to_mut();
+            let end = (p.start + p.len).min(seq.len());
+            let start = p.start.min(end);
+            for c in &mut seq[start..end] {
+                if c.eq_ignore_ascii_case(&b'N') {
+                    *c = b'A';
+                }
+            }
+        }
+        Some(true)
+    }
+}
+
+/// The kind of the reads of the mate `mate` (0 for read 1, 1 for read 2).
+fn mate_kind(mate: usize) -> ReadKind {
+    if mate == 0 {
+        ReadKind::Mate1
+    } else {
+        ReadKind::Mate2
+    }
+}
+
+/// The sequence, among `seqs` of the kinds `kinds`, of the mate `mate`.
+fn mate_seq<'a>(seqs: &'a [Cow<[u8]>], kinds: &[ReadKind], mate: usize) -> Option<&'a [u8]> {
+    let idx = kinds.iter().position(|k| *k == mate_kind(mate))?;
+    Some(&seqs[idx])
+}
+
+/// How the reads are processed by piscem before they are passed to the mapper.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Preprocessing {
+    /// the fraction of the fragments to keep, and the seed with which they are chosen
+    pub subsample: Option<(f64, u64)>,
+    /// the number of bases trimmed from the front and the tail of read 1 and read 2
+    pub trim: [(usize, usize); 2],
+    /// the 3' adapters clipped from read 1 and read 2
+    pub adapters: [Vec<Vec<u8>>; 2],
+    /// the bases whose homopolymer tails are clipped from read 1 and read 2
+    pub poly_tails: [Vec<u8>; 2],
+    /// the minimum length of a homopolymer tail to be clipped
+    pub poly_min_len: usize,
+    /// whether the quality and length of read 1 and read 2 are checked by the filters
+    pub filtered_mates: [bool; 2],
+    /// the minimum mean base quality of a read
+    pub min_mean_qual: Option<f64>,
+    /// the maximum fraction of the bases of a read with a quality below `LOW_QUALITY`
+    pub max_low_qual_frac: Option<f64>,
+    /// the minimum length of a read; 0 stands for the k-mer length of the index
+    /// until it is resolved by `resolve_min_read_len`
+    pub min_read_len: Option<usize>,
+    /// what is done with reads whose barcode or UMI contains Ns
+    pub barcode_ns: Option<BarcodeNs>,
+}
+
+impl Preprocessing {
+    /// Whether the reads need to be processed at all.
+    pub fn is_active(&self) -> bool {
+        self.subsample.is_some()
+            || self.trim.iter().any(|&(f, t)| f > 0 || t > 0)
+            || self.adapters.iter().any(|a| !a.is_empty())
+            || self.poly_tails.iter().any(|p| !p.is_empty())
+            || self.filters_quality()
+            || self.min_read_len.is_some()
+            || self.barcode_ns.is_some()
+    }
+
+    /// Replace a minimum read length of 0 with the k-mer length of the index
+    /// with the prefix `index`, since shorter reads can't contain any k-mer.
+    pub fn resolve_min_read_len(&mut self, index: &str) -> Result<()> {
+        if self.min_read_len == Some(0) {
+            let params = inspect::read_dict_params(&inspect::component_path(index, ".sshash"))?;
+            match params {
+                Some(p) => self.min_read_len = Some(p.k as usize),
+                None => bail!(
+                    "could not read the k-mer length of the index {} for --min-read-len.",
+                    index
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the (processed) read of kind `kind` with the length `len` is long
+    /// enough to be kept.
+    fn passes_length(&self, kind: ReadKind, len: usize) -> bool {
+        let checked = match kind {
+            ReadKind::Mate1 => self.filtered_mates[0],
+            ReadKind::Mate2 => self.filtered_mates[1],
+            ReadKind::Barcode => false,
+        };
+        !checked || self.min_read_len.is_none_or(|min| len >= min)
+    }
+
+    /// Whether any reads are filtered by their quality.
+    fn filters_quality(&self) -> bool {
+        self.min_mean_qual.is_some() || self.max_low_qual_frac.is_some()
+    }
+
+    /// Whether the (processed) read of kind `kind` with the qualities `qual`
+    /// passes the quality filter. Reads without qualities (i.e. FASTA records)
+    /// and empty reads always pass it.
+    fn passes_quality(&self, kind: ReadKind, qual: Option<&[u8]>) -> bool {
+        let checked = match kind {
+            ReadKind::Mate1 => self.filtered_mates[0],
+            ReadKind::Mate2 => self.filtered_mates[1],
+            ReadKind::Barcode => false,
+        };
+        let qual = match qual {
+            Some(q) if checked && !q.is_empty() => q,
+            _ => return true,
+        };
+        let len = qual.len() as f64;
+        if let Some(min) = self.min_mean_qual {
+            let sum = qual
+                .iter()
+                .map(|q| q.saturating_sub(PHRED_OFFSET) as u64)
+                .sum::<u64>();
+            if (sum as f64) / len < min {
+                return false;
+            }
+        }
+        if let Some(max) = self.max_low_qual_frac {
+            let low = qual
+                .iter()
+                .filter(|q| q.saturating_sub(PHRED_OFFSET) < LOW_QUALITY)
+                .count();
+            if (low as f64) / len > max {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// The range of the bases of the read `seq`, of kind `kind`, that are kept:
+    /// the fixed numbers of bases are trimmed from either end, the rest is
+    /// clipped at the first adapter found in it, and then any homopolymer tail is
+    /// clipped. Reads shorter than the trimmed bases become empty.
+    fn kept_range(
+        &self,
+        kind: ReadKind,
+        seq: &[u8],
+        stats: &mut PreprocessStats,
+    ) -> std::ops::Range<usize> {
+        let mate = match kind {
+            ReadKind::Mate1 => 0,
+            ReadKind::Mate2 => 1,
+            ReadKind::Barcode => return 0..seq.len(),
+        };
+        let (front, tail) = self.trim[mate];
+        let start = front.min(seq.len());
+        let mut end = seq.len().saturating_sub(tail).max(start);
+        if let Some(pos) = self.adapters[mate]
+            .iter()
+            .filter_map(|a| find_adapter(&seq[start..end], a))
+            .min()
+        {
+            end = start + pos;
+            stats.num_adapter_clipped[mate] += 1;
+        }
+        if !self.poly_tails[mate].is_empty() {
+            let len = clip_poly_tails(&seq[start..end], &self.poly_tails[mate], self.poly_min_len);
+            if start + len < end {
+                end = start + len;
+                stats.num_poly_clipped[mate] += 1;
+            }
+        }
+        start..end
+    }
+
+    /// Whether the `index`-th fragment (counting from 0) is kept by the subsampling.
+    fn keep_fragment(&self, index: u64) -> bool {
+        match self.subsample {
+            Some((fraction, seed)) => {
+                // the top 53 bits give a uniform value in [0, 1)
+                let r = splitmix64(seed ^ splitmix64(index)) >> 11;
+                (r as f64) / ((1_u64 << 53) as f64) < fraction
+            }
+            None => true,
+        }
+    }
+}
+
+impl From<&MapSCConfig> for Preprocessing {
+    fn from(opts: &MapSCConfig) -> Self {
+        Self {
+            subsample: opts.subsample_fraction.map(|f| (f, opts.subsample_seed)),
+            trim: [
+                (opts.trim_front1, opts.trim_tail1),
+                (opts.trim_front2, opts.trim_tail2),
+            ],
+            adapters: adapters(opts.adapter_preset.as_deref(), &[], &opts.adapter2),
+            poly_tails: poly_tails(opts.clip_polya, opts.clip_polyg, false),
+            poly_min_len: opts.clip_poly_min_len as usize,
+            filtered_mates: [false, true],
+            min_mean_qual: opts.min_mean_qual,
+            max_low_qual_frac: opts.max_low_qual_frac,
+            min_read_len: opts.min_read_len,
+            barcode_ns: None,
+        }
+    }
+}
+
+impl From<&MapSCAtacConfig> for Preprocessing {
+    fn from(opts: &MapSCAtacConfig) -> Self {
+        Self {
+            subsample: opts.subsample_fraction.map(|f| (f, opts.subsample_seed)),
+            trim: [
+                (opts.trim_front1, opts.trim_tail1),
+                (opts.trim_front2, opts.trim_tail2),
+            ],
+            adapters: adapters(
+                opts.adapter_preset.as_deref(),
+                &opts.adapter1,
+                &opts.adapter2,
+            ),
+            poly_tails: poly_tails(opts.clip_polya, opts.clip_polyg, true),
+            poly_min_len: opts.clip_poly_min_len as usize,
+            filtered_mates: [true, true],
+            min_mean_qual: opts.min_mean_qual,
+            max_low_qual_frac: opts.max_low_qual_frac,
+            min_read_len: opts.min_read_len,
+            barcode_ns: None,
+        }
+    }
+}
+
+impl From<&MapBulkConfig> for Preprocessing {
+    fn from(opts: &MapBulkConfig) -> Self {
+        Self {
+            subsample: opts.subsample_fraction.map(|f| (f, opts.subsample_seed)),
+            trim: [
+                (opts.trim_front1, opts.trim_tail1),
+                (opts.trim_front2, opts.trim_tail2),
+            ],
+            adapters: adapters(
+                opts.adapter_preset.as_deref(),
+                &opts.adapter1,
+                &opts.adapter2,
+            ),
+            poly_tails: poly_tails(opts.clip_polya, opts.clip_polyg, true),
+            poly_min_len: opts.clip_poly_min_len as usize,
+            filtered_mates: [true, true],
+            min_mean_qual: opts.min_mean_qual,
+            max_low_qual_frac: opts.max_low_qual_frac,
+            min_read_len: opts.min_read_len,
+            barcode_ns: None,
+        }
+    }
+}
+
+/// The SplitMix64 mixing function, which turns consecutive integers into
+/// (practically) independent, uniformly distributed values.
+pub(crate) fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// The number of fragments seen and passed on by the preprocessing.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PreprocessStats {
+    pub num_fragments: u64,
+    pub num_kept: u64,
+    /// the number of reads 1 and reads 2 clipped at an adapter
+    pub num_adapter_clipped: [u64; 2],
+    /// the number of reads 1 and reads 2 whose homopolymer tail was clipped
+    pub num_poly_clipped: [u64; 2],
+    /// the number of fragments discarded because of the quality of a read
+    pub num_low_quality: u64,
+    /// the number of fragments discarded because a read was too short
+    pub num_too_short: u64,
+    /// the number of fragments with Ns in their barcode or UMI, and the number of
+    /// those that were discarded and of those whose Ns were replaced
+    pub num_with_ns: u64,
+    pub num_ns_discarded: u64,
+    pub num_ns_replaced: u64,
+}
+
+impl PreprocessStats {
+    /// The summary recorded under `read_preprocessing` in `map_info.json`.
+    pub fn to_json(&self, pre: &Preprocessing) -> Value {
+        let mut summary = json!({
+            "num_fragments": self.num_fragments,
+            "num_kept": self.num_kept,
+        });
+        if let Some((fraction, seed)) = pre.subsample {
+            summary["subsample_fraction"] = json!(fraction);
+            summary["subsample_seed"] = json!(seed);
+        }
+        if pre.trim.iter().any(|&(f, t)| f > 0 || t > 0) {
+            summary["trim"] = json!({
+                "front1": pre.trim[0].0,
+                "tail1": pre.trim[0].1,
+                "front2": pre.trim[1].0,
+                "tail2": pre.trim[1].1,
+            });
+        }
+        if pre.adapters.iter().any(|a| !a.is_empty()) {
+            let seqs = |a: &[Vec<u8>]| {
+                a.iter()
+                    .map(|s| String::from_utf8_lossy(s).into_owned())
+                    .collect::<Vec<String>>()
+            };
+            summary["adapters"] = json!({
+                "read1": seqs(&pre.adapters[0]),
+                "read2": seqs(&pre.adapters[1]),
+                "num_clipped1": self.num_adapter_clipped[0],
+                "num_clipped2": self.num_adapter_clipped[1],
+            });
+        }
+        if pre.poly_tails.iter().any(|p| !p.is_empty()) {
+            summary["poly_tails"] = json!({
+                "read1": String::from_utf8_lossy(&pre.poly_tails[0]),
+                "read2": String::from_utf8_lossy(&pre.poly_tails[1]),
+                "min_len": pre.poly_min_len,
+                "num_clipped1": self.num_poly_clipped[0],
+                "num_clipped2": self.num_poly_clipped[1],
+            });
+        }
+        if pre.filters_quality() {
+            summary["quality_filter"] = json!({
+                "min_mean_qual": pre.min_mean_qual,
+                "max_low_qual_frac": pre.max_low_qual_frac,
+                "num_discarded": self.num_low_quality,
+            });
+        }
+        if let Some(min) = pre.min_read_len {
+            summary["length_filter"] = json!({
+                "min_read_len": min,
+                "num_discarded": self.num_too_short,
+            });
+        }
+        if let Some(ref ns) = pre.barcode_ns {
+            summary["barcode_n_policy"] = json!({
+                "policy": format!("{:?}", ns.policy).to_lowercase(),
+                "num_with_ns": self.num_with_ns,
+                "num_discarded": self.num_ns_discarded,
+                "num_replaced": self.num_ns_replaced,
+            });
+        }
+        summary
+    }
+}
+
+/// Read files that are being processed into fifos by a separate thread, from
+/// which the mapper reads them as though they were the original files.
+pub(crate) struct PreprocessedReads {
+    pre: Preprocessing,
+    join_handle: thread::JoinHandle<Result<PreprocessStats>>,
+}
+
+impl PreprocessedReads {
+    /// Wait for the thread processing the reads to finish, returning its summary.
+    pub fn join(self) -> Result<Value> {
+        let stats = match self.join_handle.join() {
+            Ok(r) => r.context("failed to preprocess the reads")?,
+            Err(_) => bail!("the thread preprocessing reads panicked"),
+        };
+        info!(
+            "passed {} of {} fragments on to the mapper.",
+            stats.num_kept, stats.num_fragments
+        );
+        Ok(stats.to_json(&self.pre))
+    }
+}
+
+/// Write a record with the name `id`, the sequence `seq` and, for FASTQ input,
+/// the qualities `qual` to `out`.
+fn write_record<W: Write>(out: &mut W, id: &[u8], seq: &[u8], qual: Option<&[u8]>) -> Result<()> {
+    match qual {
+        Some(q) => {
+            out.write_all(b"@")?;
+            out.write_all(id)?;
+            out.write_all(b"\n")?;
+            out.write_all(seq)?;
+            out.write_all(b"\n+\n")?;
+            out.write_all(q)?;
+        }
+        None => {
+            out.write_all(b">")?;
+            out.write_all(id)?;
+            out.write_all(b"\n")?;
+            out.write_all(seq)?;
+        }
+    }
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Process the reads of `lists` as described by `pre`, writing the records of
+/// each list into a fifo by a separate thread. The lists hold the files of the
+/// same fragments (e.g. read 1, read 2 and barcode files), so they are read in
+/// lockstep, and a fragment is either kept in all of them or dropped from all.
+fn preprocess_into(
+    lists: Vec<Vec<String>>,
+    kinds: Vec<ReadKind>,
+    fifos: Vec<PathBuf>,
+    pre: &Preprocessing,
+) -> Result<PreprocessStats> {
+    // the mapper may open the fifos in any order, and opening a fifo blocks
+    // until it is opened for reading, so they are opened concurrently
+    let openers = fifos
+        .into_iter()
+        .map(|f| thread::spawn(move || File::create(f)))
+        .collect::<Vec<_>>();
+    let mut outs = Vec::with_capacity(openers.len());
+    for o in openers {
+        match o.join() {
+            Ok(f) => outs.push(BufWriter::new(f?)),
+            Err(_) => bail!("the thread opening a fifo for the mapper panicked"),
+        }
+    }
+
+    let mut stats = PreprocessStats::default();
+    for i in 0..lists[0].len() {
+        let mut readers = lists
+            .iter()
+            .map(|l| parse_fastx_file(&l[i]).with_context(|| format!("could not open {}", l[i])))
+            .collect::<Result<Vec<_>>>()?;
+        loop {
+            let recs = readers.iter_mut().map(|r| r.next()).collect::<Vec<_>>();
+            if recs.iter().all(|r| r.is_none()) {
+                break;
+            }
+            if recs.iter().any(|r| r.is_none()) {
+                bail!(
+                    "the read files {} don't have the same number of records.",
+                    lists
+                        .iter()
+                        .map(|l| l[i].as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                );
+            }
+            // records are parsed before the subsampling, so that a malformed
+            // record is reported whether or not its fragment is kept
+            let recs = recs
+                .into_iter()
+                .map(|r| r.unwrap())
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            let index = stats.num_fragments;
+            stats.num_fragments += 1;
+            if !pre.keep_fragment(index) {
+                continue;
+            }
+            let mut seqs = recs.iter().map(|r| r.seq()).collect::<Vec<_>>();
+            let ranges = seqs
+                .iter()
+                .zip(&kinds)
+                .map(|(seq, kind)| pre.kept_range(*kind, seq, &mut stats))
+                .collect::<Vec<_>>();
+            if !kinds
+                .iter()
+                .zip(&ranges)
+                .all(|(kind, range)| pre.passes_length(*kind, range.len()))
+            {
+                stats.num_too_short += 1;
+                continue;
+            }
+            let quals = recs
+                .iter()
+                .zip(&ranges)
+                .map(|(r, range)| r.qual().map(|q| &q[range.clone()]))
+                .collect::<Vec<_>>();
+            if !kinds
+                .iter()
+                .zip(&quals)
+                .all(|(kind, qual)| pre.passes_quality(*kind, *qual))
+            {
+                stats.num_low_quality += 1;
+                continue;
+            }
+            if let Some(ref ns) = pre.barcode_ns {
+                match ns.apply(&mut seqs, &kinds) {
+                    Some(false) => {}
+                    Some(true) => {
+                        stats.num_with_ns += 1;
+                        stats.num_ns_replaced += 1;
+                    }
+                    None => {
+                        stats.num_with_ns += 1;
+                        stats.num_ns_discarded += 1;
+                        continue;
+                    }
+                }
+            }
+            for (m, out) in outs.iter_mut().enumerate() {
+                write_record(out, recs[m].id(), &seqs[m][ranges[m].clone()], quals[m])?;
+            }
+            stats.num_kept += 1;
+        }
+    }
+    for mut out in outs {
+        out.flush()?;
+    }
+    Ok(stats)
+}
+
+/// Process the reads in `lists`, each along with the kind of its records, as
+/// described by `pre` (e.g. subsample or trim them) before they are passed to
+/// the mapper. Every list is replaced with a single fifo into which its processed
+/// records are written by a separate thread; the lists must hold the files of the
+/// same fragments, in the same order. Returns `None`, leaving the lists as they
+/// are, if there is nothing to do.
+pub(crate) fn preprocess_to_fifos<'a>(
+    lists: impl IntoIterator<Item = (&'a mut Vec<String>, ReadKind)>,
+    pre: &Preprocessing,
+) -> Result<Option<PreprocessedReads>> {
+    if !pre.is_active() {
+        return Ok(None);
+    }
+    let (mut lists, kinds): (Vec<_>, Vec<_>) = lists.into_iter().unzip();
+    if lists.is_empty() {
+        return Ok(None);
+    }
+    if lists.iter().any(|l| l.len() != lists[0].len()) {
+        bail!("every list of read files must hold the same number of files.");
+    }
+
+    let tmp_dir = TempDir::new()?;
+    let mut files = Vec::with_capacity(lists.len());
+    let mut fifos = Vec::with_capacity(lists.len());
+    for (i, list) in lists.iter_mut().enumerate() {
+        let fifo = tmp_dir.path().join(format!("preprocessed_{}.pipe", i));
+        fifo_reads::make_fifo(&fifo)?;
+        files.push(std::mem::replace(
+            *list,
+            vec![fifo.to_string_lossy().into_owned()],
+        ));
+        fifos.push(fifo);
+    }
+
+    let thread_pre = pre.clone();
+    let join_handle = thread::spawn(move || -> Result<PreprocessStats> {
+        let stats = preprocess_into(files, kinds, fifos, &thread_pre)?;
+        tmp_dir.close()?;
+        Ok(stats)
+    });
+    Ok(Some(PreprocessedReads {
+        pre: pre.clone(),
+        join_handle,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_malformed_records_of_dropped_fragments() {
+        let dir = tempfile::tempdir().unwrap();
+        let reads = dir.path().join("reads.fq");
+        // the quality of the second record is shorter than its sequence
+        std::fs::write(&reads, "@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nII\n").unwrap();
+        let out = dir.path().join("out.fq");
+        let lists = vec![vec![reads.to_string_lossy().into_owned()]];
+        // whatever the seed, the second fragment is almost surely dropped
+        for seed in [0, 1, 2] {
+            let pre = Preprocessing {
+                subsample: Some((1e-9, seed)),
+                ..Default::default()
+            };
+            let res = preprocess_into(
+                lists.clone(),
+                vec![ReadKind::Mate1],
+                vec![out.clone()],
+                &pre,
+            );
+            assert!(res.is_err());
+        }
+    }
+
+    const NEXTERA: &[u8] = b"CTGTCTCTTATACACATCT";
+
+    #[test]
+    fn finds_a_full_adapter() {
+        let seq = [b"ACGTTGCAAC".as_slice(), NEXTERA, b"GGGCCC"].concat();
+        assert_eq!(find_adapter(&seq, NEXTERA), Some(10));
+        assert_eq!(find_adapter(b"ACGTTGCAACGGGCCCAAAT", NEXTERA), None);
+    }
+
+    #[test]
+    fn finds_a_partial_adapter_at_the_end_of_a_read() {
+        let seq = b"ACGTTGCAACGGGCCCACTG";
+        assert_eq!(find_adapter(seq, NEXTERA), Some(seq.len() - 3));
+        // fewer than MIN_ADAPTER_OVERLAP bases are not enough
+        assert_eq!(find_adapter(b"ACGTTGCAACGGGCCCAACT", NEXTERA), None);
+    }
+
+    #[test]
+    fn allows_mismatches_within_the_error_rate() {
+        // 19 overlapping bases allow a single mismatch
+        let mut one = [b"ACGTTGCAAC".as_slice(), NEXTERA].concat();
+        one[15] = b'A';
+        assert_eq!(find_adapter(&one, NEXTERA), Some(10));
+        let mut two = one.clone();
+        two[20] = b'G';
+        assert_eq!(find_adapter(&two, NEXTERA), None);
+    }
+
+    #[test]
+    fn clips_alternating_poly_tails() {
+        let seq = b"ACGTCAGTAAAAAAGGGGGGAAAAAA";
+        assert_eq!(clip_poly_tails(seq, b"AG", 5), 8);
+        // only the trailing polyA is clipped when polyG tails are not
+        assert_eq!(clip_poly_tails(seq, b"A", 5), 20);
+        // nor are tails shorter than the minimum length
+        assert_eq!(clip_poly_tails(seq, b"AG", 7), seq.len());
+        assert_eq!(clip_poly_tails(b"aaaaaa", b"A", 5), 0);
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::{bail, Result};
+use needletail::parse_fastx_file;
+use std::fs::File;
+use std::io::Read;
+use tracing::warn;
+
+use crate::fifo_reads;
+use crate::stream_input;
+
+/// Formats that are often passed as read files by mistake (e.g. the outputs
+/// of FastQC that sit next to the reads), or that the mapper can't decompress,
+/// identified by their leading bytes.
+const KNOWN_NON_READ_FORMATS: &[(&[u8], &str)] = &[
+    (b"PK\x03\x04", "a ZIP archive"),
+    (b"BZh", "bzip2-compressed, which the mapper can't read"),
+    (
+        b"\xfd7zXZ\x00",
+        "xz-compressed, which the mapper can't read",
+    ),
+    (
+        b"\x28\xb5\x2f\xfd",
+        "zstd-compressed, which the mapper can't read",
+    ),
+    (b"%PDF", "a PDF document"),
+    (b"<", "an HTML or XML document"),
+];
+
+/// The gzip magic bytes.
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+
+/// Check that the read file `f` looks like a (possibly gzipped) FASTA or FASTQ
+/// file: its leading bytes must be those of a gzip stream or of a FASTA (`>`)
+/// or FASTQ (`@`) record, and its first record must parse. Returns a description
+/// of the problem if it doesn't.
+fn check_read_file(f: &str) -> Option<String> {
+    let mut head = Vec::with_capacity(8);
+    let file = match File::open(f) {
+        Ok(file) => file,
+        Err(e) => return Some(format!("could not be opened ({})", e)),
+    };
+    if let Err(e) = file.take(8).read_to_end(&mut head) {
+        return Some(format!("could not be read ({})", e));
+    }
+    if head.is_empty() {
+        warn!("the read file {} is empty.", f);
+        return None;
+    }
+    if let Some((_, desc)) = KNOWN_NON_READ_FORMATS
+        .iter()
+        .find(|(magic, _)| head.starts_with(magic))
+    {
+        return Some(format!("is {}, not a FASTA/FASTQ file", desc));
+    }
+    if !head.starts_with(GZIP_MAGIC) && head[0] != b'>' && head[0] != b'@' {
+        return Some(String::from(
+            "does not start with a FASTA ('>') or FASTQ ('@') record, and is not gzipped",
+        ));
+    }
+    let mut reader = match parse_fastx_file(f) {
+        Ok(r) => r,
+        Err(e) => return Some(format!("could not be parsed ({})", e)),
+    };
+    match reader.next() {
+        Some(Ok(_)) => None,
+        Some(Err(e)) => Some(format!("has an invalid first record ({})", e)),
+        None => {
+            warn!("the read file {} contains no reads.", f);
+            None
+        }
+    }
+}
+
+/// Check every read file in `files` (see `check_read_file`) before it is handed
+/// to the mapper, reporting all the files that don't look like FASTA/FASTQ files
+/// at once. Streams are not checked, since they can only be read once.
+pub(crate) fn validate_read_files<'a>(files: impl IntoIterator<Item = &'a String>) -> Result<()> {
+    let problems = files
+        .into_iter()
+        .filter(|f| !stream_input::is_stream(f))
+        .filter_map(|f| check_read_file(f).map(|p| format!("  {} {}", f, p)))
+        .collect::<Vec<String>>();
+    if !problems.is_empty() {
+        bail!(
+            "{} read file(s) are not valid FASTA/FASTQ files:\n{}",
+            problems.len(),
+            problems.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Check that the read 1 files `read1` and the read 2 files `read2` can be paired,
+/// i.e. that there are as many of each.
+pub(crate) fn check_paired_lists(read1: &[String], read2: &[String]) -> Result<()> {
+    if read1.len() != read2.len() {
+        bail!(
+            "the number of read 1 files ({}) must match the number of read 2 files ({}).",
+            read1.len(),
+            read2.len()
+        );
+    }
+    Ok(())
+}
+
+/// Check that the first `num_records` records of every pair of files in `read1`
+/// and `read2` correspond: that the records at the same position in both files
+/// have the same name (ignoring any `/1` or `/2` suffix and comment), and that
+/// neither file runs out of records before the other. Streams are not checked,
+/// since they can only be read once.
+pub(crate) fn check_pairing(read1: &[String], read2: &[String], num_records: usize) -> Result<()> {
+    for (f1, f2) in read1.iter().zip(read2.iter()) {
+        if stream_input::is_stream(f1) || stream_input::is_stream(f2) {
+            warn!(
+                "the pairing of {} and {} can't be checked, since they are streamed.",
+                f1, f2
+            );
+            continue;
+        }
+        let mut reader1 = parse_fastx_file(f1)?;
+        let mut reader2 = parse_fastx_file(f2)?;
+        for i in 1..=num_records {
+            match (reader1.next(), reader2.next()) {
+                (Some(rec1), Some(rec2)) => {
+                    let (rec1, rec2) = (rec1?, rec2?);
+                    if fifo_reads::mate_name(rec1.id()) != fifo_reads::mate_name(rec2.id()) {
+                        bail!(
+                            "record {} of read 1 file {} ({}) and of read 2 file {} ({}) are not mates; the read files may be mismatched or out of order.",
+                            i,
+                            f1,
+                            String::from_utf8_lossy(rec1.id()),
+                            f2,
+                            String::from_utf8_lossy(rec2.id())
+                        );
+                    }
+                }
+                (None, None) => break,
+                (r1, _) => {
+                    let (short, long) = if r1.is_some() { (f2, f1) } else { (f1, f2) };
+                    bail!(
+                        "read file {} has only {} record(s), but its mate file {} has more.",
+                        short,
+                        i - 1,
+                        long
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,179 @@
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::map_info;
+use crate::rad::{decode_ref_id, RadReader, RadWriter};
+
+/// Statistics about the alignments removed from a RAD file
+/// by `filter_rad_refs`.
+#[derive(Debug, Default)]
+pub(crate) struct RefFilterStats {
+    /// the number of alignments removed
+    pub removed_alns: u64,
+    /// the number of reads left without any alignment, and
+    /// so removed entirely
+    pub removed_reads: u64,
+}
+
+/// Read a file listing one reference name per line.
+pub(crate) fn read_ref_list(path: &Path) -> Result<HashSet<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read reference list {}", path.display()))?;
+    let refs = contents
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect::<HashSet<String>>();
+    if refs.is_empty() {
+        bail!("reference list {} is empty.", path.display());
+    }
+    Ok(refs)
+}
+
+/// The names of the reference list `list` that are not among the references
+/// `ref_names` of the index, in sorted order.
+fn unknown_refs<'a>(list: &'a HashSet<String>, ref_names: &[String]) -> Vec<&'a str> {
+    let known = ref_names
+        .iter()
+        .map(String::as_str)
+        .collect::<HashSet<&str>>();
+    let mut unknown = list
+        .iter()
+        .map(String::as_str)
+        .filter(|r| !known.contains(r))
+        .collect::<Vec<&str>>();
+    unknown.sort_unstable();
+    unknown
+}
+
+/// Warn about the names of the reference list `list`, given with `option`, that
+/// are not among the references `ref_names` of the index, since they can't match
+/// any mapping. Returns their number.
+fn warn_unknown_refs(list: &HashSet<String>, ref_names: &[String], option: &str) -> usize {
+    let unknown = unknown_refs(list, ref_names);
+    if !unknown.is_empty() {
+        warn!(
+            "{} of the {} references listed with {} are not in the index, and are ignored (e.g. {}).",
+            unknown.len(),
+            list.len(),
+            option,
+            unknown[..unknown.len().min(3)].join(", ")
+        );
+    }
+    unknown.len()
+}
+
+/// Rewrite the RAD file at `rad_path` in place, keeping only the alignments
+/// to references for which `keep_ref` returns `true`. Reads left without any
+/// alignment are removed. This filters the mappings that the mapper reported:
+/// a read is not mapped again to other references once its alignments to the
+/// filtered references are removed.
+pub(crate) fn filter_rad_refs<F: Fn(&str) -> bool>(
+    rad_path: &Path,
+    keep_ref: F,
+) -> Result<RefFilterStats> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    let keep = rad
+        .header
+        .ref_names
+        .iter()
+        .map(|n| keep_ref(n))
+        .collect::<Vec<bool>>();
+    let num_kept = keep.iter().filter(|k| **k).count();
+    if num_kept == 0 {
+        warn!("none of the references in the index are retained; all mappings will be removed.");
+    }
+    info!(
+        "retaining mappings to {} of {} references.",
+        num_kept,
+        keep.len()
+    );
+
+    let tmp_path = PathBuf::from(rad_path.to_string_lossy().into_owned() + ".tmp");
+    let mut writer = RadWriter::create(&tmp_path, &rad.header)?;
+    let mut stats = RefFilterStats::default();
+    while let Some(mut rec) = rad.next_record()? {
+        let num_alns = rec.alns.len();
+        rec.alns.retain(|a| {
+            a.first()
+                .and_then(|v| v.as_u64())
+                .and_then(|v| keep.get(decode_ref_id(v).0 as usize).copied())
+                .unwrap_or(true)
+        });
+        stats.removed_alns += (num_alns - rec.alns.len()) as u64;
+        if rec.alns.is_empty() {
+            stats.removed_reads += 1;
+        } else {
+            writer.write_record(&rec)?;
+        }
+    }
+    writer.finish()?;
+    std::fs::rename(&tmp_path, rad_path)?;
+
+    info!(
+        "removed {} alignments to filtered references; {} reads were left without any alignment.",
+        stats.removed_alns, stats.removed_reads
+    );
+    Ok(stats)
+}
+
+/// Apply the `--restrict-refs` list `restrict` and the `--exclude-refs` list
+/// `exclude` (if either is given) to the RAD file at `rad_path`: only mappings
+/// to references in `restrict` (or to all references, if it is absent) that
+/// are not in `exclude` are kept. The number of removed alignments and reads
+/// is recorded under `ref_filter` in the mapping summary at `map_info_path`, along
+/// with the number of listed references that are not in the index (and so are
+/// ignored).
+pub(crate) fn apply_ref_filters(
+    rad_path: &Path,
+    restrict: Option<&Path>,
+    exclude: Option<&Path>,
+    map_info_path: &Path,
+) -> Result<()> {
+    if restrict.is_none() && exclude.is_none() {
+        return Ok(());
+    }
+    let restrict_set = restrict.map(read_ref_list).transpose()?;
+    let exclude_set = exclude.map(read_ref_list).transpose()?;
+    let ref_names = RadReader::from_path(rad_path)?.header.ref_names;
+    let num_unknown_restrict = restrict_set
+        .as_ref()
+        .map_or(0, |r| warn_unknown_refs(r, &ref_names, "--restrict-refs"));
+    let num_unknown_exclude = exclude_set
+        .as_ref()
+        .map_or(0, |e| warn_unknown_refs(e, &ref_names, "--exclude-refs"));
+    let stats = filter_rad_refs(rad_path, |r| {
+        restrict_set.as_ref().is_none_or(|k| k.contains(r))
+            && !exclude_set.as_ref().is_some_and(|e| e.contains(r))
+    })?;
+    map_info::add_map_info_entry(
+        map_info_path,
+        "ref_filter",
+        json!({
+            "removed_alignments": stats.removed_alns,
+            "removed_reads": stats.removed_reads,
+            "unknown_restrict_refs": num_unknown_restrict,
+            "unknown_exclude_refs": num_unknown_exclude,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_listed_references_that_are_not_in_the_index() {
+        let ref_names = ["tx1", "tx2", "tx3"].map(String::from);
+        let list = ["tx2", "tx9", "gene1"]
+            .into_iter()
+            .map(String::from)
+            .collect::<HashSet<String>>();
+        assert_eq!(unknown_refs(&list, &ref_names), vec!["gene1", "tx9"]);
+        assert_eq!(warn_unknown_refs(&list, &ref_names, "--exclude-refs"), 2);
+    }
+}
@@ -0,0 +1,494 @@
+use anyhow::{bail, Context, Result};
+use needletail::parse_fastx_file;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::exit_code::{bail_with, ExitKind};
+use crate::read_preprocess;
+
+/// How duplicate references are handled before indexing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum DedupPolicy {
+    /// fail if two references have the same name
+    Error,
+    /// keep only the first of the references with the same name or sequence
+    KeepFirst,
+    /// rename the references whose name was already used
+    Rename,
+}
+
+impl std::str::FromStr for DedupPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(Self::Error),
+            "keep-first" => Ok(Self::KeepFirst),
+            "rename" => Ok(Self::Rename),
+            _ => bail!(
+                "unknown duplicate policy {}; must be error, keep-first or rename",
+                s
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for DedupPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::KeepFirst => write!(f, "keep-first"),
+            Self::Rename => write!(f, "rename"),
+        }
+    }
+}
+
+/// How the characters other than ACGT (IUPAC ambiguity codes, including N) in the
+/// references are handled before indexing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AmbigPolicy {
+    /// leave them, so that the cDBG builder splits the references at them
+    Split,
+    /// replace each by a random base among those it stands for
+    Random,
+    /// replace each by N
+    Mask,
+}
+
+impl std::str::FromStr for AmbigPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "split" => Ok(Self::Split),
+            "random" => Ok(Self::Random),
+            "mask" => Ok(Self::Mask),
+            _ => bail!(
+                "unknown ambiguity policy {}; must be split, random or mask",
+                s
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for AmbigPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Split => write!(f, "split"),
+            Self::Random => write!(f, "random"),
+            Self::Mask => write!(f, "mask"),
+        }
+    }
+}
+
+/// How the references are prepared before indexing.
+pub(crate) struct RefPrepConfig {
+    pub dedup: DedupPolicy,
+    pub ambig: AmbigPolicy,
+    /// the seed of the bases chosen by `AmbigPolicy::Random`
+    pub seed: u64,
+    /// the length below which references (or their pieces) are dropped
+    pub min_length: usize,
+    /// the length of the runs of Ns above which references are split into
+    /// separate references, if any
+    pub max_n_run: Option<usize>,
+}
+
+/// The path of the FASTA file written by `prepare_refs` for the stem `out_stem`.
+pub(crate) fn prepared_refs_path(out_stem: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.prepared.fa", out_stem.to_string_lossy()))
+}
+
+/// The path of the report of the ambiguous bases written by `prepare_refs` for
+/// the stem `out_stem`.
+pub(crate) fn ambig_report_path(out_stem: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.ambig_bases.tsv", out_stem.to_string_lossy()))
+}
+
+/// The name of a reference as indexed: its header up to the first whitespace.
+fn record_name(id: &[u8]) -> String {
+    let id = String::from_utf8_lossy(id);
+    id.split_whitespace().next().unwrap_or_default().to_string()
+}
+
+/// The SHA-256 digest of the sequence `seq`, ignoring case.
+fn seq_digest(seq: &[u8]) -> [u8; 32] {
+    let mut h = Sha256::new();
+    for chunk in seq.chunks(1 << 16) {
+        h.update(chunk.to_ascii_uppercase());
+    }
+    h.finalize().into()
+}
+
+/// Whether `c` is one of ACGT (in either case).
+fn is_acgt(c: u8) -> bool {
+    matches!(c, b'A' | b'C' | b'G' | b'T' | b'a' | b'c' | b'g' | b't')
+}
+
+/// The bases the IUPAC code `c` stands for; any character that is not a known
+/// code stands for all four bases, like N.
+fn iupac_bases(c: u8) -> &'static [u8] {
+    match c.to_ascii_uppercase() {
+        b'R' => b"AG",
+        b'Y' => b"CT",
+        b'S' => b"CG",
+        b'W' => b"AT",
+        b'K' => b"GT",
+        b'M' => b"AC",
+        b'B' => b"CGT",
+        b'D' => b"AGT",
+        b'H' => b"ACT",
+        b'V' => b"ACG",
+        _ => b"ACGT",
+    }
+}
+
+/// Replace the characters other than ACGT in `seq` according to `policy`. The
+/// random bases are drawn from the seed `seed` and the position `offset + i`
+/// of each base `i`, so that they do not depend on how the sequence is read.
+fn resolve_ambig(seq: &mut [u8], policy: AmbigPolicy, seed: u64, offset: u64) {
+    for (i, c) in seq.iter_mut().enumerate().filter(|(_, c)| !is_acgt(**c)) {
+        *c = match policy {
+            AmbigPolicy::Split => *c,
+            AmbigPolicy::Mask => b'N',
+            AmbigPolicy::Random => {
+                let bases = iupac_bases(*c);
+                let r = read_preprocess::splitmix64(
+                    seed ^ read_preprocess::splitmix64(offset + i as u64),
+                );
+                bases[(r % bases.len() as u64) as usize]
+            }
+        };
+    }
+}
+
+/// The pieces, as half-open intervals, of the sequence `seq` that remain when it
+/// is cut at every run of more than `max_n_run` Ns (in either case). The whole
+/// sequence is a single piece if `max_n_run` is `None`.
+fn split_at_n_runs(seq: &[u8], max_n_run: Option<usize>) -> Vec<(usize, usize)> {
+    let Some(max_n_run) = max_n_run else {
+        return vec![(0, seq.len())];
+    };
+    if seq.is_empty() {
+        return vec![(0, 0)];
+    }
+    let mut pieces = Vec::new();
+    let (mut start, mut i) = (0, 0);
+    while i < seq.len() {
+        if !matches!(seq[i], b'N' | b'n') {
+            i += 1;
+            continue;
+        }
+        let run_start = i;
+        while i < seq.len() && matches!(seq[i], b'N' | b'n') {
+            i += 1;
+        }
+        if i - run_start > max_n_run {
+            if run_start > start {
+                pieces.push((start, run_start));
+            }
+            start = i;
+        }
+    }
+    if seq.len() > start {
+        pieces.push((start, seq.len()));
+    }
+    pieces
+}
+
+/// What to do with each reference, in input order.
+enum RefAction {
+    Keep,
+    Drop,
+    Rename(String),
+}
+
+/// Prepare the references in the FASTA files `files` for indexing, according to
+/// `config`.
+///
+/// First, they are checked for duplicates: references with the same name, and
+/// references with identical sequences (ignoring case) under different names.
+/// Duplicates are handled according to `config.dedup`: with `error`, duplicate
+/// names are an error; with `keep-first`, only the first of the references with
+/// the same name or sequence is kept; with `rename`, every reference whose name
+/// was already used is renamed to `<name>_dup<i>`. Identical sequences are
+/// otherwise only reported, since the index treats them as distinct references
+/// that share all their k-mers.
+///
+/// Then, the characters other than ACGT in the references that are kept are
+/// handled according to `config.ambig`: with `split`, they are left for the cDBG
+/// builder, which splits the references at them; with `random`, each is replaced
+/// by a random base among those it stands for; with `mask`, each is replaced by
+/// N. The number of such bases in every reference that has any is written to
+/// `<out_stem>.ambig_bases.tsv`.
+///
+/// With `config.max_n_run`, the references are cut at every run of more than that
+/// many Ns, and each remaining piece becomes a reference of its own, named
+/// `<name>:<start>-<end>` after its 1-based, closed interval in the reference.
+/// References (or pieces) shorter than `config.min_length` are then dropped.
+///
+/// If references have to be dropped, renamed or changed, the resulting ones are
+/// written to `<out_stem>.prepared.fa`, whose path is returned; otherwise the
+/// references are indexed as given, and `None` is returned.
+pub(crate) fn prepare_refs(
+    files: &[PathBuf],
+    config: &RefPrepConfig,
+    out_stem: &Path,
+) -> Result<Option<PathBuf>> {
+    let policy = config.dedup;
+    // the first pass collects the name, sequence digest, length, number of
+    // ambiguous bases and pieces of every reference
+    let mut records = Vec::<(String, [u8; 32])>::new();
+    let mut ambig_counts = Vec::<(usize, u64)>::new();
+    let mut pieces = Vec::<Vec<(usize, usize)>>::new();
+    for path in files {
+        let mut reader = parse_fastx_file(path)
+            .with_context(|| format!("could not open reference {}", path.display()))?;
+        while let Some(rec) = reader.next() {
+            let rec = rec.with_context(|| format!("could not parse {}", path.display()))?;
+            let seq = rec.seq();
+            records.push((record_name(rec.id()), seq_digest(&seq)));
+            ambig_counts.push((
+                seq.len(),
+                seq.iter().filter(|c| !is_acgt(**c)).count() as u64,
+            ));
+            pieces.push(split_at_n_runs(&seq, config.max_n_run));
+        }
+    }
+
+    // decide what to do with each reference, so that the references only have
+    // to be rewritten if needed; new names must not clash with any given name
+    let mut names = records
+        .iter()
+        .map(|(n, _)| n.clone())
+        .collect::<HashSet<String>>();
+    let mut seen = HashSet::<&str>::new();
+    let mut digests = HashMap::<[u8; 32], &str>::new();
+    let mut actions = Vec::<RefAction>::with_capacity(records.len());
+    let (mut num_dup_names, mut num_dup_seqs) = (0_usize, 0_usize);
+    let mut first_dup_name = None;
+    let mut first_dup_seq = None;
+    for (name, digest) in &records {
+        let dup_name = !seen.insert(name.as_str());
+        let dup_seq = digests.get(digest).filter(|n| **n != name).copied();
+        if dup_name {
+            num_dup_names += 1;
+            first_dup_name.get_or_insert(name.as_str());
+        }
+        if let Some(other) = dup_seq {
+            num_dup_seqs += 1;
+            first_dup_seq.get_or_insert((name.as_str(), other));
+        }
+        let action = match policy {
+            DedupPolicy::KeepFirst if dup_name || dup_seq.is_some() => RefAction::Drop,
+            DedupPolicy::Rename if dup_name => {
+                let new_name = (1..)
+                    .map(|i| format!("{}_dup{}", name, i))
+                    .find(|n| !names.contains(n))
+                    .unwrap_or_default();
+                names.insert(new_name.clone());
+                RefAction::Rename(new_name)
+            }
+            _ => RefAction::Keep,
+        };
+        if !matches!(action, RefAction::Drop) {
+            digests.entry(*digest).or_insert(name.as_str());
+        }
+        actions.push(action);
+    }
+
+    if num_dup_names > 0 && policy == DedupPolicy::Error {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "{} references have the name of an earlier reference (e.g. {}); rename them, or use --dedup-policy keep-first or rename.",
+            num_dup_names,
+            first_dup_name.unwrap_or_default()
+        );
+    }
+    if let Some((name, other)) = first_dup_seq {
+        let handling = if policy == DedupPolicy::KeepFirst {
+            "they were dropped"
+        } else {
+            "they are indexed as distinct references, among which reads will be split"
+        };
+        warn!(
+            "{} references have the same sequence as an earlier reference (e.g. {} and {}); {}.",
+            num_dup_seqs, name, other, handling
+        );
+    }
+    let num_dropped = actions
+        .iter()
+        .filter(|a| matches!(a, RefAction::Drop))
+        .count();
+    let num_renamed = actions
+        .iter()
+        .filter(|a| matches!(a, RefAction::Rename(_)))
+        .count();
+
+    // the ambiguous bases of the references that are kept
+    let ambig_refs = records
+        .iter()
+        .zip(actions.iter())
+        .zip(ambig_counts.iter())
+        .filter_map(|(((name, _), action), &(len, n))| match action {
+            RefAction::Drop => None,
+            _ if n == 0 => None,
+            RefAction::Rename(new_name) => Some((new_name.as_str(), len, n)),
+            RefAction::Keep => Some((name.as_str(), len, n)),
+        })
+        .collect::<Vec<(&str, usize, u64)>>();
+    let num_ambig_bases = ambig_refs.iter().map(|r| r.2).sum::<u64>();
+    let report = ambig_report_path(out_stem);
+    if !ambig_refs.is_empty() {
+        if let Some(parent_path) = report.parent() {
+            if !parent_path.as_os_str().is_empty() && !parent_path.exists() {
+                std::fs::create_dir_all(parent_path)?;
+            }
+        }
+        let f = File::create(&report)
+            .with_context(|| format!("could not create {}", report.display()))?;
+        let mut out = BufWriter::new(f);
+        writeln!(out, "name\tlength\tnum_ambiguous_bases")?;
+        for (name, len, n) in &ambig_refs {
+            writeln!(out, "{}\t{}\t{}", name, len, n)?;
+        }
+        out.flush()?;
+        let handling = match config.ambig {
+            AmbigPolicy::Split => "the references are split at them",
+            AmbigPolicy::Random => "they were replaced by random bases",
+            AmbigPolicy::Mask => "they were replaced by N",
+        };
+        info!(
+            "{} references hold {} bases other than ACGT (e.g. {} holds {}); {}. The number of such bases in each reference is written to {}.",
+            ambig_refs.len(),
+            num_ambig_bases,
+            ambig_refs[0].0,
+            ambig_refs[0].2,
+            handling,
+            report.display()
+        );
+    } else if report.exists() {
+        // a report left by an earlier build with the same prefix is stale
+        std::fs::remove_file(&report)?;
+    }
+    let rewrite_ambig = num_ambig_bases > 0 && config.ambig != AmbigPolicy::Split;
+
+    // the pieces of the references that are kept, without the short ones
+    let (mut num_split, mut num_pieces) = (0_usize, 0_usize);
+    let (mut num_short, mut num_short_bases) = (0_usize, 0_u64);
+    for ((p, action), &(len, _)) in pieces
+        .iter_mut()
+        .zip(actions.iter())
+        .zip(ambig_counts.iter())
+    {
+        if matches!(action, RefAction::Drop) {
+            continue;
+        }
+        if p.len() != 1 || p[0] != (0, len) {
+            num_split += 1;
+            num_pieces += p.len();
+        }
+        p.retain(|&(s, e)| {
+            let keep = e - s >= config.min_length;
+            if !keep {
+                num_short += 1;
+                num_short_bases += (e - s) as u64;
+            }
+            keep
+        });
+    }
+    if num_split > 0 {
+        info!(
+            "cut {} references at runs of more than {} Ns into {} pieces.",
+            num_split,
+            config.max_n_run.unwrap_or_default(),
+            num_pieces
+        );
+    }
+    if num_short > 0 {
+        warn!(
+            "dropped {} references (or pieces of references) shorter than {} bases ({} bases in total).",
+            num_short, config.min_length, num_short_bases
+        );
+    }
+    let rewrite_pieces = pieces
+        .iter()
+        .zip(actions.iter())
+        .zip(ambig_counts.iter())
+        .any(|((p, action), &(len, _))| {
+            !matches!(action, RefAction::Drop) && (p.len() != 1 || p[0] != (0, len))
+        });
+    if pieces
+        .iter()
+        .zip(actions.iter())
+        .all(|(p, action)| p.is_empty() || matches!(action, RefAction::Drop))
+    {
+        bail_with!(
+            ExitKind::InvalidArguments,
+            "no references are left to index once those shorter than {} bases are dropped.",
+            config.min_length
+        );
+    }
+    if num_dropped == 0 && num_renamed == 0 && !rewrite_ambig && !rewrite_pieces {
+        return Ok(None);
+    }
+
+    let prepared = prepared_refs_path(out_stem);
+    if let Some(parent_path) = prepared.parent() {
+        if !parent_path.as_os_str().is_empty() && !parent_path.exists() {
+            std::fs::create_dir_all(parent_path)?;
+        }
+    }
+    let f = File::create(&prepared)
+        .with_context(|| format!("could not create {}", prepared.display()))?;
+    let mut out = BufWriter::new(f);
+    let mut refs = records.iter().zip(actions).zip(pieces);
+    // the position of the current reference in the concatenation of all of them
+    let mut offset = 0_u64;
+    for path in files {
+        let mut reader = parse_fastx_file(path)
+            .with_context(|| format!("could not open reference {}", path.display()))?;
+        while let Some(rec) = reader.next() {
+            let rec = rec.with_context(|| format!("could not parse {}", path.display()))?;
+            let Some((((name, _), action), pieces)) = refs.next() else {
+                bail!(
+                    "{} changed while the references were prepared.",
+                    path.display()
+                );
+            };
+            let mut seq = rec.seq().into_owned();
+            let whole = pieces.len() == 1 && pieces[0] == (0, seq.len());
+            let name = match action {
+                RefAction::Drop => {
+                    offset += seq.len() as u64;
+                    continue;
+                }
+                RefAction::Keep if whole => String::from_utf8_lossy(rec.id()).into_owned(),
+                RefAction::Keep => name.clone(),
+                RefAction::Rename(new_name) => new_name,
+            };
+            resolve_ambig(&mut seq, config.ambig, config.seed, offset);
+            offset += seq.len() as u64;
+            for (s, e) in pieces {
+                if whole {
+                    writeln!(out, ">{}", name)?;
+                } else {
+                    writeln!(out, ">{}:{}-{}", name, s + 1, e)?;
+                }
+                out.write_all(&seq[s..e])?;
+                writeln!(out)?;
+            }
+        }
+    }
+    out.flush()?;
+    if num_dropped > 0 || num_renamed > 0 {
+        info!(
+            "dropped {} and renamed {} duplicate references.",
+            num_dropped, num_renamed
+        );
+    }
+    info!("wrote the prepared references to {}.", prepared.display());
+    Ok(Some(prepared))
+}
@@ -0,0 +1,61 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+/// The name and length of every reference in an index, in the order of
+/// their ids (i.e. the order used in the RAD header).
+#[derive(Debug)]
+pub(crate) struct RefInfo {
+    pub names: Vec<String>,
+    pub lengths: Vec<u64>,
+}
+
+/// Read the reference names and lengths from the `.refinfo` file `path` of an
+/// index. The file holds the vector of names followed by the vector of lengths,
+/// each vector being stored as a little-endian 64-bit count followed by its
+/// elements (a 64-bit length and the bytes for a name, a 64-bit value for a
+/// length).
+pub(crate) fn read_refinfo(path: &Path) -> Result<RefInfo> {
+    let f = File::open(path)
+        .with_context(|| format!("could not open reference information {}", path.display()))?;
+    let mut reader = BufReader::new(f);
+    let mut parse = || -> Result<RefInfo> {
+        let num_names = read_u64(&mut reader)?;
+        let mut names = Vec::with_capacity(num_names.min(1 << 20) as usize);
+        for _ in 0..num_names {
+            let len = read_u64(&mut reader)?;
+            if len > u16::MAX as u64 {
+                bail!("implausible reference name length {}", len);
+            }
+            let mut b = vec![0u8; len as usize];
+            reader.read_exact(&mut b)?;
+            names.push(String::from_utf8(b)?);
+        }
+        let num_lengths = read_u64(&mut reader)?;
+        if num_lengths != num_names {
+            bail!(
+                "{} reference names but {} reference lengths",
+                num_names,
+                num_lengths
+            );
+        }
+        let mut lengths = Vec::with_capacity(names.len());
+        for _ in 0..num_lengths {
+            lengths.push(read_u64(&mut reader)?);
+        }
+        Ok(RefInfo { names, lengths })
+    };
+    parse().with_context(|| {
+        format!(
+            "could not parse reference information {}; was the index built by a compatible version of piscem?",
+            path.display()
+        )
+    })
+}
@@ -0,0 +1,172 @@
+use anyhow::{bail, Context, Result};
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use tempfile::TempDir;
+use tracing::info;
+
+use crate::fifo_reads;
+
+/// The URL schemes from which reads can be streamed.
+const URL_SCHEMES: &[&str] = &["http://", "https://", "s3://"];
+
+/// Whether the read file `f` is a URL from which the reads are downloaded.
+pub(crate) fn is_url(f: &str) -> bool {
+    URL_SCHEMES.iter().any(|s| f.starts_with(s))
+}
+
+/// The HTTPS URL of the read file `f`: an `s3://<bucket>/<key>` URL is turned
+/// into the (virtual-hosted) URL of the object, from which it can be read if it
+/// is public; other URLs are returned as is.
+fn http_url(f: &str) -> Result<String> {
+    match f.strip_prefix("s3://") {
+        Some(path) => match path.split_once('/') {
+            Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => {
+                Ok(format!("https://{}.s3.amazonaws.com/{}", bucket, key))
+            }
+            _ => bail!("the S3 URL {} must be of the form s3://<bucket>/<key>.", f),
+        },
+        None => Ok(f.to_string()),
+    }
+}
+
+/// How downloads are retried when they fail to start (e.g. because of a
+/// transient network or server error).
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RetryPolicy {
+    /// the number of times a download is retried
+    pub retries: u32,
+    /// the number of seconds to wait between retries; if `None`, the wait
+    /// starts at one second and doubles with every retry
+    pub delay: Option<u64>,
+}
+
+/// Read files that are being downloaded into fifos by separate threads, from
+/// which the mapper reads them as though they were local files.
+pub(crate) struct RemoteReads {
+    /// the directory holding the fifos, which is removed once they are written
+    tmp_dir: TempDir,
+    /// the handles of the threads downloading the reads
+    join_handles: Vec<thread::JoinHandle<Result<()>>>,
+}
+
+impl RemoteReads {
+    /// Wait for all the threads downloading the reads to finish.
+    pub fn join(self) -> Result<()> {
+        for h in self.join_handles {
+            match h.join() {
+                Ok(r) => r?,
+                Err(_) => bail!("a thread downloading reads panicked"),
+            }
+        }
+        self.tmp_dir.close()?;
+        Ok(())
+    }
+}
+
+/// A `curl` command that fails on HTTP errors, follows redirects and retries
+/// as given by `retry`.
+fn curl_command(retry: RetryPolicy) -> Command {
+    let mut cmd = Command::new("curl");
+    cmd.args(["--fail", "--silent", "--show-error", "--location"])
+        .args(["--retry", &retry.retries.to_string()]);
+    if let Some(d) = retry.delay {
+        cmd.args(["--retry-delay", &d.to_string()]);
+    }
+    cmd
+}
+
+/// Download the file at the (`http(s)://` or `s3://`) URL `url` to `path` with
+/// `curl`, retrying as given by `retry`.
+pub(crate) fn download_to_file(url: &str, path: &Path, retry: RetryPolicy) -> Result<()> {
+    let output = curl_command(retry)
+        .arg("--output")
+        .arg(path)
+        .arg(http_url(url)?)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .context("could not run curl; it must be installed and on the PATH to download files")?;
+    if !output.status.success() {
+        bail!(
+            "failed to download {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Download the read file at `url` into the fifo `fifo` with `curl`, retrying
+/// as given by `retry`. If the file is gzipped, it is decompressed on the fly.
+fn download_into(url: String, fifo: PathBuf, retry: RetryPolicy) -> Result<()> {
+    // opening the fifo blocks until the mapper opens it for reading, so the
+    // download only starts once the reads are needed
+    let mut out = BufWriter::new(File::create(&fifo)?);
+
+    let mut child = curl_command(retry)
+        .arg(http_url(&url)?)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("could not run curl to download the reads")?;
+
+    let mut body = BufReader::new(child.stdout.take().unwrap());
+    let gzipped = body.fill_buf()?.starts_with(b"\x1f\x8b");
+    let copied = if gzipped {
+        std::io::copy(&mut MultiGzDecoder::new(body), &mut out)
+    } else {
+        std::io::copy(&mut body, &mut out)
+    };
+
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr)?;
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("failed to download {}: {}", url, stderr.trim());
+    }
+    let num_bytes = copied.with_context(|| format!("failed to read {}", url))?;
+    out.flush()?;
+    info!("streamed {} bytes of reads from {}.", num_bytes, url);
+    Ok(())
+}
+
+/// Replace every URL (see `is_url`) in the read file lists `lists` with a fifo
+/// into which a separate thread downloads (with `curl`) and, if needed,
+/// decompresses the reads as the mapper consumes them. Returns `None` if there
+/// are no URLs.
+pub(crate) fn stream_urls_to_fifos<'a>(
+    lists: impl IntoIterator<Item = &'a mut Vec<String>>,
+    retry: RetryPolicy,
+) -> Result<Option<RemoteReads>> {
+    let mut files = lists.into_iter().flatten().filter(|f| is_url(f)).peekable();
+    if files.peek().is_none() {
+        return Ok(None);
+    }
+    if Command::new("curl")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .status()
+        .is_err()
+    {
+        bail!("reads can only be streamed from URLs if curl is installed and on the PATH.");
+    }
+
+    let tmp_dir = TempDir::new()?;
+    let mut join_handles = Vec::new();
+    for (i, f) in files.enumerate() {
+        // check the URL now, so that a malformed one is reported before mapping
+        http_url(f)?;
+        let fifo = tmp_dir.path().join(format!("remote_{}.pipe", i));
+        fifo_reads::make_fifo(&fifo)?;
+        let url = std::mem::replace(f, fifo.to_string_lossy().into_owned());
+        join_handles.push(thread::spawn(move || download_into(url, fifo, retry)));
+    }
+    Ok(Some(RemoteReads {
+        tmp_dir,
+        join_handles,
+    }))
+}
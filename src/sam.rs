@@ -0,0 +1,343 @@
+use anyhow::{bail, Context, Result};
+use noodles_bgzf as bgzf;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::exit_code::{bail_with, ExitKind};
+use crate::inspect;
+use crate::permit_list::{decode_barcode, RAW_BARCODE_TAG};
+use crate::piscem_commands::RadToSamOpts;
+use crate::rad::{self, decode_ref_id, RadHeader, RadReader};
+use crate::refinfo::{self, RefInfo};
+
+/// The name of the BAM file written into the output directory by `map-sc-atac`.
+pub(crate) const SC_ATAC_BAM_FILE: &str = "map.bam";
+
+/// The path of the SAM or BAM file (as given by `ext`) written into the output
+/// directory `out_dir` by `map-sc`.
+pub(crate) fn sc_aln_path(out_dir: &Path, ext: &str) -> PathBuf {
+    out_dir.join(format!("map.{}", ext))
+}
+
+/// The path of the SAM or BAM file (as given by `ext`) written by `map-bulk`
+/// for the output stem `output`.
+pub(crate) fn bulk_aln_path(output: &Path, ext: &str) -> PathBuf {
+    PathBuf::from(output.to_string_lossy().into_owned() + "." + ext)
+}
+
+/// Validate the number of threads to use to compress BAM output.
+pub(crate) fn compression_threads(threads: usize) -> Result<NonZeroUsize> {
+    match NonZeroUsize::new(threads) {
+        Some(t) => Ok(t),
+        None => bail_with!(
+            ExitKind::InvalidArguments,
+            "the number of compression threads must be greater than 0."
+        ),
+    }
+}
+
+/// The SAM header text for the references in `refs`.
+fn sam_header(refs: &RefInfo) -> String {
+    let mut header = String::from("@HD\tVN:1.6\tSO:unsorted\n");
+    for (name, len) in refs.names.iter().zip(refs.lengths.iter()) {
+        header += &format!("@SQ\tSN:{}\tLN:{}\n", name, len);
+    }
+    let cmd_line = std::env::args().collect::<Vec<String>>().join(" ");
+    header += &format!(
+        "@PG\tID:piscem\tPN:piscem\tVN:{}\tCL:{}\n",
+        clap::crate_version!(),
+        cmd_line
+    );
+    header
+}
+
+/// A single alignment, as recovered from a RAD file.
+struct RadAln<'a> {
+    /// the (1-based) index of the fragment in the RAD file
+    frag_num: u64,
+    flag: u16,
+    ref_id: u32,
+    /// the 0-based position, if known
+    pos: Option<i32>,
+    tlen: i32,
+    /// the number of alignments of the fragment
+    nh: i32,
+    /// the cell barcode and UMI of the read (for single-cell RAD files)
+    cb: Option<&'a str>,
+    umi: Option<&'a str>,
+    /// the barcode of the read as it was mapped, if it was recorded along
+    /// with the corrected one
+    raw_cb: Option<&'a str>,
+}
+
+impl RadAln<'_> {
+    /// The single-cell SAM tags of the alignment, in the order in which they are
+    /// written: the corrected (CB) and raw (CR) barcode, and the UMI (UB), which
+    /// is never corrected and so is also the raw UMI (UR).
+    fn sc_tags(&self) -> [(&'static str, Option<&str>); 4] {
+        [
+            ("CB", self.cb),
+            ("CR", self.raw_cb),
+            ("UB", self.umi),
+            ("UR", self.umi),
+        ]
+    }
+}
+
+/// The position of the read-level tag `tag` of `header`, along with the length
+/// recorded in its file-level tag `len_tag`, if both are present; the mappers
+/// record barcodes and UMIs in 2-bit encoding, so they can't be decoded without it.
+fn encoded_seq_tag(header: &RadHeader, tag: &str, len_tag: &str) -> Option<(usize, usize)> {
+    let pos = header.read_tags.iter().position(|t| t.name == tag)?;
+    let len = header
+        .file_tags
+        .iter()
+        .position(|t| t.name == len_tag)
+        .and_then(|i| header.file_tag_values.get(i)?.as_u64())?;
+    Some((pos, len as usize))
+}
+
+/// Append the SAM tags of `a` (in BAM encoding) to `rec`.
+fn push_bam_tags(rec: &mut Vec<u8>, a: &RadAln) {
+    rec.extend_from_slice(b"NHi");
+    rec.extend_from_slice(&a.nh.to_le_bytes());
+    for (tag, v) in a.sc_tags() {
+        if let Some(v) = v {
+            rec.extend_from_slice(tag.as_bytes());
+            rec.push(b'Z');
+            rec.extend_from_slice(v.as_bytes());
+            rec.push(0);
+        }
+    }
+}
+
+/// Call `f` on every alignment in the RAD file at `rad_path`, whose references
+/// must be those in `refs`. RAD files record neither read names nor sequences,
+/// so each fragment is identified by its index in the file. Every alignment of a
+/// fragment is reported, the first being the primary one; the fragment length
+/// (if known) is reported as the template length, and the cell barcode and UMI
+/// of single-cell reads (and their raw barcode, if recorded) are decoded.
+/// Returns the number of fragments.
+fn for_each_rad_aln<F: FnMut(&RadAln) -> Result<()>>(
+    rad_path: &Path,
+    refs: &RefInfo,
+    mut f: F,
+) -> Result<u64> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    if rad.header.ref_names != refs.names {
+        bail!(
+            "the references of RAD file {} do not match those of the index.",
+            rad_path.display()
+        );
+    }
+    let tag_pos = |name: &str| rad.header.aln_tags.iter().position(|t| t.name == name);
+    let (pos_tag, frag_len_tag) = (tag_pos("pos"), tag_pos("frag_len"));
+    let bc_tag = encoded_seq_tag(&rad.header, "b", "cblen");
+    let umi_tag = encoded_seq_tag(&rad.header, "u", "ulen");
+    let raw_bc_tag = encoded_seq_tag(&rad.header, RAW_BARCODE_TAG, "cblen");
+
+    let mut frag_num = 0_u64;
+    while let Some(rec) = rad.next_record()? {
+        frag_num += 1;
+        let decode = |tag: Option<(usize, usize)>| {
+            let (i, len) = tag?;
+            Some(decode_barcode(rec.read_tags.get(i)?.as_u64()?, len))
+        };
+        let (cb, umi, raw_cb) = (decode(bc_tag), decode(umi_tag), decode(raw_bc_tag));
+        for (i, aln) in rec.alns.iter().enumerate() {
+            let (ref_id, fw) = match aln.first().and_then(|v| v.as_u64()) {
+                Some(v) => decode_ref_id(v),
+                None => bail!("RAD alignment record is missing its reference id"),
+            };
+            if ref_id as usize >= refs.names.len() {
+                bail!("RAD alignment refers to unknown reference id {}", ref_id);
+            }
+            let mut flag = 0_u16;
+            if !fw {
+                flag |= 0x10;
+            }
+            if i > 0 {
+                flag |= 0x100;
+            }
+            // positions are stored as (possibly negative) 0-based offsets
+            let pos = pos_tag
+                .and_then(|p| aln.get(p)?.as_u64())
+                .map(|p| (p as u32 as i32).max(0));
+            let tlen = frag_len_tag.and_then(|f| aln.get(f)?.as_u64()).unwrap_or(0) as i32;
+            f(&RadAln {
+                frag_num,
+                flag,
+                ref_id,
+                pos,
+                tlen,
+                nh: rec.alns.len() as i32,
+                cb: cb.as_deref(),
+                umi: umi.as_deref(),
+                raw_cb: raw_cb.as_deref(),
+            })?;
+        }
+    }
+    Ok(frag_num)
+}
+
+/// Convert the RAD file at `rad_path` into SAM records written to `out`,
+/// using the reference names and lengths in `refs` for the header. Returns
+/// the number of records written.
+pub(crate) fn write_rad_as_sam<W: Write>(
+    rad_path: &Path,
+    refs: &RefInfo,
+    out: &mut W,
+) -> Result<u64> {
+    out.write_all(sam_header(refs).as_bytes())?;
+    let mut num_records = 0_u64;
+    let num_frags = for_each_rad_aln(rad_path, refs, |a| {
+        write!(
+            out,
+            "frag{}\t{}\t{}\t{}\t255\t*\t*\t0\t{}\t*\t*\tNH:i:{}",
+            a.frag_num,
+            a.flag,
+            refs.names[a.ref_id as usize],
+            a.pos.map_or(0, |p| p as i64 + 1),
+            a.tlen,
+            a.nh
+        )?;
+        for (tag, v) in a.sc_tags() {
+            if let Some(v) = v {
+                write!(out, "\t{}:Z:{}", tag, v)?;
+            }
+        }
+        writeln!(out)?;
+        num_records += 1;
+        Ok(())
+    })?;
+    info!(
+        "wrote {} SAM records for {} mapped fragments.",
+        num_records, num_frags
+    );
+    Ok(num_records)
+}
+
+/// The BAI bin of an alignment covering the 0-based interval [`beg`, `end`),
+/// as computed in the SAM specification; the first bin at each of the levels
+/// is 4681, 585, 73, 9 and 1 (respectively).
+pub(crate) fn reg2bin(beg: i32, end: i32) -> u16 {
+    let end = end - 1;
+    let bin = if beg >> 14 == end >> 14 {
+        4681 + (beg >> 14)
+    } else if beg >> 17 == end >> 17 {
+        585 + (beg >> 17)
+    } else if beg >> 20 == end >> 20 {
+        73 + (beg >> 20)
+    } else if beg >> 23 == end >> 23 {
+        9 + (beg >> 23)
+    } else if beg >> 26 == end >> 26 {
+        1 + (beg >> 26)
+    } else {
+        0
+    };
+    bin as u16
+}
+
+/// Convert the RAD file at `rad_path` into a BAM file at `bam_path`, using the
+/// reference names and lengths in `refs` for the header, and compressing the
+/// output with `compression_threads` threads. Returns the number of records written.
+pub(crate) fn write_rad_as_bam(
+    rad_path: &Path,
+    refs: &RefInfo,
+    bam_path: &Path,
+    compression_threads: NonZeroUsize,
+) -> Result<u64> {
+    write_rad_as_bam_to(rad_path, refs, File::create(bam_path)?, compression_threads)
+}
+
+/// Like `write_rad_as_bam`, but writing the BAM data to `writer`.
+pub(crate) fn write_rad_as_bam_to<W: Write + Send + 'static>(
+    rad_path: &Path,
+    refs: &RefInfo,
+    writer: W,
+    compression_threads: NonZeroUsize,
+) -> Result<u64> {
+    let mut out = bgzf::io::MultithreadedWriter::with_worker_count(compression_threads, writer);
+
+    let header = sam_header(refs);
+    out.write_all(b"BAM\x01")?;
+    out.write_all(&(header.len() as i32).to_le_bytes())?;
+    out.write_all(header.as_bytes())?;
+    out.write_all(&(refs.names.len() as i32).to_le_bytes())?;
+    for (name, len) in refs.names.iter().zip(refs.lengths.iter()) {
+        out.write_all(&(name.len() as i32 + 1).to_le_bytes())?;
+        out.write_all(name.as_bytes())?;
+        out.write_all(&[0])?;
+        out.write_all(&(*len as i32).to_le_bytes())?;
+    }
+
+    let mut num_records = 0_u64;
+    let mut rec = Vec::new();
+    let num_frags = for_each_rad_aln(rad_path, refs, |a| {
+        let read_name = format!("frag{}", a.frag_num);
+        let pos = a.pos.unwrap_or(-1);
+        rec.clear();
+        rec.extend_from_slice(&(a.ref_id as i32).to_le_bytes());
+        rec.extend_from_slice(&pos.to_le_bytes());
+        rec.push(read_name.len() as u8 + 1);
+        rec.push(255); // mapq
+        rec.extend_from_slice(&reg2bin(pos.max(0), pos.max(0) + 1).to_le_bytes());
+        rec.extend_from_slice(&0_u16.to_le_bytes()); // no CIGAR operations
+        rec.extend_from_slice(&a.flag.to_le_bytes());
+        rec.extend_from_slice(&0_u32.to_le_bytes()); // no sequence
+        rec.extend_from_slice(&(-1_i32).to_le_bytes()); // mate reference
+        rec.extend_from_slice(&(-1_i32).to_le_bytes()); // mate position
+        rec.extend_from_slice(&a.tlen.to_le_bytes());
+        rec.extend_from_slice(read_name.as_bytes());
+        rec.push(0);
+        push_bam_tags(&mut rec, a);
+
+        out.write_all(&(rec.len() as u32).to_le_bytes())?;
+        out.write_all(&rec)?;
+        num_records += 1;
+        Ok(())
+    })?;
+    out.finish()?;
+    info!(
+        "wrote {} BAM records for {} mapped fragments.",
+        num_records, num_frags
+    );
+    Ok(num_records)
+}
+
+/// Convert the RAD file given in `opts` into SAM or BAM records, as described by
+/// `opts`. Returns the number of records written.
+pub(crate) fn convert_rad(opts: &RadToSamOpts) -> Result<u64> {
+    let rad_path = rad::resolve_rad_path(&opts.input)?;
+    let refs = refinfo::read_refinfo(&inspect::component_path(&opts.index, ".refinfo"))?;
+    let bam = match opts.output_format.as_deref() {
+        Some(fmt) => fmt == "bam",
+        None => opts
+            .output
+            .as_ref()
+            .is_some_and(|o| o.extension().is_some_and(|e| e == "bam")),
+    };
+    let threads = compression_threads(opts.compression_threads)?;
+    let num_records = match (&opts.output, bam) {
+        (Some(out), true) => write_rad_as_bam(&rad_path, &refs, out, threads)?,
+        (None, true) => write_rad_as_bam_to(&rad_path, &refs, std::io::stdout(), threads)?,
+        (Some(out), false) => {
+            let f =
+                File::create(out).with_context(|| format!("could not create {}", out.display()))?;
+            let mut sam_out = BufWriter::new(f);
+            let n = write_rad_as_sam(&rad_path, &refs, &mut sam_out)?;
+            sam_out.flush()?;
+            n
+        }
+        (None, false) => {
+            let mut sam_out = BufWriter::new(std::io::stdout().lock());
+            let n = write_rad_as_sam(&rad_path, &refs, &mut sam_out)?;
+            sam_out.flush()?;
+            n
+        }
+    };
+    Ok(num_records)
+}
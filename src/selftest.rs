@@ -0,0 +1,206 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tracing::info;
+
+use crate::piscem_commands::SelftestOpts;
+use crate::rad::{self, RadReader};
+use crate::{inspect, BuildConfig, LogLevel, MapBulkConfig, RunOptions};
+
+/// The miniature reference of the self-test: three random transcripts.
+const SELFTEST_REFS: &str = include_str!("../data/selftest/refs.fa");
+
+/// The reads of the self-test, each named `<transcript>_<i>_<fw|rc>` after the
+/// transcript and strand it was drawn from, or `unmapped_<i>` if it is random.
+const SELFTEST_READS: &str = include_str!("../data/selftest/reads.fq");
+
+/// The number of reads on the forward and reverse strand of each transcript.
+type StrandCounts = BTreeMap<String, (u64, u64)>;
+
+/// The number of reads drawn from the forward and reverse strand of each
+/// transcript, from the names of the self-test reads.
+fn expected_counts() -> StrandCounts {
+    let mut counts = StrandCounts::new();
+    for name in SELFTEST_READS
+        .lines()
+        .step_by(4)
+        .filter_map(|l| l.strip_prefix('@'))
+    {
+        let mut parts = name.rsplitn(3, '_');
+        let (Some(ori), Some(_), Some(tx)) = (parts.next(), parts.next(), parts.next()) else {
+            continue;
+        };
+        if tx == "unmapped" {
+            continue;
+        }
+        let c = counts.entry(tx.to_string()).or_default();
+        if ori == "fw" {
+            c.0 += 1;
+        } else {
+            c.1 += 1;
+        }
+    }
+    counts
+}
+
+/// The number of records of the bulk RAD file at `rad_path` mapped uniquely to the
+/// forward and reverse strand of each reference, along with the number of records
+/// mapped to more than one reference.
+fn observed_counts(rad_path: &Path) -> Result<(StrandCounts, u64)> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    let mut counts = StrandCounts::new();
+    let mut num_multi = 0_u64;
+    while let Some(rec) = rad.next_record()? {
+        let mut hits = rec.alns.iter().filter_map(|a| a.first()?.as_u64());
+        let Some(first) = hits.next() else {
+            continue;
+        };
+        let (ref_id, fw) = rad::decode_ref_id(first);
+        if hits.any(|h| rad::decode_ref_id(h).0 != ref_id) {
+            num_multi += 1;
+            continue;
+        }
+        let c = counts
+            .entry(rad.header.ref_names[ref_id as usize].clone())
+            .or_default();
+        if fw {
+            c.0 += 1;
+        } else {
+            c.1 += 1;
+        }
+    }
+    Ok((counts, num_multi))
+}
+
+/// The outcome of one check of the self-test.
+fn check(description: &str, passed: bool, details: impl Into<String>) -> Value {
+    json!({ "description": description, "passed": passed, "details": details.into() })
+}
+
+/// Build an index over the bundled miniature reference, map the bundled reads
+/// against it with `map-bulk`, and check the index and the mappings: every read
+/// drawn from a transcript must map uniquely to that transcript, on the strand
+/// it was drawn from, and the random reads must not map. The index and mappings
+/// are written to a temporary directory, or to `--work-dir` (where they are
+/// kept) if it is given. Returns the outcome of every check; the checks that
+/// depend on a step that failed are not performed.
+pub(crate) fn selftest(opts: &SelftestOpts, log_level: LogLevel) -> Result<Value> {
+    let tmp_dir;
+    let dir: PathBuf = match opts.work_dir {
+        Some(ref d) => {
+            std::fs::create_dir_all(d)
+                .with_context(|| format!("could not create {}", d.display()))?;
+            d.clone()
+        }
+        None => {
+            tmp_dir = TempDir::new()?;
+            tmp_dir.path().to_path_buf()
+        }
+    };
+    let refs_path = dir.join("selftest_refs.fa");
+    let reads_path = dir.join("selftest_reads.fq");
+    std::fs::write(&refs_path, SELFTEST_REFS)?;
+    std::fs::write(&reads_path, SELFTEST_READS)?;
+    let index = dir.join("selftest_index");
+    let output = dir.join("selftest_map");
+    let run = RunOptions {
+        log_level,
+        dry_run: false,
+    };
+    let mut checks = Vec::new();
+    let mut summary = None;
+
+    info!("building the self-test index in {}.", dir.display());
+    let mut config = BuildConfig::new(&[refs_path.display().to_string()], &index, 1);
+    config.klen = 31;
+    config.mlen = 19;
+    let build = crate::build_index(config, &run);
+    checks.push(match build {
+        Ok(_) => check("index build", true, "the index was built"),
+        Err(ref e) => check("index build", false, format!("{:#}", e)),
+    });
+
+    if build.is_ok() {
+        let index = index.display().to_string();
+        let failed = inspect::validate_index(&index)
+            .into_iter()
+            .filter(|c| !c.passed)
+            .map(|c| format!("{}: {}", c.description, c.details))
+            .collect::<Vec<String>>();
+        checks.push(check(
+            "index integrity",
+            failed.is_empty(),
+            if failed.is_empty() {
+                String::from("all checks of validate-index passed")
+            } else {
+                failed.join("; ")
+            },
+        ));
+
+        info!("mapping the self-test reads.");
+        let mut config =
+            MapBulkConfig::unpaired(&index, &[reads_path.display().to_string()], &output);
+        config.threads = 1;
+        let map = crate::map_bulk(config, &run);
+        checks.push(match map {
+            Ok(_) => check("mapping", true, "the reads were mapped"),
+            Err(ref e) => check("mapping", false, format!("{:#}", e)),
+        });
+        summary = map.ok();
+    }
+
+    if let Some(summary) = summary {
+        let expected = expected_counts();
+        let num_expected = expected.values().map(|(f, r)| f + r).sum::<u64>();
+        let num_reads = SELFTEST_READS.lines().count() as u64 / 4;
+        checks.push(check(
+            "mapping summary",
+            summary.num_reads == Some(num_reads) && summary.num_mapped == Some(num_expected),
+            format!(
+                "{} of {} reads mapped (expected {} of {})",
+                summary
+                    .num_mapped
+                    .map_or(String::from("unknown"), |n| n.to_string()),
+                summary
+                    .num_reads
+                    .map_or(String::from("unknown"), |n| n.to_string()),
+                num_expected,
+                num_reads
+            ),
+        ));
+
+        let (observed, num_multi) = observed_counts(&rad::bulk_rad_path(&output))?;
+        for (tx, exp) in &expected {
+            let obs = observed.get(tx).copied().unwrap_or_default();
+            checks.push(check(
+                &format!("mappings to {}", tx),
+                obs == *exp,
+                format!(
+                    "{} forward and {} reverse-complement reads (expected {} and {})",
+                    obs.0, obs.1, exp.0, exp.1
+                ),
+            ));
+        }
+        let num_unexpected = observed
+            .iter()
+            .filter(|(tx, _)| !expected.contains_key(*tx))
+            .map(|(_, (f, r))| f + r)
+            .sum::<u64>()
+            + num_multi;
+        checks.push(check(
+            "spurious mappings",
+            num_unexpected == 0,
+            format!(
+                "{} reads mapped to more than one transcript or to an unexpected one",
+                num_unexpected
+            ),
+        ));
+    }
+
+    Ok(json!({
+        "work_dir": opts.work_dir,
+        "checks": checks,
+    }))
+}
@@ -0,0 +1,209 @@
+use anyhow::{anyhow, bail, Result};
+use std::os::raw::c_int;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Mutex, Once, OnceLock};
+use std::thread;
+use tracing::{error, info, warn};
+
+use crate::exit_code::ExitKind;
+
+/// The signals upon which the build or mapping run is shut down.
+const SIGNALS: [c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// What is done to shut down cleanly upon a signal.
+enum Cleanup {
+    /// remove incomplete (or temporary) files or directories
+    Remove(Vec<PathBuf>),
+    /// run a hook, e.g. to record the statistics of the run so far
+    Run(Box<dyn Fn() + Send>),
+}
+
+/// The cleanups registered by the live `OnSignal` guards, by guard id.
+static ON_SIGNAL: Mutex<Vec<(u64, Cleanup)>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The dispositions of `SIGNALS` before the handlers were installed, restored
+/// once no guard is alive any more.
+static PREVIOUS_ACTIONS: Mutex<Vec<(c_int, libc::sigaction)>> = Mutex::new(Vec::new());
+
+/// The write end of the pipe through which the signal handler hands the signal
+/// to the thread that shuts down, as the handler itself may only make
+/// async-signal-safe calls.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+static START_WATCHER: Once = Once::new();
+
+/// The function called right before the process exits upon a signal.
+static EXIT_HOOK: OnceLock<fn()> = OnceLock::new();
+
+/// Whether a signal was received, so that the process is shutting down.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(sig: c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+    let fd = SIGNAL_PIPE.load(Ordering::Relaxed);
+    let b = sig as u8;
+    unsafe { libc::write(fd, &b as *const u8 as *const libc::c_void, 1) };
+}
+
+fn signal_name(sig: c_int) -> &'static str {
+    match sig {
+        libc::SIGINT => "SIGINT",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGHUP => "SIGHUP",
+        _ => "a signal",
+    }
+}
+
+/// Wait for the signals written to the pipe `fd` by `on_signal`; upon one, run
+/// the registered cleanups, latest first, and exit with the code of
+/// `ExitKind::Interrupted`. The C++ stages cannot be stopped from the outside,
+/// so they are stopped by the exit. The default dispositions are restored first,
+/// so that a second signal terminates the process right away.
+fn watch(fd: c_int) {
+    loop {
+        let mut b = 0_u8;
+        let n = unsafe { libc::read(fd, &mut b as *mut u8 as *mut libc::c_void, 1) };
+        if n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+            continue;
+        }
+        if n <= 0 {
+            return;
+        }
+        let sig = c_int::from(b);
+        for s in SIGNALS {
+            unsafe { libc::signal(s, libc::SIG_DFL) };
+        }
+        warn!(
+            "received {}; shutting down (send it again to terminate right away).",
+            signal_name(sig)
+        );
+        let cleanups = ON_SIGNAL
+            .lock()
+            .map(|mut c| std::mem::take(&mut *c))
+            .unwrap_or_default();
+        for (_, cleanup) in cleanups.iter().rev() {
+            match cleanup {
+                Cleanup::Remove(paths) => {
+                    for p in paths.iter().filter(|p| p.exists()) {
+                        let removed = if p.is_dir() {
+                            std::fs::remove_dir_all(p)
+                        } else {
+                            std::fs::remove_file(p)
+                        };
+                        match removed {
+                            Ok(()) => info!("removed {}.", p.display()),
+                            Err(e) => warn!("could not remove {}: {}", p.display(), e),
+                        }
+                    }
+                }
+                Cleanup::Run(hook) => hook(),
+            }
+        }
+        error!("interrupted by {}.", signal_name(sig));
+        if let Some(hook) = EXIT_HOOK.get() {
+            hook();
+        }
+        std::process::exit(i32::from(ExitKind::Interrupted.code()));
+    }
+}
+
+/// If a signal was received, block the calling thread until the process exits,
+/// so that it does not go on (e.g. with the next phase of a build, or by dropping
+/// the guards of its outputs) while they are cleaned up. This is called once a
+/// C++ stage returns, which it may do early when a signal interrupts it.
+pub(crate) fn wait_if_interrupted() {
+    while INTERRUPTED.load(Ordering::Relaxed) {
+        thread::park();
+    }
+}
+
+/// Set the function called right before the process exits upon a signal, after
+/// the registered cleanups; it can only be set once.
+pub(crate) fn set_exit_hook(hook: fn()) {
+    let _ = EXIT_HOOK.set(hook);
+}
+
+/// Install `on_signal` as the handler of `SIGNALS`, recording their previous
+/// dispositions.
+fn install_handlers() -> Result<()> {
+    START_WATCHER.call_once(|| {
+        let mut fds = [0 as c_int; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } == 0 {
+            SIGNAL_PIPE.store(fds[1], Ordering::Relaxed);
+            thread::spawn(move || watch(fds[0]));
+        }
+    });
+    if SIGNAL_PIPE.load(Ordering::Relaxed) < 0 {
+        bail!("could not create the pipe of the signal handler");
+    }
+    let mut previous = PREVIOUS_ACTIONS
+        .lock()
+        .map_err(|_| anyhow!("the signal handler state is poisoned"))?;
+    for sig in SIGNALS {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = on_signal as extern "C" fn(c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        let mut old: libc::sigaction = unsafe { std::mem::zeroed() };
+        if unsafe { libc::sigaction(sig, &action, &mut old) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        previous.push((sig, old));
+    }
+    Ok(())
+}
+
+/// Restore the dispositions of `SIGNALS` from before `install_handlers`.
+fn restore_handlers() {
+    if let Ok(mut previous) = PREVIOUS_ACTIONS.lock() {
+        for (sig, old) in previous.drain(..) {
+            unsafe { libc::sigaction(sig, &old, std::ptr::null_mut()) };
+        }
+    }
+}
+
+/// A cleanup that is performed if the process receives SIGINT, SIGTERM or
+/// SIGHUP while this guard is alive, before it exits with the code of
+/// `ExitKind::Interrupted`. The signal handlers are only installed while any such
+/// guard exists, so that embedding processes (e.g. Python) get their own handlers
+/// back afterwards. The cleanup is not performed when the guard is dropped; a
+/// command that returns, successfully or not, is left to clean up after itself.
+pub(crate) struct OnSignal {
+    id: u64,
+}
+
+impl OnSignal {
+    fn register(cleanup: Cleanup) -> Result<Self> {
+        let mut cleanups = ON_SIGNAL
+            .lock()
+            .map_err(|_| anyhow!("the signal handler state is poisoned"))?;
+        if cleanups.is_empty() {
+            install_handlers()?;
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        cleanups.push((id, cleanup));
+        Ok(Self { id })
+    }
+
+    /// Remove the files or directories `paths` (those that exist) upon a signal.
+    pub fn remove(paths: &[PathBuf]) -> Result<Self> {
+        Self::register(Cleanup::Remove(paths.to_vec()))
+    }
+
+    /// Run `hook` upon a signal.
+    pub fn run(hook: impl Fn() + Send + 'static) -> Result<Self> {
+        Self::register(Cleanup::Run(Box::new(hook)))
+    }
+}
+
+impl Drop for OnSignal {
+    fn drop(&mut self) {
+        let Ok(mut cleanups) = ON_SIGNAL.lock() else {
+            return;
+        };
+        cleanups.retain(|(id, _)| *id != self.id);
+        if cleanups.is_empty() {
+            restore_handlers();
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::rad::{RadReader, RadWriter, TagDesc, TagType, TagValue};
+
+/// The name of the read-level tag in which the splicing status of every read is
+/// recorded when `--usa-tag` is given: 0 if it maps only to spliced references,
+/// 1 if it maps only to unspliced references, and 2 otherwise (ambiguous).
+pub(crate) const USA_TAG: &str = "us";
+
+/// The splicing status of a reference, as given in the 3rd column
+/// of a USA mode t2g file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SpliceStatus {
+    Spliced,
+    Unspliced,
+    Ambiguous,
+}
+
+/// Read a 3-column (reference, gene, splicing status) t2g file, as produced
+/// along with splici or spliceu references, where the status is one of
+/// `S`, `U` or `A`.
+fn read_usa_t2g(t2g: &Path) -> Result<HashMap<String, SpliceStatus>> {
+    let reader = BufReader::new(
+        File::open(t2g).with_context(|| format!("could not open t2g file {}", t2g.display()))?,
+    );
+    let mut status = HashMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 3 {
+            bail!(
+                "line {} of t2g file {} has {} columns, but a USA mode t2g file must have 3.",
+                i + 1,
+                t2g.display(),
+                fields.len()
+            );
+        }
+        let s = match fields[2].trim() {
+            "S" | "s" => SpliceStatus::Spliced,
+            "U" | "u" => SpliceStatus::Unspliced,
+            "A" | "a" => SpliceStatus::Ambiguous,
+            x => bail!(
+                "unknown splicing status {} on line {} of t2g file {}; expected S, U or A.",
+                x,
+                i + 1,
+                t2g.display()
+            ),
+        };
+        status.insert(fields[0].to_string(), s);
+    }
+    Ok(status)
+}
+
+/// Counts (of reads or equivalence classes) by the splicing status
+/// of the references they map to.
+#[derive(Debug, Default)]
+pub(crate) struct UsaTally {
+    /// mapping only to spliced references
+    pub spliced: u64,
+    /// mapping only to unspliced (intronic) references
+    pub unspliced: u64,
+    /// mapping to both, or to ambiguous references
+    pub ambiguous: u64,
+}
+
+impl UsaTally {
+    fn add(&mut self, has_s: bool, has_u: bool) {
+        match (has_s, has_u) {
+            (true, false) => self.spliced += 1,
+            (false, true) => self.unspliced += 1,
+            (true, true) => self.ambiguous += 1,
+            (false, false) => {}
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "spliced": self.spliced,
+            "unspliced": self.unspliced,
+            "ambiguous": self.ambiguous,
+        })
+    }
+}
+
+/// The splicing status summary of a mapping run.
+#[derive(Debug, Default)]
+pub(crate) struct UsaCounts {
+    /// mapped reads, by splicing status
+    pub reads: UsaTally,
+    /// distinct equivalence classes (sets of references hit by a read), by
+    /// splicing status
+    pub ecs: UsaTally,
+}
+
+/// Determine the splicing status of every reference in the RAD file being read
+/// by `rad`, using the t2g file `t2g`.
+fn ref_statuses<R: std::io::Read>(
+    rad: &RadReader<R>,
+    t2g: &Path,
+) -> Result<Vec<Option<SpliceStatus>>> {
+    let status = read_usa_t2g(t2g)?;
+    let ref_status = rad
+        .header
+        .ref_names
+        .iter()
+        .map(|n| status.get(n).copied())
+        .collect::<Vec<Option<SpliceStatus>>>();
+    let num_missing = ref_status.iter().filter(|s| s.is_none()).count();
+    if num_missing > 0 {
+        warn!(
+            "{} of {} references in the index do not appear in t2g file {}; they will be treated as ambiguous.",
+            num_missing,
+            ref_status.len(),
+            t2g.display()
+        );
+    }
+    Ok(ref_status)
+}
+
+/// Classify every mapped read, and every distinct equivalence class, in the
+/// single-cell RAD file `rad_path` as spliced, unspliced or ambiguous according
+/// to the references it maps to, using the splicing status of each reference
+/// given in the USA mode t2g file `t2g`. If `tag_reads`, the RAD file is also
+/// rewritten in place, recording the status of every read in the read-level
+/// tag `us` (see `USA_TAG`).
+pub(crate) fn count_usa_reads(rad_path: &Path, t2g: &Path, tag_reads: bool) -> Result<UsaCounts> {
+    let mut rad = RadReader::from_path(rad_path)?;
+    let ref_status = ref_statuses(&rad, t2g)?;
+
+    let tmp_path = PathBuf::from(rad_path.to_string_lossy().into_owned() + ".tmp");
+    let mut writer = if tag_reads {
+        let mut header = rad.header.clone();
+        if header.read_tags.iter().any(|t| t.name == USA_TAG) {
+            bail!(
+                "RAD file {} already records splicing statuses.",
+                rad_path.display()
+            );
+        }
+        header.read_tags.push(TagDesc {
+            name: USA_TAG.to_string(),
+            typ: TagType::U8,
+        });
+        Some(RadWriter::create(&tmp_path, &header)?)
+    } else {
+        None
+    };
+
+    let mut counts = UsaCounts::default();
+    let mut seen_ecs = HashSet::new();
+    while let Some(mut rec) = rad.next_record()? {
+        let (mut has_s, mut has_u) = (false, false);
+        let mut ec = rec.ref_ids().collect::<Vec<u32>>();
+        for r in &ec {
+            match ref_status.get(*r as usize).copied().flatten() {
+                Some(SpliceStatus::Spliced) => has_s = true,
+                Some(SpliceStatus::Unspliced) => has_u = true,
+                _ => {
+                    has_s = true;
+                    has_u = true;
+                }
+            }
+        }
+        counts.reads.add(has_s, has_u);
+        if let Some(ref mut writer) = writer {
+            let status = match (has_s, has_u) {
+                (true, false) => 0,
+                (false, true) => 1,
+                _ => 2,
+            };
+            rec.read_tags.push(TagValue::U8(status));
+            writer.write_record(&rec)?;
+        }
+
+        ec.sort_unstable();
+        ec.dedup();
+        if seen_ecs.insert(ec) {
+            counts.ecs.add(has_s, has_u);
+        }
+    }
+    if let Some(writer) = writer {
+        writer.finish()?;
+        std::fs::rename(&tmp_path, rad_path)?;
+        info!(
+            "recorded the splicing status of every read in the read-level tag {}.",
+            USA_TAG
+        );
+    }
+    info!(
+        "mapped reads by splicing status: {} spliced, {} unspliced, {} ambiguous.",
+        counts.reads.spliced, counts.reads.unspliced, counts.reads.ambiguous
+    );
+    info!(
+        "equivalence classes by splicing status: {} spliced, {} unspliced, {} ambiguous.",
+        counts.ecs.spliced, counts.ecs.unspliced, counts.ecs.ambiguous
+    );
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rad::{RadHeader, RadRecord};
+
+    #[test]
+    fn records_the_splicing_status_of_every_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let t2g = dir.path().join("t2g.tsv");
+        std::fs::write(&t2g, "tx1\tg1\tS\ntx1-I\tg1\tU\n").unwrap();
+        let rad_path = dir.path().join("map.rad");
+        let header = RadHeader {
+            is_paired: false,
+            ref_names: vec!["tx1".to_string(), "tx1-I".to_string()],
+            num_chunks: 0,
+            file_tags: vec![],
+            read_tags: vec![TagDesc {
+                name: "b".to_string(),
+                typ: TagType::U32,
+            }],
+            aln_tags: vec![TagDesc {
+                name: "ref".to_string(),
+                typ: TagType::U32,
+            }],
+            file_tag_values: vec![],
+        };
+        let mut writer = RadWriter::create(&rad_path, &header).unwrap();
+        for refs in [&[0][..], &[1], &[0, 1], &[0]] {
+            writer
+                .write_record(&RadRecord {
+                    read_tags: vec![TagValue::U32(0)],
+                    alns: refs.iter().map(|r| vec![TagValue::U32(*r)]).collect(),
+                })
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let counts = count_usa_reads(&rad_path, &t2g, true).unwrap();
+        assert_eq!(
+            (
+                counts.reads.spliced,
+                counts.reads.unspliced,
+                counts.reads.ambiguous
+            ),
+            (2, 1, 1)
+        );
+        assert_eq!(
+            (
+                counts.ecs.spliced,
+                counts.ecs.unspliced,
+                counts.ecs.ambiguous
+            ),
+            (1, 1, 1)
+        );
+
+        let mut rad = RadReader::from_path(&rad_path).unwrap();
+        assert_eq!(rad.header.read_tags[1].name, USA_TAG);
+        let mut statuses = Vec::new();
+        while let Some(rec) = rad.next_record().unwrap() {
+            statuses.push(rec.read_tags[1].as_u64().unwrap());
+        }
+        assert_eq!(statuses, vec![0, 1, 2, 0]);
+        // the statuses can't be recorded twice
+        assert!(count_usa_reads(&rad_path, &t2g, true).is_err());
+    }
+}
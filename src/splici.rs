@@ -0,0 +1,424 @@
+use anyhow::{bail, Context, Result};
+use needletail::parse_fastx_file;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// A transcript, as described by the exon records of a GTF file.
+struct Transcript {
+    id: String,
+    gene_id: String,
+    /// whether the transcript is on the reverse strand
+    reverse: bool,
+    /// the 1-based, closed intervals of the exons
+    exons: Vec<(u64, u64)>,
+}
+
+/// Extract the value of the attribute `key` from the attribute column of a
+/// GTF record, e.g. `gene_id "ENSG00000223972"; transcript_id "ENST00000456328";`.
+fn gtf_attribute<'a>(attrs: &'a str, key: &str) -> Option<&'a str> {
+    attrs.split(';').find_map(|a| {
+        let (k, v) = a.trim().split_once(' ')?;
+        (k == key).then(|| v.trim().trim_matches('"'))
+    })
+}
+
+/// Read the exons of every transcript in the GTF file `gtf`, grouped by the
+/// name of the reference sequence (chromosome) they lie on. Transcripts are
+/// kept in the order in which they first appear.
+fn read_gtf_transcripts(gtf: &Path) -> Result<HashMap<String, Vec<Transcript>>> {
+    let reader = BufReader::new(
+        File::open(gtf).with_context(|| format!("could not open GTF file {}", gtf.display()))?,
+    );
+    let mut by_chrom = HashMap::<String, Vec<Transcript>>::new();
+    // (chromosome, index within the chromosome) of each transcript
+    let mut index = HashMap::<String, (String, usize)>::new();
+    for (line_num, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            bail!(
+                "line {} of GTF file {} has {} columns, but 9 are expected.",
+                line_num + 1,
+                gtf.display(),
+                fields.len()
+            );
+        }
+        if fields[2] != "exon" {
+            continue;
+        }
+        let parse_pos = |s: &str| {
+            s.parse::<u64>().with_context(|| {
+                format!(
+                    "invalid position {} on line {} of GTF file {}",
+                    s,
+                    line_num + 1,
+                    gtf.display()
+                )
+            })
+        };
+        let (start, end) = (parse_pos(fields[3])?, parse_pos(fields[4])?);
+        let (tid, gid) = match (
+            gtf_attribute(fields[8], "transcript_id"),
+            gtf_attribute(fields[8], "gene_id"),
+        ) {
+            (Some(t), Some(g)) => (t, g),
+            _ => bail!(
+                "the exon on line {} of GTF file {} lacks a transcript_id or gene_id attribute.",
+                line_num + 1,
+                gtf.display()
+            ),
+        };
+        let chrom = fields[0];
+        let (tx_chrom, i) = index.entry(tid.to_string()).or_insert_with(|| {
+            let txps = by_chrom.entry(chrom.to_string()).or_default();
+            txps.push(Transcript {
+                id: tid.to_string(),
+                gene_id: gid.to_string(),
+                reverse: fields[6] == "-",
+                exons: Vec::new(),
+            });
+            (chrom.to_string(), txps.len() - 1)
+        });
+        if tx_chrom != chrom {
+            bail!(
+                "transcript {} has exons on more than one reference sequence ({} and {}).",
+                tid,
+                tx_chrom,
+                chrom
+            );
+        }
+        by_chrom.get_mut(chrom).unwrap()[*i]
+            .exons
+            .push((start, end));
+    }
+    if by_chrom.is_empty() {
+        bail!("GTF file {} does not contain any exons.", gtf.display());
+    }
+    for t in by_chrom.values_mut().flatten() {
+        t.exons.sort_unstable();
+    }
+    Ok(by_chrom)
+}
+
+/// The exons of all transcripts in the GTF file `gtf`, grouped by the name of the
+/// reference sequence they lie on, as sorted and merged 1-based, closed intervals.
+pub(crate) fn read_gtf_exons(gtf: &Path) -> Result<HashMap<String, Vec<(u64, u64)>>> {
+    Ok(read_gtf_transcripts(gtf)?
+        .into_iter()
+        .map(|(chrom, txps)| {
+            let exons = txps.into_iter().flat_map(|t| t.exons).collect();
+            (chrom, merge_intervals(exons))
+        })
+        .collect())
+}
+
+/// Sort the 1-based, closed intervals `intervals` and merge those that overlap
+/// or are adjacent.
+fn merge_intervals(mut intervals: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    intervals.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(intervals.len());
+    for (s, e) in intervals {
+        match merged.last_mut() {
+            Some(last) if s <= last.1 + 1 => last.1 = last.1.max(e),
+            _ => merged.push((s, e)),
+        }
+    }
+    merged
+}
+
+pub(crate) fn reverse_complement(seq: &mut [u8]) {
+    seq.reverse();
+    for c in seq.iter_mut() {
+        *c = match *c {
+            b'A' | b'a' => b'T',
+            b'C' | b'c' => b'G',
+            b'G' | b'g' => b'C',
+            b'T' | b't' => b'A',
+            _ => b'N',
+        };
+    }
+}
+
+/// The sequence of the 1-based, closed interval (`start`, `end`) of `chrom`,
+/// clipped to the end of the sequence.
+fn subseq(chrom: &[u8], start: u64, end: u64) -> &[u8] {
+    let s = (start.max(1) - 1) as usize;
+    let e = (end as usize).min(chrom.len());
+    chrom.get(s..e).unwrap_or_default()
+}
+
+/// The augmented references that can be built.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AugmentedRefType {
+    /// spliced transcripts and (flanked) intronic regions
+    Splici,
+    /// spliced transcripts and unspliced genes
+    Spliceu,
+}
+
+impl std::str::FromStr for AugmentedRefType {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "splici" => Ok(Self::Splici),
+            "spliceu" => Ok(Self::Spliceu),
+            _ => bail!("unknown reference type {}; must be splici or spliceu", s),
+        }
+    }
+}
+
+impl std::fmt::Display for AugmentedRefType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Splici => write!(f, "splici"),
+            Self::Spliceu => write!(f, "spliceu"),
+        }
+    }
+}
+
+/// The path of the FASTA file written by `write_augmented_ref` for the stem
+/// `out_stem` and the reference type `ref_type`.
+pub(crate) fn augmented_ref_fasta_path(out_stem: &Path, ref_type: AugmentedRefType) -> PathBuf {
+    PathBuf::from(format!("{}.{}.fa", out_stem.to_string_lossy(), ref_type))
+}
+
+/// Build the augmented transcriptome of type `ref_type` from the genome FASTA
+/// file `genome` and the GTF file `gtf`, writing it next to the index with the
+/// stem `out_stem` (as `<out_stem>.<ref_type>.fa`) along with the 3-column
+/// (reference, gene, splicing status) t2g file (`<out_stem>.<ref_type>_t2g_3col.tsv`)
+/// and the 2-column (reference, gene) one (`<out_stem>.<ref_type>_t2g.tsv`).
+///
+/// The spliced transcripts are labeled `S` in the 3-column t2g file. For `splici`, the
+/// introns of all transcripts of a gene are merged, extended by `flank_len`
+/// bases on each side (so that reads overlapping exon-intron junctions are
+/// captured) and merged again; each resulting region is named after its gene
+/// (`<gene>-I`, `<gene>-I1`, ...). For `spliceu`, the whole gene body, from its
+/// first to its last exon, is named `<gene>-U`. Both are labeled `U` in the
+/// 3-column t2g file. Returns the path of the FASTA file.
+pub(crate) fn write_augmented_ref(
+    genome: &Path,
+    gtf: &Path,
+    ref_type: AugmentedRefType,
+    flank_len: u64,
+    out_stem: &Path,
+) -> Result<PathBuf> {
+    let mut by_chrom = read_gtf_transcripts(gtf)?;
+
+    let stem = out_stem.to_string_lossy().into_owned();
+    let fasta = augmented_ref_fasta_path(out_stem, ref_type);
+    let t2g = PathBuf::from(format!("{}.{}_t2g_3col.tsv", stem, ref_type));
+    let t2g_2col = PathBuf::from(format!("{}.{}_t2g.tsv", stem, ref_type));
+    if let Some(parent_path) = fasta.parent() {
+        if !parent_path.as_os_str().is_empty() && !parent_path.exists() {
+            std::fs::create_dir_all(parent_path)?;
+        }
+    }
+    let mut fasta_out = BufWriter::new(File::create(&fasta)?);
+    let mut t2g_out = BufWriter::new(File::create(&t2g)?);
+    let mut t2g_2col_out = BufWriter::new(File::create(&t2g_2col)?);
+
+    let (mut num_txps, mut num_unspliced) = (0_usize, 0_usize);
+    let mut reader = parse_fastx_file(genome)
+        .with_context(|| format!("could not open genome {}", genome.display()))?;
+    while let Some(rec) = reader.next() {
+        let rec = rec?;
+        let name = String::from_utf8_lossy(rec.id());
+        let name = name.split_whitespace().next().unwrap_or_default();
+        let txps = match by_chrom.remove(name) {
+            Some(t) => t,
+            None => continue,
+        };
+        let chrom = rec.seq();
+
+        // the genes in order of appearance, with their strand, extent and the
+        // introns of all their transcripts
+        let mut genes = Vec::<(String, bool, (u64, u64), Vec<(u64, u64)>)>::new();
+        let mut gene_index = HashMap::<String, usize>::new();
+        for t in &txps {
+            let mut seq = Vec::new();
+            for &(s, e) in &t.exons {
+                seq.extend_from_slice(subseq(&chrom, s, e));
+            }
+            if t.reverse {
+                reverse_complement(&mut seq);
+            }
+            writeln!(fasta_out, ">{}", t.id)?;
+            fasta_out.write_all(&seq)?;
+            writeln!(fasta_out)?;
+            writeln!(t2g_out, "{}\t{}\tS", t.id, t.gene_id)?;
+            writeln!(t2g_2col_out, "{}\t{}", t.id, t.gene_id)?;
+            num_txps += 1;
+
+            let extent = (t.exons[0].0, t.exons.iter().map(|e| e.1).max().unwrap());
+            let i = *gene_index.entry(t.gene_id.clone()).or_insert_with(|| {
+                genes.push((t.gene_id.clone(), t.reverse, extent, Vec::new()));
+                genes.len() - 1
+            });
+            let gene = &mut genes[i];
+            gene.2 = (gene.2 .0.min(extent.0), gene.2 .1.max(extent.1));
+            gene.3.extend(
+                t.exons
+                    .windows(2)
+                    .filter(|w| w[1].0 > w[0].1 + 1)
+                    .map(|w| (w[0].1 + 1, w[1].0 - 1)),
+            );
+        }
+
+        for (gene_id, reverse, extent, introns) in genes {
+            let regions = match ref_type {
+                AugmentedRefType::Spliceu => vec![extent],
+                AugmentedRefType::Splici => merge_intervals(
+                    merge_intervals(introns)
+                        .into_iter()
+                        .map(|(s, e)| (s.saturating_sub(flank_len).max(1), e + flank_len))
+                        .collect(),
+                ),
+            };
+            for (j, &(s, e)) in regions.iter().enumerate() {
+                let region_name = match (ref_type, j) {
+                    (AugmentedRefType::Spliceu, _) => format!("{}-U", gene_id),
+                    (AugmentedRefType::Splici, 0) => format!("{}-I", gene_id),
+                    (AugmentedRefType::Splici, j) => format!("{}-I{}", gene_id, j),
+                };
+                let mut seq = subseq(&chrom, s, e).to_vec();
+                if reverse {
+                    reverse_complement(&mut seq);
+                }
+                writeln!(fasta_out, ">{}", region_name)?;
+                fasta_out.write_all(&seq)?;
+                writeln!(fasta_out)?;
+                writeln!(t2g_out, "{}\t{}\tU", region_name, gene_id)?;
+                writeln!(t2g_2col_out, "{}\t{}", region_name, gene_id)?;
+                num_unspliced += 1;
+            }
+        }
+    }
+    fasta_out.flush()?;
+    t2g_out.flush()?;
+    t2g_2col_out.flush()?;
+
+    let num_missing: usize = by_chrom.values().map(|t| t.len()).sum();
+    if num_missing > 0 {
+        warn!(
+            "{} transcripts lie on {} reference sequences that are absent from the genome {}; they were skipped.",
+            num_missing,
+            by_chrom.len(),
+            genome.display()
+        );
+    }
+    if num_txps == 0 {
+        bail!(
+            "none of the transcripts in {} lie on the sequences of the genome {}.",
+            gtf.display(),
+            genome.display()
+        );
+    }
+    info!(
+        "wrote the {} reference ({} transcripts and {} {} sequences) to {}, and its t2g files to {} and {}.",
+        ref_type,
+        num_txps,
+        num_unspliced,
+        match ref_type {
+            AugmentedRefType::Splici => "intronic",
+            AugmentedRefType::Spliceu => "unspliced gene",
+        },
+        fasta.display(),
+        t2g.display(),
+        t2g_2col.display()
+    );
+    Ok(fasta)
+}
+
+/// The transcript id `id` without its version suffix (e.g. `ENST00000456328` for
+/// `ENST00000456328.2`).
+fn unversioned(id: &str) -> &str {
+    match id.rsplit_once('.') {
+        Some((base, v)) if !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()) => base,
+        _ => id,
+    }
+}
+
+/// Write the 3-column (reference, gene, splicing status) and 2-column (reference,
+/// gene) t2g files of the transcriptome in the FASTA files `refs`, annotated by
+/// the GTF file `gtf`, next to the index with the stem `out_stem` (as
+/// `<out_stem>.t2g_3col.tsv` and `<out_stem>.t2g.tsv`). Each reference is
+/// looked up among the transcripts of the GTF file by the first `|`-separated
+/// field of its name (as in GENCODE transcriptomes), with or without its version
+/// suffix, and is labeled `S`. References that are not annotated are mapped to
+/// themselves, as if they were genes of their own. Returns the paths of the
+/// 3-column and 2-column files.
+pub(crate) fn write_transcriptome_t2g(
+    refs: &[PathBuf],
+    gtf: &Path,
+    out_stem: &Path,
+) -> Result<(PathBuf, PathBuf)> {
+    let mut genes = HashMap::<String, String>::new();
+    for t in read_gtf_transcripts(gtf)?.into_values().flatten() {
+        genes
+            .entry(unversioned(&t.id).to_string())
+            .or_insert_with(|| t.gene_id.clone());
+        genes.insert(t.id, t.gene_id);
+    }
+
+    let stem = out_stem.to_string_lossy().into_owned();
+    let t2g = PathBuf::from(format!("{}.t2g_3col.tsv", stem));
+    let t2g_2col = PathBuf::from(format!("{}.t2g.tsv", stem));
+    if let Some(parent_path) = t2g.parent() {
+        if !parent_path.as_os_str().is_empty() && !parent_path.exists() {
+            std::fs::create_dir_all(parent_path)?;
+        }
+    }
+    let mut t2g_out = BufWriter::new(File::create(&t2g)?);
+    let mut t2g_2col_out = BufWriter::new(File::create(&t2g_2col)?);
+    let (mut num_refs, mut num_unannotated) = (0_usize, 0_usize);
+    for path in refs {
+        let mut reader = parse_fastx_file(path)
+            .with_context(|| format!("could not open reference {}", path.display()))?;
+        while let Some(rec) = reader.next() {
+            let rec = rec?;
+            let name = String::from_utf8_lossy(rec.id());
+            let name = name.split_whitespace().next().unwrap_or_default();
+            let id = name.split('|').next().unwrap_or_default();
+            let gene = match genes.get(id).or_else(|| genes.get(unversioned(id))) {
+                Some(g) => g.as_str(),
+                None => {
+                    num_unannotated += 1;
+                    name
+                }
+            };
+            writeln!(t2g_out, "{}\t{}\tS", name, gene)?;
+            writeln!(t2g_2col_out, "{}\t{}", name, gene)?;
+            num_refs += 1;
+        }
+    }
+    t2g_out.flush()?;
+    t2g_2col_out.flush()?;
+
+    if num_unannotated == num_refs {
+        bail!(
+            "none of the references are transcripts of the GTF file {}.",
+            gtf.display()
+        );
+    }
+    if num_unannotated > 0 {
+        warn!(
+            "{} of the {} references are not transcripts of the GTF file {}; they are mapped to themselves in the t2g files.",
+            num_unannotated,
+            num_refs,
+            gtf.display()
+        );
+    }
+    info!(
+        "wrote the t2g files of the {} references to {} and {}.",
+        num_refs,
+        t2g.display(),
+        t2g_2col.display()
+    );
+    Ok((t2g, t2g_2col))
+}
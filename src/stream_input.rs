@@ -0,0 +1,46 @@
+use anyhow::{bail, Result};
+use std::os::unix::fs::FileTypeExt;
+
+use crate::remote_reads;
+
+/// The read file name that stands for standard input.
+pub(crate) const STDIN_NAME: &str = "-";
+
+/// The path through which the mapper reads standard input.
+const STDIN_PATH: &str = "/dev/stdin";
+
+/// Whether the read file `f` is a stream that can only be read once, i.e.
+/// standard input, a named pipe (such as those created by process substitution)
+/// or a URL.
+pub(crate) fn is_stream(f: &str) -> bool {
+    f == STDIN_NAME
+        || f == STDIN_PATH
+        || remote_reads::is_url(f)
+        || std::fs::metadata(f)
+            .map(|m| m.file_type().is_fifo() || m.file_type().is_char_device())
+            .unwrap_or(false)
+}
+
+/// Check that every read file in `lists` either exists, is a URL or is `-`, and replace
+/// `-` with the path through which the mapper reads standard input. Since
+/// standard input can only be consumed once, at most one read file may be `-`.
+pub(crate) fn resolve_read_inputs<'a>(
+    lists: impl IntoIterator<Item = &'a mut Vec<String>>,
+) -> Result<()> {
+    let mut num_stdin = 0_usize;
+    for f in lists.into_iter().flatten() {
+        if f == STDIN_NAME {
+            num_stdin += 1;
+            *f = String::from(STDIN_PATH);
+        } else if !remote_reads::is_url(f) && std::fs::metadata(&*f).is_err() {
+            bail!("the read file {} does not exist.", f);
+        }
+    }
+    if num_stdin > 1 {
+        bail!(
+            "{} read files were given as `-`, but at most one can be read from standard input.",
+            num_stdin
+        );
+    }
+    Ok(())
+}